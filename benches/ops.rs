@@ -4,6 +4,7 @@ extern crate test;
 
 use merkdb::owner::Owner;
 use merkdb::test_utils::*;
+use merkdb::tree::{node_hash, node_hashes, HashPool, Hasher, NodeHashInput};
 use test::Bencher;
 
 #[bench]
@@ -65,3 +66,37 @@ fn update_1m_10k_rand_memonly(b: &mut Bencher) {
         i = (i + 1) % (initial_size / batch_size);
     });
 }
+
+fn node_hash_inputs(count: u32) -> Vec<NodeHashInput> {
+    (0..count)
+        .map(|i| {
+            let mut kv = [0; 32];
+            kv[..4].copy_from_slice(&i.to_be_bytes());
+            (kv, [1; 32], [2; 32])
+        })
+        .collect()
+}
+
+#[bench]
+fn node_hash_10k(b: &mut Bencher) {
+    let inputs = node_hash_inputs(10_000);
+    b.iter(|| {
+        inputs
+            .iter()
+            .map(|(kv, left, right)| node_hash::<Hasher>(kv, left, right))
+            .collect::<Vec<_>>()
+    });
+}
+
+#[bench]
+fn node_hashes_10k(b: &mut Bencher) {
+    let inputs = node_hash_inputs(10_000);
+    b.iter(|| node_hashes(&inputs, None));
+}
+
+#[bench]
+fn node_hashes_10k_pool_4(b: &mut Bencher) {
+    let inputs = node_hash_inputs(10_000);
+    let pool = HashPool::new(4);
+    b.iter(|| node_hashes(&inputs, Some(&pool)));
+}
@@ -0,0 +1,13 @@
+fn main() {
+    // the messages of `proofs::proto` are generated from the schema, compiled
+    // with protox so no `protoc` needs to be installed
+    #[cfg(feature = "proto")]
+    {
+        println!("cargo:rerun-if-changed=proto/merk.proto");
+        let descriptors = protox::compile(["proto/merk.proto"], ["proto"])
+            .expect("Failed to compile proto/merk.proto");
+        prost_build::Config::new()
+            .compile_fds(descriptors)
+            .expect("Failed to generate protobuf messages");
+    }
+}
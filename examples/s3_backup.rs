@@ -0,0 +1,100 @@
+//! Backs up a store to S3 and restores it again, using the AWS CLI (which must
+//! be installed and configured) to transfer objects.
+//!
+//!     cargo run --example s3_backup -- <store path> s3://<bucket>/<prefix> <restore path>
+
+use std::env;
+use std::io::{self, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use merkdb::backup::{restore_from_backup, BackupSink, BackupSource};
+use merkdb::{Error, Merk, Result};
+
+/// Stores backup objects under a prefix of an S3 bucket.
+struct S3Backup {
+    url: String,
+}
+
+impl S3Backup {
+    fn object_url(&self, name: &str) -> String {
+        format!("{}/{}", self.url.trim_end_matches('/'), name)
+    }
+}
+
+fn check_status(child: &mut Child) -> Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::IO(io::Error::new(
+            io::ErrorKind::Other,
+            format!("aws s3 cp failed: {}", status),
+        )));
+    }
+    Ok(())
+}
+
+impl BackupSink for S3Backup {
+    fn put(&mut self, name: &str, data: &mut dyn Read) -> Result<()> {
+        // `aws s3 cp -` uploads from stdin, using a multipart upload for large
+        // objects, so files are streamed rather than read into memory
+        let mut child = Command::new("aws")
+            .args(["s3", "cp", "-", &self.object_url(name)].iter())
+            .stdin(Stdio::piped())
+            .spawn()?;
+        io::copy(data, child.stdin.as_mut().unwrap())?;
+        drop(child.stdin.take());
+        check_status(&mut child)
+    }
+}
+
+/// Reads an object from the output of `aws s3 cp`, checking that it
+/// succeeded once the output ends.
+struct Download {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            check_status(&mut self.child)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        }
+        Ok(n)
+    }
+}
+
+impl BackupSource for S3Backup {
+    fn get(&mut self, name: &str) -> Result<Box<dyn Read + '_>> {
+        let mut child = Command::new("aws")
+            .args(["s3", "cp", &self.object_url(name), "-"].iter())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Box::new(Download { child, stdout }))
+    }
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        eprintln!("usage: s3_backup <store path> s3://<bucket>/<prefix> <restore path>");
+        std::process::exit(1);
+    }
+
+    let merk = Merk::open(&args[1])?;
+    let backup = merk.start_backup(format!("{}.backup", args[1]))?;
+    let mut s3 = S3Backup {
+        url: args[2].clone(),
+    };
+    let manifest = backup.write_files(&mut s3)?;
+    println!(
+        "backed up {} objects with root hash {}",
+        manifest.objects.len(),
+        hex::encode(manifest.root_hash)
+    );
+
+    let restored = restore_from_backup(&mut s3, &args[3])?;
+    println!("restored root hash {}", hex::encode(restored.root_hash()));
+    Ok(())
+}
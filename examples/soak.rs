@@ -0,0 +1,241 @@
+//! Runs a mixed workload against a store for a long time, killing it with
+//! SIGKILL at random points and checking after each restart that every
+//! acknowledged commit survived, then finishes with a full verification of
+//! the store by restoring it from its chunks.
+//!
+//!     cargo run --release --example soak -- <store path> [seconds] [seed]
+//!
+//! The workload runs in a child process (this binary, started with `worker`
+//! as its first argument), which reports each commit on its stdout once
+//! `apply` has returned. After a kill, the reopened store must be at the last
+//! reported commit, or at the one after it if that commit was being written
+//! when the worker was killed.
+
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use merkdb::proofs::Query;
+use merkdb::restore::Restorer;
+use merkdb::{verify, Hash, Merk, Op, Result};
+use rand::prelude::*;
+
+const KEY_SPACE: u64 = 100_000;
+const CHECK_INTERVAL: u64 = 100;
+const MAX_RUN_MILLIS: u64 = 5_000;
+
+fn key(n: u64) -> Vec<u8> {
+    n.to_be_bytes().to_vec()
+}
+
+/// Applies random batches until killed, printing `<commits> <root hash>`
+/// after each commit and checking proofs and reads every `CHECK_INTERVAL`
+/// commits.
+fn worker(path: &Path, seed: u64) -> Result<()> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut merk = Merk::open(path)?;
+    let mut commits = merk.info()?.commits;
+    let stdout = std::io::stdout();
+
+    loop {
+        let size = rng.gen_range(1..500);
+        let mut batch: Vec<_> = (0..size)
+            .map(|_| {
+                let n = rng.gen_range(0..KEY_SPACE);
+                let op = if rng.gen_bool(0.3) {
+                    Op::Delete
+                } else {
+                    let mut value = key(commits);
+                    value.resize(rng.gen_range(8..256), n as u8);
+                    Op::Put(value)
+                };
+                (key(n), op)
+            })
+            .collect();
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+        batch.dedup_by(|a, b| a.0 == b.0);
+        let aux = [(b"commits".to_vec(), Op::Put(key(commits + 1)))];
+
+        merk.apply(&batch, &aux)?;
+        commits += 1;
+        let mut out = stdout.lock();
+        writeln!(out, "{} {}", commits, hex::encode(merk.root_hash()))?;
+        out.flush()?;
+
+        if commits.is_multiple_of(CHECK_INTERVAL) {
+            check_reads(&merk, &mut rng)?;
+        }
+    }
+}
+
+/// Checks that a proof of a random range verifies against the root hash and
+/// agrees with `get`.
+fn check_reads(merk: &Merk, rng: &mut SmallRng) -> Result<()> {
+    if merk.root_hash() == [0; 32] {
+        return Ok(());
+    }
+    let start = rng.gen_range(0..KEY_SPACE);
+    let end = start + rng.gen_range(1..1_000);
+    let mut query = Query::new();
+    query.insert_range(key(start)..key(end));
+    let map = verify(&merk.prove(query)?, merk.root_hash())?;
+    for n in start..end {
+        let proven = map.get(&key(n))?.map(|value| value.to_vec());
+        assert_eq!(proven, merk.get(&key(n))?, "proof disagrees with get");
+    }
+    Ok(())
+}
+
+/// Reads the commits reported by a worker.
+fn read_reports(stdout: impl std::io::Read + Send + 'static) -> Receiver<(u64, String)> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let mut parts = line.split(' ');
+            let commits = parts.next().unwrap().parse().unwrap();
+            let root = parts.next().unwrap().to_string();
+            if sender.send((commits, root)).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Checks that the store survived the worker being killed after it reported
+/// `last` (the commit count and root hash).
+fn check_restart(path: &Path, last: &(u64, String)) -> Result<()> {
+    let merk = Merk::open(path)?;
+    let info = merk.info()?;
+    let root = hex::encode(merk.root_hash());
+    let (commits, expected) = last;
+
+    if info.commits == *commits {
+        assert_eq!(&root, expected, "root changed across restart");
+    } else {
+        // the commit in flight when the worker was killed was written
+        assert_eq!(info.commits, commits + 1, "acknowledged commits were lost");
+        let history = &info.recent_roots;
+        if let Some(previous) = history.len().checked_sub(2).map(|i| history[i]) {
+            assert_eq!(
+                &hex::encode(previous),
+                expected,
+                "last acknowledged root is not in the root history"
+            );
+        }
+    }
+    let marker = merk.get_aux(b"commits")?.map(|bytes| {
+        let mut n = [0; 8];
+        n.copy_from_slice(&bytes);
+        u64::from_be_bytes(n)
+    });
+    assert_eq!(marker.unwrap_or(0), info.commits, "aux data is out of sync");
+    Ok(())
+}
+
+/// Verifies every node of the store by restoring a copy from its chunks.
+fn full_verify(path: &Path) -> Result<Hash> {
+    let merk = Merk::open(path)?;
+    let root_hash = merk.root_hash();
+    if root_hash == [0; 32] {
+        return Ok(root_hash);
+    }
+
+    let restore_path = PathBuf::from(format!("{}-verify", path.display()));
+    let mut producer = merk.chunks()?;
+    let mut restorer = Restorer::new(&restore_path, root_hash, producer.len())?;
+    for i in 0..producer.len() {
+        restorer.process_chunk(&producer.chunk(i)?)?;
+    }
+    let restored = restorer.finalize()?;
+    assert_eq!(restored.root_hash(), root_hash);
+    restored.destroy()?;
+    Ok(root_hash)
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("worker") {
+        return worker(Path::new(&args[2]), args[3].parse().unwrap());
+    }
+
+    let path = PathBuf::from(
+        args.get(1)
+            .expect("usage: soak <store path> [seconds] [seed]"),
+    );
+    let duration = Duration::from_secs(args.get(2).map_or(3_600, |s| s.parse().unwrap()));
+    let seed = args
+        .get(3)
+        .map_or_else(|| thread_rng().gen(), |s| s.parse().unwrap());
+    eprintln!(
+        "soaking {} for {:?} with seed {}",
+        path.display(),
+        duration,
+        seed
+    );
+
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let start = Instant::now();
+    let current = |path: &Path| -> Result<(u64, String)> {
+        let merk = Merk::open(path)?;
+        Ok((merk.info()?.commits, hex::encode(merk.root_hash())))
+    };
+    let mut last = current(&path)?;
+    let mut restarts = 0u64;
+
+    while start.elapsed() < duration {
+        let mut child = Command::new(env::current_exe()?)
+            .args(
+                [
+                    "worker",
+                    path.to_str().unwrap(),
+                    &rng.gen::<u64>().to_string(),
+                ]
+                .iter(),
+            )
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let reports = read_reports(child.stdout.take().unwrap());
+
+        thread::sleep(Duration::from_millis(rng.gen_range(0..MAX_RUN_MILLIS)));
+        if let Ok(Some(status)) = child.try_wait() {
+            panic!("worker exited early with {}", status);
+        }
+        // SIGKILL, so the worker gets no chance to clean up
+        child.kill()?;
+        child.wait()?;
+
+        let before = last.0;
+        for report in reports.iter() {
+            last = report;
+        }
+        check_restart(&path, &last)?;
+        last = current(&path)?;
+
+        restarts += 1;
+        eprintln!(
+            "restart {}: {} commits in this run, {} in total, {:?} elapsed",
+            restarts,
+            last.0 - before,
+            last.0,
+            start.elapsed()
+        );
+    }
+
+    let root_hash = full_verify(&path)?;
+    eprintln!(
+        "verified {} commits over {} restarts, root hash {}",
+        last.0,
+        restarts,
+        hex::encode(root_hash)
+    );
+    Ok(())
+}
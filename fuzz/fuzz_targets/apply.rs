@@ -0,0 +1,40 @@
+//! Applies a sequence of generated batches to an in-memory tree, checking the
+//! tree's invariants after each one and its contents against a model.
+
+#![no_main]
+
+use std::collections::BTreeMap;
+
+use libfuzzer_sys::fuzz_target;
+use merkdb::fuzz::Batch;
+use merkdb::test_utils::assert_tree_invariants;
+use merkdb::tree::{NoopCommit, PanicSource, Walker};
+use merkdb::Op;
+
+fuzz_target!(|batches: Vec<Batch>| {
+    let mut expected = BTreeMap::new();
+    let mut tree = None;
+
+    for Batch(batch) in batches {
+        for (key, op) in batch.iter() {
+            match op {
+                Op::Put(value) => expected.insert(key.clone(), value.clone()),
+                Op::Delete => expected.remove(key),
+                Op::Move { .. } | Op::SetFlags(_) => unreachable!("batches only put and delete"),
+            };
+        }
+
+        let walker = tree.take().map(|tree| Walker::new(tree, PanicSource {}));
+        tree = Walker::apply_to(walker, &batch, PanicSource {})
+            .expect("apply failed")
+            .0
+            .map(|mut tree| {
+                tree.commit(&mut NoopCommit {}).expect("commit failed");
+                assert_tree_invariants(&tree);
+                tree
+            });
+    }
+
+    let actual: Vec<_> = tree.iter().flat_map(|tree| tree.iter()).collect();
+    assert_eq!(actual, expected.into_iter().collect::<Vec<_>>());
+});
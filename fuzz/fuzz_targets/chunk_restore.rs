@@ -0,0 +1,49 @@
+//! Restores a generated store from its chunks after corrupting them, which
+//! must either fail with an error or restore the original root hash.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use merkdb::fuzz::Batch;
+use merkdb::restore::Restorer;
+use merkdb::test_utils::TempMerk;
+use tempdir::TempDir;
+
+fuzz_target!(|input: (Batch, Vec<(u8, u8)>)| {
+    let (Batch(batch), flips) = input;
+    if batch.is_empty() {
+        return;
+    }
+
+    let mut merk = TempMerk::new().unwrap();
+    merk.apply(&batch, &[]).unwrap();
+    let root_hash = merk.root_hash();
+    let mut chunks = match merk.chunks() {
+        Ok(mut producer) => (0..producer.len())
+            .map(|i| producer.chunk(i).unwrap())
+            .collect::<Vec<_>>(),
+        // the batch only deleted keys
+        Err(_) => return,
+    };
+
+    // flip bytes of the chunks at the given positions
+    let count = chunks.len();
+    for (index, flip) in flips {
+        let chunk = &mut chunks[index as usize % count];
+        if !chunk.is_empty() {
+            let len = chunk.len();
+            chunk[flip as usize % len] ^= flip | 1;
+        }
+    }
+
+    let tmp_dir = TempDir::new("merkdb-fuzz").unwrap();
+    let mut restorer = Restorer::new(tmp_dir.path().join("db"), root_hash, chunks.len()).unwrap();
+    for chunk in chunks.iter() {
+        if restorer.process_chunk(chunk).is_err() {
+            return;
+        }
+    }
+    if let Ok(restored) = restorer.finalize() {
+        assert_eq!(restored.root_hash(), root_hash);
+    }
+});
@@ -0,0 +1,26 @@
+//! Decodes and verifies malformed proofs, which must be rejected with an
+//! error rather than a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use merkdb::proofs::{encode_into, Decoder, Op};
+use merkdb::{verify, verify_iter};
+
+fuzz_target!(|input: (&[u8], Vec<Op>)| {
+    let (data, ops) = input;
+
+    // raw bytes
+    let _ = Decoder::new(data).collect::<Result<Vec<_>, _>>();
+    let _ = verify(data, [0; 32]);
+    let _ = verify_iter(data, [0; 32]).count();
+
+    // well-formed operators in an arbitrary order
+    let mut bytes = vec![];
+    encode_into(ops.iter(), &mut bytes);
+    let decoded = Decoder::new(&bytes)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("encoded operators failed to decode");
+    assert_eq!(decoded, ops);
+    let _ = verify(&bytes, [0; 32]);
+});
@@ -0,0 +1,250 @@
+//! Borsh encodings of proofs and batches, for tooling which standardizes on
+//! Borsh (such as Solana and NEAR programs).
+//!
+//! The encodings are those Borsh derives for the following Rust types, so
+//! they can be read and written with `#[derive(BorshSerialize,
+//! BorshDeserialize)]` definitions of the same shape on the other side:
+//!
+//! ```text
+//! enum Node {
+//!     Hash([u8; 32]),
+//!     KVHash([u8; 32]),
+//!     KV(Vec<u8>, Vec<u8>),
+//!     KVDigest(Vec<u8>, [u8; 32]),
+//!     KVFlags(Vec<u8>, Vec<u8>, u8),
+//! }
+//! enum ProofOp { Push(Node), Parent, Child }
+//! type Proof = Vec<ProofOp>;
+//!
+//! enum Op { Put(Vec<u8>), Delete, Move { to: Vec<u8> }, SetFlags(u8) }
+//! type Batch = Vec<(Vec<u8>, Op)>;
+//! ```
+//!
+//! The variants must be declared in this order, since Borsh tags each value
+//! with the index of its variant.
+
+use std::convert::TryInto;
+
+use crate::proofs::{encode_into, Decoder, Node, Op as ProofOp};
+use crate::tree::{Batch, BatchEntry, Hash, Op, HASH_LENGTH};
+use crate::{Error, Result};
+
+/// Converts a proof in the usual encoding (as returned by `Merk::prove`) into
+/// its Borsh encoding.
+pub fn proof_to_borsh(proof: &[u8]) -> Result<Vec<u8>> {
+    let ops = Decoder::new(proof).collect::<Result<Vec<_>>>()?;
+    let mut out = Vec::with_capacity(proof.len() + ops.len() * 4);
+    put_len(&mut out, ops.len());
+    for op in ops {
+        match op {
+            ProofOp::Push(node) => {
+                out.push(0);
+                match node {
+                    Node::Hash(hash) => {
+                        out.push(0);
+                        out.extend_from_slice(&hash);
+                    }
+                    Node::KVHash(hash) => {
+                        out.push(1);
+                        out.extend_from_slice(&hash);
+                    }
+                    Node::KV(key, value) => {
+                        out.push(2);
+                        put_bytes(&mut out, &key);
+                        put_bytes(&mut out, &value);
+                    }
+                    Node::KVDigest(key, hash) => {
+                        out.push(3);
+                        put_bytes(&mut out, &key);
+                        out.extend_from_slice(&hash);
+                    }
+                    Node::KVFlags(key, value, flags) => {
+                        out.push(4);
+                        put_bytes(&mut out, &key);
+                        put_bytes(&mut out, &value);
+                        out.push(flags);
+                    }
+                }
+            }
+            ProofOp::Parent => out.push(1),
+            ProofOp::Child => out.push(2),
+        }
+    }
+    Ok(out)
+}
+
+/// Converts a Borsh-encoded proof into the usual encoding, which can then be
+/// verified with `verify`.
+pub fn proof_from_borsh(mut bytes: &[u8]) -> Result<Vec<u8>> {
+    let input = &mut bytes;
+    let len = take_len(input)?;
+    let mut ops = Vec::with_capacity(len.min(input.len()));
+    for _ in 0..len {
+        ops.push(match take_u8(input)? {
+            0 => ProofOp::Push(match take_u8(input)? {
+                0 => Node::Hash(take_hash(input)?),
+                1 => Node::KVHash(take_hash(input)?),
+                2 => Node::KV(take_bytes(input)?, take_bytes(input)?),
+                3 => Node::KVDigest(take_bytes(input)?, take_hash(input)?),
+                4 => Node::KVFlags(take_bytes(input)?, take_bytes(input)?, take_u8(input)?),
+                tag => return Err(Error::Decode(format!("Invalid node variant {}", tag))),
+            }),
+            1 => ProofOp::Parent,
+            2 => ProofOp::Child,
+            tag => return Err(Error::Decode(format!("Invalid operator variant {}", tag))),
+        });
+    }
+    check_empty(input)?;
+
+    let mut proof = vec![];
+    encode_into(ops.iter(), &mut proof);
+    Ok(proof)
+}
+
+/// Encodes a batch with Borsh.
+pub fn batch_to_borsh(batch: &Batch) -> Vec<u8> {
+    let mut out = vec![];
+    put_len(&mut out, batch.len());
+    for (key, op) in batch {
+        put_bytes(&mut out, key);
+        match op {
+            Op::Put(value) => {
+                out.push(0);
+                put_bytes(&mut out, value);
+            }
+            Op::Delete => out.push(1),
+            Op::Move { to } => {
+                out.push(2);
+                put_bytes(&mut out, to);
+            }
+            Op::SetFlags(flags) => out.extend_from_slice(&[3, *flags]),
+        }
+    }
+    out
+}
+
+/// Decodes a Borsh-encoded batch.
+pub fn batch_from_borsh(mut bytes: &[u8]) -> Result<Vec<BatchEntry>> {
+    let input = &mut bytes;
+    let len = take_len(input)?;
+    let mut batch = Vec::with_capacity(len.min(input.len()));
+    for _ in 0..len {
+        let key = take_bytes(input)?;
+        let op = match take_u8(input)? {
+            0 => Op::Put(take_bytes(input)?),
+            1 => Op::Delete,
+            2 => Op::Move {
+                to: take_bytes(input)?,
+            },
+            3 => Op::SetFlags(take_u8(input)?),
+            tag => return Err(Error::Decode(format!("Invalid op variant {}", tag))),
+        };
+        batch.push((key, op));
+    }
+    check_empty(input)?;
+    Ok(batch)
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_len(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(Error::Decode("Unexpected end of Borsh input".into()));
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head)
+}
+
+fn take_u8(input: &mut &[u8]) -> Result<u8> {
+    Ok(take(input, 1)?[0])
+}
+
+fn take_len(input: &mut &[u8]) -> Result<usize> {
+    let bytes = take(input, 4)?.try_into().unwrap();
+    Ok(u32::from_le_bytes(bytes) as usize)
+}
+
+fn take_bytes(input: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = take_len(input)?;
+    Ok(take(input, len)?.to_vec())
+}
+
+fn take_hash(input: &mut &[u8]) -> Result<Hash> {
+    Ok(take(input, HASH_LENGTH)?.try_into().unwrap())
+}
+
+fn check_empty(input: &[u8]) -> Result<()> {
+    if !input.is_empty() {
+        return Err(Error::Decode(
+            "Unexpected trailing bytes in Borsh input".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn batch_roundtrip() {
+        let batch = vec![(vec![1, 2], Op::Put(vec![3])), (vec![4], Op::Delete)];
+        let bytes = batch_to_borsh(&batch);
+        assert_eq!(
+            bytes,
+            vec![2, 0, 0, 0, 2, 0, 0, 0, 1, 2, 0, 1, 0, 0, 0, 3, 1, 0, 0, 0, 4, 1]
+        );
+        assert_eq!(batch_from_borsh(&bytes).unwrap(), batch);
+        assert!(batch_from_borsh(&bytes[..bytes.len() - 1]).is_err());
+        assert!(batch_from_borsh(&[bytes.clone(), vec![0]].concat()).is_err());
+
+        let batch = vec![
+            (vec![1], Op::Move { to: vec![2] }),
+            (vec![3], Op::SetFlags(4)),
+        ];
+        let bytes = batch_to_borsh(&batch);
+        assert_eq!(
+            bytes,
+            vec![2, 0, 0, 0, 1, 0, 0, 0, 1, 2, 1, 0, 0, 0, 2, 1, 0, 0, 0, 3, 3, 4]
+        );
+        assert_eq!(batch_from_borsh(&bytes).unwrap(), batch);
+    }
+
+    #[test]
+    fn proof_roundtrip() {
+        let ops = vec![
+            ProofOp::Push(Node::Hash([1; 32])),
+            ProofOp::Push(Node::KV(vec![5], vec![6, 7])),
+            ProofOp::Parent,
+            ProofOp::Push(Node::KVHash([2; 32])),
+            ProofOp::Child,
+        ];
+        let mut proof = vec![];
+        encode_into(ops.iter(), &mut proof);
+
+        let bytes = proof_to_borsh(&proof).unwrap();
+        assert_eq!(&bytes[..6], &[5, 0, 0, 0, 0, 0]);
+        assert_eq!(proof_from_borsh(&bytes).unwrap(), proof);
+        assert!(proof_from_borsh(&[1, 0, 0, 0, 3]).is_err());
+
+        let ops = vec![
+            ProofOp::Push(Node::KVDigest(vec![1], [2; 32])),
+            ProofOp::Push(Node::KVFlags(vec![3], vec![4], 5)),
+            ProofOp::Parent,
+        ];
+        let mut proof = vec![];
+        encode_into(ops.iter(), &mut proof);
+        let bytes = proof_to_borsh(&proof).unwrap();
+        assert_eq!(&bytes[..10], &[3, 0, 0, 0, 0, 3, 1, 0, 0, 0]);
+        assert_eq!(&bytes[43..], &[0, 4, 1, 0, 0, 0, 3, 1, 0, 0, 0, 4, 5, 1]);
+        assert_eq!(proof_from_borsh(&bytes).unwrap(), proof);
+    }
+}
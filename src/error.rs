@@ -8,43 +8,121 @@ pub enum Error {
     BatchKey(String),
     #[error("Bound Error: {0}")]
     Bound(String),
+    #[error("Proof {index} of the batch is invalid: {source}")]
+    BatchProof { index: usize, source: Box<Error> },
+    #[error(
+        "Keys in batch must be sorted, but the key at index {index} is less than the one before it"
+    )]
+    BatchUnsorted { index: usize },
+    #[error("Checksum mismatch in the record of key {0:?}")]
+    ChecksumMismatch(Vec<u8>),
     #[error("Chunk Processing Error: {0}")]
     ChunkProcessing(String),
+    #[error("Decode Error: {0}")]
+    Decode(String),
+    #[error("Replica has diverged from primary")]
+    Diverged,
+    #[error("Key {key:?} appears more than once in the batch")]
+    DuplicateKey { key: Vec<u8> },
     #[error(transparent)]
     Ed(#[from] ed::Error),
     #[error("Fetch Error: {0}")]
     Fetch(String),
+    #[error("Unsupported store format version {0}")]
+    FormatVersion(u8),
+    #[error("Key {0:?} is frozen")]
+    Frozen(Vec<u8>),
     #[error("Proof did not match expected hash\n\tExpected: {0:?}\n\tActual: {1:?}")]
     HashMismatch([u8; 32], [u8; 32]),
+    #[error("Index {0} is not registered")]
+    IndexNotRegistered(String),
+    #[error("Index {0} is already registered")]
+    IndexRegistered(String),
     #[error("Index OoB Error: {0}")]
     IndexOutOfBounds(String),
     #[error("Integer conversion error: {0}")]
     IntegerConversionError(#[from] std::num::TryFromIntError),
+    #[error("Invalid signature for replicated batch with root hash {0:?}")]
+    InvalidBatchSignature([u8; 32]),
+    #[error("Invalid signature for root attestation at height {0}")]
+    InvalidSignature(u64),
+    #[error("Tree invariant violated at key {key:?}: {detail}")]
+    InvariantViolation { key: Vec<u8>, detail: String },
     #[error(transparent)]
     IO(#[from] std::io::Error),
+    #[error("Store is locked by process {pid} (since {since})")]
+    LockHeld { pid: u32, since: u64 },
     #[error("Tried to delete non-existent key {0:?}")]
     KeyDelete(Vec<u8>),
+    #[error("Tried to move non-existent key {0:?}")]
+    KeyMove(Vec<u8>),
+    #[error("Tried to set flags of non-existent key {0:?}")]
+    KeySetFlags(Vec<u8>),
     #[error("Key Error: {0}")]
     Key(String),
     #[error("Key not found: {0}")]
     KeyNotFound(String),
+    #[error("Migrated keys collide at key {0:?}")]
+    MigrationCollision(Vec<u8>),
     #[error("Proof is missing data for query")]
     MissingData,
+    #[error("Named trees cannot be {0}")]
+    NamedTree(&'static str),
+    #[error("No open savepoint")]
+    NoSavepoint,
+    #[error("No retained versions to roll back")]
+    NothingToRollBack,
+    #[error("{} is not a merkdb store", .0.display())]
+    NotAStore(std::path::PathBuf),
+    #[error("Cannot {0} while nodes are offloaded, rehydrate them first")]
+    Offloaded(&'static str),
     #[error("Path Error: {0}")]
     Path(String),
     #[error("Proof Error: {0}")]
     Proof(String),
+    #[error("Query exceeds a limit of the proof service: {0}")]
+    QueryLimit(String),
+    #[error("Batch would use {used} bytes under prefix {prefix:?}, over its quota of {limit}")]
+    QuotaExceeded {
+        prefix: Vec<u8>,
+        used: u64,
+        limit: u64,
+    },
+    #[error("Cannot destroy a store while {0} readers of it are open")]
+    ReadersOpen(usize),
+    #[error("Cannot {0} a store with a replication log, replicas would diverge")]
+    Replicated(&'static str),
     #[cfg(feature = "full")]
     #[error(transparent)]
     RocksDB(#[from] rocksdb::Error),
+    #[error("Cannot insert or delete key {key:?} in a partial tree")]
+    ShapeChange { key: Vec<u8> },
+    #[error("Shard {index} holds key {key:?} outside of its range")]
+    ShardRange { index: usize, key: Vec<u8> },
     #[error("Stack Underflow")]
     StackUnderflow,
+    #[error("Root at height {height} is stale, the trusted root is at height {trusted}")]
+    StaleRoot { height: u64, trusted: u64 },
+    #[error("A store already exists at {}", .0.display())]
+    StoreExists(std::path::PathBuf),
+    #[error("No store exists at {}", .0.display())]
+    StoreNotFound(std::path::PathBuf),
     #[error("Tree Error: {0}")]
     Tree(String),
     #[error("Unexpected Node Error: {0}")]
     UnexpectedNode(String),
     #[error("Unknown Error")]
     Unknown,
+    #[error("Value of key {0:?} is withheld from the proof")]
+    ValueWithheld(Vec<u8>),
+    #[error("Value of key {0:?} is not withheld")]
+    ValueNotWithheld(Vec<u8>),
+    #[error("No retained version with root hash {0:?}")]
+    VersionNotFound([u8; 32]),
+    #[error("Version with root hash {0:?} is pinned by a reader")]
+    VersionPinned([u8; 32]),
+    #[error("Write to key {0:?} is denied by the write policy")]
+    WriteDenied(Vec<u8>),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
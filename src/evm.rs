@@ -0,0 +1,389 @@
+//! Layout conventions and proof helpers for storing EVM state in a store.
+//!
+//! Accounts are keyed by the Keccak-256 hash of their 20-byte address, as in
+//! Ethereum's state trie, so keys are spread evenly through the tree whatever
+//! addresses are used. An account's storage slots are keyed by the account's
+//! key followed by the Keccak-256 hash of the 32-byte slot, which nests each
+//! account's storage in a contiguous range of the tree right after the
+//! account itself: the whole storage of an account can be iterated, proven
+//! or deleted as a single range, while the store's root hash commits to every
+//! account and slot at once.
+//!
+//! Contract code is kept in the auxiliary data, keyed by its hash. It is
+//! committed to by the `code_hash` of the accounts using it, so code read
+//! from the auxiliary data can be checked against a proven account.
+//!
+//! As in the EVM, storage slots hold 32-byte words and a slot holding zero is
+//! the same as one which was never written, so zero values are deleted.
+
+use crate::mpt::keccak256;
+use crate::proofs::query::verify;
+use crate::{Error, Hash, Result};
+
+/// A 20-byte account address.
+pub type Address = [u8; 20];
+
+/// A 32-byte EVM word, such as a balance, a storage slot or a stored value.
+pub type Word = [u8; 32];
+
+/// The hash of empty code, which accounts without code have as their
+/// `code_hash`.
+pub const EMPTY_CODE_HASH: Hash = [
+    0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+    0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
+];
+
+const ACCOUNT_LENGTH: usize = 8 + 32 + 32;
+
+/// The state of an account, excluding its storage.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: u64,
+    /// The balance, as a big-endian 256-bit integer.
+    pub balance: Word,
+    /// The Keccak-256 hash of the account's code.
+    pub code_hash: Hash,
+}
+
+impl Default for Account {
+    fn default() -> Account {
+        Account {
+            nonce: 0,
+            balance: [0; 32],
+            code_hash: EMPTY_CODE_HASH,
+        }
+    }
+}
+
+impl Account {
+    /// Encodes the account into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ACCOUNT_LENGTH);
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&self.balance);
+        bytes.extend_from_slice(&self.code_hash);
+        bytes
+    }
+
+    /// Decodes an account from bytes.
+    pub fn decode(bytes: &[u8]) -> Result<Account> {
+        if bytes.len() != ACCOUNT_LENGTH {
+            return Err(Error::Decode("Invalid account length".into()));
+        }
+        let mut nonce = [0; 8];
+        nonce.copy_from_slice(&bytes[..8]);
+        let mut account = Account {
+            nonce: u64::from_be_bytes(nonce),
+            ..Default::default()
+        };
+        account.balance.copy_from_slice(&bytes[8..40]);
+        account.code_hash.copy_from_slice(&bytes[40..]);
+        Ok(account)
+    }
+}
+
+/// Returns the key of an account.
+pub fn account_key(address: &Address) -> Vec<u8> {
+    keccak256(address).to_vec()
+}
+
+/// Returns the key of a storage slot of an account.
+pub fn storage_key(address: &Address, slot: &Word) -> Vec<u8> {
+    let mut key = account_key(address);
+    key.extend_from_slice(&keccak256(slot));
+    key
+}
+
+/// Returns the range of keys holding the storage of an account.
+pub fn storage_range(address: &Address) -> std::ops::RangeInclusive<Vec<u8>> {
+    let key = account_key(address);
+    let mut start = key.clone();
+    start.extend_from_slice(&[0; 32]);
+    let mut end = key;
+    end.extend_from_slice(&[0xff; 32]);
+    start..=end
+}
+
+/// Checks a proof created by `EvmState::prove` against `root_hash`, and
+/// returns the proven state of the account at `address` (`None` if it does
+/// not exist) and the values of `slots`.
+pub fn verify_account(
+    proof: &[u8],
+    root_hash: Hash,
+    address: &Address,
+    slots: &[Word],
+) -> Result<(Option<Account>, Vec<Word>)> {
+    let map = verify(proof, root_hash)?;
+    let account = map
+        .get(&account_key(address))?
+        .map(Account::decode)
+        .transpose()?;
+    let values = slots
+        .iter()
+        .map(|slot| match map.get(&storage_key(address, slot))? {
+            None => Ok([0; 32]),
+            Some(value) => decode_word(value),
+        })
+        .collect::<Result<_>>()?;
+    Ok((account, values))
+}
+
+fn decode_word(bytes: &[u8]) -> Result<Word> {
+    let mut word = [0; 32];
+    if bytes.len() != word.len() {
+        return Err(Error::Decode("Invalid storage value length".into()));
+    }
+    word.copy_from_slice(bytes);
+    Ok(word)
+}
+
+#[cfg(feature = "full")]
+pub use state::{EvmBatch, EvmState};
+
+#[cfg(feature = "full")]
+mod state {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::proofs::Query;
+    use crate::{Merk, Op};
+
+    /// Changes to EVM state, written to the store at once with
+    /// `EvmState::commit`.
+    #[derive(Default)]
+    pub struct EvmBatch {
+        entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        cleared: Vec<Address>,
+        code: Vec<(Hash, Vec<u8>)>,
+    }
+
+    impl EvmBatch {
+        pub fn new() -> EvmBatch {
+            EvmBatch::default()
+        }
+
+        /// Sets the state of an account.
+        pub fn set_account(&mut self, address: &Address, account: &Account) {
+            self.entries
+                .insert(account_key(address), Some(account.encode()));
+        }
+
+        /// Deletes an account along with all of its storage, as when it
+        /// self-destructs. Storage set for the account earlier in the same
+        /// batch is deleted too, while storage set later is kept.
+        pub fn delete_account(&mut self, address: &Address) {
+            let range = storage_range(address);
+            let keys: Vec<_> = self
+                .entries
+                .range(range)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                self.entries.remove(&key);
+            }
+            self.entries.insert(account_key(address), None);
+            self.cleared.push(*address);
+        }
+
+        /// Sets a storage slot of an account. Setting a slot to zero deletes
+        /// it.
+        pub fn set_storage(&mut self, address: &Address, slot: &Word, value: &Word) {
+            let value = if *value == [0; 32] {
+                None
+            } else {
+                Some(value.to_vec())
+            };
+            self.entries.insert(storage_key(address, slot), value);
+        }
+
+        /// Stores contract code, returning its hash for use as an account's
+        /// `code_hash`.
+        pub fn set_code(&mut self, code: Vec<u8>) -> Hash {
+            let hash = keccak256(&code);
+            self.code.push((hash, code));
+            hash
+        }
+    }
+
+    /// A view of a `Merk` holding EVM state, see the `evm` module.
+    pub struct EvmState<'a> {
+        merk: &'a mut Merk,
+    }
+
+    impl Merk {
+        /// Creates a view of the store holding EVM state.
+        pub fn evm(&mut self) -> EvmState<'_> {
+            EvmState { merk: self }
+        }
+    }
+
+    impl<'a> EvmState<'a> {
+        /// Returns the state of the account at `address`, or `None` if it
+        /// does not exist.
+        pub fn account(&self, address: &Address) -> Result<Option<Account>> {
+            self.merk
+                .get(&account_key(address))?
+                .map(|bytes| Account::decode(&bytes))
+                .transpose()
+        }
+
+        /// Returns the value of a storage slot of an account, which is zero
+        /// if it was never set.
+        pub fn storage(&self, address: &Address, slot: &Word) -> Result<Word> {
+            match self.merk.get(&storage_key(address, slot))? {
+                None => Ok([0; 32]),
+                Some(value) => decode_word(&value),
+            }
+        }
+
+        /// Returns the code with the given hash, if it has been stored.
+        pub fn code(&self, code_hash: &Hash) -> Result<Option<Vec<u8>>> {
+            if *code_hash == EMPTY_CODE_HASH {
+                return Ok(Some(vec![]));
+            }
+            self.merk.get_aux(code_hash)
+        }
+
+        /// Returns the root hash of the store.
+        pub fn root_hash(&self) -> Hash {
+            self.merk.root_hash()
+        }
+
+        /// Writes the changes in `batch` to the store in a single commit.
+        pub fn commit(&mut self, batch: EvmBatch) -> Result<()> {
+            let mut entries = batch.entries;
+
+            // delete the stored storage of deleted accounts, except for slots
+            // which are set again in the same batch
+            for address in batch.cleared.iter() {
+                let range = storage_range(address);
+                let mut iter = self.merk.raw_iter();
+                iter.seek(range.start());
+                while let Some(key) = iter.key() {
+                    if key > range.end().as_slice() {
+                        break;
+                    }
+                    entries.entry(key.to_vec()).or_insert(None);
+                    iter.next();
+                }
+            }
+
+            let ops: Vec<_> = entries
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => (key, Op::Put(value)),
+                    None => (key, Op::Delete),
+                })
+                .collect();
+
+            let mut aux: Vec<_> = batch
+                .code
+                .into_iter()
+                .map(|(hash, code)| (hash.to_vec(), Op::Put(code)))
+                .collect();
+            aux.sort_by(|a, b| a.0.cmp(&b.0));
+            aux.dedup_by(|a, b| a.0 == b.0);
+
+            self.merk.apply(&ops, &aux)
+        }
+
+        /// Creates a proof of the state of the account at `address` and the
+        /// values of the given storage slots, which can be checked with
+        /// `verify_account`.
+        pub fn prove(&self, address: &Address, slots: &[Word]) -> Result<Vec<u8>> {
+            let mut query = Query::new();
+            query.insert_key(account_key(address));
+            for slot in slots {
+                query.insert_key(storage_key(address, slot));
+            }
+            self.merk.prove(query)
+        }
+
+        /// Returns every non-zero storage slot of an account, by the hash of
+        /// the slot (the original slots are not stored).
+        pub fn storage_entries(&self, address: &Address) -> Result<Vec<(Hash, Word)>> {
+            let range = storage_range(address);
+            let mut entries = vec![];
+            let mut iter = self.merk.raw_iter();
+            iter.seek(range.start());
+            while let Some(key) = iter.key() {
+                if key > range.end().as_slice() {
+                    break;
+                }
+                let mut slot_hash = [0; 32];
+                slot_hash.copy_from_slice(&key[32..]);
+                let value = self.merk.get(key)?.ok_or_else(|| {
+                    Error::Fetch("Storage entry disappeared while iterating".into())
+                })?;
+                entries.push((slot_hash, decode_word(&value)?));
+                iter.next();
+            }
+            Ok(entries)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::test_utils::*;
+
+        #[test]
+        fn accounts_and_storage() {
+            let mut merk = TempMerk::new().unwrap();
+            let mut evm = merk.evm();
+            let alice = [1; 20];
+            let bob = [2; 20];
+            let slot = |n: u8| {
+                let mut slot = [0; 32];
+                slot[31] = n;
+                slot
+            };
+
+            let mut batch = EvmBatch::new();
+            let code_hash = batch.set_code(vec![0x60, 0x00]);
+            batch.set_account(
+                &alice,
+                &Account {
+                    nonce: 1,
+                    balance: slot(100),
+                    code_hash,
+                },
+            );
+            batch.set_account(&bob, &Account::default());
+            for n in 1..=3 {
+                batch.set_storage(&alice, &slot(n), &slot(n * 10));
+                batch.set_storage(&bob, &slot(n), &slot(n));
+            }
+            batch.set_storage(&alice, &slot(4), &slot(0));
+            evm.commit(batch).unwrap();
+
+            let account = evm.account(&alice).unwrap().unwrap();
+            assert_eq!(account.nonce, 1);
+            assert_eq!(evm.code(&account.code_hash).unwrap(), Some(vec![0x60, 0]));
+            assert_eq!(evm.storage(&alice, &slot(2)).unwrap(), slot(20));
+            assert_eq!(evm.storage(&alice, &slot(4)).unwrap(), slot(0));
+            assert_eq!(evm.storage_entries(&alice).unwrap().len(), 3);
+            assert_eq!(evm.account(&[3; 20]).unwrap(), None);
+
+            let slots = [slot(1), slot(5)];
+            let proof = evm.prove(&alice, &slots).unwrap();
+            let (proven, values) = verify_account(&proof, evm.root_hash(), &alice, &slots).unwrap();
+            assert_eq!(proven, Some(account));
+            assert_eq!(values, vec![slot(10), slot(0)]);
+            assert!(verify_account(&proof, evm.root_hash(), &bob, &[]).is_err());
+
+            // deleting an account deletes its storage, except for slots set
+            // after the deletion
+            let mut batch = EvmBatch::new();
+            batch.set_storage(&alice, &slot(1), &slot(0));
+            batch.delete_account(&alice);
+            batch.set_account(&alice, &Account::default());
+            batch.set_storage(&alice, &slot(3), &slot(7));
+            evm.commit(batch).unwrap();
+            assert_eq!(evm.storage(&alice, &slot(2)).unwrap(), slot(0));
+            assert_eq!(evm.storage(&alice, &slot(3)).unwrap(), slot(7));
+            assert_eq!(evm.storage_entries(&alice).unwrap().len(), 1);
+            assert_eq!(evm.storage_entries(&bob).unwrap().len(), 3);
+        }
+    }
+}
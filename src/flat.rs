@@ -0,0 +1,383 @@
+//! A flat binary Merkle commitment to a store's entries in key order, for
+//! interoperability with systems which verify plain Merkle roots rather than
+//! AVL proofs.
+//!
+//! The tree has the shape and prefixes of the Merkle hash tree of RFC 6962
+//! (section 2.1): the leaves are the entries in key order, a leaf hash is
+//! `H(0x00 || kv_hash)` where `kv_hash` is the hash of the entry's key,
+//! value and flags the AVL tree itself uses (see `kv_hash_with_flags`), an inner node hash is
+//! `H(0x01 || left || right)`, and a list of `n > 1` leaves is split into
+//! the first `k` and the remaining `n - k`, where `k` is the largest power of
+//! two less than `n`. The root of no leaves is `H()`. `H` is the tree's hash
+//! function, so a verifier needs the same hash function and the same
+//! `kv_hash` encoding, but no knowledge of the AVL tree.
+//!
+//! Inserting a key shifts the position of every later leaf, changing the
+//! hash of every node to its right, so the commitment is not maintained by
+//! commits: it is computed on demand from every entry's hash, see
+//! `Merk::flat_root` and `Merk::store_flat_root`.
+
+use sha2::Digest;
+
+use crate::tree::{kv_hash_with_flags, Hash, Hasher, HASH_LENGTH, NULL_HASH};
+use crate::{Error, Result};
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// The root of a commitment to no entries, `H()`.
+pub fn empty_root() -> Hash {
+    Hasher::new().finalize().into()
+}
+
+/// Hashes the leaf of an entry from the entry's `kv_hash`.
+pub fn leaf_hash(kv_hash: &Hash) -> Hash {
+    let mut hasher = Hasher::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(kv_hash);
+    hasher.finalize().into()
+}
+
+/// Hashes the leaf of an entry from its key, value and flags.
+pub fn entry_leaf_hash(key: &[u8], value: &[u8], flags: u8) -> Result<Hash> {
+    Ok(leaf_hash(&kv_hash_with_flags::<Hasher>(key, value, flags)?))
+}
+
+/// Hashes an inner node from the hashes of its children.
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Hasher::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The number of leaves in the left subtree of a tree of `len > 1` leaves.
+fn split(len: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < len {
+        k *= 2;
+    }
+    k
+}
+
+/// Computes the root of the commitment to the given leaf hashes, which must
+/// be in key order.
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => empty_root(),
+        1 => leaves[0],
+        len => {
+            let (left, right) = leaves.split_at(split(len));
+            node_hash(&root(left), &root(right))
+        }
+    }
+}
+
+/// A proof that an entry is the leaf at `index` of a commitment to `len`
+/// entries: the entry's flags, which its leaf hash commits to, and the audit
+/// path of RFC 6962, the sibling hashes from the leaf up to the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlatProof {
+    pub index: u64,
+    pub len: u64,
+    pub flags: u8,
+    pub path: Vec<Hash>,
+}
+
+impl FlatProof {
+    /// Creates the proof for the leaf at `index` of the given leaf hashes,
+    /// whose entry has the given flags.
+    pub fn new(leaves: &[Hash], index: usize, flags: u8) -> Result<FlatProof> {
+        if index >= leaves.len() {
+            return Err(Error::IndexOutOfBounds(format!(
+                "Leaf {} of {} is out of bounds",
+                index,
+                leaves.len()
+            )));
+        }
+        let mut path = vec![];
+        let (mut subtree, mut offset) = (leaves, index);
+        // walk down from the root, collecting siblings from the top
+        while subtree.len() > 1 {
+            let (left, right) = subtree.split_at(split(subtree.len()));
+            if offset < left.len() {
+                path.push(root(right));
+                subtree = left;
+            } else {
+                path.push(root(left));
+                offset -= left.len();
+                subtree = right;
+            }
+        }
+        path.reverse();
+        Ok(FlatProof {
+            index: index as u64,
+            len: leaves.len() as u64,
+            flags,
+            path,
+        })
+    }
+
+    /// Computes the root the proof commits to for the given leaf hash, with
+    /// the verification algorithm of RFC 9162 (section 2.1.3.2).
+    pub fn root(&self, leaf: &Hash) -> Result<Hash> {
+        if self.index >= self.len {
+            return Err(Error::Proof("Leaf index is out of bounds".into()));
+        }
+        let (mut index, mut last) = (self.index, self.len - 1);
+        let mut hash = *leaf;
+        for sibling in self.path.iter() {
+            if last == 0 {
+                return Err(Error::Proof("Proof has too many hashes".into()));
+            }
+            if index & 1 == 1 || index == last {
+                hash = node_hash(sibling, &hash);
+                // skip the levels where the node has no right sibling
+                while index & 1 == 0 && index != 0 {
+                    index >>= 1;
+                    last >>= 1;
+                }
+            } else {
+                hash = node_hash(&hash, sibling);
+            }
+            index >>= 1;
+            last >>= 1;
+        }
+        if last != 0 {
+            return Err(Error::Proof("Proof has too few hashes".into()));
+        }
+        Ok(hash)
+    }
+
+    /// Verifies that the entry with the given key and value, and the proof's
+    /// flags, is the leaf at `index` of the commitment with the given root.
+    pub fn verify(&self, root: &Hash, key: &[u8], value: &[u8]) -> Result<()> {
+        let actual = self.root(&entry_leaf_hash(key, value, self.flags)?)?;
+        if actual != *root {
+            return Err(Error::HashMismatch(*root, actual));
+        }
+        Ok(())
+    }
+
+    /// Encodes the proof as its index and length as big-endian `u64`s, then
+    /// the flags byte, followed by the path's hashes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(17 + self.path.len() * HASH_LENGTH);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes.push(self.flags);
+        for hash in self.path.iter() {
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    /// Decodes a proof from bytes.
+    pub fn decode(bytes: &[u8]) -> Result<FlatProof> {
+        if bytes.len() < 17 || !(bytes.len() - 17).is_multiple_of(HASH_LENGTH) {
+            return Err(Error::Decode("Invalid flat proof length".into()));
+        }
+        let mut int = [0; 8];
+        int.copy_from_slice(&bytes[..8]);
+        let index = u64::from_be_bytes(int);
+        int.copy_from_slice(&bytes[8..16]);
+        let len = u64::from_be_bytes(int);
+        let flags = bytes[16];
+        let path = bytes[17..]
+            .chunks(HASH_LENGTH)
+            .map(|chunk| {
+                let mut hash = NULL_HASH;
+                hash.copy_from_slice(chunk);
+                hash
+            })
+            .collect();
+        Ok(FlatProof {
+            index,
+            len,
+            flags,
+            path,
+        })
+    }
+}
+
+#[cfg(feature = "full")]
+mod store {
+    use super::*;
+    use crate::merk::INTERNAL_CF_NAME;
+    use crate::tree::TreeRef;
+    use crate::Merk;
+
+    /// The key of the maintained commitment in the internal column family,
+    /// stored as the AVL root hash it was computed for followed by its root.
+    const FLAT_ROOT_KEY: &[u8] = b"flat";
+
+    impl Merk {
+        /// Returns the root of the flat commitment (see the `flat` module) to
+        /// the store's entries, reading the root stored by `store_flat_root`
+        /// if it is for the current AVL root, and computing it from every
+        /// node otherwise.
+        pub fn flat_root(&self) -> Result<Hash> {
+            let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+            if let Some(bytes) = self.db.get_cf(internal_cf, FLAT_ROOT_KEY)? {
+                if bytes.len() == 2 * HASH_LENGTH && bytes[..HASH_LENGTH] == self.root_hash() {
+                    let mut flat_root = NULL_HASH;
+                    flat_root.copy_from_slice(&bytes[HASH_LENGTH..]);
+                    return Ok(flat_root);
+                }
+            }
+            Ok(root(&self.flat_leaves()?.1))
+        }
+
+        /// Creates a flat proof for the entry with the given key, or returns
+        /// `None` if the key is not in the store. The proof has to be created
+        /// from every entry's hash, so it reads every node.
+        pub fn prove_flat(&self, key: &[u8]) -> Result<Option<FlatProof>> {
+            let (keys, leaves) = self.flat_leaves()?;
+            match keys.binary_search_by(|other| other.as_slice().cmp(key)) {
+                Ok(index) => {
+                    let flags = self.get_flags(key)?.unwrap_or_default();
+                    FlatProof::new(&leaves, index, flags).map(Some)
+                }
+                Err(_) => Ok(None),
+            }
+        }
+
+        /// Reads the keys and leaf hashes of every entry, in key order.
+        fn flat_leaves(&self) -> Result<(Vec<Vec<u8>>, Vec<Hash>)> {
+            self.check_not_offloaded("compute the flat commitment")?;
+            let mut keys = vec![];
+            let mut leaves = vec![];
+            for (key, bytes) in self.db.iterator(rocksdb::IteratorMode::Start) {
+                leaves.push(leaf_hash(TreeRef::decode(&key, &bytes)?.kv_hash()));
+                keys.push(key.to_vec());
+            }
+            Ok((keys, leaves))
+        }
+
+        /// Computes the root of the flat commitment and stores it, so
+        /// `flat_root` reads it back until the next commit instead of
+        /// iterating the store. Commits do not update the stored root, which
+        /// is tagged with the AVL root hash it was computed for, so once the
+        /// store changes it is ignored rather than returned; call this again
+        /// at the checkpoints where the flat root is needed.
+        ///
+        /// This reads every node, and writes the root on its own rather than
+        /// in any commit's batch.
+        pub fn store_flat_root(&mut self) -> Result<Hash> {
+            let flat_root = root(&self.flat_leaves()?.1);
+            let mut bytes = self.root_hash().to_vec();
+            bytes.extend_from_slice(&flat_root);
+            let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+            let mut batch = rocksdb::WriteBatch::default();
+            batch.put_cf(internal_cf, FLAT_ROOT_KEY, bytes);
+            self.write(batch)?;
+            Ok(flat_root)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::test_utils::*;
+        use crate::Op;
+
+        #[test]
+        fn flat_commitment() {
+            let mut merk = TempMerk::new().unwrap();
+            assert_eq!(merk.flat_root().unwrap(), empty_root());
+
+            let batch = make_batch_seq(0..50);
+            merk.apply(&batch, &[]).unwrap();
+            let leaves: Vec<_> = batch
+                .iter()
+                .map(|(key, op)| match op {
+                    Op::Put(value) => entry_leaf_hash(key, value, 0).unwrap(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            let flat_root = merk.store_flat_root().unwrap();
+            assert_eq!(flat_root, root(&leaves));
+            assert_eq!(merk.flat_root().unwrap(), flat_root);
+
+            let (key, value) = (seq_key(7), merk.get(&seq_key(7)).unwrap().unwrap());
+            let proof = merk.prove_flat(&key).unwrap().unwrap();
+            assert_eq!(proof.index, 7);
+            proof.verify(&flat_root, &key, &value).unwrap();
+            assert!(merk.prove_flat(b"missing").unwrap().is_none());
+
+            // the proof of an entry with flags commits to them
+            merk.apply(&[(key.clone(), Op::SetFlags(0x80))], &[])
+                .unwrap();
+            let flat_root = merk.flat_root().unwrap();
+            let proof = merk.prove_flat(&key).unwrap().unwrap();
+            assert_eq!(proof.flags, 0x80);
+            proof.verify(&flat_root, &key, &value).unwrap();
+            let mut unflagged = proof.clone();
+            unflagged.flags = 0;
+            assert!(unflagged.verify(&flat_root, &key, &value).is_err());
+            merk.apply(&[(key.clone(), Op::SetFlags(0))], &[]).unwrap();
+
+            // a stored root left stale by a later commit is ignored
+            merk.apply(&[(seq_key(7), Op::Delete)], &[]).unwrap();
+            let mut leaves = leaves;
+            leaves.remove(7);
+            assert_eq!(merk.flat_root().unwrap(), root(&leaves));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn leaves(n: u8) -> Vec<Hash> {
+        (0..n)
+            .map(|i| entry_leaf_hash(&[i], &[i, i], 0).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn roots() {
+        assert_eq!(root(&[]), empty_root());
+        let leaves = leaves(5);
+        assert_eq!(root(&leaves[..1]), leaves[0]);
+        // 5 leaves split into 4 and 1, and 4 into 2 and 2
+        let left = node_hash(
+            &node_hash(&leaves[0], &leaves[1]),
+            &node_hash(&leaves[2], &leaves[3]),
+        );
+        assert_eq!(root(&leaves), node_hash(&left, &leaves[4]));
+        assert_ne!(leaf_hash(&leaves[0]), leaves[0]);
+    }
+
+    #[test]
+    fn proofs() {
+        for n in 1..20 {
+            let leaves = leaves(n);
+            let root = root(&leaves);
+            for i in 0..n as usize {
+                let proof = FlatProof::new(&leaves, i, 0).unwrap();
+                assert_eq!(FlatProof::decode(&proof.encode()).unwrap(), proof);
+                let key = [i as u8];
+                proof.verify(&root, &key, &[i as u8, i as u8]).unwrap();
+                assert!(proof.verify(&root, &key, &[0xff]).is_err());
+
+                let mut wrong_index = proof.clone();
+                wrong_index.index ^= 1;
+                if n > 1 && wrong_index.index < wrong_index.len {
+                    assert!(wrong_index
+                        .verify(&root, &key, &[i as u8, i as u8])
+                        .is_err());
+                }
+                let mut short = proof.clone();
+                if short.path.pop().is_some() {
+                    assert!(short.root(&leaves[i]).is_err());
+                }
+            }
+            assert!(FlatProof::new(&leaves, n as usize, 0).is_err());
+        }
+        assert!(FlatProof::decode(&[0; 16]).is_err());
+        assert!(FlatProof::decode(&[0; 18]).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! `arbitrary::Arbitrary` implementations for fuzzing, so batches, queries
+//! and proof operators can be taken directly as fuzz target inputs (e.g.
+//! `fuzz_target!(|batch: fuzz::Batch| ..)`) or generated by any other tool
+//! built on `arbitrary`.
+//!
+//! Keys are drawn from a small alphabet so generated batches and queries touch
+//! the same keys often. The cargo-fuzz targets in the `fuzz` directory use
+//! these.
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::proofs::query::{Query, QueryItem};
+use crate::proofs::{Node, Op as ProofOp};
+use crate::tree::{BatchEntry, Op};
+
+const MAX_KEY_LENGTH: u8 = 3;
+const KEY_ALPHABET: u8 = 16;
+const MAX_VALUE_LENGTH: usize = 16;
+const MAX_BATCH_LENGTH: usize = 32;
+
+/// Generates a key of 1 to `MAX_KEY_LENGTH` bytes from the key alphabet.
+pub fn key(u: &mut Unstructured) -> Result<Vec<u8>> {
+    let len = u.int_in_range(1..=MAX_KEY_LENGTH)?;
+    (0..len)
+        .map(|_| u.int_in_range(0..=KEY_ALPHABET - 1))
+        .collect()
+}
+
+/// Generates a value of up to `MAX_VALUE_LENGTH` bytes.
+fn value(u: &mut Unstructured) -> Result<Vec<u8>> {
+    let len = u.int_in_range(0..=MAX_VALUE_LENGTH)?;
+    Ok(u.bytes(len)?.to_vec())
+}
+
+impl<'a> Arbitrary<'a> for Op {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=3)? {
+            0 => Op::Put(value(u)?),
+            1 => Op::Delete,
+            2 => Op::Move { to: key(u)? },
+            _ => Op::SetFlags(u.arbitrary()?),
+        })
+    }
+}
+
+/// A batch with sorted, unique keys of puts and deletes, which applies to any
+/// tree, as accepted by `Merk::apply` and `Walker::apply_to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch(pub Vec<BatchEntry>);
+
+impl<'a> Arbitrary<'a> for Batch {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = u.int_in_range(0..=MAX_BATCH_LENGTH)?;
+        let mut batch = (0..len)
+            .map(|_| {
+                let key = key(u)?;
+                let op = match u.ratio(1, 4)? {
+                    true => Op::Delete,
+                    false => Op::Put(value(u)?),
+                };
+                Ok((key, op))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+        batch.dedup_by(|a, b| a.0 == b.0);
+        Ok(Batch(batch))
+    }
+}
+
+impl<'a> Arbitrary<'a> for QueryItem {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let (start, end) = (key(u)?, key(u)?);
+        let (start, end) = if start <= end {
+            (start, end)
+        } else {
+            (end, start)
+        };
+        Ok(match u.int_in_range(0..=2)? {
+            0 => QueryItem::Key(start),
+            1 if start < end => QueryItem::Range(start..end),
+            _ => QueryItem::RangeInclusive(start..=end),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Query {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut query = Query::new();
+        for _ in 0..u.int_in_range(0..=7)? {
+            query.insert_item(u.arbitrary()?);
+        }
+        Ok(query)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Node {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=4)? {
+            0 => Node::Hash(u.arbitrary()?),
+            1 => Node::KVHash(u.arbitrary()?),
+            2 => Node::KV(key(u)?, value(u)?),
+            3 => Node::KVDigest(key(u)?, u.arbitrary()?),
+            _ => Node::KVFlags(key(u)?, value(u)?, u.arbitrary()?),
+        })
+    }
+}
+
+/// Sequences of proof operators (`Vec<proofs::Op>`) are not usually a valid
+/// proof, which makes them useful for checking that verification rejects
+/// malformed proofs without panicking.
+impl<'a> Arbitrary<'a> for ProofOp {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => ProofOp::Push(u.arbitrary()?),
+            1 => ProofOp::Parent,
+            _ => ProofOp::Child,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arbitrary() {
+        let empty = &mut Unstructured::new(&[]);
+        assert!(Batch::arbitrary(empty).unwrap().0.is_empty());
+        assert!(Vec::<ProofOp>::arbitrary(empty).unwrap().is_empty());
+
+        let data: Vec<u8> = (0..=255).rev().cycle().take(2048).collect();
+        let batch = Batch::arbitrary(&mut Unstructured::new(&data)).unwrap().0;
+        assert!(!batch.is_empty());
+        assert!(batch.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(batch
+            .iter()
+            .all(|(_, op)| matches!(op, Op::Put(_) | Op::Delete)));
+        assert_eq!(
+            Batch::arbitrary(&mut Unstructured::new(&data)).unwrap().0,
+            batch
+        );
+
+        let u = &mut Unstructured::new(&data);
+        let _ = Query::arbitrary(u).unwrap();
+        let _ = Op::arbitrary(u).unwrap();
+        assert!(
+            !Vec::<ProofOp>::arbitrary_take_rest(Unstructured::new(&data))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -7,16 +7,44 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 #[cfg(feature = "full")]
 pub use rocksdb;
 
+/// Borsh encodings of proofs and batches.
+#[cfg(feature = "borsh")]
+pub mod borsh;
 /// Error and Result types.
 mod error;
+/// Layout conventions and proof helpers for EVM account and storage state.
+#[cfg(feature = "evm")]
+pub mod evm;
+/// A flat binary Merkle commitment to a store's entries in key order.
+pub mod flat;
+/// `arbitrary::Arbitrary` implementations for fuzzing.
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 /// The top-level store API.
 #[cfg(feature = "full")]
 mod merk;
+/// A Merkle Mountain Range accumulator for append-only logs.
+pub mod mmr;
+/// An Ethereum Merkle Patricia trie commitment to a store's entries.
+#[cfg(feature = "mpt")]
+pub mod mpt;
+/// Custom key orderings, expressed as order-preserving key encodings.
+pub mod order;
 /// Provides a container type that allows temporarily taking ownership of a value.
 // TODO: move this into its own crate
 pub mod owner;
 /// Algorithms for generating and verifying Merkle proofs.
 pub mod proofs;
+/// A reference implementation of the tree for differential testing.
+pub mod reference;
+/// Serde support for public types.
+#[cfg(feature = "serde")]
+mod serde_impls;
+/// Sharding of a key space across several stores by key range.
+pub mod sharded;
+/// A sparse Merkle tree mode with fixed-shape proofs.
+#[cfg(feature = "smt")]
+pub mod smt;
 
 /// Various helpers useful for tests or benchmarks.
 #[cfg(feature = "full")]
@@ -24,13 +52,23 @@ pub mod test_utils;
 /// The core tree data structure.
 pub mod tree;
 
+#[cfg(all(feature = "full", feature = "profiling"))]
+pub use crate::merk::hot_keys;
 #[cfg(feature = "full")]
-pub use crate::merk::{chunks, restore, Merk, MerkSource, Snapshot};
+pub use crate::merk::{
+    analyze, attest, backup, chunks, dump, explain, fork, health, hooks, index, info, migrate,
+    options, ordered, pinned, policy, proof_cache, quota, reader, replication, restore, scan,
+    service, slow_log, staged, subscribe, tiered, transaction, Merk, MerkReader, MerkSource,
+    PinnedVersion, Projection, Snapshot, SyncMode, VersionOverlap, CANONICAL_STATE_PREFIX,
+    LATEST_FORMAT_VERSION,
+};
 
 pub use error::{Error, Result};
-pub use tree::{Batch, BatchEntry, Hash, Op, PanicSource, HASH_LENGTH};
+pub use tree::{Batch, BatchEntry, BatchEntryRef, Hash, Op, OpRef, PanicSource, HASH_LENGTH};
 
 #[allow(deprecated)]
 pub use proofs::query::verify_query;
 
-pub use proofs::query::verify;
+pub use proofs::query::{
+    verify, verify_batch, verify_count, verify_empty_range, verify_iter, verify_with,
+};
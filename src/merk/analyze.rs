@@ -0,0 +1,224 @@
+//! Statistics about the shape of a store's tree, see `Merk::analyze`.
+
+use std::fmt;
+
+use super::integrity::value_of;
+use super::{Merk, MerkSource};
+use crate::tree::{Fetch, Tree};
+use crate::Result;
+
+/// The width of the longest bar in a histogram's text report.
+const BAR_WIDTH: u64 = 40;
+
+/// Counts of values which fall into power-of-two buckets: bucket 0 holds
+/// zeroes, and bucket `i` holds values in `2^(i-1)..2^i`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Counts `value` in its bucket.
+    pub fn record(&mut self, value: usize) {
+        let bucket = (usize::BITS - value.leading_zeros()) as usize;
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+    }
+
+    /// Returns the range of values counted in bucket `i`.
+    pub fn bucket_range(i: usize) -> std::ops::Range<usize> {
+        match i {
+            0 => 0..1,
+            i => 1 << (i - 1)..1 << i,
+        }
+    }
+
+    /// Returns the number of values counted.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// The shape of a store's tree, to help find out whether its keys or values
+/// are degrading performance, e.g. through values which are much larger than
+/// expected or a tree which is much deeper than its number of nodes needs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Analysis {
+    /// The number of nodes.
+    pub nodes: u64,
+    /// The height of the tree (0 if it is empty).
+    pub height: u8,
+    /// The number of leaves at each depth, where `leaf_depths[d]` is the
+    /// number of leaves `d` levels below the root (the root has depth 0).
+    pub leaf_depths: Vec<u64>,
+    /// The lengths of the keys.
+    pub key_lengths: Histogram,
+    /// The lengths of the values.
+    pub value_lengths: Histogram,
+    /// The number of nodes with a balance factor of -1, 0 and 1. Nodes of a
+    /// valid tree have no other balance factors.
+    pub balance_factors: [u64; 3],
+}
+
+impl Analysis {
+    /// Returns the smallest height a tree of this many nodes can have.
+    pub fn min_height(&self) -> u8 {
+        (u64::BITS - self.nodes.leading_zeros()) as u8
+    }
+
+    fn record(&mut self, tree: &Tree, source: &MerkSource, depth: usize) -> Result<()> {
+        self.nodes += 1;
+        self.key_lengths.record(tree.key().len());
+        self.value_lengths.record(value_of(tree, source)?.len());
+        let balance_factor = tree.balance_factor().clamp(-1, 1);
+        self.balance_factors[(balance_factor + 1) as usize] += 1;
+
+        if tree.link(true).is_none() && tree.link(false).is_none() {
+            if self.leaf_depths.len() <= depth {
+                self.leaf_depths.resize(depth + 1, 0);
+            }
+            self.leaf_depths[depth] += 1;
+        }
+
+        for &left in [true, false].iter() {
+            let link = match tree.link(left) {
+                Some(link) => link,
+                None => continue,
+            };
+            match tree.child(left) {
+                Some(child) => self.record(child, source, depth + 1)?,
+                None => self.record(&source.fetch(link)?, source, depth + 1)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_bars(
+    f: &mut fmt::Formatter,
+    rows: impl Iterator<Item = (String, u64)> + Clone,
+) -> fmt::Result {
+    let max = rows.clone().map(|(_, count)| count).max().unwrap_or(0);
+    let label_width = rows
+        .clone()
+        .map(|(label, _)| label.len())
+        .max()
+        .unwrap_or(0);
+    for (label, count) in rows {
+        let width = match max {
+            0 => 0,
+            max => (count * BAR_WIDTH).div_ceil(max),
+        };
+        writeln!(
+            f,
+            "  {:>label_width$} | {:<bar_width$} {}",
+            label,
+            "#".repeat(width as usize),
+            count,
+            label_width = label_width,
+            bar_width = BAR_WIDTH as usize
+        )?;
+    }
+    Ok(())
+}
+
+fn size_rows(histogram: &Histogram) -> impl Iterator<Item = (String, u64)> + Clone + '_ {
+    histogram.counts.iter().enumerate().map(|(i, &count)| {
+        let range = Histogram::bucket_range(i);
+        (format!("{}..{}", range.start, range.end), count)
+    })
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "nodes: {}, height: {} (at least {})",
+            self.nodes,
+            self.height,
+            self.min_height()
+        )?;
+        writeln!(f, "leaf depth:")?;
+        write_bars(
+            f,
+            self.leaf_depths
+                .iter()
+                .enumerate()
+                .filter(|(_, &count)| count > 0)
+                .map(|(depth, &count)| (depth.to_string(), count)),
+        )?;
+        writeln!(f, "key length (bytes):")?;
+        write_bars(f, size_rows(&self.key_lengths))?;
+        writeln!(f, "value length (bytes):")?;
+        write_bars(f, size_rows(&self.value_lengths))?;
+        let [left, even, right] = self.balance_factors;
+        write!(
+            f,
+            "balance: {} left-heavy, {} even, {} right-heavy",
+            left, even, right
+        )
+    }
+}
+
+impl Merk {
+    /// Reads every node of the tree and returns statistics about its shape:
+    /// the depths of its leaves, the lengths of its keys and values and the
+    /// balance of its nodes. The `Analysis` displays as a text report.
+    ///
+    /// This reads the whole store, so is meant for offline analysis rather
+    /// than routine use.
+    pub fn analyze(&self) -> Result<Analysis> {
+        let mut analysis = Analysis::default();
+        self.use_tree(|maybe_tree| match maybe_tree {
+            None => Ok(()),
+            Some(tree) => {
+                analysis.height = tree.height();
+                analysis.record(tree, &self.source(), 0)
+            }
+        })?;
+        Ok(analysis)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Histogram;
+    use crate::test_utils::{make_batch_seq, TempMerk};
+    use crate::Merk;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        for value in [0, 1, 2, 3, 4, 1000] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.counts, vec![1, 1, 2, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(Histogram::bucket_range(2), 2..4);
+        assert_eq!(Histogram::bucket_range(10), 512..1024);
+        assert_eq!(histogram.total(), 6);
+    }
+
+    #[test]
+    fn analyze() {
+        let mut merk = TempMerk::with_opts(Merk::default_db_opts(), 1).unwrap();
+        assert_eq!(merk.analyze().unwrap().nodes, 0);
+
+        merk.apply(&make_batch_seq(0..1000), &[]).unwrap();
+        merk.reopen().unwrap();
+        let analysis = merk.analyze().unwrap();
+        assert_eq!(analysis.nodes, 1000);
+        assert_eq!(analysis.height, merk.walk(|w| w.unwrap().tree().height()));
+        assert!(analysis.height >= analysis.min_height());
+        assert_eq!(analysis.min_height(), 10);
+        assert_eq!(analysis.key_lengths.total(), 1000);
+        assert_eq!(analysis.balance_factors.iter().sum::<u64>(), 1000);
+        let deepest = analysis.leaf_depths.len() as u8;
+        assert_eq!(deepest, analysis.height);
+
+        let report = analysis.to_string();
+        assert!(report.starts_with("nodes: 1000, height:"));
+        assert!(report.contains("value length (bytes):"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Signed attestations of root hashes, for anchoring a store's state
+//! commitments externally.
+//!
+//! A `RootAttestation` states that a store had a given root hash after a
+//! given number of commits (its height, as in `Info::commits`), signed by the
+//! store's operator. Publishing attestations (e.g. to a transparency log or a
+//! public chain) lets auditors later check that proofs served by the store
+//! match a root hash it committed to, and that it never attested to two
+//! different roots at the same height.
+//!
+//! Signing schemes are provided by implementing `Signer` and `Verifier`, so any
+//! signature library can be used; `HmacSha256` is a symmetric scheme for
+//! auditors which share a key with the operator.
+
+use sha2::{Digest, Sha256};
+
+use super::Merk;
+use crate::{Error, Hash, Result, HASH_LENGTH};
+
+/// Prefixed to the signed message, so attestation signatures cannot be
+/// replayed as signatures of other messages.
+const DOMAIN: &[u8] = b"merkdb root attestation v1";
+
+/// Signs attestation messages, and replicated batches (see
+/// `replication::SignedBatch`).
+pub trait Signer: Send + Sync {
+    /// Returns the signature of `message`.
+    fn sign(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Verifies the signatures of attestation messages and replicated batches.
+pub trait Verifier {
+    /// Returns `true` if `signature` is a valid signature of `message`.
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// A function called with the attestation of each commit.
+pub type Publisher = Box<dyn Fn(&RootAttestation) + Send + Sync>;
+
+/// A signed statement that a store had `root_hash` after `height` commits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootAttestation {
+    pub height: u64,
+    pub root_hash: Hash,
+    pub signature: Vec<u8>,
+}
+
+impl RootAttestation {
+    /// Returns the message which is signed to attest to `root_hash` at
+    /// `height`.
+    pub fn message(height: u64, root_hash: &Hash) -> Vec<u8> {
+        let mut message = Vec::with_capacity(DOMAIN.len() + 8 + HASH_LENGTH);
+        message.extend_from_slice(DOMAIN);
+        message.extend_from_slice(&height.to_be_bytes());
+        message.extend_from_slice(root_hash);
+        message
+    }
+
+    /// Creates an attestation of `root_hash` at `height`, signed by `signer`.
+    pub fn sign<S: Signer + ?Sized>(height: u64, root_hash: Hash, signer: &S) -> RootAttestation {
+        RootAttestation {
+            height,
+            root_hash,
+            signature: signer.sign(&RootAttestation::message(height, &root_hash)),
+        }
+    }
+
+    /// Checks the attestation's signature, failing with
+    /// `Error::InvalidSignature` if it is not valid.
+    pub fn verify<V: Verifier + ?Sized>(&self, verifier: &V) -> Result<()> {
+        let message = RootAttestation::message(self.height, &self.root_hash);
+        if !verifier.verify(&message, &self.signature) {
+            return Err(Error::InvalidSignature(self.height));
+        }
+        Ok(())
+    }
+
+    /// Encodes the attestation into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + HASH_LENGTH + self.signature.len());
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.root_hash);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Decodes an attestation from bytes.
+    pub fn decode(bytes: &[u8]) -> Result<RootAttestation> {
+        if bytes.len() < 8 + HASH_LENGTH {
+            return Err(Error::Decode("Root attestation is too short".into()));
+        }
+        let mut height = [0; 8];
+        height.copy_from_slice(&bytes[..8]);
+        let mut root_hash = [0; HASH_LENGTH];
+        root_hash.copy_from_slice(&bytes[8..8 + HASH_LENGTH]);
+
+        Ok(RootAttestation {
+            height: u64::from_be_bytes(height),
+            root_hash,
+            signature: bytes[8 + HASH_LENGTH..].to_vec(),
+        })
+    }
+}
+
+/// Signs and verifies messages with HMAC-SHA256. Anyone who can verify these
+/// signatures can also create them, so this is only suitable for auditors
+/// trusted with the key; other auditors need a public-key `Signer`.
+pub struct HmacSha256 {
+    key: [u8; 64],
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        let mut padded = [0; 64];
+        if key.len() > padded.len() {
+            padded[..32].copy_from_slice(&Sha256::digest(key));
+        } else {
+            padded[..key.len()].copy_from_slice(key);
+        }
+        HmacSha256 { key: padded }
+    }
+
+    fn mac(&self, message: &[u8]) -> [u8; 32] {
+        let pad = |byte: u8| self.key.iter().map(move |k| k ^ byte).collect::<Vec<_>>();
+        let inner = Sha256::new()
+            .chain_update(pad(0x36))
+            .chain_update(message)
+            .finalize();
+        Sha256::new()
+            .chain_update(pad(0x5c))
+            .chain_update(inner)
+            .finalize()
+            .into()
+    }
+}
+
+impl Signer for HmacSha256 {
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.mac(message).to_vec()
+    }
+}
+
+impl Verifier for HmacSha256 {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        // compare in constant time so the mac cannot be found byte by byte
+        let expected = self.mac(message);
+        signature.len() == expected.len()
+            && expected
+                .iter()
+                .zip(signature)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl Merk {
+    /// Sets a signer which attests to the root hash after each commit. The
+    /// attestation is signed before the commit is written, so a failure to
+    /// sign it fails the commit rather than leaving it unattested, and is
+    /// passed to `publish` once the commit has been written to the database.
+    pub fn set_root_signer<S, F>(&mut self, signer: S, publish: F)
+    where
+        S: Signer + 'static,
+        F: Fn(&RootAttestation) + Send + Sync + 'static,
+    {
+        self.root_signer = Some((Box::new(signer), Box::new(publish)));
+    }
+
+    /// Stops attesting to root hashes on commit.
+    pub fn clear_root_signer(&mut self) {
+        self.root_signer = None;
+    }
+
+    /// Returns a signed attestation of the store's current root hash and
+    /// height. Heights only increase, also across rollbacks, so a store never
+    /// attests to two different roots at the same height by rolling back and
+    /// committing again.
+    pub fn attest<S: Signer + ?Sized>(&self, signer: &S) -> Result<RootAttestation> {
+        let height = self.info()?.commits;
+        Ok(RootAttestation::sign(height, self.root_hash(), signer))
+    }
+
+    /// Signs the attestation to the commit being made, at the height the
+    /// store has once it is written, so the attestation is ready before the
+    /// commit becomes durable and publishing it afterwards cannot fail.
+    pub(crate) fn sign_attestation(&self) -> Result<Option<RootAttestation>> {
+        match &self.root_signer {
+            Some((signer, _)) => {
+                let height = self.info()?.commits + 1;
+                let attestation = RootAttestation::sign(height, self.root_hash(), signer.as_ref());
+                Ok(Some(attestation))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn publish_attestation(&self, attestation: Option<RootAttestation>) {
+        if let (Some((_, publish)), Some(attestation)) = (&self.root_signer, attestation) {
+            publish(&attestation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn hmac_sha256() {
+        // RFC 4231 test case 2
+        let mac = HmacSha256::new(b"Jefe").sign(b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn attest_commits() {
+        let mut merk = TempMerk::new().unwrap();
+        let published = Arc::new(Mutex::new(vec![]));
+        let sink = published.clone();
+        merk.set_root_signer(HmacSha256::new(b"key"), move |attestation| {
+            sink.lock().unwrap().push(attestation.clone())
+        });
+
+        merk.apply(&make_batch_seq(0..10), &[]).unwrap();
+        merk.apply(&make_batch_seq(10..20), &[]).unwrap();
+        merk.clear_root_signer();
+        merk.apply(&make_batch_seq(20..30), &[]).unwrap();
+
+        let published = published.lock().unwrap();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0].height, 1);
+        assert_eq!(published[1].height, 2);
+        assert_eq!(published[1].root_hash, merk.info().unwrap().recent_roots[1]);
+
+        let verifier = HmacSha256::new(b"key");
+        let attestation = RootAttestation::decode(&published[1].encode()).unwrap();
+        assert_eq!(attestation, published[1]);
+        attestation.verify(&verifier).unwrap();
+        assert!(attestation.verify(&HmacSha256::new(b"other key")).is_err());
+
+        let mut forged = attestation.clone();
+        forged.height = 3;
+        assert!(matches!(
+            forged.verify(&verifier),
+            Err(Error::InvalidSignature(3))
+        ));
+
+        let attestation = merk.attest(&verifier).unwrap();
+        assert_eq!(attestation.height, 3);
+        assert_eq!(attestation.root_hash, merk.root_hash());
+    }
+
+    #[test]
+    fn attest_after_rollback() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retained_versions(4);
+        let signer = HmacSha256::new(b"key");
+        merk.apply(&make_batch_seq(0..10), &[]).unwrap();
+        merk.apply(&make_batch_seq(10..20), &[]).unwrap();
+        let attested = merk.attest(&signer).unwrap();
+        assert_eq!(attested.height, 2);
+
+        // committing a different batch after a rollback attests at a new
+        // height
+        merk.rollback().unwrap();
+        assert_eq!(merk.attest(&signer).unwrap().height, 2);
+        merk.apply(&make_batch_seq(20..30), &[]).unwrap();
+        let attestation = merk.attest(&signer).unwrap();
+        assert_ne!(attestation.root_hash, attested.root_hash);
+        assert_eq!(attestation.height, 3);
+
+        // the restored metadata keeps the rolled back root history
+        merk.rollback().unwrap();
+        let info = merk.info().unwrap();
+        assert_eq!(info.commits, 3);
+        assert_eq!(info.recent_roots.len(), 1);
+    }
+}
@@ -0,0 +1,372 @@
+//! Backups of a live store to object storage.
+//!
+//! `Merk::start_backup` takes a RocksDB checkpoint of the store, which is
+//! cheap since it hard-links the database's immutable files, so the store can
+//! keep committing while the backup is uploaded. The checkpoint is then
+//! written to a `BackupSink` as a set of named objects, either as the raw
+//! database files (`Backup::write_files`, which includes auxiliary data and
+//! indexes) or as chunk proofs of the tree (`Backup::write_chunks`, which is
+//! independent of the RocksDB version and is verified against the root hash
+//! when restored). A manifest listing the objects is written last, so a backup
+//! which was interrupted is never mistaken for a complete one.
+//!
+//! `restore_from_backup` reads a backup back from a `BackupSource` into a new
+//! store, checking that it has the root hash recorded in the manifest.
+//!
+//! Sinks and sources are implemented for specific storage services by
+//! applications (see `examples/s3_backup.rs` for one backed by S3);
+//! `DirectoryBackup` stores objects as files in a local directory.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use super::lock::LOCK_FILE_NAME;
+use super::Merk;
+use crate::tree::NULL_HASH;
+use crate::{Error, Hash, Result, HASH_LENGTH};
+
+/// The name of the object holding a backup's manifest.
+pub const MANIFEST_NAME: &str = "backup-manifest";
+
+const CHUNKS_TAG: u8 = 0;
+const FILES_TAG: u8 = 1;
+
+/// Somewhere backup objects can be written, such as a bucket of an object
+/// storage service.
+pub trait BackupSink {
+    /// Writes the object with the given name, replacing any existing object of
+    /// that name. The object's contents are read from `data` until it is
+    /// exhausted.
+    fn put(&mut self, name: &str, data: &mut dyn Read) -> Result<()>;
+}
+
+/// Somewhere backup objects can be read from, see `BackupSink`.
+pub trait BackupSource {
+    /// Returns a reader of the contents of the object with the given name.
+    fn get(&mut self, name: &str) -> Result<Box<dyn Read + '_>>;
+}
+
+/// The form objects of a backup are stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupKind {
+    /// Chunk proofs of the tree, as produced by `Merk::chunks`.
+    Chunks,
+    /// The files of a RocksDB checkpoint of the store.
+    Files,
+}
+
+/// Describes a complete backup, written as the object named `MANIFEST_NAME`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub kind: BackupKind,
+    /// The root hash of the backed up store.
+    pub root_hash: Hash,
+    /// The names of the backup's objects, in the order they are restored.
+    pub objects: Vec<String>,
+}
+
+impl BackupManifest {
+    /// Encodes the manifest into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![match self.kind {
+            BackupKind::Chunks => CHUNKS_TAG,
+            BackupKind::Files => FILES_TAG,
+        }];
+        bytes.extend_from_slice(&self.root_hash);
+        bytes.extend_from_slice(&(self.objects.len() as u32).to_be_bytes());
+        for name in self.objects.iter() {
+            bytes.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a manifest from bytes.
+    pub fn decode(mut bytes: &[u8]) -> Result<BackupManifest> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+            if bytes.len() < len {
+                return Err(Error::Decode("Unexpected end of backup manifest".into()));
+            }
+            let (head, tail) = bytes.split_at(len);
+            *bytes = tail;
+            Ok(head)
+        }
+        fn take_u32(bytes: &mut &[u8]) -> Result<usize> {
+            let mut int = [0; 4];
+            int.copy_from_slice(take(bytes, 4)?);
+            Ok(u32::from_be_bytes(int) as usize)
+        }
+
+        let kind = match take(&mut bytes, 1)?[0] {
+            CHUNKS_TAG => BackupKind::Chunks,
+            FILES_TAG => BackupKind::Files,
+            tag => return Err(Error::Decode(format!("Unknown backup kind {}", tag))),
+        };
+        let mut root_hash = NULL_HASH;
+        root_hash.copy_from_slice(take(&mut bytes, HASH_LENGTH)?);
+        let mut objects = vec![];
+        for _ in 0..take_u32(&mut bytes)? {
+            let len = take_u32(&mut bytes)?;
+            let name = String::from_utf8(take(&mut bytes, len)?.to_vec())
+                .map_err(|_| Error::Decode("Invalid object name in backup manifest".into()))?;
+            objects.push(name);
+        }
+        if !bytes.is_empty() {
+            return Err(Error::Decode(
+                "Unexpected bytes after backup manifest".into(),
+            ));
+        }
+
+        Ok(BackupManifest {
+            kind,
+            root_hash,
+            objects,
+        })
+    }
+}
+
+/// A checkpoint of a store being backed up, see `Merk::start_backup`. The
+/// checkpoint is deleted when the `Backup` is dropped.
+pub struct Backup {
+    path: PathBuf,
+    root_hash: Hash,
+}
+
+impl Merk {
+    /// Starts a backup of the store by taking a checkpoint of it at
+    /// `scratch_path`, which must not exist. The store is not borrowed by the
+    /// returned `Backup`, so it can keep committing while the backup is
+    /// written.
+    pub fn start_backup<P: AsRef<Path>>(&self, scratch_path: P) -> Result<Backup> {
+        let path = scratch_path.as_ref().to_path_buf();
+        self.flush()?;
+        rocksdb::checkpoint::Checkpoint::new(self.db.inner())?.create_checkpoint(&path)?;
+        Ok(Backup {
+            path,
+            root_hash: self.root_hash(),
+        })
+    }
+}
+
+impl Backup {
+    /// Returns the root hash of the store at the time of the checkpoint.
+    pub fn root_hash(&self) -> Hash {
+        self.root_hash
+    }
+
+    /// Writes the checkpoint's database files to `sink`, followed by the
+    /// manifest.
+    pub fn write_files<S: BackupSink>(&self, sink: &mut S) -> Result<BackupManifest> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if is_local_file(&name) {
+                continue;
+            }
+            names.push(name);
+        }
+        // CURRENT points to the MANIFEST, so it is restored last
+        names.sort_by_key(|name| name == "CURRENT");
+
+        let mut objects = vec![];
+        for name in names {
+            let object = format!("file-{}", name);
+            sink.put(&object, &mut File::open(self.path.join(&name))?)?;
+            objects.push(object);
+        }
+
+        self.write_manifest(BackupKind::Files, objects, sink)
+    }
+
+    /// Writes chunk proofs of the checkpoint's tree to `sink`, followed by the
+    /// manifest.
+    ///
+    /// Only the tree is included: auxiliary data and the expiry and secondary
+    /// indexes are not restored from a chunk backup.
+    pub fn write_chunks<S: BackupSink>(&self, sink: &mut S) -> Result<BackupManifest> {
+        let mut objects = vec![];
+        if self.root_hash != NULL_HASH {
+            let merk = Merk::open(&self.path)?;
+            for (i, chunk) in merk.chunks()?.into_iter().enumerate() {
+                let object = format!("chunk-{:08}", i);
+                sink.put(&object, &mut chunk?.as_slice())?;
+                objects.push(object);
+            }
+        }
+
+        self.write_manifest(BackupKind::Chunks, objects, sink)
+    }
+
+    fn write_manifest<S: BackupSink>(
+        &self,
+        kind: BackupKind,
+        objects: Vec<String>,
+        sink: &mut S,
+    ) -> Result<BackupManifest> {
+        let manifest = BackupManifest {
+            kind,
+            root_hash: self.root_hash,
+            objects,
+        };
+        sink.put(MANIFEST_NAME, &mut manifest.encode().as_slice())?;
+        Ok(manifest)
+    }
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Files of a database directory which belong to the process which has it
+/// open rather than to the database.
+fn is_local_file(name: &str) -> bool {
+    name == "LOCK" || name == LOCK_FILE_NAME || name.starts_with("LOG")
+}
+
+/// Restores the backup in `source` to a new store at `path`, which must not
+/// exist. Fails with `Error::HashMismatch` if the restored store does not have
+/// the root hash recorded in the backup's manifest.
+pub fn restore_from_backup<S, P>(source: &mut S, path: P) -> Result<Merk>
+where
+    S: BackupSource,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    if path.exists() {
+        return Err(Error::StoreExists(path.to_path_buf()));
+    }
+
+    let mut manifest_bytes = vec![];
+    source
+        .get(MANIFEST_NAME)?
+        .read_to_end(&mut manifest_bytes)?;
+    let manifest = BackupManifest::decode(&manifest_bytes)?;
+
+    let merk = match manifest.kind {
+        BackupKind::Files => {
+            fs::create_dir_all(path)?;
+            for object in manifest.objects.iter() {
+                let name = object
+                    .strip_prefix("file-")
+                    .filter(|name| !name.contains('/') && !name.contains('\\'))
+                    .ok_or_else(|| Error::Decode(format!("Invalid file object {}", object)))?;
+                let mut file = File::create(path.join(name))?;
+                io::copy(&mut source.get(object)?, &mut file)?;
+                file.sync_all()?;
+            }
+            Merk::open(path)?
+        }
+        BackupKind::Chunks if manifest.objects.is_empty() => Merk::open(path)?,
+        BackupKind::Chunks => {
+            let mut restorer = Merk::restore(path, manifest.root_hash, manifest.objects.len())?;
+            for object in manifest.objects.iter() {
+                let mut chunk = vec![];
+                source.get(object)?.read_to_end(&mut chunk)?;
+                restorer.process_chunk(&chunk)?;
+            }
+            restorer.finalize()?
+        }
+    };
+
+    if merk.root_hash() != manifest.root_hash {
+        return Err(Error::HashMismatch(manifest.root_hash, merk.root_hash()));
+    }
+    Ok(merk)
+}
+
+/// A backup sink and source which stores objects as files in a directory.
+pub struct DirectoryBackup {
+    path: PathBuf,
+}
+
+impl DirectoryBackup {
+    /// Uses the directory at `path`, creating it if it does not exist.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<DirectoryBackup> {
+        fs::create_dir_all(&path)?;
+        Ok(DirectoryBackup {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+impl BackupSink for DirectoryBackup {
+    fn put(&mut self, name: &str, data: &mut dyn Read) -> Result<()> {
+        // write to a temporary file first so objects are replaced atomically
+        let tmp_path = self.path.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp_path)?;
+        io::copy(data, &mut file)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.path.join(name))?;
+        Ok(())
+    }
+}
+
+impl BackupSource for DirectoryBackup {
+    fn get(&mut self, name: &str) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(self.path.join(name))?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn backup_and_restore() {
+        let tmp_dir = tempdir::TempDir::new("merk_backup").unwrap();
+        let mut merk = Merk::open(tmp_dir.path().join("store")).unwrap();
+        merk.apply(&make_batch_seq(0..1000), &[(vec![1], Op::Put(vec![2]))])
+            .unwrap();
+
+        let backup = merk.start_backup(tmp_dir.path().join("scratch")).unwrap();
+        // the store can keep committing while the backup is written
+        merk.apply(&make_batch_seq(1000..1010), &[]).unwrap();
+
+        let mut chunks = DirectoryBackup::new(tmp_dir.path().join("chunks")).unwrap();
+        let manifest = backup.write_chunks(&mut chunks).unwrap();
+        assert_eq!(manifest.kind, BackupKind::Chunks);
+        assert!(manifest.objects.len() > 1);
+        let mut files = DirectoryBackup::new(tmp_dir.path().join("files")).unwrap();
+        let manifest = backup.write_files(&mut files).unwrap();
+        assert_eq!(manifest.kind, BackupKind::Files);
+        assert_eq!(manifest.objects.last().unwrap(), "file-CURRENT");
+        assert_eq!(
+            BackupManifest::decode(&manifest.encode()).unwrap(),
+            manifest
+        );
+        let root_hash = backup.root_hash();
+        drop(backup);
+        assert!(!tmp_dir.path().join("scratch").exists());
+
+        let restored = restore_from_backup(&mut chunks, tmp_dir.path().join("a")).unwrap();
+        assert_eq!(restored.root_hash(), root_hash);
+        assert_eq!(restored.get_aux(&[1]).unwrap(), None);
+        let restored = restore_from_backup(&mut files, tmp_dir.path().join("b")).unwrap();
+        assert_eq!(restored.root_hash(), root_hash);
+        assert_eq!(restored.get_aux(&[1]).unwrap(), Some(vec![2]));
+        assert_ne!(merk.root_hash(), root_hash);
+
+        // an interrupted backup has no manifest
+        let mut empty = DirectoryBackup::new(tmp_dir.path().join("empty")).unwrap();
+        assert!(restore_from_backup(&mut empty, tmp_dir.path().join("c")).is_err());
+
+        // backups of an empty store have no chunks
+        let empty_store = TempMerk::new().unwrap();
+        let backup = empty_store
+            .start_backup(tmp_dir.path().join("scratch"))
+            .unwrap();
+        let mut sink = DirectoryBackup::new(tmp_dir.path().join("no_chunks")).unwrap();
+        assert!(backup.write_chunks(&mut sink).unwrap().objects.is_empty());
+        let restored = restore_from_backup(&mut sink, tmp_dir.path().join("d")).unwrap();
+        assert_eq!(restored.root_hash(), NULL_HASH);
+    }
+}
@@ -1,7 +1,7 @@
 //! Provides `ChunkProducer`, which creates chunk proofs for full replication of
 //! a Merk.
 
-use super::Merk;
+use super::{separated_value, Merk, TreeDb};
 use crate::proofs::{chunk::get_next_chunk, Node, Op};
 
 use crate::{Error, Result};
@@ -15,6 +15,7 @@ pub struct ChunkProducer<'a> {
     trunk: Vec<Op>,
     chunk_boundaries: Vec<Vec<u8>>,
     raw_iter: DBRawIterator<'a>,
+    db: &'a TreeDb,
     index: usize,
 }
 
@@ -31,7 +32,9 @@ impl<'a> ChunkProducer<'a> {
             trunk
                 .iter()
                 .filter_map(|op| match op {
-                    Op::Push(Node::KV(key, _)) => Some(key.clone()),
+                    Op::Push(Node::KV(key, _)) | Op::Push(Node::KVFlags(key, ..)) => {
+                        Some(key.clone())
+                    }
                     _ => None,
                 })
                 .collect()
@@ -46,6 +49,7 @@ impl<'a> ChunkProducer<'a> {
             trunk,
             chunk_boundaries,
             raw_iter,
+            db: &merk.db,
             index: 0,
         })
     }
@@ -103,7 +107,10 @@ impl<'a> ChunkProducer<'a> {
 
         self.index += 1;
 
-        let chunk = get_next_chunk(&mut self.raw_iter, end_key_slice)?;
+        let db = self.db;
+        let chunk = get_next_chunk(&mut self.raw_iter, end_key_slice, |key| {
+            separated_value(db, key)
+        })?;
         Ok(chunk.encode()?)
     }
 }
@@ -142,6 +149,7 @@ impl Merk {
     /// Creates a `ChunkProducer` which can return chunk proofs for replicating
     /// the entire Merk tree.
     pub fn chunks(&self) -> Result<ChunkProducer> {
+        self.check_not_offloaded("produce chunks")?;
         ChunkProducer::new(self)
     }
 }
@@ -149,13 +157,7 @@ impl Merk {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{
-        proofs::{
-            chunk::{verify_leaf, verify_trunk},
-            Decoder,
-        },
-        test_utils::*,
-    };
+    use crate::{proofs::ChunkProof, test_utils::*};
 
     #[test]
     fn len_small() {
@@ -188,16 +190,14 @@ mod tests {
         let mut chunks = merk.chunks().unwrap().into_iter().map(Result::unwrap);
 
         let chunk = chunks.next().unwrap();
-        let ops = Decoder::new(chunk.as_slice());
-        let (trunk, height) = verify_trunk(ops).unwrap();
+        let (trunk, height) = ChunkProof::new(&chunk).verify_trunk().unwrap();
         assert_eq!(height, 14);
         assert_eq!(trunk.hash()?, merk.root_hash());
 
         assert_eq!(trunk.layer(7).count(), 128);
 
         for (chunk, node) in chunks.zip(trunk.layer(height / 2)) {
-            let ops = Decoder::new(chunk.as_slice());
-            verify_leaf(ops, node.hash()?).unwrap();
+            ChunkProof::new(&chunk).verify_leaf(node.hash()?).unwrap();
         }
         Ok(())
     }
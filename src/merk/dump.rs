@@ -0,0 +1,277 @@
+//! Dumps of a store's raw node records, for moving trees between machines and
+//! offline analysis.
+//!
+//! `Merk::dump_nodes` writes the records of the nodes in a range of keys as
+//! they are stored, each with its key, its node hash and (for nodes whose
+//! values are stored apart from their records) its value. `load_nodes` builds
+//! a new store from a dump of every node, checking each record against its
+//! hash and the whole tree against the expected root hash, so the dump does
+//! not need to be trusted.
+//!
+//! A dump starts with a header:
+//!
+//! - the magic bytes `MAGIC` and the dump format version (1 byte),
+//! - the store's format version (1 byte, see `Merk::format_version`),
+//! - the root hash (32 bytes) and the root key (`u32` length, then the key),
+//!
+//! followed by one entry per node, in key order:
+//!
+//! - the key (`u32` length, then the key) and the node hash (32 bytes),
+//! - the record (`u32` length, then the record),
+//! - the separately stored value, or none (`u32` length, or `u32::MAX` for
+//!   none, then the value).
+//!
+//! All integers are big-endian.
+
+use std::io::{self, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rocksdb::WriteBatch;
+
+use super::{Merk, INTERNAL_CF_NAME, ROOT_KEY_KEY, VALUES_CF_NAME};
+use crate::tree::{TreeRef, NULL_HASH};
+use crate::{Error, Hash, Result, HASH_LENGTH};
+
+/// The bytes a dump starts with.
+pub const MAGIC: &[u8; 8] = b"merkdump";
+
+const DUMP_VERSION: u8 = 1;
+
+/// The number of nodes written to the new store in each batch while loading.
+const LOAD_BATCH_SIZE: usize = 10_000;
+
+fn write_bytes<W: Write>(out: &mut W, bytes: &[u8]) -> Result<()> {
+    out.write_u32::<BigEndian>(bytes.len() as u32)?;
+    out.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let length = input.read_u32::<BigEndian>()?;
+    read_exact(input, length)
+}
+
+fn read_exact<R: Read>(input: &mut R, length: u32) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    input.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+fn read_hash<R: Read>(input: &mut R) -> Result<Hash> {
+    let mut hash = [0; HASH_LENGTH];
+    input.read_exact(&mut hash)?;
+    Ok(hash)
+}
+
+impl Merk {
+    /// Writes the raw records of the nodes with keys in `range` to `out` in
+    /// key order, in the format described in the `dump` module, and returns
+    /// the number of nodes written. A dump of every node (`..`) can be loaded
+    /// into a new store with `load_nodes`.
+    pub fn dump_nodes<R, W>(&self, range: R, mut out: W) -> Result<u64>
+    where
+        R: RangeBounds<Vec<u8>>,
+        W: Write,
+    {
+        self.check_not_offloaded("dump nodes")?;
+        out.write_all(MAGIC)?;
+        out.write_all(&[DUMP_VERSION, self.format_version])?;
+        out.write_all(&self.root_hash())?;
+        let root_key = self.use_tree(|maybe_tree| maybe_tree.map(|tree| tree.key().to_vec()));
+        write_bytes(&mut out, &root_key.unwrap_or_default())?;
+
+        let values_cf = self.db.cf_handle(VALUES_CF_NAME).unwrap();
+        let mut iter = self.db.raw_iterator();
+        match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => iter.seek(start),
+            Bound::Unbounded => iter.seek_to_first(),
+        }
+
+        let mut count = 0;
+        while let (Some(key), Some(record)) = (iter.key(), iter.value()) {
+            if !range.contains(&key.to_vec()) {
+                if let Bound::Excluded(start) = range.start_bound() {
+                    if key == start.as_slice() {
+                        iter.next();
+                        continue;
+                    }
+                }
+                break;
+            }
+
+            let node = TreeRef::decode(key, record)?;
+            write_bytes(&mut out, key)?;
+            out.write_all(&node.hash())?;
+            write_bytes(&mut out, record)?;
+            match node.value() {
+                Some(_) => out.write_u32::<BigEndian>(u32::MAX)?,
+                None => {
+                    let value = self
+                        .db
+                        .get_cf(values_cf, key)?
+                        .ok_or_else(|| super::missing_separated_value(key))?;
+                    write_bytes(&mut out, &value)?;
+                }
+            }
+            count += 1;
+            iter.next();
+        }
+        Ok(count)
+    }
+}
+
+/// Creates a store at `path` from a dump of every node of a store written by
+/// `Merk::dump_nodes`, and checks that it has the root hash
+/// `expected_root_hash`. Fails with `Error::HashMismatch` if the dump is of a
+/// different tree, or `Error::InvariantViolation` if any of its records are
+/// corrupt or missing, in which case the new store is deleted. The new
+/// store's metadata (see `Merk::info`) records the dumped root as its only
+/// commit.
+pub fn load_nodes<P, R>(path: P, mut input: R, expected_root_hash: Hash) -> Result<Merk>
+where
+    P: AsRef<Path>,
+    R: Read,
+{
+    let mut header = [0; 10];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC || header[8] != DUMP_VERSION {
+        return Err(Error::Decode(
+            "Not a node dump of a supported version".into(),
+        ));
+    }
+    let format_version = header[9];
+    let root_hash = read_hash(&mut input)?;
+    if root_hash != expected_root_hash {
+        return Err(Error::HashMismatch(expected_root_hash, root_hash));
+    }
+    let root_key = read_bytes(&mut input)?;
+
+    let mut merk = Merk::create_new(path)?;
+    match load_into(&mut merk, input, format_version, root_key, root_hash) {
+        Ok(()) => Ok(merk),
+        Err(err) => {
+            merk.destroy()?;
+            Err(err)
+        }
+    }
+}
+
+fn load_into<R: Read>(
+    merk: &mut Merk,
+    mut input: R,
+    format_version: u8,
+    root_key: Vec<u8>,
+    root_hash: Hash,
+) -> Result<()> {
+    merk.set_format_version(format_version)?;
+
+    let mut batch = WriteBatch::default();
+    loop {
+        let key = match input.read_u32::<BigEndian>() {
+            Ok(length) => read_exact(&mut input, length)?,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        let hash = read_hash(&mut input)?;
+        let record = read_bytes(&mut input)?;
+        if TreeRef::decode(&key, &record)?.hash() != hash {
+            return Err(Error::InvariantViolation {
+                key,
+                detail: "Dumped record does not match its hash".into(),
+            });
+        }
+        match input.read_u32::<BigEndian>()? {
+            u32::MAX => {}
+            length => {
+                let values_cf = merk.db.cf_handle(VALUES_CF_NAME).unwrap();
+                batch.put_cf(values_cf, &key, read_exact(&mut input, length)?);
+            }
+        }
+        batch.put(&key, record);
+
+        if batch.len() >= LOAD_BATCH_SIZE {
+            merk.write(std::mem::take(&mut batch))?;
+        }
+    }
+    merk.write(batch)?;
+
+    // set the root and record it in the store's metadata as a commit would,
+    // so the loaded store's history starts at the dumped root
+    if root_hash != NULL_HASH {
+        let mut writes = vec![(INTERNAL_CF_NAME, ROOT_KEY_KEY.to_vec(), Some(root_key))];
+        merk.info_writes(root_hash, &mut writes)?;
+        merk.write_pending(writes)?;
+    }
+    merk.load_root()?;
+
+    // every hash is checked against the data below it, so a store with the
+    // expected root hash has exactly the dumped tree
+    merk.verify_integrity()?;
+    if merk.root_hash() != root_hash {
+        return Err(Error::HashMismatch(root_hash, merk.root_hash()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_nodes;
+    use crate::test_utils::*;
+    use crate::{Error, Merk};
+    use tempdir::TempDir;
+
+    #[test]
+    fn dump_and_load() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_separate_value_length(64);
+        merk.apply(&make_batch_rand(1_000, 1), &[]).unwrap();
+        let batch: Vec<_> = (0..10u64)
+            .map(|i| (seq_key(i), crate::Op::Put(vec![1; 100])))
+            .collect();
+        merk.apply(&batch, &[]).unwrap();
+
+        let mut dump = vec![];
+        assert_eq!(merk.dump_nodes(.., &mut dump).unwrap(), 1_010);
+
+        let dir = TempDir::new("dump").unwrap();
+        let loaded = load_nodes(dir.path().join("a"), dump.as_slice(), merk.root_hash()).unwrap();
+        assert_eq!(loaded.root_hash(), merk.root_hash());
+        assert_eq!(loaded.get(&seq_key(3)).unwrap(), Some(vec![1; 100]));
+        let info = loaded.info().unwrap();
+        assert_eq!(info.commits, 1);
+        assert_eq!(info.recent_roots, vec![merk.root_hash()]);
+        drop(loaded);
+
+        // the wrong root hash, a corrupt record or a missing node
+        let path = dir.path().join("b");
+        assert!(matches!(
+            load_nodes(&path, dump.as_slice(), [1; 32]),
+            Err(Error::HashMismatch(..))
+        ));
+        let mut corrupt = dump.clone();
+        let last = corrupt.len() - 10;
+        corrupt[last] ^= 1;
+        assert!(load_nodes(&path, corrupt.as_slice(), merk.root_hash()).is_err());
+        assert!(!path.exists());
+
+        let mut partial = vec![];
+        let count = merk
+            .dump_nodes(seq_key(0)..=seq_key(5), &mut partial)
+            .unwrap();
+        assert_eq!(count, 6);
+        assert!(load_nodes(&path, partial.as_slice(), merk.root_hash()).is_err());
+
+        // an empty store
+        let empty = TempMerk::new().unwrap();
+        let mut dump = vec![];
+        assert_eq!(empty.dump_nodes(.., &mut dump).unwrap(), 0);
+        let loaded = load_nodes(&path, dump.as_slice(), empty.root_hash()).unwrap();
+        assert_eq!(loaded.root_hash(), empty.root_hash());
+        assert_eq!(Merk::destroy(loaded).ok(), Some(()));
+    }
+}
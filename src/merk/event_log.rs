@@ -0,0 +1,140 @@
+//! An append-only event log kept alongside the tree, accumulated in a Merkle
+//! Mountain Range (see the `mmr` module).
+//!
+//! Events are appended in the same write as the tree batch they are committed
+//! with, so the log's root and the tree's root always describe the same
+//! commit. The log's nodes are kept in their own column family and are not
+//! part of the tree.
+
+use std::convert::TryInto;
+
+use super::{check_batch_keys, Merk, PendingWrite, TreeDb, LOG_CF_NAME};
+use crate::mmr::{self, InclusionProof, MmrStore, NodeId};
+use crate::{tree::Batch, Error, Hash, Result};
+
+const LEN_KEY: &[u8] = b"len";
+
+fn node_key((level, index): NodeId) -> Vec<u8> {
+    let mut key = Vec::with_capacity(9);
+    key.push(level);
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+struct LogStore<'a>(&'a TreeDb);
+
+impl<'a> MmrStore for LogStore<'a> {
+    fn node(&self, id: NodeId) -> Result<Option<Hash>> {
+        let log_cf = self.0.cf_handle(LOG_CF_NAME).unwrap();
+        self.0
+            .get_pinned_cf(log_cf, node_key(id))?
+            .map(|bytes| {
+                bytes[..]
+                    .try_into()
+                    .map_err(|_| Error::Decode("Invalid MMR node".into()))
+            })
+            .transpose()
+    }
+}
+
+impl Merk {
+    /// Applies a batch of operations like `apply`, and appends `events` to the
+    /// event log in the same commit.
+    pub fn apply_with_log<E: AsRef<[u8]>>(
+        &mut self,
+        batch: &Batch,
+        aux: &Batch,
+        events: &[E],
+    ) -> Result<()> {
+        check_batch_keys(batch)?;
+
+        let (batch, mut writes) = self.prepare_writes(batch, None)?;
+        self.log_writes(events, &mut writes)?;
+        unsafe { self.apply_unchecked_with(&batch, aux, writes) }
+    }
+
+    /// Returns the number of events in the event log.
+    pub fn log_len(&self) -> Result<u64> {
+        let log_cf = self.db.cf_handle(LOG_CF_NAME).unwrap();
+        match self.db.get_pinned_cf(log_cf, LEN_KEY)? {
+            None => Ok(0),
+            Some(bytes) => {
+                Ok(u64::from_be_bytes(bytes[..].try_into().map_err(|_| {
+                    Error::Decode("Invalid event log length".into())
+                })?))
+            }
+        }
+    }
+
+    /// Returns the root hash of the event log, or `NULL_HASH` if it is empty.
+    pub fn log_root(&self) -> Result<Hash> {
+        mmr::root(&LogStore(&self.db), self.log_len()?)
+    }
+
+    /// Creates a proof that an event is the one at `index` in the event log,
+    /// which can be checked against `log_root` with `InclusionProof::verify`.
+    pub fn prove_log(&self, index: u64) -> Result<InclusionProof> {
+        mmr::prove(&LogStore(&self.db), self.log_len()?, index)
+    }
+
+    fn log_writes<E: AsRef<[u8]>>(
+        &self,
+        events: &[E],
+        writes: &mut Vec<PendingWrite>,
+    ) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let len = self.log_len()?;
+        for (id, hash) in mmr::append(&LogStore(&self.db), len, events)? {
+            writes.push((LOG_CF_NAME, node_key(id), Some(hash.to_vec())));
+        }
+        let len = len + events.len() as u64;
+        writes.push((
+            LOG_CF_NAME,
+            LEN_KEY.to_vec(),
+            Some(len.to_be_bytes().to_vec()),
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mmr::Mmr;
+    use crate::test_utils::*;
+    use crate::tree::NULL_HASH;
+
+    #[test]
+    fn apply_with_log() {
+        let mut merk = TempMerk::new().unwrap();
+        assert_eq!(merk.log_len().unwrap(), 0);
+        assert_eq!(merk.log_root().unwrap(), NULL_HASH);
+
+        let mut expected = Mmr::new();
+        for i in 0..10u64 {
+            let events: Vec<_> = (0..i).map(|j| (i * 100 + j).to_be_bytes()).collect();
+            events.iter().for_each(|event| expected.push(event));
+            merk.apply_with_log(&make_batch_seq(i * 10..(i + 1) * 10), &[], &events)
+                .unwrap();
+        }
+        assert_eq!(merk.log_len().unwrap(), 45);
+        assert_eq!(merk.log_root().unwrap(), expected.root());
+
+        let proof = merk.prove_log(44).unwrap();
+        assert_eq!(proof, expected.prove(44).unwrap());
+        proof
+            .verify(&merk.log_root().unwrap(), &908u64.to_be_bytes())
+            .unwrap();
+        assert!(merk.prove_log(45).is_err());
+
+        // rolling back a commit rolls back its events
+        merk.set_retained_versions(1);
+        merk.apply_with_log(&[], &[], &[b"event"]).unwrap();
+        assert_eq!(merk.log_len().unwrap(), 46);
+        merk.rollback().unwrap();
+        assert_eq!(merk.log_len().unwrap(), 45);
+        assert_eq!(merk.log_root().unwrap(), expected.root());
+    }
+}
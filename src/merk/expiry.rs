@@ -0,0 +1,237 @@
+//! Epoch-based expiry of entries, for rent-like mechanisms where state is
+//! only kept for as long as it has been paid for.
+//!
+//! Expiry epochs are tracked in a separate column family which is not part of
+//! the Merkle tree, so attaching an expiry to a key does not change the root
+//! hash. The index holds two records per expiring key: one keyed by the
+//! entry's key (to find its current epoch when it is overwritten or deleted),
+//! and one keyed by the epoch followed by the entry's key (so `expire` can
+//! find every expired key with a single range scan).
+
+use std::convert::TryInto;
+
+use rocksdb::ReadOptions;
+
+use super::{check_batch_keys, Merk, PendingWrite, EXPIRY_CF_NAME};
+use crate::tree::{Batch, Entry, OpRef};
+use crate::{Error, Op, Result};
+
+const BY_KEY_PREFIX: u8 = b'k';
+const BY_EPOCH_PREFIX: u8 = b'e';
+
+fn by_key(key: &[u8]) -> Vec<u8> {
+    let mut index_key = Vec::with_capacity(key.len() + 1);
+    index_key.push(BY_KEY_PREFIX);
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+fn by_epoch(epoch: u64, key: &[u8]) -> Vec<u8> {
+    let mut index_key = Vec::with_capacity(key.len() + 9);
+    index_key.push(BY_EPOCH_PREFIX);
+    index_key.extend_from_slice(&epoch.to_be_bytes());
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+fn decode_epoch(bytes: &[u8]) -> Result<u64> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| Error::Decode("Invalid expiry index entry".into()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl Merk {
+    /// Applies a batch of operations like `apply`, attaching the expiry epoch
+    /// `expires_at` to every key put by the batch. Calling `expire` with an
+    /// epoch greater than or equal to `expires_at` will delete these keys.
+    ///
+    /// Keys put with a plain `apply` never expire, so writing a key again
+    /// without an expiry clears any epoch previously attached to it.
+    pub fn apply_with_expiry(&mut self, batch: &Batch, aux: &Batch, expires_at: u64) -> Result<()> {
+        check_batch_keys(batch)?;
+
+        let (batch, writes) = self.prepare_writes(batch, Some(expires_at))?;
+        unsafe { self.apply_unchecked_with(&batch, aux, writes) }
+    }
+
+    /// Gets the expiry epoch attached to the given key, or `None` if the key
+    /// does not expire (or does not exist).
+    pub fn get_expiry(&self, key: &[u8]) -> Result<Option<u64>> {
+        let expiry_cf = self.db.cf_handle(EXPIRY_CF_NAME).unwrap();
+        self.db
+            .get_pinned_cf(expiry_cf, by_key(key))?
+            .map(|bytes| decode_epoch(&bytes))
+            .transpose()
+    }
+
+    /// Deletes every key whose expiry epoch is less than or equal to `epoch`,
+    /// in a single batch. Returns the number of keys which were deleted.
+    pub fn expire(&mut self, epoch: u64) -> Result<usize> {
+        let expiry_cf = self.db.cf_handle(EXPIRY_CF_NAME).unwrap();
+
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_lower_bound(vec![BY_EPOCH_PREFIX]);
+        readopts.set_iterate_upper_bound(match epoch.checked_add(1) {
+            Some(end) => by_epoch(end, &[]),
+            None => vec![BY_EPOCH_PREFIX + 1],
+        });
+
+        let mut iter = self.db.raw_iterator_cf_opt(expiry_cf, readopts);
+        iter.seek_to_first();
+        let mut batch = vec![];
+        while iter.valid() {
+            let key = iter.key().unwrap()[9..].to_vec();
+            batch.push((key, Op::Delete));
+            iter.next();
+        }
+        iter.status()?;
+        drop(iter);
+
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let count = batch.len();
+        let (batch, writes) = self.prepare_writes(&batch, None)?;
+        unsafe { self.apply_unchecked_with(&batch, &[], writes)? };
+        Ok(count)
+    }
+
+    /// Stages the expiry index updates for `batch`: every key touched by the
+    /// batch loses its current expiry, and keys which are put get `expires_at`
+    /// if one is given.
+    pub(crate) fn expiry_writes<E: Entry>(
+        &self,
+        batch: &[E],
+        expires_at: Option<u64>,
+        writes: &mut Vec<PendingWrite>,
+    ) -> Result<()> {
+        let expiry_cf = self.db.cf_handle(EXPIRY_CF_NAME).unwrap();
+
+        // skip lookups entirely when nothing has ever been given an expiry
+        let mut iter = self.db.raw_iterator_cf(expiry_cf);
+        iter.seek_to_first();
+        let index_empty = !iter.valid();
+        drop(iter);
+        if index_empty && expires_at.is_none() {
+            return Ok(());
+        }
+
+        for entry in batch.iter() {
+            // setting flags keeps the entry, and so its expiry
+            if let OpRef::SetFlags(_) = entry.op() {
+                continue;
+            }
+            let key = entry.key();
+            if !index_empty {
+                if let Some(epoch) = self.get_expiry(key)? {
+                    writes.push((EXPIRY_CF_NAME, by_epoch(epoch, key), None));
+                    writes.push((EXPIRY_CF_NAME, by_key(key), None));
+                }
+            }
+
+            if let (OpRef::Put(_), Some(epoch)) = (entry.op(), expires_at) {
+                let epoch_bytes = epoch.to_be_bytes().to_vec();
+                writes.push((EXPIRY_CF_NAME, by_key(key), Some(epoch_bytes)));
+                writes.push((EXPIRY_CF_NAME, by_epoch(epoch, key), Some(vec![])));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn expire_prunes_expired_keys() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&[(vec![0], Op::Put(vec![0]))], &[]).unwrap();
+        merk.apply_with_expiry(
+            &[(vec![1], Op::Put(vec![1])), (vec![2], Op::Put(vec![2]))],
+            &[],
+            10,
+        )
+        .unwrap();
+        merk.apply_with_expiry(&[(vec![3], Op::Put(vec![3]))], &[], 20)
+            .unwrap();
+
+        assert_eq!(merk.get_expiry(&[0]).unwrap(), None);
+        assert_eq!(merk.get_expiry(&[1]).unwrap(), Some(10));
+        assert_eq!(merk.get_expiry(&[3]).unwrap(), Some(20));
+
+        assert_eq!(merk.expire(9).unwrap(), 0);
+        assert_eq!(merk.expire(10).unwrap(), 2);
+        assert_eq!(merk.get(&[1]).unwrap(), None);
+        assert_eq!(merk.get(&[2]).unwrap(), None);
+        assert_eq!(merk.get(&[3]).unwrap(), Some(vec![3]));
+        assert_eq!(merk.get_expiry(&[1]).unwrap(), None);
+
+        assert_eq!(merk.expire(u64::MAX).unwrap(), 1);
+        assert_eq!(merk.get(&[3]).unwrap(), None);
+        assert_eq!(merk.get(&[0]).unwrap(), Some(vec![0]));
+        assert_eq!(merk.expire(u64::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn writes_replace_expiry() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply_with_expiry(
+            &[
+                (vec![1], Op::Put(vec![1])),
+                (vec![2], Op::Put(vec![2])),
+                (vec![3], Op::Put(vec![3])),
+            ],
+            &[],
+            10,
+        )
+        .unwrap();
+
+        // extend, clear and delete
+        merk.apply_with_expiry(&[(vec![1], Op::Put(vec![1]))], &[], 30)
+            .unwrap();
+        merk.apply(&[(vec![2], Op::Put(vec![2]))], &[]).unwrap();
+        merk.apply(&[(vec![3], Op::Delete)], &[]).unwrap();
+
+        assert_eq!(merk.get_expiry(&[1]).unwrap(), Some(30));
+        assert_eq!(merk.get_expiry(&[2]).unwrap(), None);
+        assert_eq!(merk.get_expiry(&[3]).unwrap(), None);
+
+        assert_eq!(merk.expire(20).unwrap(), 0);
+        assert_eq!(merk.get(&[1]).unwrap(), Some(vec![1]));
+        assert_eq!(merk.get(&[2]).unwrap(), Some(vec![2]));
+        assert_eq!(merk.expire(30).unwrap(), 1);
+        assert_eq!(merk.get(&[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn set_flags_keeps_expiry() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply_with_expiry(&[(vec![1], Op::Put(vec![1]))], &[], 10)
+            .unwrap();
+
+        // an application-defined flag
+        merk.apply(&[(vec![1], Op::SetFlags(0x80))], &[]).unwrap();
+        assert_eq!(merk.get_expiry(&[1]).unwrap(), Some(10));
+        assert_eq!(merk.expire(10).unwrap(), 1);
+        assert_eq!(merk.get(&[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn expiry_does_not_change_root_hash() {
+        let mut plain = TempMerk::new().unwrap();
+        let mut expiring = TempMerk::new().unwrap();
+        let batch = make_batch_seq(0..100);
+        plain.apply(&batch, &[]).unwrap();
+        expiring.apply_with_expiry(&batch, &[], 5).unwrap();
+        assert_eq!(plain.root_hash(), expiring.root_hash());
+
+        assert_eq!(expiring.expire(5).unwrap(), 100);
+        assert_eq!(expiring.root_hash(), crate::tree::NULL_HASH);
+    }
+}
@@ -0,0 +1,144 @@
+//! Reports of the work done to apply a batch, see `Merk::apply_explain`.
+
+use std::fmt;
+use std::time::Duration;
+
+use rocksdb::DEFAULT_COLUMN_FAMILY_NAME;
+
+use super::{check_batch_keys, Merk, PendingWrite};
+use crate::tree::stats::OpCounts;
+use crate::{Batch, Result};
+
+/// A breakdown of the work done to apply and commit a batch, to help find out
+/// why a batch is slow.
+///
+/// Applying a batch goes through four phases: the batch is applied to the
+/// in-memory tree (loading the pruned nodes it touches), the modified nodes
+/// are hashed, the modified nodes are encoded and staged as writes along with
+/// the store's other updates, and the writes are committed to the database.
+#[derive(Clone, Debug, Default)]
+pub struct Explain {
+    /// The work done by tree operations while applying the batch.
+    pub counts: OpCounts,
+    /// The number of nodes written, whether new or modified.
+    pub nodes_written: usize,
+    /// The number of nodes deleted.
+    pub nodes_deleted: usize,
+    /// The number of keys written or deleted in any column family, not
+    /// counting undo records.
+    pub writes: usize,
+    /// The total length of the keys and values written, not counting undo
+    /// records.
+    pub bytes_written: usize,
+    /// The time spent applying the batch to the in-memory tree.
+    pub apply_time: Duration,
+    /// The time spent hashing modified nodes.
+    pub hash_time: Duration,
+    /// The time spent encoding modified nodes and staging writes.
+    pub commit_time: Duration,
+    /// The time spent running pre-commit hooks and writing to the database.
+    pub write_time: Duration,
+}
+
+impl Explain {
+    /// Returns the total time spent in all phases.
+    pub fn total_time(&self) -> Duration {
+        self.apply_time + self.hash_time + self.commit_time + self.write_time
+    }
+
+    pub(crate) fn record_writes(&mut self, writes: &[PendingWrite]) {
+        for (cf_name, key, maybe_value) in writes {
+            let is_node = *cf_name == DEFAULT_COLUMN_FAMILY_NAME;
+            match maybe_value {
+                Some(value) => {
+                    self.nodes_written += is_node as usize;
+                    self.bytes_written += key.len() + value.len();
+                }
+                None => {
+                    self.nodes_deleted += is_node as usize;
+                    self.bytes_written += key.len();
+                }
+            }
+        }
+        self.writes += writes.len();
+    }
+}
+
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "nodes: {} loaded, {} created, {} written, {} deleted",
+            self.counts.nodes_loaded,
+            self.counts.nodes_created,
+            self.nodes_written,
+            self.nodes_deleted
+        )?;
+        writeln!(f, "rotations: {}", self.counts.rotations)?;
+        writeln!(f, "writes: {} ({} bytes)", self.writes, self.bytes_written)?;
+        write!(
+            f,
+            "time: {:?} total ({:?} apply, {:?} hash, {:?} commit, {:?} write)",
+            self.total_time(),
+            self.apply_time,
+            self.hash_time,
+            self.commit_time,
+            self.write_time
+        )
+    }
+}
+
+impl Merk {
+    /// Applies a batch of operations like `apply`, and returns a breakdown of
+    /// the work done to apply it.
+    ///
+    /// Counting the work is cheap, so explaining a batch takes about as long
+    /// as applying it normally.
+    pub fn apply_explain(&mut self, batch: &Batch) -> Result<Explain> {
+        check_batch_keys(batch)?;
+
+        let mut explain = Explain::default();
+        let (batch, writes) = self.prepare_writes(batch, None)?;
+        self.apply_explained(&batch, &[], writes, Some(&mut explain))?;
+        Ok(explain)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::{Merk, Op};
+
+    #[test]
+    fn apply_explain() {
+        let tmp_dir = tempdir::TempDir::new("merk_apply_explain").unwrap();
+        let mut merk = Merk::open_opt(tmp_dir.path(), Merk::default_db_opts(), 1).unwrap();
+
+        let explain = merk.apply_explain(&make_batch_seq(0..100)).unwrap();
+        assert_eq!(explain.counts.nodes_loaded, 0);
+        assert_eq!(explain.counts.nodes_created, 100);
+        assert_eq!(explain.nodes_written, 100);
+        assert_eq!(explain.nodes_deleted, 0);
+        // the nodes, the root key and the store's metadata
+        assert_eq!(explain.writes, 102);
+        assert!(explain.bytes_written > 100 * 68);
+
+        // the path to the updated key is loaded, and only it is written again
+        let batch = [(50u64.to_be_bytes().to_vec(), Op::Put(vec![1]))];
+        let explain = merk.apply_explain(&batch).unwrap();
+        let height = merk.walk(|walker| walker.unwrap().tree().height()) as usize;
+        assert!(explain.counts.nodes_loaded < height);
+        assert_eq!(explain.counts.nodes_created, 0);
+        assert_eq!(explain.counts.rotations, 0);
+        assert_eq!(explain.nodes_written, explain.counts.nodes_loaded + 1);
+
+        // inserting ascending keys keeps rotating the right edge
+        let explain = merk.apply_explain(&make_batch_seq(100..110)).unwrap();
+        assert_eq!(explain.counts.nodes_created, 10);
+        assert!(explain.counts.rotations > 0);
+
+        let explain = merk.apply_explain(&make_del_batch_seq(0..10)).unwrap();
+        assert_eq!(explain.nodes_deleted, 10);
+        assert!(explain.to_string().contains("10 deleted"));
+    }
+}
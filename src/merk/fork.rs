@@ -0,0 +1,257 @@
+//! Copy-on-write forks of a store, for speculatively executing several
+//! proposals from the same base state.
+
+use std::collections::BTreeMap;
+
+use super::snapshot::SnapshotSource;
+use super::{check_batch_keys, load_root, Merk, TreeDb};
+use crate::{
+    tree::{resolve_moves, Batch, BatchEntry, Commit, Fetch, FetchBytes, Tree, Walker, NULL_HASH},
+    Hash, Result,
+};
+
+/// The records of the nodes a fork has written, by key, with `None` for the
+/// nodes it has deleted.
+type Nodes = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// An independent, writable view of a `Merk` as of the time it was forked.
+///
+/// The fork reads unmodified nodes from a snapshot of the parent's database
+/// and keeps the records of the nodes it modifies in memory, so forking is
+/// cheap and changes to the fork never reach the parent (nor do later changes
+/// to the parent affect the fork). To adopt the changes, apply the fork's
+/// `batches` to the parent in order, which produces the same root hash.
+pub struct Fork<'a> {
+    db: rocksdb::Snapshot<'a>,
+    parent: &'a TreeDb,
+    tree: Option<Tree>,
+    nodes: Nodes,
+    batches: Vec<Vec<BatchEntry>>,
+}
+
+impl Merk {
+    /// Creates a copy-on-write fork of the store.
+    pub fn fork(&self) -> Result<Fork<'_>> {
+        Ok(Fork {
+            db: self.db.snapshot(),
+            parent: &self.db,
+            tree: load_root(&self.db)?,
+            nodes: BTreeMap::new(),
+            batches: vec![],
+        })
+    }
+}
+
+impl<'a> Fork<'a> {
+    /// Gets a value for the given key in the fork.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match &self.tree {
+            Some(tree) => super::get(tree, self.source(), key),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the root hash of the fork.
+    pub fn root_hash(&self) -> Hash {
+        self.tree.as_ref().map_or(NULL_HASH, |tree| tree.hash())
+    }
+
+    /// Applies a batch of operations to the fork, keeping the records of all
+    /// modified nodes in memory. If this fails, the fork is left as it was.
+    ///
+    /// This will fail if the keys in `batch` are not sorted and unique.
+    pub fn apply(&mut self, batch: &Batch) -> Result<()> {
+        check_batch_keys(batch)?;
+        let resolved = resolve_moves(batch, |key| self.get(key))?;
+        let batch = resolved.as_deref().unwrap_or(batch);
+
+        let root_key = self.tree.as_ref().map(|tree| tree.key().to_vec());
+        let source = ForkSource {
+            snapshot: SnapshotSource(&self.db, self.parent),
+            nodes: &self.nodes,
+        };
+        let maybe_walker = self
+            .tree
+            .take()
+            .map(|tree| Walker::new(tree, source.clone()));
+        let mut committer = ForkCommitter::default();
+        let result = Walker::apply_to(maybe_walker, batch, source.clone()).and_then(
+            |(mut maybe_tree, deleted_keys)| {
+                if let Some(tree) = &mut maybe_tree {
+                    tree.commit(&mut committer)?;
+                }
+                Ok((maybe_tree, deleted_keys))
+            },
+        );
+        let (maybe_tree, deleted_keys) = match result {
+            Ok(applied) => applied,
+            Err(err) => {
+                // nothing was written, so the last root can be reloaded
+                self.tree = root_key
+                    .map(|key| source.fetch_by_key_expect(&key))
+                    .transpose()?;
+                return Err(err);
+            }
+        };
+
+        for key in deleted_keys {
+            self.nodes.insert(key, None);
+        }
+        for (key, record) in committer.nodes {
+            self.nodes.insert(key, Some(record));
+        }
+        self.tree = maybe_tree;
+        self.batches.push(batch.to_vec());
+        Ok(())
+    }
+
+    /// Returns the batches applied to the fork, in order.
+    pub fn batches(&self) -> &[Vec<BatchEntry>] {
+        &self.batches
+    }
+
+    /// Consumes the fork, returning the batches applied to it.
+    pub fn into_batches(self) -> Vec<Vec<BatchEntry>> {
+        self.batches
+    }
+
+    fn source(&self) -> ForkSource<'_> {
+        ForkSource {
+            snapshot: SnapshotSource(&self.db, self.parent),
+            nodes: &self.nodes,
+        }
+    }
+}
+
+/// Collects the records of the nodes a fork modifies.
+#[derive(Default)]
+struct ForkCommitter {
+    nodes: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Commit for ForkCommitter {
+    fn write(&mut self, tree: &Tree) -> Result<()> {
+        self.nodes.push((tree.key().to_vec(), tree.encode()));
+        Ok(())
+    }
+
+    fn separate_value(&self, tree: &Tree) -> bool {
+        // values which were not loaded stay in the snapshot
+        !tree.value_loaded()
+    }
+}
+
+/// Reads the nodes a fork has written, and any other nodes from the snapshot
+/// it was forked from.
+#[derive(Clone)]
+struct ForkSource<'a> {
+    snapshot: SnapshotSource<'a>,
+    nodes: &'a Nodes,
+}
+
+impl Fetch for ForkSource<'_> {
+    fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
+        match self.nodes.get(key) {
+            Some(Some(record)) => Tree::decode_lazy(key.to_vec(), record).map(Some),
+            Some(None) => Ok(None),
+            None => self.snapshot.fetch_by_key(key),
+        }
+    }
+
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        FetchBytes::fetch_value(&self.snapshot, key)
+    }
+}
+
+impl FetchBytes for ForkSource<'_> {
+    type Bytes = Vec<u8>;
+
+    fn fetch_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.nodes.get(key) {
+            Some(record) => Ok(record.clone()),
+            None => self.snapshot.fetch_bytes(key),
+        }
+    }
+
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        FetchBytes::fetch_value(&self.snapshot, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::tree::flags::FROZEN;
+    use crate::{Error, Op};
+
+    #[test]
+    fn forks_are_independent() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        let base_hash = merk.root_hash();
+
+        let (batches, fork_hash) = {
+            let mut a = merk.fork().unwrap();
+            let mut b = merk.fork().unwrap();
+            assert_eq!(a.root_hash(), base_hash);
+
+            a.apply(&make_del_batch_seq(0..10)).unwrap();
+            a.apply(&[(seq_key(500), Op::Put(vec![1]))]).unwrap();
+            b.apply(&make_batch_seq(1_000..1_010)).unwrap();
+
+            assert_eq!(a.get(&seq_key(0)).unwrap(), None);
+            assert_eq!(a.get(&seq_key(500)).unwrap(), Some(vec![1]));
+            assert_eq!(a.get(&seq_key(1_005)).unwrap(), None);
+            assert_eq!(b.get(&seq_key(0)).unwrap(), Some(put_entry_value()));
+            assert_eq!(b.get(&seq_key(1_005)).unwrap(), Some(put_entry_value()));
+            assert_ne!(a.root_hash(), b.root_hash());
+
+            let fork_hash = a.root_hash();
+            (a.into_batches(), fork_hash)
+        };
+
+        // the parent is unaffected until the fork's batches are applied
+        assert_eq!(merk.root_hash(), base_hash);
+        assert_eq!(merk.get(&seq_key(0)).unwrap(), Some(put_entry_value()));
+        for batch in batches {
+            merk.apply(&batch, &[]).unwrap();
+        }
+        assert_eq!(merk.root_hash(), fork_hash);
+    }
+
+    #[test]
+    fn fork_of_empty_store() {
+        let merk = TempMerk::new().unwrap();
+        let mut fork = merk.fork().unwrap();
+        fork.apply(&make_batch_seq(0..10)).unwrap();
+        assert_eq!(fork.get(&seq_key(3)).unwrap(), Some(put_entry_value()));
+        fork.apply(&make_del_batch_seq(0..10)).unwrap();
+        assert_eq!(fork.root_hash(), crate::tree::NULL_HASH);
+    }
+
+    #[test]
+    fn failed_apply_leaves_fork_unchanged() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let mut fork = merk.fork().unwrap();
+        fork.apply(&[(seq_key(5), Op::SetFlags(FROZEN))]).unwrap();
+        fork.apply(&make_batch_seq(100..110)).unwrap();
+        let hash = fork.root_hash();
+
+        let batch = vec![(seq_key(4), Op::Delete), (seq_key(5), Op::Delete)];
+        assert!(matches!(fork.apply(&batch), Err(Error::Frozen(_))));
+        assert_eq!(fork.root_hash(), hash);
+        assert_eq!(fork.get(&seq_key(4)).unwrap(), Some(put_entry_value()));
+        assert_eq!(fork.get(&seq_key(105)).unwrap(), Some(put_entry_value()));
+        assert_eq!(fork.batches().len(), 2);
+
+        // the fork can still be applied to, and its batches to the parent
+        fork.apply(&make_del_batch_seq(100..105)).unwrap();
+        assert_eq!(fork.get(&seq_key(102)).unwrap(), None);
+        let hash = fork.root_hash();
+        for batch in fork.into_batches() {
+            merk.apply(&batch, &[]).unwrap();
+        }
+        assert_eq!(merk.root_hash(), hash);
+    }
+}
@@ -0,0 +1,206 @@
+//! Quick self-checks of a store for readiness probes, see `Merk::health`.
+
+use std::path::Path;
+use std::time::Duration;
+
+use super::{load_root, Merk};
+use crate::tree::NULL_HASH;
+use crate::{Hash, Result};
+
+/// The overall result of a health check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    /// Every check passed.
+    Healthy,
+    /// The store works, but a check went over its limit, see `Health::problems`.
+    Degraded,
+    /// The store can not serve reads or writes.
+    Unhealthy,
+}
+
+/// The limits `Merk::health_with` checks a store against. Limits of `None`
+/// are not checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthLimits {
+    /// The longest the most recent commit may have taken.
+    pub max_commit_time: Option<Duration>,
+    /// The most bytes RocksDB may estimate compaction still has to rewrite.
+    pub max_pending_compaction_bytes: Option<u64>,
+    /// The most bytes the files in the store's directory may take up.
+    pub max_disk_usage_bytes: Option<u64>,
+}
+
+impl Default for HealthLimits {
+    fn default() -> Self {
+        HealthLimits {
+            max_commit_time: Some(Duration::from_secs(1)),
+            max_pending_compaction_bytes: Some(64 << 30),
+            max_disk_usage_bytes: None,
+        }
+    }
+}
+
+/// The result of a health check, see `Merk::health`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Health {
+    pub status: HealthStatus,
+    /// The root hash of the in-memory tree.
+    pub root_hash: Hash,
+    /// How long the most recent commit took, from applying the batch to
+    /// writing it, or `None` if there was no commit since the store was
+    /// opened.
+    pub last_commit_time: Option<Duration>,
+    /// RocksDB's estimate of the bytes compaction still has to rewrite.
+    pub pending_compaction_bytes: u64,
+    /// The bytes taken up by the files in the store's directory.
+    pub disk_usage_bytes: u64,
+    /// A description of each failed check.
+    pub problems: Vec<String>,
+}
+
+impl Health {
+    fn problem(&mut self, status: HealthStatus, problem: String) {
+        self.status = self.status.max(status);
+        self.problems.push(problem);
+    }
+}
+
+/// Returns the total size of the files in `path`, ignoring files which are
+/// removed while it is being read.
+fn disk_usage(path: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(path)? {
+        if let Ok(metadata) = entry?.metadata() {
+            if metadata.is_file() {
+                bytes += metadata.len();
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+impl Merk {
+    /// Runs a quick self-check of the store with the default `HealthLimits`,
+    /// for readiness probes, see `health_with`.
+    pub fn health(&self) -> Result<Health> {
+        self.health_with(&HealthLimits::default())
+    }
+
+    /// Runs a quick self-check of the store: that its root node can be read
+    /// back from the database and matches the in-memory tree, that RocksDB has
+    /// not stopped writes (for example after running out of disk space), and
+    /// that the last commit time, pending compaction bytes and disk usage are
+    /// within `limits`. Read errors are reported in the result rather than
+    /// returned, so only a failure to read the store's directory is an error.
+    ///
+    /// The check reads one node and a few RocksDB properties, so it is cheap
+    /// enough to run on every probe.
+    pub fn health_with(&self, limits: &HealthLimits) -> Result<Health> {
+        let mut health = Health {
+            status: HealthStatus::Healthy,
+            root_hash: self.root_hash(),
+            last_commit_time: self.last_commit_time,
+            pending_compaction_bytes: 0,
+            disk_usage_bytes: disk_usage(&self.path)?,
+            problems: vec![],
+        };
+
+        match load_root(&self.db) {
+            Ok(maybe_tree) => {
+                let stored = maybe_tree.map_or(NULL_HASH, |tree| tree.hash());
+                if stored != health.root_hash {
+                    health.problem(
+                        HealthStatus::Unhealthy,
+                        format!(
+                            "Stored root hash {} does not match the tree's root hash {}",
+                            hex::encode(stored),
+                            hex::encode(health.root_hash)
+                        ),
+                    );
+                }
+            }
+            Err(err) => health.problem(
+                HealthStatus::Unhealthy,
+                format!("Failed to read the root node: {}", err),
+            ),
+        }
+
+        let db = self.db.inner();
+        let property = |name| db.property_int_value(name).ok().flatten().unwrap_or(0);
+        if property("rocksdb.is-write-stopped") != 0 {
+            health.problem(HealthStatus::Unhealthy, "Writes are stopped".into());
+        }
+        let errors = property("rocksdb.background-errors");
+        if errors != 0 {
+            health.problem(
+                HealthStatus::Degraded,
+                format!("{} background errors", errors),
+            );
+        }
+        health.pending_compaction_bytes = property("rocksdb.estimate-pending-compaction-bytes");
+
+        if let (Some(time), Some(max)) = (health.last_commit_time, limits.max_commit_time) {
+            if time > max {
+                health.problem(
+                    HealthStatus::Degraded,
+                    format!("Last commit took {:?}, over {:?}", time, max),
+                );
+            }
+        }
+        if let Some(max) = limits.max_pending_compaction_bytes {
+            if health.pending_compaction_bytes > max {
+                health.problem(
+                    HealthStatus::Degraded,
+                    format!(
+                        "{} bytes pending compaction, over {}",
+                        health.pending_compaction_bytes, max
+                    ),
+                );
+            }
+        }
+        if let Some(max) = limits.max_disk_usage_bytes {
+            if health.disk_usage_bytes > max {
+                health.problem(
+                    HealthStatus::Degraded,
+                    format!("{} bytes on disk, over {}", health.disk_usage_bytes, max),
+                );
+            }
+        }
+        Ok(health)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn health() {
+        let mut merk = TempMerk::new().unwrap();
+        let health = merk.health().unwrap();
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert_eq!(health.root_hash, NULL_HASH);
+        assert_eq!(health.last_commit_time, None);
+
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let health = merk.health().unwrap();
+        assert_eq!(
+            health.status,
+            HealthStatus::Healthy,
+            "{:?}",
+            health.problems
+        );
+        assert_eq!(health.root_hash, merk.root_hash());
+        assert!(health.last_commit_time.is_some());
+
+        let limits = HealthLimits {
+            max_commit_time: Some(Duration::ZERO),
+            max_pending_compaction_bytes: None,
+            max_disk_usage_bytes: Some(0),
+        };
+        let health = merk.health_with(&limits).unwrap();
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(health.problems.len(), 2);
+    }
+}
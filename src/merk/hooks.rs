@@ -0,0 +1,89 @@
+//! Hooks which run on every commit, for audit logging, metrics or triggering
+//! replication without wrapping every call to `apply`.
+
+use super::Merk;
+use crate::tree::{Batch, Entry};
+use crate::Hash;
+
+/// A function called with the applied batch and the resulting root hash.
+pub type Hook = Box<dyn Fn(&Batch, &Hash) + Send + Sync>;
+
+impl Merk {
+    /// Registers a hook which is called once a batch has been applied to the
+    /// tree and its new root hash is known, but before anything has been
+    /// written to the database.
+    pub fn add_pre_commit_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&Batch, &Hash) + Send + Sync + 'static,
+    {
+        self.pre_commit_hooks.push(Box::new(hook));
+    }
+
+    /// Registers a hook which is called after a batch and its resulting tree
+    /// have been written to the database.
+    pub fn add_post_commit_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&Batch, &Hash) + Send + Sync + 'static,
+    {
+        self.post_commit_hooks.push(Box::new(hook));
+    }
+}
+
+/// Runs `hooks` with `batch`, which is only copied into owned entries if it
+/// is borrowed and there are hooks to run.
+pub(crate) fn run_hooks<E: Entry>(hooks: &[Hook], batch: &[E], root_hash: &Hash) {
+    if hooks.is_empty() {
+        return;
+    }
+    let batch = E::to_batch(batch);
+    for hook in hooks {
+        hook(&batch, root_hash);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn hooks_run_in_order() {
+        let mut merk = TempMerk::new().unwrap();
+        let calls = Arc::new(Mutex::new(vec![]));
+
+        let pre_calls = calls.clone();
+        merk.add_pre_commit_hook(move |batch, hash| {
+            pre_calls.lock().unwrap().push(("pre", batch.len(), *hash));
+        });
+        let post_calls = calls.clone();
+        merk.add_post_commit_hook(move |batch, hash| {
+            post_calls
+                .lock()
+                .unwrap()
+                .push(("post", batch.len(), *hash));
+        });
+
+        merk.apply(&make_batch_seq(0..10), &[]).unwrap();
+
+        let root_hash = merk.root_hash();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![("pre", 10, root_hash), ("post", 10, root_hash)]
+        );
+    }
+
+    #[test]
+    fn hooks_see_empty_tree() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&[(vec![1], Op::Put(vec![1]))], &[]).unwrap();
+
+        let hashes = Arc::new(Mutex::new(vec![]));
+        let post_hashes = hashes.clone();
+        merk.add_post_commit_hook(move |_, hash| post_hashes.lock().unwrap().push(*hash));
+
+        merk.apply(&[(vec![1], Op::Delete)], &[]).unwrap();
+        assert_eq!(*hashes.lock().unwrap(), vec![crate::tree::NULL_HASH]);
+    }
+}
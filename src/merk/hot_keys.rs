@@ -0,0 +1,185 @@
+//! Tracking of the most accessed keys, see `Merk::hot_keys`.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::PoisonError;
+
+use super::Merk;
+use crate::tree::Entry;
+
+/// The number of most recent accesses tracked by default.
+pub const DEFAULT_HOT_KEY_WINDOW: usize = 10_000;
+
+/// A key and the number of times it was accessed within the tracked window,
+/// see `Merk::hot_keys`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HotKey {
+    pub key: Vec<u8>,
+    /// The number of times the key was read with `Merk::get`.
+    pub reads: u64,
+    /// The number of times the key was put or deleted by a batch.
+    pub writes: u64,
+}
+
+/// Counts the accesses to each key within a sliding window of the most recent
+/// accesses.
+pub(crate) struct HotKeys {
+    window: usize,
+    accesses: VecDeque<(Vec<u8>, bool)>,
+    counts: HashMap<Vec<u8>, (u64, u64)>,
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        HotKeys {
+            window: DEFAULT_HOT_KEY_WINDOW,
+            accesses: VecDeque::new(),
+            counts: HashMap::new(),
+        }
+    }
+}
+
+impl HotKeys {
+    fn record(&mut self, key: &[u8], write: bool) {
+        if self.window == 0 {
+            return;
+        }
+        let counts = self.counts.entry(key.to_vec()).or_default();
+        if write {
+            counts.1 += 1;
+        } else {
+            counts.0 += 1;
+        }
+        self.accesses.push_back((key.to_vec(), write));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.accesses.len() > self.window {
+            let (key, write) = self.accesses.pop_front().unwrap();
+            let counts = self.counts.get_mut(&key).unwrap();
+            if write {
+                counts.1 -= 1;
+            } else {
+                counts.0 -= 1;
+            }
+            if *counts == (0, 0) {
+                self.counts.remove(&key);
+            }
+        }
+    }
+
+    fn top(&self, n: usize) -> Vec<HotKey> {
+        let mut keys: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(key, &(reads, writes))| HotKey {
+                key: key.clone(),
+                reads,
+                writes,
+            })
+            .collect();
+        keys.sort_by(|a, b| {
+            (b.reads + b.writes)
+                .cmp(&(a.reads + a.writes))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        keys.truncate(n);
+        keys
+    }
+}
+
+impl Merk {
+    /// Returns up to `n` of the keys accessed most often within the most
+    /// recent accesses (see `set_hot_key_window`), most accessed first, to
+    /// help decide which data to cache in the application. Reads are counted
+    /// by `get`, writes by applying batches.
+    ///
+    /// Only available with the `profiling` feature, since tracking has a cost
+    /// on every access.
+    pub fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        let hot_keys = self.hot_keys.lock().unwrap_or_else(PoisonError::into_inner);
+        hot_keys.top(n)
+    }
+
+    /// Sets the number of most recent accesses tracked for `hot_keys`
+    /// (`DEFAULT_HOT_KEY_WINDOW` by default), forgetting older ones. A window
+    /// of 0 turns tracking off.
+    pub fn set_hot_key_window(&mut self, accesses: usize) {
+        let hot_keys = self
+            .hot_keys
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        hot_keys.window = accesses;
+        hot_keys.evict();
+    }
+
+    pub(crate) fn record_read(&self, key: &[u8]) {
+        let mut hot_keys = self.hot_keys.lock().unwrap_or_else(PoisonError::into_inner);
+        hot_keys.record(key, false);
+    }
+
+    pub(crate) fn record_writes<E: Entry>(&mut self, batch: &[E]) {
+        let hot_keys = self
+            .hot_keys
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for entry in batch {
+            hot_keys.record(entry.key(), true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HotKey;
+    use crate::test_utils::TempMerk;
+    use crate::Op;
+
+    #[test]
+    fn hot_keys() {
+        let mut merk = TempMerk::new().unwrap();
+        let batch: Vec<_> = (0..10u8).map(|i| (vec![i], Op::Put(vec![i]))).collect();
+        merk.apply(&batch, &[]).unwrap();
+        for _ in 0..3 {
+            merk.get(&[7]).unwrap();
+        }
+        merk.get(&[2]).unwrap();
+        merk.apply(&[(vec![2], Op::Delete)], &[]).unwrap();
+
+        let hot = merk.hot_keys(2);
+        assert_eq!(
+            hot,
+            vec![
+                HotKey {
+                    key: vec![7],
+                    reads: 3,
+                    writes: 1
+                },
+                HotKey {
+                    key: vec![2],
+                    reads: 1,
+                    writes: 2
+                },
+            ]
+        );
+
+        // only the last 3 accesses are kept
+        merk.set_hot_key_window(3);
+        assert_eq!(merk.hot_keys(10).len(), 2);
+        merk.get(&[9]).unwrap();
+        merk.get(&[9]).unwrap();
+        merk.get(&[9]).unwrap();
+        assert_eq!(
+            merk.hot_keys(10),
+            vec![HotKey {
+                key: vec![9],
+                reads: 3,
+                writes: 0
+            }]
+        );
+
+        merk.set_hot_key_window(0);
+        merk.get(&[1]).unwrap();
+        assert!(merk.hot_keys(10).is_empty());
+    }
+}
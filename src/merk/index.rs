@@ -0,0 +1,242 @@
+//! Secondary indexes over the entries of a Merk store.
+//!
+//! An index is registered with a name and an extractor function which maps
+//! each entry to zero or more index keys. Index entries are kept in their own
+//! column family, outside of the Merkle tree, and are updated in the same
+//! write as the tree changes of every apply so they can never be observed out
+//! of sync.
+//!
+//! Extractors are plain functions and are not persisted, so indexes must be
+//! registered again each time the store is opened.
+
+use std::convert::TryInto;
+
+use rocksdb::{IteratorMode, ReadOptions, WriteBatch};
+
+use super::{Merk, PendingWrite, INDEX_CF_NAME};
+use crate::{
+    tree::{Entry, OpRef, Tree},
+    Error, Result,
+};
+
+/// Maps an entry's key and value to the index keys it should be found under.
+pub type IndexExtractor = fn(&[u8], &[u8]) -> Vec<Vec<u8>>;
+
+fn index_prefix(name: &str, index_key: Option<&[u8]>) -> Result<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(name.len() + 3);
+    prefix.push(name.len() as u8);
+    prefix.extend_from_slice(name.as_bytes());
+    if let Some(index_key) = index_key {
+        let len: u16 = index_key
+            .len()
+            .try_into()
+            .map_err(|_| Error::Key("Index keys must be at most 65535 bytes".into()))?;
+        prefix.extend_from_slice(&len.to_be_bytes());
+        prefix.extend_from_slice(index_key);
+    }
+    Ok(prefix)
+}
+
+fn index_entry(name: &str, index_key: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    let mut entry = index_prefix(name, Some(index_key))?;
+    entry.extend_from_slice(key);
+    Ok(entry)
+}
+
+impl Merk {
+    /// Registers a secondary index named `name`, maintained by calling
+    /// `extractor` on every entry that is put into the store from now on.
+    ///
+    /// Entries which were written before the index was first registered are
+    /// not indexed until `rebuild_index` is called.
+    pub fn register_index(&mut self, name: &str, extractor: IndexExtractor) -> Result<()> {
+        if name.len() > u8::MAX as usize {
+            return Err(Error::Key("Index names must be at most 255 bytes".into()));
+        }
+        if self.indexes.iter().any(|(n, _)| n == name) {
+            return Err(Error::IndexRegistered(name.to_string()));
+        }
+
+        self.indexes.push((name.to_string(), extractor));
+        Ok(())
+    }
+
+    /// Discards all entries of the given index and recomputes them from every
+    /// key/value pair currently in the store.
+    pub fn rebuild_index(&mut self, name: &str) -> Result<()> {
+        let extractor = self.index_extractor(name)?;
+        self.check_not_offloaded("rebuild an index")?;
+        let index_cf = self.db.cf_handle(INDEX_CF_NAME).unwrap();
+        let mut write_batch = WriteBatch::default();
+
+        let prefix = index_prefix(name, None)?;
+        for (key, _) in self
+            .db
+            .iterator_cf(
+                index_cf,
+                IteratorMode::From(&prefix, rocksdb::Direction::Forward),
+            )
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            write_batch.delete_cf(index_cf, key);
+        }
+
+        let mut node = Tree::new(vec![], vec![])?;
+        for (key, node_bytes) in self.db.iterator(IteratorMode::Start) {
+            node.decode_into_with(key.to_vec(), &node_bytes, |key| {
+                super::separated_value(&self.db, key)
+            })?;
+            for index_key in extractor(&node.key(), node.value()) {
+                write_batch.put_cf(index_cf, index_entry(name, &index_key, &key)?, []);
+            }
+        }
+
+        self.write(write_batch)
+    }
+
+    /// Returns the keys of all entries found under `index_key` in the given
+    /// index, in ascending order.
+    pub fn get_by_index(&self, name: &str, index_key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.index_extractor(name)?;
+        let index_cf = self.db.cf_handle(INDEX_CF_NAME).unwrap();
+
+        let prefix = index_prefix(name, Some(index_key))?;
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_lower_bound(prefix.clone());
+
+        let mut keys = vec![];
+        let mut iter = self.db.raw_iterator_cf_opt(index_cf, readopts);
+        iter.seek_to_first();
+        while let Some(entry) = iter.key() {
+            if !entry.starts_with(&prefix) {
+                break;
+            }
+            keys.push(entry[prefix.len()..].to_vec());
+            iter.next();
+        }
+        iter.status()?;
+
+        Ok(keys)
+    }
+
+    fn index_extractor(&self, name: &str) -> Result<IndexExtractor> {
+        self.indexes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, extractor)| *extractor)
+            .ok_or_else(|| Error::IndexNotRegistered(name.to_string()))
+    }
+
+    /// Stages the updates to all registered indexes for `batch`, removing the
+    /// index entries of the values being replaced or deleted and adding those
+    /// of the values being put.
+    pub(crate) fn index_writes<E: Entry>(
+        &self,
+        batch: &[E],
+        writes: &mut Vec<PendingWrite>,
+    ) -> Result<()> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+
+        for entry in batch.iter() {
+            // setting flags leaves the value, and so its index entries, as is
+            if let OpRef::SetFlags(_) = entry.op() {
+                continue;
+            }
+            let key = entry.key();
+            let maybe_old_value = self.get(key)?;
+            for (name, extractor) in self.indexes.iter() {
+                if let Some(old_value) = &maybe_old_value {
+                    for index_key in extractor(key, old_value) {
+                        writes.push((INDEX_CF_NAME, index_entry(name, &index_key, key)?, None));
+                    }
+                }
+                if let OpRef::Put(value) = entry.op() {
+                    for index_key in extractor(key, value) {
+                        let entry = index_entry(name, &index_key, key)?;
+                        writes.push((INDEX_CF_NAME, entry, Some(vec![])));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::Op;
+
+    fn by_first_byte(_key: &[u8], value: &[u8]) -> Vec<Vec<u8>> {
+        value.first().map(|b| vec![vec![*b]]).unwrap_or_default()
+    }
+
+    #[test]
+    fn index_follows_applies() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.register_index("first", by_first_byte).unwrap();
+
+        merk.apply(
+            &[
+                (vec![1], Op::Put(vec![10, 0])),
+                (vec![2], Op::Put(vec![10, 1])),
+                (vec![3], Op::Put(vec![20])),
+                (vec![4], Op::Put(vec![])),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            merk.get_by_index("first", &[10]).unwrap(),
+            vec![vec![1], vec![2]]
+        );
+        assert_eq!(merk.get_by_index("first", &[20]).unwrap(), vec![vec![3]]);
+
+        merk.apply(&[(vec![1], Op::Put(vec![20])), (vec![3], Op::Delete)], &[])
+            .unwrap();
+        assert_eq!(merk.get_by_index("first", &[10]).unwrap(), vec![vec![2]]);
+        assert_eq!(merk.get_by_index("first", &[20]).unwrap(), vec![vec![1]]);
+        assert!(merk.get_by_index("first", &[30]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn index_survives_set_flags() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.register_index("first", by_first_byte).unwrap();
+        merk.apply(&[(vec![1], Op::Put(vec![10]))], &[]).unwrap();
+
+        merk.apply(&[(vec![1], Op::SetFlags(crate::tree::flags::FROZEN))], &[])
+            .unwrap();
+        assert_eq!(merk.get_by_index("first", &[10]).unwrap(), vec![vec![1]]);
+    }
+
+    #[test]
+    fn rebuild_indexes_existing_entries() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(
+            &[(vec![1], Op::Put(vec![10])), (vec![2], Op::Put(vec![10]))],
+            &[],
+        )
+        .unwrap();
+
+        merk.register_index("first", by_first_byte).unwrap();
+        assert!(merk.get_by_index("first", &[10]).unwrap().is_empty());
+
+        merk.rebuild_index("first").unwrap();
+        assert_eq!(
+            merk.get_by_index("first", &[10]).unwrap(),
+            vec![vec![1], vec![2]]
+        );
+    }
+
+    #[test]
+    fn unregistered_index() {
+        let mut merk = TempMerk::new().unwrap();
+        assert!(merk.get_by_index("first", &[10]).is_err());
+        merk.register_index("first", by_first_byte).unwrap();
+        assert!(merk.register_index("first", by_first_byte).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Identity and history metadata of a store, see `Merk::info`.
+
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Merk, PendingWrite, TreeDb, INTERNAL_CF_NAME};
+use crate::{Error, Hash, Result};
+
+pub(crate) const INFO_KEY: &[u8] = b"info";
+
+/// The number of recent root hashes kept in a store's metadata.
+pub const ROOT_HISTORY_LENGTH: usize = 16;
+
+/// Metadata identifying a store, see `Merk::info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Info {
+    /// A random identifier for the store, fixed when it is created. Copies of
+    /// a store (e.g. checkpoints) have the same id.
+    pub id: [u8; 16],
+    /// When the store was created, in seconds since the Unix epoch. For stores
+    /// which predate this metadata, this is when they were first opened by a
+    /// version which keeps it.
+    pub created_at: u64,
+    /// The format version nodes are written in, see `Merk::format_version`.
+    pub format_version: u8,
+    /// The number of commits made to the store. Rollbacks do not decrease it,
+    /// so the heights of attested roots (see `Merk::attest`) are never reused
+    /// for other roots.
+    pub commits: u64,
+    /// The root hashes after the most recent commits, oldest first, up to
+    /// `ROOT_HISTORY_LENGTH` of them.
+    pub recent_roots: Vec<Hash>,
+}
+
+impl Info {
+    /// Returns the store's id formatted as a UUID.
+    pub fn uuid(&self) -> String {
+        let hex = hex::encode(self.id);
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    fn new() -> Info {
+        let mut id: [u8; 16] = rand::random();
+        // mark the id as a random (version 4) UUID
+        id[6] = (id[6] & 0x0f) | 0x40;
+        id[8] = (id[8] & 0x3f) | 0x80;
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+
+        Info {
+            id,
+            created_at,
+            format_version: 0,
+            commits: 0,
+            recent_roots: vec![],
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(33 + self.recent_roots.len() * 32);
+        bytes.extend_from_slice(&self.id);
+        bytes.extend_from_slice(&self.created_at.to_be_bytes());
+        bytes.extend_from_slice(&self.commits.to_be_bytes());
+        bytes.push(self.recent_roots.len() as u8);
+        for root in self.recent_roots.iter() {
+            bytes.extend_from_slice(root);
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8], format_version: u8) -> Result<Info> {
+        let invalid = || Error::Decode("Invalid store metadata".into());
+        if bytes.len() < 33 || (bytes.len() - 33) != bytes[32] as usize * 32 {
+            return Err(invalid());
+        }
+
+        Ok(Info {
+            id: bytes[..16].try_into().map_err(|_| invalid())?,
+            created_at: u64::from_be_bytes(bytes[16..24].try_into().map_err(|_| invalid())?),
+            format_version,
+            commits: u64::from_be_bytes(bytes[24..32].try_into().map_err(|_| invalid())?),
+            recent_roots: bytes[33..]
+                .chunks(32)
+                .map(|root| root.try_into().map_err(|_| invalid()))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl Merk {
+    /// Returns the metadata identifying the store: its id, when it was
+    /// created, its format version and its recent root hashes, for tooling
+    /// which needs to tell data directories apart or check that one holds the
+    /// expected state.
+    pub fn info(&self) -> Result<Info> {
+        load_info(&self.db, self.format_version)?
+            .ok_or_else(|| Error::Decode("Store metadata is missing".into()))
+    }
+
+    /// Stages the update to the store's metadata for a commit resulting in
+    /// `root_hash`.
+    pub(crate) fn info_writes(
+        &self,
+        root_hash: Hash,
+        writes: &mut Vec<PendingWrite>,
+    ) -> Result<()> {
+        let mut info = self.info()?;
+        info.commits += 1;
+        info.recent_roots.push(root_hash);
+        if info.recent_roots.len() > ROOT_HISTORY_LENGTH {
+            info.recent_roots.remove(0);
+        }
+        writes.push((INTERNAL_CF_NAME, INFO_KEY.to_vec(), Some(info.encode())));
+        Ok(())
+    }
+
+    /// Returns the metadata `bytes` a rollback writes back, with the store's
+    /// current commit count kept, see `Info::commits`.
+    pub(crate) fn rolled_back_info(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut info = Info::decode(bytes, self.format_version)?;
+        info.commits = self.info()?.commits;
+        Ok(info.encode())
+    }
+}
+
+fn load_info(db: &TreeDb, format_version: u8) -> Result<Option<Info>> {
+    let internal_cf = db.cf_handle(INTERNAL_CF_NAME).unwrap();
+    db.get_pinned_cf(internal_cf, INFO_KEY)?
+        .map(|bytes| Info::decode(&bytes, format_version))
+        .transpose()
+}
+
+/// Writes the metadata of a store which does not have it yet.
+pub(crate) fn init_info(db: &TreeDb) -> Result<()> {
+    if load_info(db, 0)?.is_some() {
+        return Ok(());
+    }
+    let internal_cf = db.cf_handle(INTERNAL_CF_NAME).unwrap();
+    db.put_cf(internal_cf, INFO_KEY, Info::new().encode())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::tree::NULL_HASH;
+    use crate::Merk;
+
+    #[test]
+    fn info() {
+        let tmp_dir = tempdir::TempDir::new("merk_info").unwrap();
+        let path = tmp_dir.path().join("store");
+        let (id, roots) = {
+            let mut merk = Merk::open(&path).unwrap();
+            let info = merk.info().unwrap();
+            assert_eq!(info.commits, 0);
+            assert!(info.recent_roots.is_empty());
+            assert!(info.created_at > 0);
+            assert_eq!(info.uuid().len(), 36);
+            assert_eq!(&info.uuid()[14..15], "4");
+
+            let mut roots = vec![];
+            for i in 0..20 {
+                merk.apply(&make_batch_seq(i * 10..(i + 1) * 10), &[])
+                    .unwrap();
+                roots.push(merk.root_hash());
+            }
+            merk.set_format_version(1).unwrap();
+            (info.id, roots)
+        };
+
+        let merk = Merk::open(&path).unwrap();
+        let info = merk.info().unwrap();
+        assert_eq!(info.id, id);
+        assert_eq!(info.format_version, 1);
+        assert_eq!(info.commits, 20);
+        assert_eq!(
+            info.recent_roots,
+            roots[20 - ROOT_HISTORY_LENGTH..].to_vec()
+        );
+        assert_eq!(Info::decode(&info.encode(), 1).unwrap(), info);
+        assert!(Info::decode(&info.encode()[1..], 1).is_err());
+        assert_ne!(TempMerk::new().unwrap().info().unwrap().id, id);
+        assert_ne!(info.recent_roots[0], NULL_HASH);
+    }
+}
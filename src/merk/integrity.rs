@@ -0,0 +1,263 @@
+//! Full structural verification of a store, see `Merk::verify_integrity`,
+//! and sampled verification of the root hash on every commit, see
+//! `Merk::set_audit_samples`.
+
+use std::borrow::Cow;
+
+use rand::prelude::*;
+
+use super::{Merk, MerkSource};
+use crate::tree::{kv_hash_with_flags, node_hash, Fetch, Hash, Hasher, Tree, NULL_HASH};
+use crate::{Error, Result};
+
+/// The height of the subtrees whose hashes are recomputed in full by an
+/// audit, so each sample reads at most 255 nodes below its path.
+const AUDIT_SUBTREE_HEIGHT: u8 = 8;
+
+fn violation(key: &[u8], detail: &str) -> Error {
+    Error::InvariantViolation {
+        key: key.to_vec(),
+        detail: detail.into(),
+    }
+}
+
+/// Returns the value of `tree`, fetching it if it is not loaded.
+pub(crate) fn value_of<'a>(tree: &'a Tree, source: &MerkSource) -> Result<Cow<'a, [u8]>> {
+    Ok(if tree.value_loaded() {
+        Cow::Borrowed(tree.value())
+    } else {
+        Cow::Owned(source.fetch_value(&tree.key())?)
+    })
+}
+
+/// Checks the subtree rooted at `tree`, whose keys must be greater than
+/// `min` and less than `max`, and returns its number of nodes.
+fn check(tree: &Tree, source: &MerkSource, min: Option<&[u8]>, max: Option<&[u8]>) -> Result<u64> {
+    let key = &*tree.key();
+    if min.is_some_and(|min| key <= min) || max.is_some_and(|max| key >= max) {
+        return Err(violation(key, "Key is out of order"));
+    }
+    if kv_hash_with_flags::<Hasher>(key, &value_of(tree, source)?, tree.flags())? != *tree.kv_hash()
+    {
+        return Err(violation(key, "Key/value hash does not match the entry"));
+    }
+    if tree.balance_factor().abs() > 1 {
+        return Err(violation(key, "Node is unbalanced"));
+    }
+
+    let mut count = 1;
+    for &left in [true, false].iter() {
+        let link = match tree.link(left) {
+            Some(link) => link,
+            None => continue,
+        };
+        let fetched;
+        let child = match tree.child(left) {
+            Some(child) => child,
+            None => {
+                fetched = source.fetch(link)?;
+                &fetched
+            }
+        };
+        if child.key() != link.key() {
+            return Err(violation(key, "Child key does not match its link"));
+        }
+        if child.hash() != *link.hash() {
+            return Err(violation(&child.key(), "Node hash does not match its link"));
+        }
+        if child.height() != link.height() {
+            return Err(violation(
+                &child.key(),
+                "Node height does not match its link",
+            ));
+        }
+        let (min, max) = if left {
+            (min, Some(key))
+        } else {
+            (Some(key), max)
+        };
+        count += check(child, source, min, max)?;
+    }
+    Ok(count)
+}
+
+/// Calls `f` with the child of `tree` on the given side, fetching it if it is
+/// not in memory.
+fn with_child<T>(
+    tree: &Tree,
+    left: bool,
+    source: &MerkSource,
+    f: impl FnOnce(&Tree) -> Result<T>,
+) -> Result<Option<T>> {
+    let link = match tree.link(left) {
+        Some(link) => link,
+        None => return Ok(None),
+    };
+    match tree.child(left) {
+        Some(child) => f(child).map(Some),
+        None => f(&source.fetch(link)?).map(Some),
+    }
+}
+
+/// Recomputes the hash of the subtree rooted at `tree` from its entries,
+/// without using any of the hashes stored in its nodes or links.
+fn recompute_hash(tree: &Tree, source: &MerkSource) -> Result<Hash> {
+    let kv = kv_hash_with_flags::<Hasher>(&tree.key(), &value_of(tree, source)?, tree.flags())?;
+    let left = with_child(tree, true, source, |child| recompute_hash(child, source))?;
+    let right = with_child(tree, false, source, |child| recompute_hash(child, source))?;
+    Ok(node_hash::<Hasher>(
+        &kv,
+        &left.unwrap_or(NULL_HASH),
+        &right.unwrap_or(NULL_HASH),
+    ))
+}
+
+/// Recomputes the hash of `tree` along a random path down to a subtree of
+/// at most `AUDIT_SUBTREE_HEIGHT`, whose hash is recomputed from its entries.
+/// The hashes of the other children along the path are taken from their
+/// links.
+fn audit_path(tree: &Tree, source: &MerkSource, rng: &mut impl Rng) -> Result<Hash> {
+    if tree.height() <= AUDIT_SUBTREE_HEIGHT {
+        return recompute_hash(tree, source);
+    }
+
+    let left = match (tree.link(true), tree.link(false)) {
+        (Some(_), Some(_)) => rng.gen(),
+        (left, _) => left.is_some(),
+    };
+    let child_hash = with_child(tree, left, source, |child| audit_path(child, source, rng))?
+        .unwrap_or(NULL_HASH);
+    let other_hash = tree.link(!left).map_or(NULL_HASH, |link| *link.hash());
+    let kv = kv_hash_with_flags::<Hasher>(&tree.key(), &value_of(tree, source)?, tree.flags())?;
+    Ok(if left {
+        node_hash::<Hasher>(&kv, &child_hash, &other_hash)
+    } else {
+        node_hash::<Hasher>(&kv, &other_hash, &child_hash)
+    })
+}
+
+impl Merk {
+    /// Enables or disables auditing of commits. With `samples` above zero,
+    /// every commit recomputes the root hash from scratch along `samples`
+    /// random paths through the tree, each reaching a subtree whose hash is
+    /// recomputed from all of its entries, and fails with
+    /// `Error::InvariantViolation` if any of the results differ from the
+    /// root hash. This is a canary for hashing bugs, at the cost of reading
+    /// up to a few hundred nodes per sample on every commit.
+    ///
+    /// An audit runs once the commit has been written and passed to the
+    /// post-commit hooks and subscribers, so a failed audit means the store
+    /// already holds the suspect tree, and the commit's attestation (see
+    /// `set_root_signer`) is not published. Defaults to zero.
+    pub fn set_audit_samples(&mut self, samples: usize) {
+        self.audit_samples = samples;
+    }
+
+    /// Runs the audit enabled by `set_audit_samples` over the committed tree.
+    pub(crate) fn audit(&self) -> Result<()> {
+        if self.audit_samples == 0 {
+            return Ok(());
+        }
+
+        let root_hash = self.root_hash();
+        let mut rng = thread_rng();
+        self.use_tree(|maybe_tree| {
+            let tree = match maybe_tree {
+                Some(tree) => tree,
+                None => return Ok(()),
+            };
+            for _ in 0..self.audit_samples {
+                if audit_path(tree, &self.source(), &mut rng)? != root_hash {
+                    return Err(violation(
+                        &tree.key(),
+                        "Recomputed root hash does not match the committed root hash",
+                    ));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads every node of the tree and checks that the tree is well formed:
+    /// keys are in order, every hash matches the data it commits to, and the
+    /// tree is balanced. Returns the number of nodes, or
+    /// `Error::InvariantViolation` for the first problem found.
+    ///
+    /// This reads the whole store, so is meant for tests and offline checks
+    /// rather than routine use.
+    pub fn verify_integrity(&self) -> Result<u64> {
+        self.use_tree(|maybe_tree| match maybe_tree {
+            None => Ok(0),
+            Some(tree) => check(tree, &self.source(), None, None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::test_utils::{make_batch_seq, TempMerk};
+    use crate::{Error, Op};
+
+    #[test]
+    fn verify_integrity() {
+        let mut merk = TempMerk::new().unwrap();
+        assert_eq!(merk.verify_integrity().unwrap(), 0);
+
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        merk.apply(&[(make_batch_seq(10..11).remove(0).0, Op::Delete)], &[])
+            .unwrap();
+        assert_eq!(merk.verify_integrity().unwrap(), 999);
+    }
+
+    #[test]
+    fn detects_corrupt_nodes() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let key = make_batch_seq(40..41).remove(0).0;
+
+        // overwrite a node with a copy whose value changed but not its hash
+        let mut bytes = merk.db.get(&key).unwrap().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        merk.db.put(&key, bytes).unwrap();
+        merk.load_root().unwrap();
+
+        assert!(merk.verify_integrity().is_err());
+        merk.skip_integrity_check();
+    }
+
+    #[test]
+    fn audits_commits() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_audit_samples(4);
+        merk.apply(&make_batch_seq(0..1_000), &[]).unwrap();
+        merk.apply(&make_batch_seq(500..600), &[]).unwrap();
+        merk.apply(&[(make_batch_seq(10..11).remove(0).0, Op::Delete)], &[])
+            .unwrap();
+
+        // a tree this small is audited in full by every sample
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let key = make_batch_seq(40..41).remove(0).0;
+        let mut bytes = merk.db.get(&key).unwrap().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        merk.db.put(&key, bytes).unwrap();
+        merk.load_root().unwrap();
+
+        // the failed audit is reported after the commit is delivered
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let counter = delivered.clone();
+        merk.add_post_commit_hook(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        merk.set_audit_samples(1);
+        let result = merk.apply(&make_batch_seq(90..91), &[]);
+        assert!(matches!(result, Err(Error::InvariantViolation { .. })));
+        assert_eq!(delivered.load(Ordering::SeqCst), 1);
+        merk.skip_integrity_check();
+    }
+}
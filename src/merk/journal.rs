@@ -0,0 +1,471 @@
+//! Commits too large for a single RocksDB write batch.
+//!
+//! RocksDB builds a write batch in memory and copies it into its memtables as
+//! a whole, so a commit of millions of nodes needs several times its size in
+//! memory. Commits whose writes add up to more than
+//! `Merk::set_max_write_batch_bytes` are instead written in two phases, each
+//! split into batches of about that size:
+//!
+//! 1. The writes are copied into a journal in the internal column family,
+//!    which does not affect the store. The last batch also writes a marker
+//!    saying the journal is complete, which is the commit's atomic point.
+//! 2. The journaled writes are applied to their column families, each batch
+//!    deleting the journal entries it applies, and finally the marker.
+//!
+//! When a store is opened, a complete journal left by a crash during the
+//! second phase is applied, and an incomplete one left by a crash during the
+//! first phase is discarded, so the commit takes effect either fully or not at
+//! all.
+
+use std::convert::TryInto;
+
+use rocksdb::{Direction, IteratorMode, WriteBatch};
+
+use super::reader::CommitLock;
+use super::{Merk, PendingWrite, TreeDb, CF_NAMES, INTERNAL_CF_NAME};
+use crate::{Error, Result};
+
+/// The default maximum total length of the keys and values written by a
+/// commit in a single write batch.
+pub const DEFAULT_MAX_WRITE_BATCH_BYTES: usize = 64 << 20;
+
+const JOURNAL_PREFIX: &[u8] = b"journal/";
+const COMPLETE_KEY: &[u8] = b"journal-complete";
+
+/// Returns the total length of the keys and values of `writes`.
+pub(crate) fn writes_size(writes: &[PendingWrite]) -> usize {
+    writes
+        .iter()
+        .map(|(_, key, maybe_value)| key.len() + maybe_value.as_ref().map_or(0, Vec::len))
+        .sum()
+}
+
+fn entry_key(seq: u64) -> Vec<u8> {
+    let mut key = JOURNAL_PREFIX.to_vec();
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+/// Encodes a write as the index of its column family (0 for the default one,
+/// then the entries of `CF_NAMES` from 1), the key length and key, and the
+/// value if there is one.
+fn encode_entry((cf_name, key, maybe_value): &PendingWrite) -> Vec<u8> {
+    let cf_index = CF_NAMES
+        .iter()
+        .position(|name| name == cf_name)
+        .map_or(0, |i| i + 1);
+    let mut bytes = Vec::with_capacity(6 + key.len() + maybe_value.as_ref().map_or(0, Vec::len));
+    bytes.push(cf_index as u8);
+    bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(key);
+    if let Some(value) = maybe_value {
+        bytes.push(1);
+        bytes.extend_from_slice(value);
+    }
+    bytes
+}
+
+fn decode_entry(bytes: &[u8]) -> Result<PendingWrite> {
+    let invalid = || Error::Decode("Invalid journal entry".into());
+    let cf_name = match bytes.first().ok_or_else(invalid)? {
+        0 => rocksdb::DEFAULT_COLUMN_FAMILY_NAME,
+        &i => *CF_NAMES.get(i as usize - 1).ok_or_else(invalid)?,
+    };
+    let length = bytes.get(1..5).ok_or_else(invalid)?;
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+    let key = bytes.get(5..5 + length).ok_or_else(invalid)?.to_vec();
+    let maybe_value = match bytes.get(5 + length..) {
+        Some([]) => None,
+        Some([1, value @ ..]) => Some(value.to_vec()),
+        _ => return Err(invalid()),
+    };
+    Ok((cf_name, key, maybe_value))
+}
+
+/// The operations of a journaled commit on the database. Implemented by
+/// `TreeDb`, and by a model of it in the loom tests, which check the commit's
+/// handoff to readers taking snapshots.
+pub(crate) trait JournalDb {
+    type Batch: Default;
+
+    fn put(&self, batch: &mut Self::Batch, cf_name: &str, key: &[u8], value: &[u8]);
+
+    fn delete(&self, batch: &mut Self::Batch, cf_name: &str, key: &[u8]);
+
+    /// Writes `batch` atomically, syncing it to disk if `sync` is set.
+    fn write(&self, batch: Self::Batch, sync: bool) -> Result<()>;
+
+    fn has_marker(&self) -> Result<bool>;
+
+    /// Calls `f` with the key and encoding of each journal entry, in order.
+    fn for_each_entry(&self, f: impl FnMut(&[u8], &[u8]) -> Result<()>) -> Result<()>;
+}
+
+impl JournalDb for TreeDb {
+    type Batch = WriteBatch;
+
+    fn put(&self, batch: &mut WriteBatch, cf_name: &str, key: &[u8], value: &[u8]) {
+        batch.put_cf(self.cf_handle(cf_name).unwrap(), key, value);
+    }
+
+    fn delete(&self, batch: &mut WriteBatch, cf_name: &str, key: &[u8]) {
+        batch.delete_cf(self.cf_handle(cf_name).unwrap(), key);
+    }
+
+    fn write(&self, batch: WriteBatch, sync: bool) -> Result<()> {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(sync);
+        Ok(self.write_opt(batch, &opts)?)
+    }
+
+    fn has_marker(&self) -> Result<bool> {
+        let internal_cf = self.cf_handle(INTERNAL_CF_NAME).unwrap();
+        Ok(self.get_pinned_cf(internal_cf, COMPLETE_KEY)?.is_some())
+    }
+
+    fn for_each_entry(&self, mut f: impl FnMut(&[u8], &[u8]) -> Result<()>) -> Result<()> {
+        let internal_cf = self.cf_handle(INTERNAL_CF_NAME).unwrap();
+        let mode = IteratorMode::From(JOURNAL_PREFIX, Direction::Forward);
+        for (key, bytes) in self.iterator_cf(internal_cf, mode) {
+            if !key.starts_with(JOURNAL_PREFIX) {
+                break;
+            }
+            f(&key, &bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Commits `writes` through the journal, see the module documentation. The
+/// marker and the journaled writes are written under `commit_lock`, so
+/// readers' snapshots see either all of the writes or none of them.
+pub(crate) fn write_journaled<D: JournalDb>(
+    db: &D,
+    commit_lock: &CommitLock,
+    writes: &[PendingWrite],
+    max_bytes: usize,
+    sync: bool,
+) -> Result<()> {
+    let mut batch = D::Batch::default();
+    let mut size = 0;
+    for (seq, write) in writes.iter().enumerate() {
+        let entry = encode_entry(write);
+        size += entry.len();
+        db.put(&mut batch, INTERNAL_CF_NAME, &entry_key(seq as u64), &entry);
+        if size >= max_bytes {
+            db.write(std::mem::take(&mut batch), false)?;
+            size = 0;
+        }
+    }
+
+    // the commit takes effect once the marker is written
+    db.put(&mut batch, INTERNAL_CF_NAME, COMPLETE_KEY, &[]);
+    commit_lock.commit(|| db.write(batch, sync))?;
+
+    commit_lock.commit(|| apply_journal(db, max_bytes))
+}
+
+/// Applies the writes in a complete journal, in batches of about `max_bytes`,
+/// then deletes the journal.
+fn apply_journal<D: JournalDb>(db: &D, max_bytes: usize) -> Result<()> {
+    let mut batch = D::Batch::default();
+    let mut size = 0;
+    db.for_each_entry(|entry_key, bytes| {
+        let (cf_name, key, maybe_value) = decode_entry(bytes)?;
+        size += bytes.len();
+        match maybe_value {
+            Some(value) => db.put(&mut batch, cf_name, &key, &value),
+            None => db.delete(&mut batch, cf_name, &key),
+        }
+        db.delete(&mut batch, INTERNAL_CF_NAME, entry_key);
+        if size >= max_bytes {
+            db.write(std::mem::take(&mut batch), false)?;
+            size = 0;
+        }
+        Ok(())
+    })?;
+    db.delete(&mut batch, INTERNAL_CF_NAME, COMPLETE_KEY);
+    db.write(batch, false)
+}
+
+/// Finishes or discards a journaled commit interrupted by a crash, see the
+/// module documentation.
+pub(crate) fn recover<D: JournalDb>(db: &D) -> Result<()> {
+    if db.has_marker()? {
+        return apply_journal(db, DEFAULT_MAX_WRITE_BATCH_BYTES);
+    }
+
+    let mut batch = D::Batch::default();
+    let mut empty = true;
+    db.for_each_entry(|key, _| {
+        db.delete(&mut batch, INTERNAL_CF_NAME, key);
+        empty = false;
+        Ok(())
+    })?;
+    if !empty {
+        db.write(batch, false)?;
+    }
+    Ok(())
+}
+
+impl Merk {
+    /// Sets the maximum total length of the keys and values a commit writes
+    /// in a single RocksDB write batch (`DEFAULT_MAX_WRITE_BATCH_BYTES` by
+    /// default). Larger commits are split into several batches, and are still
+    /// atomic, at the cost of writing everything twice (see the `journal`
+    /// module).
+    pub fn set_max_write_batch_bytes(&mut self, max_bytes: usize) {
+        self.max_write_batch_bytes = max_bytes;
+    }
+
+    #[inline]
+    pub fn get_max_write_batch_bytes(&self) -> usize {
+        self.max_write_batch_bytes
+    }
+
+    /// Commits `writes` through the journal, see the module documentation.
+    pub(crate) fn write_journaled(&mut self, writes: Vec<PendingWrite>) -> Result<()> {
+        self.check_write_fault()?;
+        let sync = self.next_write_syncs();
+        write_journaled(
+            &*self.db,
+            &self.commit_lock,
+            &writes,
+            self.max_write_batch_bytes,
+            sync,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_entry, encode_entry, recover, COMPLETE_KEY};
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn entries() {
+        for write in [
+            ("default", vec![1, 2], Some(vec![3])),
+            ("aux", vec![], None),
+            ("log", vec![4], Some(vec![])),
+        ] {
+            assert_eq!(decode_entry(&encode_entry(&write)).unwrap(), write);
+        }
+        assert!(decode_entry(&[9, 0, 0, 0, 0]).is_err());
+        assert!(decode_entry(&[0, 0, 0, 0, 5]).is_err());
+    }
+
+    #[test]
+    fn split_commits() {
+        let mut merk = TempMerk::new().unwrap();
+        let mut reference = TempMerk::new().unwrap();
+        merk.set_max_write_batch_bytes(4_096);
+        merk.set_retained_versions(2);
+
+        for batch in [make_batch_seq(0..2_000), make_del_batch_seq(500..1_500)].iter() {
+            let aux = [(vec![1], Op::Put(vec![2]))];
+            merk.apply(batch, &aux).unwrap();
+            reference.apply(batch, &aux).unwrap();
+            assert_eq!(merk.root_hash(), reference.root_hash());
+        }
+        let root_hash = merk.root_hash();
+        merk.reopen().unwrap();
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get_aux(&[1]).unwrap(), Some(vec![2]));
+        assert_eq!(merk.get(&seq_key(1_000)).unwrap(), None);
+        merk.verify_integrity().unwrap();
+
+        // undo records are journaled along with the rest of the commit
+        merk.rollback().unwrap();
+        assert!(merk.get(&seq_key(1_000)).unwrap().is_some());
+    }
+
+    #[test]
+    fn recovers_interrupted_commits() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let root_hash = merk.root_hash();
+        let writes = vec![
+            ("aux", vec![7], Some(vec![7])),
+            ("default", seq_key(5), None),
+        ];
+        let internal_cf = merk.db.cf_handle(super::INTERNAL_CF_NAME).unwrap();
+
+        // an incomplete journal is discarded
+        for (seq, write) in writes.iter().enumerate() {
+            merk.db
+                .put_cf(
+                    internal_cf,
+                    super::entry_key(seq as u64),
+                    encode_entry(write),
+                )
+                .unwrap();
+        }
+        recover(&*merk.db).unwrap();
+        merk.reopen().unwrap();
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get_aux(&[7]).unwrap(), None);
+
+        // a complete one is applied
+        let internal_cf = merk.db.cf_handle(super::INTERNAL_CF_NAME).unwrap();
+        merk.db
+            .put_cf(internal_cf, super::entry_key(0), encode_entry(&writes[0]))
+            .unwrap();
+        merk.db.put_cf(internal_cf, COMPLETE_KEY, []).unwrap();
+        merk.reopen().unwrap();
+        assert_eq!(merk.get_aux(&[7]).unwrap(), Some(vec![7]));
+        let internal_cf = merk.db.cf_handle(super::INTERNAL_CF_NAME).unwrap();
+        assert!(merk.db.get_cf(internal_cf, COMPLETE_KEY).unwrap().is_none());
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_test {
+    use std::collections::BTreeMap;
+
+    use loom::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use loom::sync::{Arc, Mutex};
+    use loom::thread;
+
+    use super::super::reader::CommitLock;
+    use super::super::{INTERNAL_CF_NAME, ROOT_KEY_KEY};
+    use super::{write_journaled, JournalDb, COMPLETE_KEY, JOURNAL_PREFIX};
+    use crate::Result;
+
+    type Batch = Vec<(String, Vec<u8>, Option<Vec<u8>>)>;
+    type Contents = BTreeMap<(String, Vec<u8>), Vec<u8>>;
+
+    /// A model of the database, which applies each write batch atomically
+    /// and takes snapshots by copying its contents.
+    #[derive(Default)]
+    struct ModelDb(Mutex<Contents>);
+
+    impl ModelDb {
+        fn snapshot(&self) -> Contents {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl JournalDb for ModelDb {
+        type Batch = Batch;
+
+        fn put(&self, batch: &mut Batch, cf_name: &str, key: &[u8], value: &[u8]) {
+            batch.push((cf_name.to_string(), key.to_vec(), Some(value.to_vec())));
+        }
+
+        fn delete(&self, batch: &mut Batch, cf_name: &str, key: &[u8]) {
+            batch.push((cf_name.to_string(), key.to_vec(), None));
+        }
+
+        fn write(&self, batch: Batch, _sync: bool) -> Result<()> {
+            let mut contents = self.0.lock().unwrap();
+            for (cf_name, key, maybe_value) in batch {
+                match maybe_value {
+                    Some(value) => contents.insert((cf_name, key), value),
+                    None => contents.remove(&(cf_name, key)),
+                };
+            }
+            Ok(())
+        }
+
+        fn has_marker(&self) -> Result<bool> {
+            let key = (INTERNAL_CF_NAME.to_string(), COMPLETE_KEY.to_vec());
+            Ok(self.0.lock().unwrap().contains_key(&key))
+        }
+
+        fn for_each_entry(&self, mut f: impl FnMut(&[u8], &[u8]) -> Result<()>) -> Result<()> {
+            let entries: Vec<_> = self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|((cf_name, key), _)| {
+                    cf_name == INTERNAL_CF_NAME && key.starts_with(JOURNAL_PREFIX)
+                })
+                .map(|((_, key), value)| (key.clone(), value.clone()))
+                .collect();
+            for (key, value) in entries {
+                f(&key, &value)?;
+            }
+            Ok(())
+        }
+    }
+
+    const KEYS: [&[u8]; 2] = [b"a", b"b"];
+
+    /// Commits `version` through the journal, one write per batch, as the
+    /// value of each of `KEYS` and of the root key.
+    fn commit(db: &ModelDb, lock: &CommitLock, version: u8) {
+        let mut writes: Vec<_> = KEYS
+            .iter()
+            .map(|key| {
+                let cf_name = rocksdb::DEFAULT_COLUMN_FAMILY_NAME;
+                (cf_name, key.to_vec(), Some(vec![version]))
+            })
+            .collect();
+        writes.push((INTERNAL_CF_NAME, ROOT_KEY_KEY.to_vec(), Some(vec![version])));
+        write_journaled(db, lock, &writes, 1, false).unwrap();
+    }
+
+    /// Takes a snapshot the way `MerkReader::snapshot` does and returns the
+    /// version it is of, checking it is not part way through a commit.
+    fn read(db: &ModelDb, lock: &CommitLock) -> u8 {
+        let snapshot = lock.snapshot(|| db.snapshot());
+        let version_of = |cf_name: &str, key: &[u8]| {
+            snapshot
+                .get(&(cf_name.to_string(), key.to_vec()))
+                .map_or(0, |value| value[0])
+        };
+        let version = version_of(INTERNAL_CF_NAME, ROOT_KEY_KEY);
+        for key in KEYS.iter() {
+            let key_version = version_of(rocksdb::DEFAULT_COLUMN_FAMILY_NAME, key);
+            assert_eq!(key_version, version, "read a torn commit");
+        }
+        version
+    }
+
+    #[test]
+    fn loom_snapshots_see_whole_journaled_commits() {
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(3);
+        model.check(|| {
+            let db = Arc::new(ModelDb::default());
+            let lock = CommitLock::default();
+            let reader = {
+                let (db, lock) = (db.clone(), lock.clone());
+                thread::spawn(move || {
+                    let first = read(&db, &lock);
+                    let second = read(&db, &lock);
+                    assert!(second >= first, "read an older commit");
+                })
+            };
+            commit(&db, &lock, 1);
+            commit(&db, &lock, 2);
+            reader.join().unwrap();
+            assert_eq!(read(&db, &lock), 2);
+        });
+    }
+
+    #[test]
+    fn loom_snapshots_of_journaled_commits_see_earlier_writes() {
+        loom::model(|| {
+            let db = Arc::new(ModelDb::default());
+            let lock = CommitLock::default();
+            let data = Arc::new(AtomicUsize::new(0));
+            let writer = {
+                let (db, lock, data) = (db.clone(), lock.clone(), data.clone());
+                thread::spawn(move || {
+                    data.store(1, Relaxed);
+                    commit(&db, &lock, 1);
+                })
+            };
+            // the writer's earlier writes are visible along with its commit
+            if read(&db, &lock) == 1 {
+                assert_eq!(data.load(Relaxed), 1);
+            }
+            writer.join().unwrap();
+            assert_eq!(read(&db, &lock), 1);
+        });
+    }
+}
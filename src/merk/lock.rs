@@ -0,0 +1,154 @@
+//! An advisory lock file marking a store as open by a process.
+//!
+//! RocksDB already refuses to open a database which another process has open,
+//! but only with an IO error about its `LOCK` file, which does not say who
+//! holds it. Before opening the database, `Merk` creates a lock file holding
+//! the id of the process opening it and when it was opened, so a second open
+//! fails with `Error::LockHeld` naming the owner instead. Lock files left
+//! behind by processes which exited without closing the store are taken over.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Merk;
+use crate::{Error, Result};
+
+pub(crate) const LOCK_FILE_NAME: &str = "merk.lock";
+
+/// A held lock file, which is removed when dropped.
+pub(crate) struct LockFile {
+    path: PathBuf,
+    contents: String,
+}
+
+impl LockFile {
+    /// Creates the lock file of the store at `path`, failing with
+    /// `Error::LockHeld` if a live process holds it.
+    pub(crate) fn acquire(path: &Path) -> Result<LockFile> {
+        fs::create_dir_all(path)?;
+        let path = path.join(LOCK_FILE_NAME);
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        // the nonce tells apart locks taken by the same process in the same
+        // second, so a forced-out owner does not remove its successor's lock
+        let nonce: u64 = rand::random();
+        let contents = format!("{} {} {:016x}\n", std::process::id(), since, nonce);
+
+        // a second attempt is made after removing a stale lock file, which
+        // fails if another process took it over meanwhile
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(contents.as_bytes())?;
+                    file.sync_all()?;
+                    return Ok(LockFile { path, contents });
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => match read_owner(&path)? {
+                    Some((pid, since)) if is_running(pid) => {
+                        return Err(Error::LockHeld { pid, since })
+                    }
+                    _ => remove(&path)?,
+                },
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        match read_owner(&path)? {
+            Some((pid, since)) => Err(Error::LockHeld { pid, since }),
+            None => Err(Error::LockHeld { pid: 0, since: 0 }),
+        }
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // the lock may have been forcibly taken over by another process
+        if fs::read_to_string(&self.path).ok().as_ref() == Some(&self.contents) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Merk {
+    /// Removes the lock file of the store at `path`, so it can be opened even
+    /// though the process which holds it is still running. This is the
+    /// equivalent of a `--force` flag, for when the lock is known to be held
+    /// by a process which is hung or no longer uses the store.
+    ///
+    /// Opening still fails if the other process has the store open, since
+    /// RocksDB's own lock is held until it exits.
+    pub fn force_unlock<P: AsRef<Path>>(path: P) -> Result<()> {
+        remove(&path.as_ref().join(LOCK_FILE_NAME))
+    }
+}
+
+/// Returns the process id and lock time written in a lock file, or `None` if
+/// the file is missing or malformed.
+fn read_owner(path: &Path) -> Result<Option<(u32, u64)>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut fields = contents.split_whitespace();
+    let pid = fields.next().and_then(|pid| pid.parse().ok());
+    let since = fields.next().and_then(|since| since.parse().ok());
+    Ok(pid.zip(since))
+}
+
+/// Returns `true` if a process with the given id is running. Where this
+/// cannot be checked, every process is assumed to be running.
+fn is_running(pid: u32) -> bool {
+    let proc_dir = Path::new("/proc");
+    !proc_dir.is_dir() || proc_dir.join(pid.to_string()).exists()
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_file() {
+        let tmp_dir = tempdir::TempDir::new("merk_lock").unwrap();
+        let path = tmp_dir.path().join("store");
+
+        let merk = Merk::open(&path).unwrap();
+        match Merk::open(&path) {
+            Err(Error::LockHeld { pid, since }) => {
+                assert_eq!(pid, std::process::id());
+                assert!(since > 0);
+            }
+            _ => panic!("expected LockHeld"),
+        }
+        drop(merk);
+        assert!(!path.join(LOCK_FILE_NAME).exists());
+
+        // lock files of processes which are no longer running are taken over
+        let merk = Merk::open(&path).unwrap();
+        drop(merk);
+        fs::write(path.join(LOCK_FILE_NAME), format!("{} 1\n", u32::MAX)).unwrap();
+        let merk = Merk::open(&path).unwrap();
+        drop(merk);
+
+        // a running owner's lock is only taken over when forced
+        let lock = LockFile::acquire(&path).unwrap();
+        assert!(matches!(Merk::open(&path), Err(Error::LockHeld { .. })));
+        Merk::force_unlock(&path).unwrap();
+        let merk = Merk::open(&path).unwrap();
+        // the forced-out owner leaves the new owner's lock in place
+        drop(lock);
+        assert!(path.join(LOCK_FILE_NAME).exists());
+        drop(merk);
+        assert!(!path.join(LOCK_FILE_NAME).exists());
+    }
+}
@@ -0,0 +1,206 @@
+//! Export of a store's statistics in the Prometheus text format, see
+//! `Merk::prometheus_metrics`.
+
+use std::fmt::Write;
+
+use super::{Merk, AUX_CF_NAME};
+use crate::Result;
+
+/// The RocksDB properties exported for each column family, with the name and
+/// help text of their metric.
+const CF_PROPERTIES: &[(&str, &str, &str)] = &[
+    (
+        "rocksdb.estimate-num-keys",
+        "rocksdb_estimated_keys",
+        "Estimated number of keys in the column family.",
+    ),
+    (
+        "rocksdb.total-sst-files-size",
+        "rocksdb_sst_files_bytes",
+        "Total size of the column family's SST files.",
+    ),
+    (
+        "rocksdb.cur-size-all-mem-tables",
+        "rocksdb_memtable_bytes",
+        "Size of the column family's memtables.",
+    ),
+    (
+        "rocksdb.estimate-pending-compaction-bytes",
+        "rocksdb_pending_compaction_bytes",
+        "Estimated bytes compaction needs to rewrite.",
+    ),
+];
+
+/// The RocksDB properties exported for the whole database.
+const DB_PROPERTIES: &[(&str, &str, &str)] = &[
+    (
+        "rocksdb.block-cache-usage",
+        "rocksdb_block_cache_bytes",
+        "Memory used by the block cache.",
+    ),
+    (
+        "rocksdb.num-running-compactions",
+        "rocksdb_running_compactions",
+        "Number of compactions running.",
+    ),
+    (
+        "rocksdb.num-running-flushes",
+        "rocksdb_running_flushes",
+        "Number of flushes running.",
+    ),
+    (
+        "rocksdb.background-errors",
+        "rocksdb_background_errors",
+        "Number of background errors.",
+    ),
+];
+
+/// Writes the `HELP` and `TYPE` lines of a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Writes a sample of a metric with the given labels.
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (label, label_value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "{}=\"{}\"", label, escape(label_value)).unwrap();
+        }
+        out.push('}');
+    }
+    writeln!(out, " {}", value).unwrap();
+}
+
+/// Escapes a label value for the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Merk {
+    /// Returns the store's counters and RocksDB's statistics in the Prometheus
+    /// text exposition format, for serving from a `/metrics` endpoint so they
+    /// can be scraped without a sidecar exporter. Metrics are prefixed with
+    /// `merk_`, and labeled with the tree's name for named trees.
+    ///
+    /// Only available with the `prometheus` feature.
+    pub fn prometheus_metrics(&self) -> Result<String> {
+        let tree_labels: Vec<_> = self
+            .tree_name()
+            .map(|tree| ("tree", tree))
+            .into_iter()
+            .collect();
+        let info = self.info()?;
+        let cache = self.proof_cache_stats();
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "merk_commits_total",
+                "Number of commits made to the store.",
+                info.commits,
+            ),
+            (
+                "merk_proof_cache_hits_total",
+                "Number of proofs returned from the proof cache.",
+                cache.hits,
+            ),
+            (
+                "merk_proof_cache_misses_total",
+                "Number of proofs created while the proof cache was enabled.",
+                cache.misses,
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            header(&mut out, name, "counter", help);
+            sample(&mut out, name, &tree_labels, value);
+        }
+
+        let gauges = [
+            (
+                "merk_format_version",
+                "Format version nodes are written in.",
+                self.format_version as u64,
+            ),
+            (
+                "merk_retained_versions",
+                "Number of commits which can be rolled back.",
+                self.retained_versions() as u64,
+            ),
+            (
+                "merk_proof_cache_entries",
+                "Number of proofs held by the proof cache.",
+                cache.entries as u64,
+            ),
+            (
+                "merk_proof_cache_capacity",
+                "Maximum number of proofs held by the proof cache.",
+                cache.capacity as u64,
+            ),
+        ];
+        for (name, help, value) in gauges.iter() {
+            header(&mut out, name, "gauge", help);
+            sample(&mut out, name, &tree_labels, value);
+        }
+
+        let db = self.db.inner();
+        for (property, name, help) in CF_PROPERTIES.iter() {
+            let name = format!("merk_{}", name);
+            header(&mut out, &name, "gauge", help);
+            for (cf_label, cf) in [
+                ("nodes", self.db.nodes_cf()),
+                ("aux", self.db.cf_handle(AUX_CF_NAME).unwrap()),
+            ] {
+                if let Some(value) = db.property_int_value_cf(cf, property)? {
+                    let mut labels = tree_labels.to_vec();
+                    labels.push(("cf", cf_label));
+                    sample(&mut out, &name, &labels, value);
+                }
+            }
+        }
+        for (property, name, help) in DB_PROPERTIES.iter() {
+            if let Some(value) = db.property_int_value(property)? {
+                let name = format!("merk_{}", name);
+                header(&mut out, &name, "gauge", help);
+                sample(&mut out, &name, &[], value);
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use crate::test_utils::*;
+    use crate::Merk;
+
+    #[test]
+    fn prometheus_metrics() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        merk.apply(&make_batch_seq(100..200), &[]).unwrap();
+        let metrics = merk.prometheus_metrics().unwrap();
+        assert!(metrics.contains("# TYPE merk_commits_total counter\nmerk_commits_total 2\n"));
+        assert!(metrics.contains("merk_retained_versions 0\n"));
+        assert!(metrics.contains("merk_rocksdb_estimated_keys{cf=\"nodes\"}"));
+        for line in metrics.lines().filter(|line| !line.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            value.parse::<f64>().unwrap();
+        }
+
+        let dir = TempDir::new("merk_metrics").unwrap();
+        let merk = Merk::open_named(dir.path().join("db"), "a\"b").unwrap();
+        let metrics = merk.prometheus_metrics().unwrap();
+        assert!(metrics.contains("merk_commits_total{tree=\"a\\\"b\"} 0\n"));
+    }
+}
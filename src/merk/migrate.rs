@@ -0,0 +1,630 @@
+//! Bulk rewrites of a store's entries, for migrations which move or copy whole
+//! ranges of entries, such as renaming a module or migrating an account, or
+//! which change the scheme of every key or the schema of every value in the
+//! store.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, LinkedList};
+use std::time::Instant;
+
+use rocksdb::WriteBatch;
+
+use super::{check_aux, check_batch_keys, Applied, Merk, PendingWrite, INTERNAL_CF_NAME};
+use crate::tree::{Batch, BatchEntry, Op, Walker};
+use crate::{Error, Hash, Result};
+
+/// The key in the internal column family of the last key scanned by an
+/// unfinished key migration.
+const CURSOR_KEY: &[u8] = b"migrate-keys-cursor";
+
+/// The number of entries scanned for each batch of a key migration by
+/// `Merk::migrate_keys`.
+pub const DEFAULT_MIGRATION_BATCH_SIZE: usize = 10_000;
+
+/// The progress of a key migration, see `Merk::migrate_keys_opt`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationProgress {
+    /// The number of entries scanned so far by this call.
+    pub scanned: u64,
+    /// The number of entries moved to new keys so far by this call.
+    pub migrated: u64,
+    /// The last key scanned, after which an interrupted migration resumes,
+    /// or `None` once the migration has finished.
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// The report of a finished value migration, see `Merk::migrate_values`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValueMigration {
+    /// The number of entries scanned.
+    pub scanned: u64,
+    /// The number of entries given new values.
+    pub migrated: u64,
+    /// The root hash before the migration.
+    pub old_root_hash: Hash,
+    /// The root hash after the migration.
+    pub new_root_hash: Hash,
+}
+
+/// Returns the first key after all of the keys starting with `prefix`, or
+/// `None` if there is no such key (the prefix is empty or all `0xff` bytes).
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl Merk {
+    /// Copies every entry with a key starting with `src_prefix` to the key
+    /// with `src_prefix` replaced by `dst_prefix`, overwriting any existing
+    /// entries there, in a single `apply`. Returns the number of entries
+    /// copied.
+    ///
+    /// The entries are read and written inside the store, so the application
+    /// does not have to stream them through itself, but they are all held in
+    /// memory for the batch. The source entries are read before any are
+    /// written, so the prefixes may overlap.
+    pub fn copy_prefix(&mut self, src_prefix: &[u8], dst_prefix: &[u8]) -> Result<usize> {
+        let batch = self
+            .scan_all(src_prefix, prefix_end(src_prefix))
+            .map(|entry| {
+                let (key, value) = entry?;
+                let dst_key = [dst_prefix, &key[src_prefix.len()..]].concat();
+                Ok((dst_key, Op::Put(value)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.apply(&batch, &[])?;
+        Ok(batch.len())
+    }
+
+    /// Moves every entry to the key returned for it by `f`, or leaves it
+    /// where it is if `f` returns `None`, like `migrate_keys_opt` with
+    /// batches of `DEFAULT_MIGRATION_BATCH_SIZE` entries and no progress
+    /// reporting.
+    pub fn migrate_keys<F>(&mut self, f: F) -> Result<MigrationProgress>
+    where
+        F: FnMut(&[u8]) -> Option<Vec<u8>>,
+    {
+        self.migrate_keys_opt(f, DEFAULT_MIGRATION_BATCH_SIZE, |_| {})
+    }
+
+    /// Rewrites the tree under a new key scheme, moving every entry to the
+    /// key returned for it by `f`, or leaving it where it is if `f` returns
+    /// `None`.
+    ///
+    /// The entries are scanned in key order and moved in batches of up to
+    /// `batch_size` scanned entries, each committed with its own `apply`, and
+    /// `on_progress` is called after each batch. The last key scanned is
+    /// committed with each batch, so if the migration is interrupted, the
+    /// next call resumes after it, and must be made with the same `f`.
+    ///
+    /// Moved entries may be scanned again when the scan reaches their new
+    /// keys, so `f` must return `None` for keys which are already in the new
+    /// scheme. Fails if two keys are moved to the same key, or if a key is
+    /// moved to the key of an entry which is not moved away in the same
+    /// batch.
+    pub fn migrate_keys_opt<F, P>(
+        &mut self,
+        mut f: F,
+        batch_size: usize,
+        mut on_progress: P,
+    ) -> Result<MigrationProgress>
+    where
+        F: FnMut(&[u8]) -> Option<Vec<u8>>,
+        P: FnMut(&MigrationProgress),
+    {
+        let batch_size = batch_size.max(1);
+        let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+        let mut progress = MigrationProgress {
+            cursor: self.db.get_cf(internal_cf, CURSOR_KEY)?,
+            ..Default::default()
+        };
+
+        loop {
+            let start = match &progress.cursor {
+                Some(cursor) => [cursor.as_slice(), &[0]].concat(),
+                None => vec![],
+            };
+            let entries = self
+                .scan_all(&start, None)
+                .take(batch_size)
+                .collect::<Result<Vec<_>>>()?;
+            let done = entries.len() < batch_size;
+            progress.scanned += entries.len() as u64;
+            progress.cursor = entries.last().filter(|_| !done).map(|(key, _)| key.clone());
+
+            let mut moved = BTreeMap::new();
+            let mut puts = BTreeMap::new();
+            for (key, value) in entries {
+                let new_key = match f(&key) {
+                    Some(new_key) if new_key != key => new_key,
+                    _ => continue,
+                };
+                if puts.insert(new_key.clone(), Op::Put(value)).is_some() {
+                    return Err(Error::MigrationCollision(new_key));
+                }
+                moved.insert(key, Op::Delete);
+            }
+            for new_key in puts.keys() {
+                if !moved.contains_key(new_key) && self.get(new_key)?.is_some() {
+                    return Err(Error::MigrationCollision(new_key.clone()));
+                }
+            }
+            progress.migrated += moved.len() as u64;
+
+            if moved.is_empty() {
+                let internal_cf = self.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+                let mut batch = WriteBatch::default();
+                match &progress.cursor {
+                    Some(cursor) => batch.put_cf(internal_cf, CURSOR_KEY, cursor),
+                    None => batch.delete_cf(internal_cf, CURSOR_KEY),
+                }
+                self.write(batch)?;
+            } else {
+                moved.extend(puts);
+                let batch: Vec<_> = moved.into_iter().collect();
+                check_batch_keys(&batch)?;
+                let (batch, mut writes) = self.prepare_writes(&batch, None)?;
+                writes.push((
+                    INTERNAL_CF_NAME,
+                    CURSOR_KEY.to_vec(),
+                    progress.cursor.clone(),
+                ));
+                unsafe { self.apply_unchecked_with(&batch, &[], writes)? };
+            }
+
+            on_progress(&progress);
+            if done {
+                return Ok(progress);
+            }
+        }
+    }
+
+    /// Gives every entry the value returned for it by `f`, called with its
+    /// key and current value, or leaves it unchanged if `f` returns `None`,
+    /// like `migrate_values_opt` with batches of
+    /// `DEFAULT_MIGRATION_BATCH_SIZE` entries.
+    pub fn migrate_values<F>(&mut self, f: F) -> Result<ValueMigration>
+    where
+        F: FnMut(&[u8], &[u8]) -> Option<Vec<u8>>,
+    {
+        self.migrate_values_opt(f, DEFAULT_MIGRATION_BATCH_SIZE)
+    }
+
+    /// Upgrades the stored values to a new schema, giving every entry the
+    /// value returned for it by `f`, called with its key and current value,
+    /// or leaving it unchanged if `f` returns `None`.
+    ///
+    /// The entries are scanned in key order and updated in batches of up to
+    /// `batch_size` scanned entries, each committed with its own `apply`, so
+    /// an interrupted migration leaves the store with the entries before
+    /// some key migrated. Returns the numbers of entries scanned and updated
+    /// along with the root hashes before and after the migration.
+    pub fn migrate_values_opt<F>(&mut self, mut f: F, batch_size: usize) -> Result<ValueMigration>
+    where
+        F: FnMut(&[u8], &[u8]) -> Option<Vec<u8>>,
+    {
+        let batch_size = batch_size.max(1);
+        let mut report = ValueMigration {
+            scanned: 0,
+            migrated: 0,
+            old_root_hash: self.root_hash(),
+            new_root_hash: self.root_hash(),
+        };
+
+        let mut start = vec![];
+        loop {
+            let entries = self
+                .scan_all(&start, None)
+                .take(batch_size)
+                .collect::<Result<Vec<_>>>()?;
+            report.scanned += entries.len() as u64;
+            let done = entries.len() < batch_size;
+            if let Some((key, _)) = entries.last() {
+                start = [key.as_slice(), &[0]].concat();
+            }
+
+            let batch: Vec<_> = entries
+                .into_iter()
+                .filter_map(|(key, value)| f(&key, &value).map(|value| (key, Op::Put(value))))
+                .collect();
+            if !batch.is_empty() {
+                report.migrated += batch.len() as u64;
+                self.apply(&batch, &[])?;
+            }
+
+            if done {
+                report.new_root_hash = self.root_hash();
+                return Ok(report);
+            }
+        }
+    }
+
+    /// Applies the sorted entries of `entries` in a single commit like
+    /// `apply_iter_opt`, in batches of `DEFAULT_MIGRATION_BATCH_SIZE` entries.
+    pub fn apply_iter<I>(&mut self, entries: I, aux: &Batch) -> Result<u64>
+    where
+        I: IntoIterator<Item = BatchEntry>,
+    {
+        self.apply_iter_opt(entries, aux, DEFAULT_MIGRATION_BATCH_SIZE)
+    }
+
+    /// Applies the entries of `entries`, which must be sorted and unique, in
+    /// a single commit, without collecting them into a single batch first:
+    /// they are read and applied to the tree in batches of up to `batch_size`
+    /// entries, so only one batch is held in memory at a time (along with the
+    /// nodes they modify, until the commit). `aux` is committed with the
+    /// entries. The resulting tree is the same as if each batch were applied
+    /// with its own `apply`. Returns the number of entries applied.
+    ///
+    /// Keys are checked to be sorted across batches as well as within them,
+    /// and errors give the index of a key in `entries`. Keys may not be moved
+    /// to keys before the last key of an earlier batch. Large commits are
+    /// split into journaled writes (see `set_max_write_batch_bytes`), so the
+    /// commit is atomic: if any entry fails, none are applied. If commit
+    /// hooks or subscribers are registered, the entries are kept in memory
+    /// until the commit, to be passed to them.
+    pub fn apply_iter_opt<I>(&mut self, entries: I, aux: &Batch, batch_size: usize) -> Result<u64>
+    where
+        I: IntoIterator<Item = BatchEntry>,
+    {
+        check_aux(aux)?;
+        let batch_size = batch_size.max(1);
+        self.with_node_pool(|merk| merk.apply_iter_and_commit(entries.into_iter(), aux, batch_size))
+    }
+
+    fn apply_iter_and_commit<I>(
+        &mut self,
+        entries: I,
+        aux: &Batch,
+        batch_size: usize,
+    ) -> Result<u64>
+    where
+        I: Iterator<Item = BatchEntry>,
+    {
+        let start = Instant::now();
+        let slow_op_start = self.slow_op_start();
+        let quotas = self.quotas.clone();
+        let mut staged = StagedEntries::default();
+        if let Err(err) = self.apply_iter_to_tree(entries, batch_size, &mut staged) {
+            // nothing was written, so the last commit can be reloaded
+            self.load_root()?;
+            self.quotas = quotas;
+            return Err(err);
+        }
+
+        let StagedEntries {
+            entries,
+            deleted_keys,
+            writes,
+            count,
+        } = staged;
+        // the quotas' usage was updated with each batch
+        let applied = Applied {
+            start,
+            slow_op_start,
+            quota_usage: vec![],
+        };
+        self.commit_applied(&entries, deleted_keys, aux, writes, applied, None)?;
+        Ok(count)
+    }
+
+    /// Applies `entries` to the in-memory tree in batches of up to
+    /// `batch_size` entries, staging the changes to commit in `staged`.
+    fn apply_iter_to_tree<I>(
+        &mut self,
+        mut entries: I,
+        batch_size: usize,
+        staged: &mut StagedEntries,
+    ) -> Result<()>
+    where
+        I: Iterator<Item = BatchEntry>,
+    {
+        let keep_entries = !self.pre_commit_hooks.is_empty()
+            || !self.post_commit_hooks.is_empty()
+            || !self.subscribers.is_empty()
+            || cfg!(feature = "profiling");
+        let mut batch = Vec::with_capacity(batch_size.min(DEFAULT_MIGRATION_BATCH_SIZE));
+        let mut last_key: Option<Vec<u8>> = None;
+        loop {
+            batch.clear();
+            batch.extend(entries.by_ref().take(batch_size));
+            if batch.is_empty() {
+                return Ok(());
+            }
+
+            if self.check_batches {
+                if let (Some(last_key), Some((key, _))) = (&last_key, batch.first()) {
+                    match key.cmp(last_key) {
+                        Ordering::Less => {
+                            return Err(Error::BatchUnsorted {
+                                index: staged.count as usize,
+                            })
+                        }
+                        Ordering::Equal => return Err(Error::DuplicateKey { key: key.clone() }),
+                        Ordering::Greater => {}
+                    }
+                }
+                check_batch_keys(&batch).map_err(|err| offset_index(err, staged.count))?;
+            }
+            let (resolved, writes) = self.prepare_writes(&batch, None)?;
+            // reads of keys changed by earlier batches could see their last
+            // committed values, since the tree is not committed in between
+            if let (Some(last_key), Some((key, _))) = (&last_key, resolved.first()) {
+                if key <= last_key {
+                    return Err(Error::BatchKey(format!(
+                        "Key moved to {:?}, before the entries already applied",
+                        key
+                    )));
+                }
+            }
+            self.check_write_policy(&resolved)?;
+            let quota_usage = self.check_quotas(&resolved)?;
+            self.set_quota_usage(quota_usage);
+
+            let maybe_walker = self
+                .tree_mut()
+                .take()
+                .map(|tree| Walker::new(tree, self.source()));
+            let (maybe_tree, mut deleted_keys) =
+                Walker::apply_to(maybe_walker, &resolved, self.source())?;
+            *self.tree_mut() = maybe_tree;
+            staged.deleted_keys.append(&mut deleted_keys);
+            staged.writes.extend(writes);
+            if keep_entries {
+                staged.entries.extend(resolved.iter().cloned());
+            }
+
+            staged.count += batch.len() as u64;
+            if batch.len() < batch_size {
+                return Ok(());
+            }
+            last_key = batch.last().map(|(key, _)| key.clone());
+        }
+    }
+}
+
+/// The changes staged by `Merk::apply_iter_opt` until they are committed.
+#[derive(Default)]
+struct StagedEntries {
+    /// The entries applied, if they are needed by commit hooks or
+    /// subscribers.
+    entries: Vec<BatchEntry>,
+    deleted_keys: LinkedList<Vec<u8>>,
+    writes: Vec<PendingWrite>,
+    /// The number of entries applied.
+    count: u64,
+}
+
+/// Offsets the index in an `Error::BatchUnsorted` for a batch which starts
+/// at `start` in a larger sequence of entries.
+fn offset_index(err: Error, start: u64) -> Error {
+    match err {
+        Error::BatchUnsorted { index } => Error::BatchUnsorted {
+            index: index + start as usize,
+        },
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::test_utils::TempMerk;
+
+    fn put(key: &[u8], value: u8) -> (Vec<u8>, Op) {
+        (key.to_vec(), Op::Put(vec![value]))
+    }
+
+    #[test]
+    fn prefix_ends() {
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(&[1, 0xff]), Some(vec![2]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+        assert_eq!(prefix_end(&[]), None);
+    }
+
+    #[test]
+    fn copy_prefix() {
+        let mut merk = TempMerk::new().unwrap();
+        let batch = [
+            put(b"a", 0),
+            put(b"b/1", 1),
+            put(b"b/2", 2),
+            put(b"b0", 3),
+            put(b"c/2", 9),
+        ];
+        merk.apply(&batch, &[]).unwrap();
+
+        assert_eq!(merk.copy_prefix(b"b/", b"c/").unwrap(), 2);
+        assert_eq!(merk.get(b"c/1").unwrap(), Some(vec![1]));
+        assert_eq!(merk.get(b"c/2").unwrap(), Some(vec![2]));
+        assert_eq!(merk.get(b"b/1").unwrap(), Some(vec![1]));
+        assert_eq!(merk.get(b"c0").unwrap(), None);
+
+        // overlapping prefixes
+        assert_eq!(merk.copy_prefix(b"c", b"c/c").unwrap(), 2);
+        assert_eq!(merk.get(b"c/c/1").unwrap(), Some(vec![1]));
+        assert_eq!(merk.get(b"c/c/2").unwrap(), Some(vec![2]));
+
+        assert_eq!(merk.copy_prefix(b"d", b"e").unwrap(), 0);
+        assert_eq!(merk.copy_prefix(b"", b"z").unwrap(), 8);
+        assert_eq!(merk.get(b"za").unwrap(), Some(vec![0]));
+    }
+
+    /// Moves the keys starting with `a/` to `b/`, after them in key order.
+    fn rename(key: &[u8]) -> Option<Vec<u8>> {
+        key.strip_prefix(b"a/".as_ref())
+            .map(|rest| [b"b/".as_ref(), rest].concat())
+    }
+
+    #[test]
+    fn migrate_keys() {
+        let dir = TempDir::new("migrate_keys").unwrap();
+        let mut merk = Merk::open(dir.path()).unwrap();
+        let mut batch: Vec<_> = (0..20u8).map(|i| put(&[b'a', b'/', i], i)).collect();
+        batch.push(put(b"c", 20));
+        merk.apply(&batch, &[]).unwrap();
+
+        // interrupted after the first batch
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            merk.migrate_keys_opt(rename, 8, |_| panic!("interrupted"))
+        }));
+        assert!(result.is_err());
+        drop(merk);
+        let mut merk = Merk::open(dir.path()).unwrap();
+        let internal_cf = merk.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+        let cursor = merk.db.get_cf(internal_cf, CURSOR_KEY).unwrap();
+        assert_eq!(cursor, Some(vec![b'a', b'/', 7]));
+
+        let mut reports = vec![];
+        let progress = merk
+            .migrate_keys_opt(rename, 8, |progress| reports.push(progress.clone()))
+            .unwrap();
+        assert_eq!(progress.migrated, 12);
+        assert_eq!(progress.cursor, None);
+        assert_eq!(reports.last(), Some(&progress));
+        for i in 0..20u8 {
+            assert_eq!(merk.get(&[b'a', b'/', i]).unwrap(), None);
+            assert_eq!(merk.get(&[b'b', b'/', i]).unwrap(), Some(vec![i]));
+        }
+        assert_eq!(merk.get(b"c").unwrap(), Some(vec![20]));
+
+        // a finished migration starts over, finding nothing to do
+        let progress = merk.migrate_keys(rename).unwrap();
+        assert_eq!(progress.scanned, 21);
+        assert_eq!(progress.migrated, 0);
+    }
+
+    #[test]
+    fn migrate_keys_conflicts() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&[put(b"a/1", 1), put(b"b/1", 2)], &[]).unwrap();
+        assert!(merk.migrate_keys(rename).is_err());
+        assert!(merk.migrate_keys(|_| Some(vec![0])).is_err());
+        assert_eq!(merk.get(b"a/1").unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn migrate_values() {
+        let mut merk = TempMerk::new().unwrap();
+        let batch: Vec<_> = (0..20u8).map(|i| put(&[i], i)).collect();
+        merk.apply(&batch, &[]).unwrap();
+        let old_root_hash = merk.root_hash();
+
+        // doubles the even values
+        let report = merk
+            .migrate_values_opt(
+                |_, value| (value[0] % 2 == 0).then(|| vec![value[0] * 2]),
+                6,
+            )
+            .unwrap();
+        assert_eq!(report.scanned, 20);
+        assert_eq!(report.migrated, 10);
+        assert_eq!(report.old_root_hash, old_root_hash);
+        assert_eq!(report.new_root_hash, merk.root_hash());
+        assert_ne!(report.new_root_hash, old_root_hash);
+        for i in 0..20u8 {
+            let expected = if i % 2 == 0 { i * 2 } else { i };
+            assert_eq!(merk.get(&[i]).unwrap(), Some(vec![expected]));
+        }
+
+        let report = merk.migrate_values(|_, _| None).unwrap();
+        assert_eq!(report.migrated, 0);
+        assert_eq!(report.old_root_hash, report.new_root_hash);
+    }
+
+    #[test]
+    fn apply_iter() {
+        let mut merk = TempMerk::new().unwrap();
+        let entries = (0..100u8).map(|i| put(&[i], i));
+        let aux = [put(b"aux", 1)];
+        assert_eq!(merk.apply_iter_opt(entries, &aux, 30).unwrap(), 100);
+        for i in 0..100u8 {
+            assert_eq!(merk.get(&[i]).unwrap(), Some(vec![i]));
+        }
+        assert_eq!(merk.get_aux(b"aux").unwrap(), Some(vec![1]));
+        assert_eq!(merk.apply_iter(vec![], &[]).unwrap(), 0);
+
+        // keys out of order across batches are reported by their index
+        let entries = (100..110u8).chain(105..110).map(|i| put(&[i], i));
+        assert!(matches!(
+            merk.apply_iter_opt(entries, &[], 5),
+            Err(Error::BatchUnsorted { index: 10 })
+        ));
+        let entries = (110..120u8).chain(119..125).map(|i| put(&[i], i));
+        assert!(matches!(
+            merk.apply_iter_opt(entries, &[], 10),
+            Err(Error::DuplicateKey { .. })
+        ));
+        let entries = (130..135u8).chain(132..135).map(|i| put(&[i], i));
+        assert!(matches!(
+            merk.apply_iter_opt(entries, &[], 10),
+            Err(Error::BatchUnsorted { index: 5 })
+        ));
+        // nothing is committed if a batch fails
+        assert_eq!(merk.get(&[100]).unwrap(), None);
+        assert_eq!(merk.get(&[130]).unwrap(), None);
+    }
+
+    #[test]
+    fn apply_iter_commits_once() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&(0..50u8).map(|i| put(&[i], i)).collect::<Vec<_>>(), &[])
+            .unwrap();
+        let root_hash = merk.root_hash();
+        let entries = (0..50u8)
+            .map(|i| match i {
+                _ if i % 3 == 0 => ([i].to_vec(), Op::Delete),
+                46 => ([i].to_vec(), Op::Move { to: vec![200] }),
+                _ => put(&[i], i + 1),
+            })
+            .chain((100..150u8).map(|i| put(&[i], i)));
+
+        // a failure in the last batch leaves the store as it was
+        merk.set_prefix_quota(&[149], 1).unwrap();
+        let aux = [put(b"aux", 1)];
+        assert!(matches!(
+            merk.apply_iter_opt(entries.clone(), &aux, 7),
+            Err(Error::QuotaExceeded { .. })
+        ));
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get(&[3]).unwrap(), Some(vec![3]));
+        assert_eq!(merk.get(&[100]).unwrap(), None);
+        assert_eq!(merk.get_aux(b"aux").unwrap(), None);
+        assert_eq!(merk.prefix_quota(&[149]).unwrap().used, 0);
+
+        assert!(merk.remove_prefix_quota(&[149]));
+        assert_eq!(merk.apply_iter_opt(entries.clone(), &aux, 7).unwrap(), 100);
+        let mut expected = TempMerk::new().unwrap();
+        expected
+            .apply(&(0..50u8).map(|i| put(&[i], i)).collect::<Vec<_>>(), &[])
+            .unwrap();
+        // the tree is the same as if each batch were applied on its own
+        for batch in entries.collect::<Vec<_>>().chunks(7) {
+            expected.apply(batch, &[]).unwrap();
+        }
+        assert_eq!(merk.root_hash(), expected.root_hash());
+        assert_eq!(merk.get(&[3]).unwrap(), None);
+        assert_eq!(merk.get(&[200]).unwrap(), Some(vec![46]));
+        assert_eq!(merk.get_aux(b"aux").unwrap(), Some(vec![1]));
+
+        // keys may not be moved back into earlier batches
+        let entries = vec![put(&[1], 0), ([149].to_vec(), Op::Move { to: vec![0] })];
+        assert!(matches!(
+            merk.apply_iter_opt(entries, &[], 1),
+            Err(Error::BatchKey(_))
+        ));
+        assert_eq!(merk.get(&[149]).unwrap(), Some(vec![149]));
+    }
+}
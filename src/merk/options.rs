@@ -0,0 +1,218 @@
+//! Typed options for opening a store, see `Merk::open_with`.
+
+use std::path::{Path, PathBuf};
+
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType};
+
+use super::{Merk, SyncMode};
+use crate::Result;
+
+/// The options to open a store with, built up from the defaults of
+/// `Merk::open`.
+///
+/// The typed methods cover the RocksDB settings most often tuned for a
+/// store, and the store's own settings which would otherwise be set after
+/// opening it. Anything else can be set on a `rocksdb::Options` passed to
+/// `db_opts`, which the typed RocksDB settings are applied on top of.
+///
+/// ```no_run
+/// # fn main() -> merkdb::Result<()> {
+/// use merkdb::options::OptionsBuilder;
+/// use merkdb::rocksdb::DBCompressionType;
+/// use merkdb::{Merk, SyncMode};
+///
+/// let merk = Merk::open_with(
+///     OptionsBuilder::new("./db")
+///         .block_cache_bytes(1 << 30)
+///         .compression_per_level(vec![DBCompressionType::None, DBCompressionType::Lz4])
+///         .max_open_files(1024)
+///         .sync_mode(SyncMode::Always),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct OptionsBuilder {
+    path: PathBuf,
+    tree_name: Option<String>,
+    db_opts: Option<rocksdb::Options>,
+    block_cache_bytes: Option<usize>,
+    compression_per_level: Option<Vec<DBCompressionType>>,
+    max_open_files: Option<i32>,
+    levels: u8,
+    sync_mode: Option<SyncMode>,
+    retained_versions: Option<usize>,
+    proof_cache_capacity: Option<usize>,
+    interned_prefix_length: Option<usize>,
+}
+
+impl OptionsBuilder {
+    /// Starts building the options to open the store at `path` with.
+    pub fn new<P: AsRef<Path>>(path: P) -> OptionsBuilder {
+        OptionsBuilder {
+            path: path.as_ref().to_path_buf(),
+            tree_name: None,
+            db_opts: None,
+            block_cache_bytes: None,
+            compression_per_level: None,
+            max_open_files: None,
+            levels: 100,
+            sync_mode: None,
+            retained_versions: None,
+            proof_cache_capacity: None,
+            interned_prefix_length: None,
+        }
+    }
+
+    /// Opens the tree named `tree_name` in the store, see `Merk::open_named`.
+    pub fn tree_name(mut self, tree_name: &str) -> Self {
+        self.tree_name = Some(tree_name.to_string());
+        self
+    }
+
+    /// Replaces the RocksDB options the other RocksDB settings are applied
+    /// to, which default to `Merk::default_db_opts`, as an escape hatch for
+    /// settings this builder does not cover.
+    pub fn db_opts(mut self, db_opts: rocksdb::Options) -> Self {
+        self.db_opts = Some(db_opts);
+        self
+    }
+
+    /// Sets the size of the LRU cache of uncompressed data blocks, which is
+    /// 8 MB by default.
+    pub fn block_cache_bytes(mut self, bytes: usize) -> Self {
+        self.block_cache_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets the compression of each level of the LSM tree, from level 0 up.
+    /// Levels past the end of the list use the last entry.
+    pub fn compression_per_level(mut self, compression: Vec<DBCompressionType>) -> Self {
+        self.compression_per_level = Some(compression);
+        self
+    }
+
+    /// Sets the maximum number of files RocksDB keeps open, or -1 (the
+    /// default) to keep every file open.
+    pub fn max_open_files(mut self, files: i32) -> Self {
+        self.max_open_files = Some(files);
+        self
+    }
+
+    /// Sets the number of levels of the tree kept in memory between commits,
+    /// see `Merk::open_opt`. Defaults to 100.
+    pub fn levels_in_memory(mut self, levels: u8) -> Self {
+        self.levels = levels;
+        self
+    }
+
+    /// Sets when commits are synced to disk, see `Merk::set_sync_mode`.
+    pub fn sync_mode(mut self, mode: SyncMode) -> Self {
+        self.sync_mode = Some(mode);
+        self
+    }
+
+    /// Sets the number of earlier versions retained for rollback, see
+    /// `Merk::set_retained_versions`.
+    pub fn retained_versions(mut self, versions: usize) -> Self {
+        self.retained_versions = Some(versions);
+        self
+    }
+
+    /// Sets the capacity of the proof cache, see
+    /// `Merk::set_proof_cache_capacity`.
+    pub fn proof_cache_capacity(mut self, proofs: usize) -> Self {
+        self.proof_cache_capacity = Some(proofs);
+        self
+    }
+
+    /// Sets the length of the key prefixes shared by nodes of the in-memory
+    /// tree, see `Merk::set_interned_prefix_length`.
+    pub fn interned_prefix_length(mut self, length: usize) -> Self {
+        self.interned_prefix_length = Some(length);
+        self
+    }
+
+    /// Returns the RocksDB options with the typed settings applied.
+    fn build_db_opts(&self) -> Result<rocksdb::Options> {
+        let mut opts = self.db_opts.clone().unwrap_or_else(Merk::default_db_opts);
+        if let Some(bytes) = self.block_cache_bytes {
+            let cache = Cache::new_lru_cache(bytes)?;
+            let mut table_opts = BlockBasedOptions::default();
+            table_opts.set_block_cache(&cache);
+            opts.set_block_based_table_factory(&table_opts);
+        }
+        if let Some(compression) = &self.compression_per_level {
+            opts.set_compression_per_level(compression);
+        }
+        if let Some(files) = self.max_open_files {
+            opts.set_max_open_files(files);
+        }
+        Ok(opts)
+    }
+}
+
+impl Merk {
+    /// Opens a store with the given options, creating it if it does not
+    /// exist, see `OptionsBuilder`.
+    pub fn open_with(options: OptionsBuilder) -> Result<Merk> {
+        let db_opts = options.build_db_opts()?;
+        let mut merk = Merk::open_tree(
+            &options.path,
+            options.tree_name.as_deref(),
+            db_opts,
+            options.levels,
+        )?;
+        if let Some(mode) = options.sync_mode {
+            merk.set_sync_mode(mode);
+        }
+        if let Some(versions) = options.retained_versions {
+            merk.set_retained_versions(versions);
+        }
+        if let Some(proofs) = options.proof_cache_capacity {
+            merk.set_proof_cache_capacity(proofs);
+        }
+        if let Some(length) = options.interned_prefix_length {
+            merk.set_interned_prefix_length(length);
+        }
+        Ok(merk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocksdb::DBCompressionType;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn open_with() {
+        let dir = TempDir::new("merk_open_with").unwrap();
+        let options = || {
+            OptionsBuilder::new(dir.path().join("db"))
+                .tree_name("a")
+                .block_cache_bytes(1 << 20)
+                .compression_per_level(vec![DBCompressionType::None, DBCompressionType::Snappy])
+                .max_open_files(64)
+                .levels_in_memory(1)
+                .sync_mode(SyncMode::Always)
+                .retained_versions(2)
+                .proof_cache_capacity(4)
+                .interned_prefix_length(2)
+        };
+        let mut merk = Merk::open_with(options()).unwrap();
+        assert_eq!(merk.tree_name(), Some("a"));
+        assert_eq!(merk.get_max_levels_in_memory(), 1);
+        assert_eq!(merk.get_sync_mode(), SyncMode::Always);
+        assert_eq!(merk.proof_cache_stats().capacity, 4);
+        assert_eq!(merk.get_interned_prefix_length(), 2);
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let root_hash = merk.root_hash();
+        drop(merk);
+
+        let merk = Merk::open_with(options().db_opts(Merk::deterministic_db_opts())).unwrap();
+        assert_eq!(merk.root_hash(), root_hash);
+        assert!(merk.get(&seq_key(50)).unwrap().is_some());
+    }
+}
@@ -0,0 +1,105 @@
+//! A view of a store which sorts keys in a custom order, see `order`.
+
+use super::Merk;
+use crate::order::KeyEncoding;
+use crate::proofs::Query;
+use crate::{Batch, Hash, Result};
+
+/// A view of a `Merk` whose keys are encoded with a `KeyEncoding`, so they
+/// sort in the order it defines.
+///
+/// Every access to the store must go through a view with the same encoding,
+/// since the keys are stored in their encoded form.
+pub struct Ordered<'a, E> {
+    merk: &'a mut Merk,
+    encoding: E,
+}
+
+impl Merk {
+    /// Creates a view of the store which encodes keys with `encoding`.
+    pub fn ordered<E: KeyEncoding>(&mut self, encoding: E) -> Ordered<'_, E> {
+        Ordered {
+            merk: self,
+            encoding,
+        }
+    }
+}
+
+impl<'a, E: KeyEncoding> Ordered<'a, E> {
+    /// Gets a value for the given key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.merk.get(&self.encoding.encode(key)?)
+    }
+
+    /// Returns the root hash of the store.
+    pub fn root_hash(&self) -> Hash {
+        self.merk.root_hash()
+    }
+
+    /// Applies a batch of operations to the store, like `Merk::apply`. The
+    /// keys do not need to be sorted, since their order changes once they are
+    /// encoded, but they must be unique. Keys in `aux` are not encoded.
+    pub fn apply(&mut self, batch: &Batch, aux: &Batch) -> Result<()> {
+        let mut encoded = batch
+            .iter()
+            .map(|(key, op)| Ok((self.encoding.encode(key)?, op.clone())))
+            .collect::<Result<Vec<_>>>()?;
+        encoded.sort_by(|a, b| a.0.cmp(&b.0));
+
+        self.merk.apply(&encoded, aux)
+    }
+
+    /// Creates a Merkle proof for the keys and ranges in the query, which are
+    /// selected in the custom order. The proof can be read with
+    /// `order::verify` and the same encoding.
+    pub fn prove(&self, query: Query) -> Result<Vec<u8>> {
+        self.merk.prove(self.encoding.encode_query(&query)?)
+    }
+
+    /// Returns the encoding used by the view.
+    pub fn encoding(&self) -> &E {
+        &self.encoding
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::order::{self, FieldOrder};
+    use crate::proofs::query::Term;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    /// A key made of a 1-byte account followed by a 2-byte timestamp.
+    fn key(account: u8, time: u16) -> Vec<u8> {
+        let mut key = vec![account];
+        key.extend_from_slice(&time.to_be_bytes());
+        key
+    }
+
+    #[test]
+    fn sorts_by_field() {
+        let mut merk = TempMerk::new().unwrap();
+        let order = FieldOrder::new(vec![1..3]).unwrap();
+        let mut ordered = merk.ordered(order.clone());
+
+        let batch: Vec<_> = (0..20u16)
+            .map(|i| (key((i % 4) as u8, 100 - i), Op::Put(vec![i as u8])))
+            .collect();
+        ordered.apply(&batch, &[]).unwrap();
+        assert_eq!(ordered.get(&key(1, 95)).unwrap(), Some(vec![5]));
+
+        // the range spans every account, ordered by time
+        let term = Term::range(key(0, 90)..key(0, 95)).limit(3);
+        let proof = ordered.prove(term.clone().into()).unwrap();
+        let map = order::verify(&proof, ordered.root_hash(), order).unwrap();
+        let entries = map.term(&term).unwrap();
+        let keys: Vec<_> = entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![key(2, 90), key(1, 91), key(0, 92)]);
+        assert_eq!(map.get(&key(3, 91)).unwrap(), None);
+        assert!(map.range(key(0, 80)..key(0, 90)).is_err());
+
+        ordered.apply(&[(key(1, 95), Op::Delete)], &[]).unwrap();
+        assert_eq!(ordered.get(&key(1, 95)).unwrap(), None);
+        assert!(ordered.apply(&[(vec![1], Op::Delete)], &[]).is_err());
+    }
+}
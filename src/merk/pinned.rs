@@ -0,0 +1,227 @@
+//! Reads which borrow values from the database instead of copying them, see
+//! `Merk::get_pinned` and `Merk::cursor`.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::{Deref, Range};
+
+use rocksdb::{DBPinnableSlice, DBRawIterator};
+
+use super::slow_log::{SlowOp, SlowOpKind};
+use super::tiered::NodeBytes;
+use super::{separated_value, Merk, VALUES_CF_NAME};
+use crate::tree::{FetchBytes, GetResult, TreeRef};
+use crate::{Error, Result};
+
+/// A value read with `Merk::get_pinned`. Values read from the database stay
+/// pinned in RocksDB's block cache until this is dropped, and are not copied.
+pub struct PinnedValue<'a> {
+    inner: Inner<'a>,
+}
+
+enum Inner<'a> {
+    /// The part of a record (or separately stored value) which is the value.
+    Pinned(DBPinnableSlice<'a>, Range<usize>),
+    /// A value copied from a node held in memory.
+    Owned(Vec<u8>),
+}
+
+impl PinnedValue<'_> {
+    /// Copies the value into a `Vec`, releasing the pinned slice.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.inner {
+            Inner::Pinned(bytes, range) => bytes[range].to_vec(),
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl Deref for PinnedValue<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.inner {
+            Inner::Pinned(bytes, range) => &bytes[range.clone()],
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for PinnedValue<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PinnedValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PinnedValue").field(&self.deref()).finish()
+    }
+}
+
+/// A key and value read in place by a `Cursor`.
+pub type CursorEntry<'a> = (&'a [u8], Cow<'a, [u8]>);
+
+/// A cursor over the entries of a range, in key order, see `Merk::cursor`.
+/// Unlike an iterator, each entry borrows from the cursor, so keys and values
+/// are read in place from the database's buffers.
+pub struct Cursor<'a> {
+    merk: &'a Merk,
+    iter: DBRawIterator<'a>,
+    end: Vec<u8>,
+    offloaded: bool,
+}
+
+impl Cursor<'_> {
+    /// Returns the key and value of the entry at the cursor, or `None` once it
+    /// has passed the end of its range. Only values stored apart from their
+    /// nodes (see `Merk::set_separate_value_length`) are copied. Fails with
+    /// `Error::Offloaded` if any node is offloaded (see the `tiered` module).
+    pub fn entry(&self) -> Result<Option<CursorEntry<'_>>> {
+        if self.offloaded {
+            return Err(Error::Offloaded("scan"));
+        }
+        let (key, bytes) = match (self.iter.key(), self.iter.value()) {
+            (Some(key), Some(bytes)) if key < self.end.as_slice() => (key, bytes),
+            _ => return Ok(None),
+        };
+        let value = match TreeRef::decode(key, bytes)?.value() {
+            Some(value) => Cow::Borrowed(value),
+            None => Cow::Owned(separated_value(&self.merk.db, key)?),
+        };
+        Ok(Some((key, value)))
+    }
+
+    /// Moves the cursor to the next entry.
+    pub fn advance(&mut self) {
+        self.iter.next();
+    }
+}
+
+impl Merk {
+    /// Gets the value for `key` like `get`, but without copying values read
+    /// from the database: the value is borrowed from the slice RocksDB pins in
+    /// its block cache. Values of nodes held in memory are copied, as with
+    /// `get`.
+    ///
+    /// This is for reads of large values, where the copy would dominate the
+    /// cost of the read.
+    pub fn get_pinned(&self, key: &[u8]) -> Result<Option<PinnedValue<'_>>> {
+        #[cfg(feature = "profiling")]
+        self.record_read(key);
+        let start = self.slow_op_start();
+        let value = self.use_tree(|maybe_tree| match maybe_tree {
+            None => Ok(None),
+            Some(tree) => match tree.get_value(key)? {
+                GetResult::Found(value) => Ok(Some(PinnedValue {
+                    inner: Inner::Owned(value),
+                })),
+                GetResult::NotFound => Ok(None),
+                GetResult::Pruned => self.fetch_pinned(key),
+            },
+        })?;
+        self.log_if_slow(start, |duration| SlowOp {
+            kind: SlowOpKind::Get,
+            duration,
+            keys: 1,
+            bytes: value.as_ref().map_or(0, |value| value.len()),
+            explain: None,
+        });
+        Ok(value)
+    }
+
+    /// Reads the value of a pruned node from the database without copying it.
+    fn fetch_pinned(&self, key: &[u8]) -> Result<Option<PinnedValue<'_>>> {
+        let source = self.source();
+        let bytes = match source.fetch_bytes(key)? {
+            Some(NodeBytes::Local(bytes)) => bytes,
+            // nodes read from the cold store are not pinned
+            Some(NodeBytes::Offloaded(record)) => {
+                let value = match TreeRef::decode(key, &record)?.value() {
+                    Some(value) => value.to_vec(),
+                    None => source.fetch_value(key)?,
+                };
+                return Ok(Some(PinnedValue {
+                    inner: Inner::Owned(value),
+                }));
+            }
+            None => return Ok(None),
+        };
+        let range = match TreeRef::decode(key, &bytes)?.value() {
+            Some(value) => {
+                let start = value.as_ptr() as usize - bytes.as_ptr() as usize;
+                start..start + value.len()
+            }
+            None => {
+                let values_cf = self.db.cf_handle(VALUES_CF_NAME).unwrap();
+                let inner = match self.db.get_pinned_cf(values_cf, key)? {
+                    Some(value) => {
+                        let len = value.len();
+                        Inner::Pinned(value, 0..len)
+                    }
+                    None => Inner::Owned(source.fetch_value(key)?),
+                };
+                return Ok(Some(PinnedValue { inner }));
+            }
+        };
+        Ok(Some(PinnedValue {
+            inner: Inner::Pinned(bytes, range),
+        }))
+    }
+
+    /// Returns a cursor over the entries with keys in `range`, starting at the
+    /// first of them. Keys and values are read in place rather than copied
+    /// into each entry as with `iter_filtered`.
+    pub fn cursor(&self, range: Range<Vec<u8>>) -> Cursor<'_> {
+        let mut iter = self.db.raw_iterator();
+        iter.seek(&range.start);
+        Cursor {
+            merk: self,
+            iter,
+            end: range.end,
+            offloaded: self.has_offloaded(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn pinned_reads() {
+        let mut merk = TempMerk::with_opts(Merk::default_db_opts(), 2).unwrap();
+        merk.set_separate_value_length(70);
+        let mut batch = make_batch_seq(0..100);
+        batch[50].1 = crate::Op::Put(vec![7; 100]);
+        merk.apply(&batch, &[]).unwrap();
+
+        let mut pinned = 0;
+        for (key, _) in batch.iter() {
+            let value = merk.get_pinned(key).unwrap().unwrap();
+            assert_eq!(&*value, merk.get(key).unwrap().unwrap().as_slice());
+            if let Inner::Pinned(..) = value.inner {
+                pinned += 1;
+            }
+        }
+        // nodes below the levels kept in memory are read from the database
+        assert!(pinned > 0);
+        assert!(merk.get_pinned(&seq_key(100)).unwrap().is_none());
+        let value = merk.get_pinned(&seq_key(50)).unwrap().unwrap();
+        assert_eq!(value.into_vec(), vec![7; 100]);
+
+        let mut cursor = merk.cursor(seq_key(10)..seq_key(60));
+        let mut entries = vec![];
+        while let Some((key, value)) = cursor.entry().unwrap() {
+            entries.push((key.to_vec(), value.into_owned()));
+            cursor.advance();
+        }
+        let expected: Vec<_> = merk
+            .iter_filtered(seq_key(10)..seq_key(60), |_, _| true)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(entries.len(), 50);
+        assert_eq!(entries, expected);
+    }
+}
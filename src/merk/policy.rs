@@ -0,0 +1,106 @@
+//! Authorization of writes, so embedding frameworks can enforce which module
+//! owns which keys inside the store itself, see `Merk::set_write_policy`.
+
+use super::Merk;
+use crate::error::{Error, Result};
+use crate::tree::{Entry, OpRef};
+
+/// Decides which writes a store accepts. The policy is consulted for each
+/// operation of a batch of tree operations before it is applied, and a batch
+/// with any denied operation fails with `Error::WriteDenied` without
+/// changing the store.
+///
+/// Moves are checked as the delete of their source key and the put of their
+/// destination key. Aux entries are not checked.
+pub trait WritePolicy: Send + Sync {
+    /// Returns `true` if `op` may be applied to `key`.
+    fn allow(&self, key: &[u8], op: OpRef) -> bool;
+}
+
+impl<F> WritePolicy for F
+where
+    F: Fn(&[u8], OpRef) -> bool + Send + Sync,
+{
+    fn allow(&self, key: &[u8], op: OpRef) -> bool {
+        self(key, op)
+    }
+}
+
+impl Merk {
+    /// Sets the policy which batches applied to the store must satisfy,
+    /// replacing any previous one.
+    pub fn set_write_policy<P: WritePolicy + 'static>(&mut self, policy: P) {
+        self.write_policy = Some(Box::new(policy));
+    }
+
+    /// Removes the write policy, so every write is allowed again.
+    pub fn clear_write_policy(&mut self) {
+        self.write_policy = None;
+    }
+
+    /// Returns `Error::WriteDenied` for the first operation in `batch` the
+    /// write policy does not allow.
+    pub(crate) fn check_write_policy<E: Entry>(&self, batch: &[E]) -> Result<()> {
+        let policy = match &self.write_policy {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        match batch
+            .iter()
+            .find(|entry| !policy.allow(entry.key(), entry.op()))
+        {
+            Some(entry) => Err(Error::WriteDenied(entry.key().to_vec())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn write_policy() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&[(b"bank/a".to_vec(), Op::Put(vec![1]))], &[])
+            .unwrap();
+
+        // only the bank module may write under its prefix, and it may not
+        // delete
+        merk.set_write_policy(|key: &[u8], op: OpRef| {
+            !key.starts_with(b"bank/") || matches!(op, OpRef::Put(_))
+        });
+        let root_hash = merk.root_hash();
+        let batch = [
+            (b"auth/a".to_vec(), Op::Put(vec![1])),
+            (b"bank/a".to_vec(), Op::Delete),
+        ];
+        match merk.apply(&batch, &[]) {
+            Err(Error::WriteDenied(key)) => assert_eq!(key, b"bank/a"),
+            other => panic!("expected a denied write, got {:?}", other),
+        }
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get(b"auth/a").unwrap(), None);
+
+        // moves are checked as a delete and a put
+        let batch = [(
+            b"bank/a".to_vec(),
+            Op::Move {
+                to: b"bank/b".to_vec(),
+            },
+        )];
+        assert!(matches!(
+            merk.apply(&batch, &[]),
+            Err(Error::WriteDenied(_))
+        ));
+
+        merk.apply(&[(b"bank/a".to_vec(), Op::Put(vec![2]))], &[])
+            .unwrap();
+        merk.clear_write_policy();
+        merk.apply(&[(b"bank/a".to_vec(), Op::Delete)], &[])
+            .unwrap();
+        assert_eq!(merk.get(b"bank/a").unwrap(), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Caching of encoded proofs, see `Merk::set_proof_cache_capacity`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::PoisonError;
+
+use super::service::query_key;
+use super::Merk;
+use crate::proofs::Query;
+
+/// The hit and miss counts of a store's proof cache, see
+/// `Merk::proof_cache_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProofCacheStats {
+    /// The number of proofs returned from the cache.
+    pub hits: u64,
+    /// The number of proofs created while the cache was enabled.
+    pub misses: u64,
+    /// The number of proofs currently held.
+    pub entries: usize,
+    /// The maximum number of proofs held.
+    pub capacity: usize,
+}
+
+impl ProofCacheStats {
+    /// Returns the share of lookups answered from the cache, or 0 if there
+    /// were none.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+/// A least recently used cache of encoded proofs, keyed by the root hash and
+/// the query they prove.
+#[derive(Default)]
+pub(crate) struct ProofCache {
+    capacity: usize,
+    /// The last use and proof of each key.
+    proofs: HashMap<Vec<u8>, (u64, Vec<u8>)>,
+    /// The keys by their last use, oldest first.
+    uses: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl ProofCache {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let (last_use, proof) = match self.proofs.get_mut(key) {
+            Some(entry) => entry,
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        let key = self.uses.remove(last_use).unwrap();
+        *last_use = self.clock;
+        self.uses.insert(self.clock, key);
+        self.hits += 1;
+        Some(proof.clone())
+    }
+
+    fn insert(&mut self, key: Vec<u8>, proof: Vec<u8>) {
+        self.clock += 1;
+        self.uses.insert(self.clock, key.clone());
+        if let Some((last_use, _)) = self.proofs.insert(key, (self.clock, proof)) {
+            self.uses.remove(&last_use);
+        }
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.proofs.len() > self.capacity {
+            let (_, key) = self.uses.pop_first().unwrap();
+            self.proofs.remove(&key);
+        }
+    }
+
+    fn stats(&self) -> ProofCacheStats {
+        ProofCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.proofs.len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl Merk {
+    /// Sets the number of proofs kept by the proof cache, evicting the least
+    /// recently used ones beyond it. With a capacity above zero, `prove`
+    /// returns the same encoded proof for repeated identical queries at the
+    /// same root hash without walking the tree again, which helps when a few
+    /// popular keys are proven over and over. Proofs at earlier roots are
+    /// never returned after a commit, and age out of the cache as new ones
+    /// are added. Defaults to zero, which disables the cache.
+    pub fn set_proof_cache_capacity(&mut self, proofs: usize) {
+        let cache = self
+            .proof_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache.capacity = proofs;
+        cache.evict();
+    }
+
+    /// Returns the hit and miss counts of the proof cache since the store was
+    /// opened.
+    pub fn proof_cache_stats(&self) -> ProofCacheStats {
+        let cache = self
+            .proof_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache.stats()
+    }
+
+    /// Returns the proof of `query` from the proof cache, or creates it with
+    /// `prove` and caches it. The query's limits must already be resolved.
+    pub(crate) fn prove_cached(
+        &self,
+        query: Query,
+        prove: impl FnOnce(Query) -> crate::Result<Vec<u8>>,
+    ) -> crate::Result<Vec<u8>> {
+        let mut cache = self
+            .proof_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if cache.capacity == 0 {
+            drop(cache);
+            return prove(query);
+        }
+
+        let key = [&self.root_hash()[..], &query_key(&query)].concat();
+        if let Some(proof) = cache.get(&key) {
+            return Ok(proof);
+        }
+        // don't hold the lock while proving, so other reads can use the cache
+        drop(cache);
+        let proof = prove(query)?;
+        let mut cache = self
+            .proof_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache.insert(key, proof.clone());
+        Ok(proof)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProofCacheStats;
+    use crate::proofs::Query;
+    use crate::test_utils::{make_batch_seq, seq_key, TempMerk};
+    use crate::verify;
+
+    fn query(keys: &[u64]) -> Query {
+        let mut query = Query::new();
+        for &n in keys {
+            query.insert_key(seq_key(n));
+        }
+        query
+    }
+
+    #[test]
+    fn proof_cache() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let uncached = merk.prove(query(&[1, 2])).unwrap();
+        assert_eq!(merk.proof_cache_stats(), ProofCacheStats::default());
+
+        merk.set_proof_cache_capacity(2);
+        assert_eq!(merk.prove(query(&[1, 2])).unwrap(), uncached);
+        assert_eq!(merk.prove(query(&[1, 2])).unwrap(), uncached);
+        merk.prove(query(&[3])).unwrap();
+        let stats = merk.proof_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+        assert!((stats.hit_rate() - 1.0 / 3.0).abs() < 1e-9);
+
+        // [1, 2] was used more recently than [3], so [3] is evicted
+        merk.prove(query(&[1, 2])).unwrap();
+        merk.prove(query(&[4])).unwrap();
+        merk.prove(query(&[1, 2])).unwrap();
+        merk.prove(query(&[3])).unwrap();
+        let stats = merk.proof_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (3, 4, 2));
+
+        // a commit changes the root, so the proof is created again
+        merk.apply(&make_batch_seq(100..101), &[]).unwrap();
+        let proof = merk.prove(query(&[1, 2])).unwrap();
+        assert_eq!(merk.proof_cache_stats().misses, 5);
+        let map = verify(&proof, merk.root_hash()).unwrap();
+        assert!(map.get(&seq_key(1)).unwrap().is_some());
+
+        merk.set_proof_cache_capacity(0);
+        assert_eq!(merk.proof_cache_stats().entries, 0);
+    }
+}
@@ -0,0 +1,200 @@
+//! Byte quotas on key prefixes, see `Merk::set_prefix_quota`.
+
+use std::collections::BTreeMap;
+
+use super::migrate::prefix_end;
+use super::Merk;
+use crate::tree::{Entry, OpRef};
+use crate::{Error, Result};
+
+/// A limit on the bytes of the entries under a key prefix, along with the
+/// bytes they currently use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrefixQuota {
+    /// The most bytes the entries under the prefix may use.
+    pub limit: u64,
+    /// The bytes the entries under the prefix use, counting the length of
+    /// each key and value.
+    pub used: u64,
+}
+
+/// The quotas of a store, by prefix.
+pub(crate) type Quotas = BTreeMap<Vec<u8>, PrefixQuota>;
+
+fn entry_size(key: &[u8], value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+impl Merk {
+    /// Limits the entries with keys starting with `prefix` to `limit` bytes,
+    /// counting the length of each key and value, so one tenant or module of
+    /// a shared store cannot grow its state without bound. Setting a quota
+    /// reads every entry under the prefix to find its current usage.
+    ///
+    /// While a quota is set, applying a batch which would grow the usage of
+    /// its prefix past the limit fails with `Error::QuotaExceeded` before
+    /// anything is written. Batches which shrink the usage always succeed, so
+    /// a prefix already over a lowered limit can be cleaned up. Prefixes may
+    /// overlap, in which case an entry counts towards each of them.
+    ///
+    /// Quotas are not persisted, so they must be set again after reopening
+    /// the store. Checking them costs a read of the previous value of every
+    /// key in a batch which is under a quota.
+    pub fn set_prefix_quota(&mut self, prefix: &[u8], limit: u64) -> Result<()> {
+        let used = self.prefix_usage(prefix)?;
+        self.quotas
+            .insert(prefix.to_vec(), PrefixQuota { limit, used });
+        Ok(())
+    }
+
+    /// Removes the quota on `prefix`, returning true if there was one.
+    pub fn remove_prefix_quota(&mut self, prefix: &[u8]) -> bool {
+        self.quotas.remove(prefix).is_some()
+    }
+
+    /// Returns the quota on `prefix` and its current usage, if a quota is set.
+    pub fn prefix_quota(&self, prefix: &[u8]) -> Option<PrefixQuota> {
+        self.quotas.get(prefix).copied()
+    }
+
+    /// Returns the bytes used by the entries with keys starting with `prefix`.
+    fn prefix_usage(&self, prefix: &[u8]) -> Result<u64> {
+        let mut used = 0;
+        for entry in self.scan_all(prefix, prefix_end(prefix)) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            used += entry_size(&key, &value);
+        }
+        Ok(used)
+    }
+
+    /// Returns the usage of each quota after applying `batch`, failing with
+    /// `Error::QuotaExceeded` if it would grow any past its limit.
+    pub(crate) fn check_quotas<E: Entry>(&self, batch: &[E]) -> Result<Vec<(Vec<u8>, u64)>> {
+        if self.quotas.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut usage: Vec<_> = self
+            .quotas
+            .iter()
+            .map(|(prefix, quota)| (prefix.clone(), quota.used))
+            .collect();
+        for entry in batch.iter() {
+            let key = entry.key();
+            if !usage.iter().any(|(prefix, _)| key.starts_with(prefix)) {
+                continue;
+            }
+            let old_size = self.get(key)?.map_or(0, |value| entry_size(key, &value));
+            let new_size = match entry.op() {
+                OpRef::Put(value) => entry_size(key, value),
+                OpRef::SetFlags(_) => old_size,
+                // moves are resolved into puts and deletes before this
+                OpRef::Delete | OpRef::Move { .. } => 0,
+            };
+            for (prefix, used) in usage.iter_mut() {
+                if key.starts_with(prefix) {
+                    *used = (*used + new_size).saturating_sub(old_size);
+                }
+            }
+        }
+
+        for (prefix, used) in usage.iter() {
+            let quota = &self.quotas[prefix];
+            if *used > quota.limit && *used > quota.used {
+                return Err(Error::QuotaExceeded {
+                    prefix: prefix.clone(),
+                    used: *used,
+                    limit: quota.limit,
+                });
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Records the usage of the quotas after a batch was committed, as
+    /// returned by `check_quotas`.
+    pub(crate) fn set_quota_usage(&mut self, usage: Vec<(Vec<u8>, u64)>) {
+        for (prefix, used) in usage {
+            if let Some(quota) = self.quotas.get_mut(&prefix) {
+                quota.used = used;
+            }
+        }
+    }
+
+    /// Recomputes the usage of every quota, after the store was changed other
+    /// than by applying a batch (e.g. rolled back).
+    pub(crate) fn refresh_quota_usage(&mut self) -> Result<()> {
+        let prefixes: Vec<_> = self.quotas.keys().cloned().collect();
+        for prefix in prefixes {
+            let used = self.prefix_usage(&prefix)?;
+            self.quotas.get_mut(&prefix).unwrap().used = used;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn prefix_quotas() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retained_versions(1);
+        let put = |key: &[u8], len| (key.to_vec(), Op::Put(vec![0; len]));
+        merk.apply(&[put(b"a1", 8), put(b"b1", 8)], &[]).unwrap();
+
+        merk.set_prefix_quota(b"a", 30).unwrap();
+        merk.set_prefix_quota(b"", 100).unwrap();
+        assert_eq!(
+            merk.prefix_quota(b"a"),
+            Some(PrefixQuota {
+                limit: 30,
+                used: 10
+            })
+        );
+        assert_eq!(merk.prefix_quota(b"").unwrap().used, 20);
+        assert_eq!(merk.prefix_quota(b"c"), None);
+
+        // overwriting a value only counts the difference
+        merk.apply(&[put(b"a1", 12), put(b"a2", 4)], &[]).unwrap();
+        assert_eq!(merk.prefix_quota(b"a").unwrap().used, 20);
+        assert_eq!(merk.prefix_quota(b"").unwrap().used, 30);
+
+        // a failed batch writes nothing
+        let root_hash = merk.root_hash();
+        let err = merk
+            .apply(&[put(b"a3", 9), put(b"b2", 1)], &[])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::QuotaExceeded { ref prefix, used: 31, limit: 30 } if prefix == b"a"
+        ));
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.prefix_quota(b"a").unwrap().used, 20);
+        assert!(matches!(
+            merk.apply(&[put(b"b2", 100)], &[]),
+            Err(Error::QuotaExceeded { ref prefix, .. }) if prefix.is_empty()
+        ));
+
+        // shrinking is allowed even over a lowered limit
+        merk.set_prefix_quota(b"a", 5).unwrap();
+        merk.apply(&[(b"a1".to_vec(), Op::Delete)], &[]).unwrap();
+        assert_eq!(merk.prefix_quota(b"a").unwrap().used, 6);
+        assert!(merk.apply(&[put(b"a2", 5)], &[]).is_err());
+
+        // rolling back recomputes usage
+        merk.rollback().unwrap();
+        assert_eq!(merk.prefix_quota(b"a").unwrap().used, 20);
+
+        assert!(merk.remove_prefix_quota(b"a"));
+        assert!(!merk.remove_prefix_quota(b"a"));
+        merk.apply(&[put(b"a3", 50)], &[]).unwrap();
+        assert_eq!(merk.prefix_quota(b"").unwrap().used, 82);
+    }
+}
@@ -0,0 +1,206 @@
+//! Reading a store from other threads while it is being written to, see
+//! `Merk::reader`.
+//!
+//! Readers never use the writer's in-memory tree. Each read takes a RocksDB
+//! snapshot of the store and reads the root it points to, so a read sees the
+//! store as of a single commit. Commits are made visible, and snapshots taken,
+//! under a `CommitLock`, so commits applied in several write batches (see the
+//! `journal` module) are never seen part way through.
+//!
+//! Journaled commits and snapshots are model checked with loom against a
+//! model of the database (see the `journal` module):
+//! `RUSTFLAGS="--cfg loom" cargo test --lib loom`.
+
+#[cfg(loom)]
+use loom::sync::RwLock;
+#[cfg(not(loom))]
+use std::sync::RwLock;
+use std::sync::{Arc, PoisonError};
+
+use super::rollback::VersionPins;
+use super::snapshot::{Snapshot, SnapshotSource};
+use super::{Merk, TreeDb, INTERNAL_CF_NAME, ROOT_KEY_KEY};
+use crate::proofs::Query;
+use crate::tree::{Fetch, Tree};
+use crate::{Hash, Result};
+
+/// Orders commits against readers taking snapshots, so every snapshot is of
+/// the store as of a commit.
+///
+/// Commits made inside `commit` and snapshots taken inside `snapshot` are
+/// totally ordered, and each one happens before the ones after it: a
+/// snapshot sees either all of a commit's writes or none of them, along with
+/// everything the writer did before making the commit.
+#[derive(Clone, Default)]
+pub(crate) struct CommitLock(Arc<RwLock<()>>);
+
+impl CommitLock {
+    /// Runs `write`, which makes a commit visible, possibly in several steps,
+    /// while no snapshot is being taken.
+    pub(crate) fn commit<T>(&self, write: impl FnOnce() -> T) -> T {
+        let _guard = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        write()
+    }
+
+    /// Runs `snapshot`, which takes a snapshot of the store, while no commit
+    /// is being made visible.
+    pub(crate) fn snapshot<T>(&self, snapshot: impl FnOnce() -> T) -> T {
+        let _guard = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        snapshot()
+    }
+}
+
+/// A handle for reading a store from other threads while it is written to.
+///
+/// A `MerkReader` is cheap to clone, `Send + Sync`, and does not borrow the
+/// `Merk` it was created from, so reads run concurrently with `apply` and
+/// the other methods which take `&mut Merk`. Every read is of the store as of
+/// the last commit made visible before the read started: a read which starts
+/// after `apply` returns sees its batch, a read which runs concurrently with
+/// `apply` sees either the state before the batch or after it, never part of
+/// it, and a read never sees a commit older than one an earlier read on the
+/// same thread saw.
+///
+/// Reads take a snapshot of the database and read the nodes they need from
+/// it, rather than from the writer's in-memory tree, so they are slower than
+/// reads through the `Merk` itself. Like snapshots, they do not see entries
+/// offloaded to a cold store (see the `tiered` module).
+///
+/// A reader keeps the database open, so `Merk::destroy` fails while readers
+/// of the store exist.
+///
+/// # Ordering
+///
+/// Commits and the snapshots reads are made from are totally ordered, and
+/// synchronize with each other like a lock's release and acquire: if a read
+/// sees a commit, everything the writing thread did before the `apply` (or
+/// other write) which made the commit happens before the read, and a read
+/// which sees a commit sees every commit before it. A read which the caller
+/// orders after a write returns (e.g. through a channel, or by joining the
+/// writing thread) sees its commit.
+#[derive(Clone)]
+pub struct MerkReader {
+    pub(super) db: Arc<TreeDb>,
+    pub(super) commit_lock: CommitLock,
+    pub(super) version_pins: VersionPins,
+}
+
+impl Merk {
+    /// Returns a handle for reading the store from other threads while it is
+    /// being written to, see `MerkReader`.
+    pub fn reader(&self) -> MerkReader {
+        MerkReader {
+            db: self.db.clone(),
+            commit_lock: self.commit_lock.clone(),
+            version_pins: self.version_pins.clone(),
+        }
+    }
+}
+
+impl MerkReader {
+    /// Returns a snapshot of the store as of the last commit.
+    pub fn snapshot(&self) -> Result<Snapshot<'_>> {
+        let db = self.commit_lock.snapshot(|| self.db.snapshot());
+        let tree = load_snapshot_root(&db, &self.db)?;
+        Ok(Snapshot::new(db, &self.db, tree))
+    }
+
+    /// Gets the value for a key as of the last commit, see `Merk::get`.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.snapshot()?.get(key)
+    }
+
+    /// Returns the root hash of the store as of the last commit.
+    pub fn root_hash(&self) -> Result<Hash> {
+        Ok(self.snapshot()?.root_hash())
+    }
+
+    /// Creates a proof for `query` against the root hash of the last commit,
+    /// see `Merk::prove`. Returns the root hash along with the proof, since
+    /// the store may have been committed to since the caller last read it.
+    pub fn prove(&self, query: Query) -> Result<(Hash, Vec<u8>)> {
+        let snapshot = self.snapshot()?;
+        Ok((snapshot.root_hash(), snapshot.prove(query)?))
+    }
+}
+
+/// Loads the root node of the tree pointed to in a snapshot of `parent`.
+pub(super) fn load_snapshot_root(db: &rocksdb::Snapshot, parent: &TreeDb) -> Result<Option<Tree>> {
+    let internal_cf = parent.cf_handle(INTERNAL_CF_NAME).unwrap();
+    db.get_cf(internal_cf, ROOT_KEY_KEY)?
+        .map(|key| SnapshotSource(db, parent).fetch_by_key_expect(&key))
+        .transpose()
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use std::thread;
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::test_utils::*;
+    use crate::tree::NULL_HASH;
+    use crate::{verify, Error};
+
+    #[test]
+    fn reads_during_apply() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MerkReader>();
+
+        let batches: Vec<_> = (0..10)
+            .map(|i| make_batch_seq(i * 100..(i + 1) * 100))
+            .collect();
+        let mut expected = TempMerk::new().unwrap();
+        let mut committed = vec![];
+        for batch in batches.iter() {
+            expected.apply(batch, &[]).unwrap();
+            committed.push(expected.root_hash());
+        }
+        let last = *committed.last().unwrap();
+
+        let mut merk = TempMerk::new().unwrap();
+        // small write batches make commits go through the journal
+        merk.set_max_write_batch_bytes(4_096);
+        let reader = merk.reader();
+        assert_eq!(reader.root_hash().unwrap(), NULL_HASH);
+        assert_eq!(reader.get(&seq_key(0)).unwrap(), None);
+        // empty stores cannot be proven
+        merk.apply(&batches[0], &[]).unwrap();
+
+        let reader_thread = thread::spawn(move || {
+            let mut reads = 0;
+            let mut seen = 0;
+            while seen < committed.len() - 1 || reads < 100 {
+                let mut query = Query::new();
+                query.insert_key(seq_key(reads % 1_000));
+                let (root_hash, proof) = reader.prove(query).unwrap();
+                // every read is of the store as of a commit, and no older
+                // than the commits read before it
+                let index = committed.iter().position(|hash| hash == &root_hash);
+                assert!(index.map_or(false, |index| index >= seen));
+                seen = index.unwrap();
+                verify(&proof, root_hash).unwrap();
+                reads += 1;
+            }
+            reader
+        });
+        for batch in batches[1..].iter() {
+            merk.apply(batch, &[]).unwrap();
+        }
+        let reader = reader_thread.join().unwrap();
+
+        // reads after a commit see it
+        assert_eq!(reader.root_hash().unwrap(), last);
+        assert_eq!(reader.get(&seq_key(950)).unwrap(), Some(put_entry_value()));
+    }
+
+    #[test]
+    fn readers_keep_store_open() {
+        let dir = TempDir::new("readers_keep_store_open").unwrap();
+        let merk = Merk::open(dir.path()).unwrap();
+        let reader = merk.reader();
+        assert!(matches!(merk.destroy(), Err(Error::ReadersOpen(1))));
+        assert_eq!(reader.root_hash().unwrap(), NULL_HASH);
+    }
+}
@@ -0,0 +1,537 @@
+//! Leader-follower replication by shipping committed batches.
+//!
+//! A primary store produces a log of `ReplicatedBatch`es with
+//! `Merk::replication_log`, which can be sent to replicas over a channel or
+//! written to any byte stream (such as a socket) with
+//! `ReplicatedBatch::write_to`. A `Replica` applies each batch in order and
+//! checks that its root hashes match the primary's both before and after the
+//! batch, so a missed, reordered or corrupted batch is detected immediately.
+//!
+//! Only the tree is replicated; auxiliary data and the expiry and secondary
+//! indexes are local to each store.
+//!
+//! When batches pass through parties which are not trusted, the primary can
+//! sign each one with `SignedBatch::sign` and replicas check the signature
+//! with `Replica::apply_signed` before applying it. The signature covers the
+//! root hashes and `batch_digest` of the batch, under a domain of its own so
+//! it cannot be passed off as a root attestation or vice versa.
+
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+
+use super::attest::{Signer, Verifier};
+use super::Merk;
+use crate::{
+    tree::{Batch, BatchEntry},
+    Error, Hash, Op, Result, HASH_LENGTH,
+};
+
+const PUT_TAG: u8 = 0;
+const DELETE_TAG: u8 = 1;
+const MOVE_TAG: u8 = 2;
+const SET_FLAGS_TAG: u8 = 3;
+
+/// Prefixed to the hashed encoding of a batch, see `batch_digest`.
+const BATCH_DOMAIN: &[u8] = b"merkdb batch v1";
+/// Prefixed to the signed message of a replicated batch.
+const SIGNED_BATCH_DOMAIN: &[u8] = b"merkdb replicated batch v1";
+
+/// A batch committed on the primary, along with the root hashes of the
+/// primary's tree before and after the batch was applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicatedBatch {
+    pub prev_root_hash: Hash,
+    pub root_hash: Hash,
+    pub batch: Vec<BatchEntry>,
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_u32(reader)?;
+    read_exact_len(reader, len)
+}
+
+/// Reads exactly `len` bytes. Lengths come from peers, so the buffer grows as
+/// bytes arrive rather than being allocated up front.
+fn read_exact_len<R: Read>(reader: &mut R, len: u32) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+fn write_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+    output.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    output.extend_from_slice(bytes);
+}
+
+/// Appends the canonical encoding of `batch` to `output`: the number of
+/// entries, then each key and operation, all lengths as 4 big-endian bytes.
+fn encode_batch(batch: &Batch, output: &mut Vec<u8>) {
+    output.extend_from_slice(&(batch.len() as u32).to_be_bytes());
+    for (key, op) in batch.iter() {
+        write_bytes(output, key);
+        match op {
+            Op::Put(value) => {
+                output.push(PUT_TAG);
+                write_bytes(output, value);
+            }
+            Op::Delete => output.push(DELETE_TAG),
+            Op::Move { to } => {
+                output.push(MOVE_TAG);
+                write_bytes(output, to);
+            }
+            Op::SetFlags(flags) => output.extend_from_slice(&[SET_FLAGS_TAG, *flags]),
+        }
+    }
+}
+
+/// Returns the SHA-256 hash of the canonical encoding of `batch` (as in
+/// `ReplicatedBatch::encode`), prefixed with a domain separator. Equal
+/// batches always have the same digest, so it identifies a batch to sign or
+/// agree on.
+pub fn batch_digest(batch: &Batch) -> Hash {
+    let mut encoding = BATCH_DOMAIN.to_vec();
+    encode_batch(batch, &mut encoding);
+    Sha256::digest(&encoding).into()
+}
+
+impl ReplicatedBatch {
+    /// Encodes the batch into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = Vec::with_capacity(HASH_LENGTH * 2 + 4);
+        output.extend_from_slice(&self.prev_root_hash);
+        output.extend_from_slice(&self.root_hash);
+        encode_batch(&self.batch, &mut output);
+        output
+    }
+
+    /// Returns the message which is signed for this batch, binding its
+    /// digest to the root hashes before and after it.
+    pub fn signing_message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(SIGNED_BATCH_DOMAIN.len() + HASH_LENGTH * 3);
+        message.extend_from_slice(SIGNED_BATCH_DOMAIN);
+        message.extend_from_slice(&self.prev_root_hash);
+        message.extend_from_slice(&self.root_hash);
+        message.extend_from_slice(&batch_digest(&self.batch));
+        message
+    }
+
+    /// Decodes a batch from bytes created by `encode`.
+    pub fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let input = &mut bytes;
+
+        let mut prev_root_hash = [0; HASH_LENGTH];
+        input.read_exact(&mut prev_root_hash)?;
+        let mut root_hash = [0; HASH_LENGTH];
+        input.read_exact(&mut root_hash)?;
+
+        let len = read_u32(input)? as usize;
+        let mut batch = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            let key = read_bytes(input)?;
+            let mut tag = [0];
+            input.read_exact(&mut tag)?;
+            let op = match tag[0] {
+                PUT_TAG => Op::Put(read_bytes(input)?),
+                DELETE_TAG => Op::Delete,
+                MOVE_TAG => Op::Move {
+                    to: read_bytes(input)?,
+                },
+                SET_FLAGS_TAG => {
+                    let mut flags = [0];
+                    input.read_exact(&mut flags)?;
+                    Op::SetFlags(flags[0])
+                }
+                byte => return Err(ed::Error::UnexpectedByte(byte).into()),
+            };
+            batch.push((key, op));
+        }
+
+        Ok(ReplicatedBatch {
+            prev_root_hash,
+            root_hash,
+            batch,
+        })
+    }
+
+    /// Writes the encoded batch to `writer`, prefixed with its length.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let bytes = self.encode();
+        writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Reads a batch written by `write_to` from `reader`. Returns `None` if
+    /// the reader is at the end of its stream.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>> {
+        let mut len = [0; 4];
+        match reader.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut len[1..])?,
+        }
+
+        let bytes = read_exact_len(reader, u32::from_be_bytes(len))?;
+        Self::decode(&bytes).map(Some)
+    }
+}
+
+/// A `ReplicatedBatch` signed by the primary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedBatch {
+    pub batch: ReplicatedBatch,
+    pub signature: Vec<u8>,
+}
+
+impl SignedBatch {
+    /// Signs `batch` with `signer`.
+    pub fn sign<S: Signer + ?Sized>(batch: ReplicatedBatch, signer: &S) -> SignedBatch {
+        let signature = signer.sign(&batch.signing_message());
+        SignedBatch { batch, signature }
+    }
+
+    /// Checks the batch's signature, failing with
+    /// `Error::InvalidBatchSignature` if it is not valid.
+    pub fn verify<V: Verifier + ?Sized>(&self, verifier: &V) -> Result<()> {
+        if !verifier.verify(&self.batch.signing_message(), &self.signature) {
+            return Err(Error::InvalidBatchSignature(self.batch.root_hash));
+        }
+        Ok(())
+    }
+
+    /// Encodes the signed batch into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = vec![];
+        write_bytes(&mut output, &self.signature);
+        output.extend_from_slice(&self.batch.encode());
+        output
+    }
+
+    /// Decodes a signed batch from bytes created by `encode`.
+    pub fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let signature = read_bytes(&mut bytes)?;
+        let batch = ReplicatedBatch::decode(bytes)?;
+        Ok(SignedBatch { batch, signature })
+    }
+
+    /// Writes the encoded signed batch to `writer`, prefixed with its length.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let bytes = self.encode();
+        writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Reads a signed batch written by `write_to` from `reader`. Returns
+    /// `None` if the reader is at the end of its stream.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>> {
+        let mut len = [0; 4];
+        match reader.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut len[1..])?,
+        }
+
+        let bytes = read_exact_len(reader, u32::from_be_bytes(len))?;
+        Self::decode(&bytes).map(Some)
+    }
+}
+
+impl Merk {
+    /// Returns a receiver which gets a `ReplicatedBatch` for every batch
+    /// committed from now on, to be shipped to replicas.
+    ///
+    /// Once a log is installed, operations which change the root hash without
+    /// committing a batch (`rollback`, `rollback_to` and `canonicalize`) fail
+    /// with `Error::Replicated`, since the log would not carry them to
+    /// replicas.
+    pub fn replication_log(&mut self) -> Receiver<ReplicatedBatch> {
+        self.replicated = true;
+        let (sender, receiver) = channel();
+        let state = Mutex::new((sender, self.root_hash()));
+
+        self.add_post_commit_hook(move |batch, root_hash| {
+            let mut state = state.lock().unwrap();
+            let (sender, prev_root_hash) = &mut *state;
+            let _ = sender.send(ReplicatedBatch {
+                prev_root_hash: *prev_root_hash,
+                root_hash: *root_hash,
+                batch: batch.to_vec(),
+            });
+            *prev_root_hash = *root_hash;
+        });
+
+        receiver
+    }
+
+    /// Fails with `Error::Replicated` if a replication log is installed, for
+    /// operations which change the root hash without committing a batch.
+    pub(crate) fn check_not_replicated(&self, operation: &'static str) -> Result<()> {
+        if self.replicated {
+            return Err(Error::Replicated(operation));
+        }
+        Ok(())
+    }
+}
+
+/// A store which follows a primary by applying its `ReplicatedBatch`es.
+pub struct Replica {
+    merk: Merk,
+    diverged: bool,
+}
+
+impl Replica {
+    /// Creates a replica which applies batches to `merk`. The store must be at
+    /// the same state as the primary was when its replication log started.
+    pub fn new(merk: Merk) -> Self {
+        Replica {
+            merk,
+            diverged: false,
+        }
+    }
+
+    /// Applies a batch from the primary, checking the replica's root hash
+    /// against the primary's before and after applying it.
+    ///
+    /// If the root hash after applying does not match, the batch has already
+    /// been written, so the replica is marked as diverged and refuses any
+    /// further batches.
+    pub fn apply(&mut self, replicated: &ReplicatedBatch) -> Result<()> {
+        if self.diverged {
+            return Err(Error::Diverged);
+        }
+
+        let prev_root_hash = self.merk.root_hash();
+        if prev_root_hash != replicated.prev_root_hash {
+            return Err(Error::HashMismatch(
+                replicated.prev_root_hash,
+                prev_root_hash,
+            ));
+        }
+
+        self.merk.apply(&replicated.batch, &[])?;
+
+        let root_hash = self.merk.root_hash();
+        if root_hash != replicated.root_hash {
+            self.diverged = true;
+            return Err(Error::HashMismatch(replicated.root_hash, root_hash));
+        }
+
+        Ok(())
+    }
+
+    /// Reads batches from `reader` and applies them until the end of the
+    /// stream. Returns the number of batches applied.
+    pub fn follow<R: Read>(&mut self, reader: &mut R) -> Result<usize> {
+        let mut count = 0;
+        while let Some(replicated) = ReplicatedBatch::read_from(reader)? {
+            self.apply(&replicated)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Applies a signed batch from the primary like `apply`, after checking
+    /// its signature with `verifier`. A batch with an invalid signature fails
+    /// with `Error::InvalidBatchSignature` before anything is written.
+    pub fn apply_signed<V: Verifier + ?Sized>(
+        &mut self,
+        signed: &SignedBatch,
+        verifier: &V,
+    ) -> Result<()> {
+        signed.verify(verifier)?;
+        self.apply(&signed.batch)
+    }
+
+    /// Reads signed batches from `reader` and applies them with
+    /// `apply_signed` until the end of the stream. Returns the number of
+    /// batches applied.
+    pub fn follow_signed<R: Read, V: Verifier + ?Sized>(
+        &mut self,
+        reader: &mut R,
+        verifier: &V,
+    ) -> Result<usize> {
+        let mut count = 0;
+        while let Some(signed) = SignedBatch::read_from(reader)? {
+            self.apply_signed(&signed, verifier)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns true if a batch produced a different root hash than on the
+    /// primary.
+    pub fn diverged(&self) -> bool {
+        self.diverged
+    }
+
+    pub fn merk(&self) -> &Merk {
+        &self.merk
+    }
+
+    pub fn into_inner(self) -> Merk {
+        self.merk
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attest::HmacSha256;
+    use crate::test_utils::*;
+    use crate::tree::NULL_HASH;
+    use crate::Error;
+    use tempdir::TempDir;
+
+    fn open_replica(dir: &TempDir) -> Replica {
+        Replica::new(Merk::open(dir.path().join("replica")).unwrap())
+    }
+
+    #[test]
+    fn encode_decode() {
+        let replicated = ReplicatedBatch {
+            prev_root_hash: [1; HASH_LENGTH],
+            root_hash: [2; HASH_LENGTH],
+            batch: vec![
+                (vec![1], Op::Put(vec![1, 2, 3])),
+                (vec![2], Op::Delete),
+                (vec![3], Op::Move { to: vec![4] }),
+            ],
+        };
+        let bytes = replicated.encode();
+        assert_eq!(ReplicatedBatch::decode(&bytes).unwrap(), replicated);
+        assert!(ReplicatedBatch::decode(&bytes[..bytes.len() - 1]).is_err());
+
+        // lengths are not trusted to allocate buffers
+        let mut stream = u32::MAX.to_be_bytes().to_vec();
+        stream.extend_from_slice(&bytes);
+        assert!(ReplicatedBatch::read_from(&mut stream.as_slice()).is_err());
+        let mut bytes = bytes[..HASH_LENGTH * 2 + 4].to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(ReplicatedBatch::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn replica_follows_primary() {
+        let mut primary = TempMerk::new().unwrap();
+        let log = primary.replication_log();
+
+        primary.apply(&make_batch_seq(0..100), &[]).unwrap();
+        primary.apply(&make_del_batch_seq(10..20), &[]).unwrap();
+
+        let mut stream = vec![];
+        for replicated in log.try_iter() {
+            replicated.write_to(&mut stream).unwrap();
+        }
+
+        let dir = TempDir::new("replica_follows_primary").unwrap();
+        let mut replica = open_replica(&dir);
+        assert_eq!(replica.follow(&mut stream.as_slice()).unwrap(), 2);
+        assert_eq!(replica.merk().root_hash(), primary.root_hash());
+
+        // changes to the root which the log would not carry are refused
+        primary.set_retained_versions(1);
+        primary.apply(&make_del_batch_seq(20..30), &[]).unwrap();
+        let root_hash = primary.root_hash();
+        assert!(matches!(primary.rollback(), Err(Error::Replicated(_))));
+        assert!(matches!(
+            primary.rollback_to(NULL_HASH),
+            Err(Error::Replicated(_))
+        ));
+        assert!(matches!(primary.canonicalize(), Err(Error::Replicated(_))));
+        assert_eq!(primary.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn batch_digests() {
+        let batch = vec![(vec![1], Op::Put(vec![2])), (vec![3], Op::Delete)];
+        assert_eq!(batch_digest(&batch), batch_digest(&batch.clone()));
+        assert_ne!(batch_digest(&batch), batch_digest(&batch[..1]));
+        assert_ne!(batch_digest(&[]), batch_digest(&[(vec![], Op::Delete)]));
+        // the key and value boundaries are part of the encoding
+        assert_ne!(
+            batch_digest(&[(vec![1, 2], Op::Put(vec![3]))]),
+            batch_digest(&[(vec![1], Op::Put(vec![2, 3]))])
+        );
+    }
+
+    #[test]
+    fn replica_follows_signed_batches() {
+        let signer = HmacSha256::new(b"primary key");
+        let mut primary = TempMerk::new().unwrap();
+        let log = primary.replication_log();
+        primary.apply(&make_batch_seq(0..10), &[]).unwrap();
+        primary.apply(&make_batch_seq(10..20), &[]).unwrap();
+
+        let signed: Vec<_> = log
+            .try_iter()
+            .map(|batch| SignedBatch::sign(batch, &signer))
+            .collect();
+        assert_eq!(SignedBatch::decode(&signed[0].encode()).unwrap(), signed[0]);
+
+        let dir = TempDir::new("replica_follows_signed_batches").unwrap();
+        let mut replica = open_replica(&dir);
+
+        // a batch tampered with in transit, or signed with another key, is
+        // rejected before it is applied
+        let mut tampered = signed[0].clone();
+        tampered.batch.batch[0].1 = Op::Put(vec![123]);
+        assert!(matches!(
+            replica.apply_signed(&tampered, &signer),
+            Err(Error::InvalidBatchSignature(_))
+        ));
+        let forged = SignedBatch::sign(signed[0].batch.clone(), &HmacSha256::new(b"other"));
+        assert!(replica.apply_signed(&forged, &signer).is_err());
+        assert!(!replica.diverged());
+        assert_eq!(replica.merk().root_hash(), NULL_HASH);
+
+        let mut stream = vec![];
+        for signed in signed.iter() {
+            signed.write_to(&mut stream).unwrap();
+        }
+        assert_eq!(
+            replica
+                .follow_signed(&mut stream.as_slice(), &signer)
+                .unwrap(),
+            2
+        );
+        assert_eq!(replica.merk().root_hash(), primary.root_hash());
+    }
+
+    #[test]
+    fn replica_detects_divergence() {
+        let mut primary = TempMerk::new().unwrap();
+        let log = primary.replication_log();
+        primary.apply(&make_batch_seq(0..10), &[]).unwrap();
+        primary.apply(&make_batch_seq(10..20), &[]).unwrap();
+
+        let mut batches: Vec<_> = log.try_iter().collect();
+        let dir = TempDir::new("replica_detects_divergence").unwrap();
+        let mut replica = open_replica(&dir);
+
+        // skipping a batch fails before anything is written
+        assert!(matches!(
+            replica.apply(&batches[1]),
+            Err(Error::HashMismatch(..))
+        ));
+        assert!(!replica.diverged());
+
+        batches[0].batch[0].1 = Op::Put(vec![123]);
+        assert!(matches!(
+            replica.apply(&batches[0]),
+            Err(Error::HashMismatch(..))
+        ));
+        assert!(replica.diverged());
+        assert!(replica.apply(&batches[1]).is_err());
+    }
+}
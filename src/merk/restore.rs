@@ -1,19 +1,35 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
 //! Provides `Restorer`, which can create a replica of a Merk instance by
 //! receiving chunk proofs.
+//!
+//! Leaf chunks are processed in key order, so while a restore is in progress
+//! the restored tree is complete up to the last key of the last processed
+//! chunk (see `Restorer::restored_until`), and proofs of queries within that
+//! range can already be served with `Restorer::prove`.
 
 use super::Merk;
 use crate::{
     merk::MerkSource,
     proofs::{
-        chunk::{verify_leaf, verify_trunk, MIN_TRUNK_HEIGHT},
+        chunk::MIN_TRUNK_HEIGHT,
+        query::QueryItem,
         tree::{Child, Tree as ProofTree},
-        Decoder, Node,
+        ChunkProof, Node, Query,
     },
     tree::{Link, RefWalker, Tree},
     Error, Hash, Result,
 };
 use rocksdb::WriteBatch;
 use std::iter::Peekable;
+use std::ops::Bound;
 use std::{path::Path, u8};
 
 /// A `Restorer` handles decoding, verifying, and storing chunk proofs to
@@ -23,6 +39,9 @@ pub struct Restorer {
     leaf_hashes: Option<Peekable<std::vec::IntoIter<Hash>>>,
     parent_keys: Option<Peekable<std::vec::IntoIter<Vec<u8>>>>,
     trunk_height: Option<usize>,
+    /// The keys of the trunk's nodes in order, each of which follows the keys
+    /// of one leaf chunk.
+    trunk_keys: Vec<Vec<u8>>,
     merk: Merk,
     expected_root_hash: Hash,
     stated_length: usize,
@@ -52,6 +71,7 @@ impl Restorer {
             expected_root_hash,
             stated_length,
             trunk_height: None,
+            trunk_keys: vec![],
             merk: Merk::open(db_path)?,
             leaf_hashes: None,
             parent_keys: None,
@@ -65,11 +85,11 @@ impl Restorer {
     /// Once there are no remaining chunks to be processed, `finalize` should
     /// be called.
     pub fn process_chunk(&mut self, chunk_bytes: &[u8]) -> Result<usize> {
-        let ops = Decoder::new(chunk_bytes);
+        let chunk = ChunkProof::new(chunk_bytes);
 
         match self.leaf_hashes {
-            None => self.process_trunk(ops),
-            Some(_) => self.process_leaf(ops),
+            None => self.process_trunk(chunk),
+            Some(_) => self.process_leaf(chunk),
         }
     }
 
@@ -78,13 +98,16 @@ impl Restorer {
     /// processing all chunks (e.g. `restorer.remaining_chunks()` is not equal
     /// to 0).
     pub fn finalize(mut self) -> Result<Merk> {
-        if self.remaining_chunks().is_none() || self.remaining_chunks().unwrap() != 0 {
+        if self.remaining_chunks() != Some(0) {
             return Err(Error::ChunkProcessing(
                 "Called finalize before all chunks were processed".into(),
             ));
         }
 
-        if self.trunk_height.unwrap() >= MIN_TRUNK_HEIGHT {
+        if self
+            .trunk_height
+            .is_some_and(|height| height >= MIN_TRUNK_HEIGHT)
+        {
             self.rewrite_trunk_child_heights()?;
         }
 
@@ -101,6 +124,45 @@ impl Restorer {
         self.leaf_hashes.as_ref().map(|lh| lh.len())
     }
 
+    /// Returns the upper bound of the keys the restored tree is complete up
+    /// to so far, or `None` before the first leaf chunk has been processed.
+    /// Every key, as well as the absence of any key, up to the bound can be
+    /// proven with `prove`.
+    pub fn restored_until(&self) -> Option<Bound<&[u8]>> {
+        let remaining = self.remaining_chunks()?;
+        if remaining == 0 {
+            return Some(Bound::Unbounded);
+        }
+        let processed = self.trunk_keys.len() + 1 - remaining;
+        processed
+            .checked_sub(1)
+            .and_then(|i| self.trunk_keys.get(i))
+            .map(|key| Bound::Included(key.as_slice()))
+    }
+
+    /// Creates a proof for `query` from the part of the tree restored so far,
+    /// which verifies against the expected root hash just like one created by
+    /// a fully restored store. Fails if the query reaches past
+    /// `restored_until`.
+    pub fn prove(&mut self, mut query: Query) -> Result<Vec<u8>> {
+        super::resolve_limits(&mut query, self.merk.raw_iter());
+
+        let covered = |item: &QueryItem| match self.restored_until() {
+            None => false,
+            Some(Bound::Included(until)) => item.upper_bound().0 <= until,
+            Some(_) => true,
+        };
+        if !query.iter().all(covered) {
+            return Err(Error::Proof(
+                "Query reaches past the restored part of the tree".into(),
+            ));
+        }
+
+        // the trunk's links to leaf chunks are rewritten as the chunks arrive
+        self.merk.load_root()?;
+        self.merk.prove_resolved(query)
+    }
+
     /// Writes the data contained in `tree` (extracted from a verified chunk
     /// proof) to the RocksDB.
     fn write_chunk(&mut self, tree: ProofTree) -> Result<()> {
@@ -113,6 +175,14 @@ impl Restorer {
                     Ok(node) => (key, node),
                     Err(_) => return,
                 },
+                Node::KVFlags(key, value, flags) => {
+                    match Tree::new(key.clone(), value.clone())
+                        .and_then(|node| node.with_flags(*flags))
+                    {
+                        Ok(node) => (key, node),
+                        Err(_) => return,
+                    }
+                }
                 _ => return,
             };
 
@@ -131,41 +201,38 @@ impl Restorer {
     /// The trunk contains a height proof which lets us verify the total number
     /// of expected chunks is the same as `stated_length` as passed into
     /// `Restorer::new()`. We also verify the expected root hash at this step.
-    fn process_trunk(&mut self, ops: Decoder) -> Result<usize> {
-        let (trunk, height) = verify_trunk(ops)?;
+    fn process_trunk(&mut self, chunk: ChunkProof) -> Result<usize> {
+        let (trunk, height) = chunk.verify_trunk()?;
+        trunk.check_hash(self.expected_root_hash)?;
 
-        if trunk.hash()? != self.expected_root_hash {
-            return Err(Error::HashMismatch(self.expected_root_hash, trunk.hash()?));
-        }
-
-        let root_key = trunk.key().to_vec();
+        let root_key = trunk.key()?.to_vec();
 
         let trunk_height = height / 2;
+        trunk.visit_refs(&mut |node| {
+            if let Node::KV(key, _) | Node::KVFlags(key, ..) = &node.node {
+                self.trunk_keys.push(key.clone());
+            }
+        });
         self.trunk_height = Some(trunk_height);
 
         let chunks_remaining = if trunk_height >= MIN_TRUNK_HEIGHT {
             let leaf_hashes = trunk
                 .layer(trunk_height)
                 .map(|node| node.hash())
-                .collect::<Result<Vec<_>>>()?
-                .into_iter()
-                .peekable();
-            self.leaf_hashes = Some(leaf_hashes);
-
+                .collect::<Result<Vec<_>>>()?;
             let parent_keys = trunk
                 .layer(trunk_height - 1)
-                .map(|node| node.key().to_vec())
-                .collect::<Vec<Vec<u8>>>()
-                .into_iter()
-                .peekable();
-            self.parent_keys = Some(parent_keys);
-            assert_eq!(
-                self.parent_keys.as_ref().unwrap().len(),
-                self.leaf_hashes.as_ref().unwrap().len() / 2
-            );
-
-            let chunks_remaining = (2_usize).pow(trunk_height as u32);
-            assert_eq!(self.remaining_chunks_unchecked(), chunks_remaining);
+                .map(|node| node.key().map(<[u8]>::to_vec))
+                .collect::<Result<Vec<Vec<u8>>>>()?;
+
+            let chunks_remaining = 1_usize
+                .checked_shl(trunk_height as u32)
+                .filter(|count| {
+                    leaf_hashes.len() == *count && parent_keys.len() == leaf_hashes.len() / 2
+                })
+                .ok_or_else(|| Error::ChunkProcessing("Trunk is not complete".into()))?;
+            self.leaf_hashes = Some(leaf_hashes.into_iter().peekable());
+            self.parent_keys = Some(parent_keys.into_iter().peekable());
             chunks_remaining
         } else {
             self.leaf_hashes = Some(vec![].into_iter().peekable());
@@ -173,8 +240,13 @@ impl Restorer {
             0
         };
 
-        // FIXME: this one shouldn't be an assert because it comes from a peer
-        assert_eq!(self.stated_length, chunks_remaining + 1);
+        if self.stated_length != chunks_remaining + 1 {
+            return Err(Error::ChunkProcessing(format!(
+                "Expected {} chunks, trunk proves {}",
+                self.stated_length,
+                chunks_remaining + 1
+            )));
+        }
 
         // note that these writes don't happen atomically, which is fine here
         // because if anything fails during the restore process we will just
@@ -187,20 +259,22 @@ impl Restorer {
 
     /// Verifies a leaf chunk then writes it to the RocksDB. This needs to be
     /// called in order, retrying the last chunk for any failed verifications.
-    fn process_leaf(&mut self, ops: Decoder) -> Result<usize> {
-        let leaf_hashes = self.leaf_hashes.as_mut().unwrap();
-        let leaf_hash = leaf_hashes
-            .peek()
-            .expect("Received more chunks than expected");
-
-        let leaf = verify_leaf(ops, *leaf_hash)?;
+    fn process_leaf(&mut self, chunk: ChunkProof) -> Result<usize> {
+        let leaf_hash = *self
+            .leaf_hashes
+            .as_mut()
+            .and_then(|leaf_hashes| leaf_hashes.peek())
+            .ok_or_else(|| Error::ChunkProcessing("Received more chunks than expected".into()))?;
+
+        let leaf = chunk.verify_leaf(leaf_hash)?;
         self.rewrite_parent_link(&leaf)?;
         self.write_chunk(leaf)?;
 
-        let leaf_hashes = self.leaf_hashes.as_mut().unwrap();
-        leaf_hashes.next();
+        if let Some(leaf_hashes) = self.leaf_hashes.as_mut() {
+            leaf_hashes.next();
+        }
 
-        Ok(self.remaining_chunks_unchecked())
+        Ok(self.remaining_chunks().unwrap_or(0))
     }
 
     /// The parent of the root node of the leaf does not know the key of its
@@ -208,26 +282,37 @@ impl Restorer {
     /// we can write the key into the parent node's entry. Note that this does
     /// not need to recalcuate hashes since it already had the child hash.
     fn rewrite_parent_link(&mut self, leaf: &ProofTree) -> Result<()> {
-        let parent_keys = self.parent_keys.as_mut().unwrap();
-        let parent_key = parent_keys.peek().unwrap().clone();
+        let parent_key = self
+            .parent_keys
+            .as_mut()
+            .and_then(|parent_keys| parent_keys.peek())
+            .ok_or_else(|| Error::ChunkProcessing("Leaf chunk has no parent".into()))?
+            .clone();
         let mut parent = self
             .merk
             .fetch_node(parent_key.as_slice())?
-            .expect("Could not find parent of leaf chunk");
+            .ok_or_else(|| Error::InvariantViolation {
+                key: parent_key.clone(),
+                detail: "Could not find parent of leaf chunk".into(),
+            })?;
 
-        let is_left_child = self.remaining_chunks_unchecked() % 2 == 0;
+        let is_left_child = self.remaining_chunks().unwrap_or(0) & 1 == 0;
         if let Some(Link::Reference { ref mut key, .. }) = parent.link_mut(is_left_child) {
-            *key = leaf.key().to_vec();
+            *key = leaf.key()?.to_vec().into();
         } else {
-            panic!("Expected parent links to be type Link::Reference");
+            return Err(Error::InvariantViolation {
+                key: parent_key,
+                detail: "Expected parent links to be type Link::Reference".into(),
+            });
         };
 
         let parent_bytes = parent.encode();
         self.merk.db.put(parent_key, parent_bytes)?;
 
         if !is_left_child {
-            let parent_keys = self.parent_keys.as_mut().unwrap();
-            parent_keys.next();
+            if let Some(parent_keys) = self.parent_keys.as_mut() {
+                parent_keys.next();
+            }
         }
 
         Ok(())
@@ -244,17 +329,24 @@ impl Restorer {
             }
 
             let mut cloned_node =
-                Tree::decode(node.tree().key().to_vec(), node.tree().encode().as_slice());
+                Tree::decode(node.tree().key().to_vec(), node.tree().encode().as_slice())?;
 
-            let left_child = node.walk(true)?.unwrap();
+            let missing_child = || Error::ChunkProcessing("Trunk node is missing a child".into());
+            let left_child = node.walk(true)?.ok_or_else(missing_child)?;
             let left_child_heights = recurse(left_child, remaining_depth - 1, batch)?;
             let left_height = left_child_heights.0.max(left_child_heights.1) + 1;
-            *cloned_node.link_mut(true).unwrap().child_heights_mut() = left_child_heights;
+            *cloned_node
+                .link_mut(true)
+                .ok_or_else(missing_child)?
+                .child_heights_mut() = left_child_heights;
 
-            let right_child = node.walk(false)?.unwrap();
+            let right_child = node.walk(false)?.ok_or_else(missing_child)?;
             let right_child_heights = recurse(right_child, remaining_depth - 1, batch)?;
             let right_height = right_child_heights.0.max(right_child_heights.1) + 1;
-            *cloned_node.link_mut(false).unwrap().child_heights_mut() = right_child_heights;
+            *cloned_node
+                .link_mut(false)
+                .ok_or_else(missing_child)?
+                .child_heights_mut() = right_child_heights;
 
             let bytes = cloned_node.encode();
             batch.put(node.tree().key(), bytes);
@@ -267,9 +359,12 @@ impl Restorer {
 
         let mut batch = WriteBatch::default();
 
-        let depth = self.trunk_height.unwrap();
+        let depth = self
+            .trunk_height
+            .ok_or_else(|| Error::ChunkProcessing("No trunk was processed".into()))?;
         self.merk.use_tree_mut(|maybe_tree| {
-            let tree = maybe_tree.unwrap();
+            let tree = maybe_tree
+                .ok_or_else(|| Error::ChunkProcessing("Restored tree has no root".into()))?;
             let walker = RefWalker::new(tree, self.merk.source());
             recurse(walker, depth, &mut batch)
         })?;
@@ -282,6 +377,7 @@ impl Restorer {
     /// Returns the number of remaining chunks to be processed. This method will
     /// panic if called before processing the first chunk (since that chunk
     /// gives us the information to know how many chunks to expect).
+    #[allow(clippy::unwrap_used)]
     pub fn remaining_chunks_unchecked(&self) -> usize {
         self.leaf_hashes.as_ref().unwrap().len()
     }
@@ -317,7 +413,7 @@ impl ProofTree {
 impl Child {
     fn as_link(&self) -> Link {
         let key = match &self.tree.node {
-            Node::KV(key, _) => key.as_slice(),
+            Node::KV(key, _) | Node::KVFlags(key, ..) => key.as_slice(),
             // for the connection between the trunk and leaf chunks, we don't
             // have the child key so we must first write in an empty one. once
             // the leaf gets verified, we can write in this key to its parent
@@ -327,7 +423,7 @@ impl Child {
         Link::Reference {
             hash: self.hash,
             child_heights: self.tree.child_heights(),
-            key: key.to_vec(),
+            key: key.to_vec().into(),
         }
     }
 }
@@ -335,6 +431,7 @@ impl Child {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proofs::{encode_into, Op as ProofOp};
     use crate::test_utils::*;
     use crate::tree::{Batch, Op};
     use std::path::PathBuf;
@@ -401,11 +498,119 @@ mod tests {
         );
     }
 
+    #[test]
+    fn restore_flags() {
+        let flags: Vec<_> = (0..10_000)
+            .step_by(99)
+            .map(|i| (seq_key(i), Op::SetFlags(1)))
+            .collect();
+        restore_test(&[&make_batch_seq(0..10_000), &flags], 10_000);
+    }
+
     #[test]
     fn restore_1() {
         restore_test(&[&make_batch_seq(0..1)], 1);
     }
 
+    #[test]
+    fn prove_while_restoring() {
+        let mut original = TempMerk::new().unwrap();
+        original.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        let root_hash = original.root_hash();
+        let chunks = original.chunks().unwrap();
+        let chunk_count = chunks.len();
+        let mut chunks = chunks.into_iter();
+
+        let dir = tempdir::TempDir::new("prove_while_restoring").unwrap();
+        let path = dir.path().join("restore");
+        let mut restorer = Merk::restore(&path, root_hash, chunk_count).unwrap();
+        let mut query = Query::new();
+        query.insert_key(seq_key(0));
+        assert!(restorer.prove(query.clone()).is_err());
+
+        restorer
+            .process_chunk(&chunks.next().unwrap().unwrap())
+            .unwrap();
+        assert_eq!(restorer.restored_until(), None);
+        assert!(restorer.prove(query).is_err());
+
+        for _ in 0..3 {
+            restorer
+                .process_chunk(&chunks.next().unwrap().unwrap())
+                .unwrap();
+        }
+        let until = match restorer.restored_until() {
+            Some(Bound::Included(until)) => until.to_vec(),
+            bound => panic!("unexpected bound {:?}", bound),
+        };
+        let mut query = Query::new();
+        let absent = [seq_key(1), vec![0]].concat();
+        query.insert_key(seq_key(0));
+        query.insert_key(absent.clone());
+        query.insert_range_inclusive(seq_key(2)..=until.clone());
+        let proof = restorer.prove(query).unwrap();
+        let map = crate::verify(&proof, root_hash).unwrap();
+        assert_eq!(map.get(&absent).unwrap(), None);
+        for i in (0..10_000).filter(|i| *i != 1) {
+            let key = seq_key(i);
+            if key > until {
+                break;
+            }
+            assert_eq!(
+                map.get(&key).unwrap(),
+                original.get(&key).unwrap().as_deref()
+            );
+        }
+
+        let mut query = Query::new();
+        query.insert_range(seq_key(0)..seq_key(9_999));
+        assert!(restorer.prove(query).is_err());
+
+        for chunk in chunks {
+            restorer.process_chunk(&chunk.unwrap()).unwrap();
+        }
+        assert_eq!(restorer.restored_until(), Some(Bound::Unbounded));
+        let mut query = Query::new();
+        query.insert_key(seq_key(9_999));
+        let proof = restorer.prove(query).unwrap();
+        crate::verify(&proof, root_hash).unwrap();
+        assert_eq!(restorer.finalize().unwrap().root_hash(), root_hash);
+    }
+
+    #[test]
+    fn malformed_chunks_are_rejected() {
+        let mut original = TempMerk::new().unwrap();
+        original.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        let chunks: Vec<_> = original
+            .chunks()
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        let dir = tempdir::TempDir::new("malformed_chunks_are_rejected").unwrap();
+        let path = dir.path().join("restore");
+        let mut restorer = Merk::restore(&path, original.root_hash(), chunks.len()).unwrap();
+        assert!(restorer.process_chunk(&[0x10, 0xff]).is_err());
+        restorer.process_chunk(&chunks[0]).unwrap();
+
+        // chunks from peers fail with an error rather than panicking
+        let mut hash_only = vec![];
+        encode_into([ProofOp::Push(Node::Hash([0; 32]))].iter(), &mut hash_only);
+        for chunk in [&[][..], &[0x10, 0xff], &hash_only, &chunks[0], &chunks[2]] {
+            assert!(restorer.process_chunk(chunk).is_err());
+        }
+
+        for chunk in chunks[1..].iter() {
+            restorer.process_chunk(chunk).unwrap();
+        }
+        assert!(restorer.process_chunk(&chunks[1]).is_err());
+        assert_eq!(
+            restorer.finalize().unwrap().root_hash(),
+            original.root_hash()
+        );
+    }
+
     fn assert_raw_db_entries_eq(restored: &Merk, original: &Merk, length: usize) {
         let mut original_entries = original.raw_iter();
         let mut restored_entries = restored.raw_iter();
@@ -0,0 +1,841 @@
+//! Reverting the store to the state before recent commits.
+//!
+//! Nodes are stored by key, so each commit overwrites the previous version of
+//! the tree. When versions are retained, every commit additionally saves the
+//! previous value of each key it writes (in any column family) as an undo
+//! record, written atomically with the commit itself. Rolling back writes
+//! these previous values back, restoring the exact previous tree, root hash
+//! and auxiliary data.
+//!
+//! Versions never share stored nodes: the database only holds the latest
+//! tree, and each undo record holds its own copy of every previous value it
+//! restores. A version is read by overlaying the records newer than it on
+//! the latest tree, so it needs all of them, and discarding the oldest
+//! records when the retention limit is reached loses the versions they lead
+//! to. Readers on other threads can pin a version with
+//! `MerkReader::pin_version`, which counts a reference to it: commits keep
+//! every record a pinned version needs, even past the retention limit, and
+//! rolling back past a pinned version fails. Commits and rollbacks hold the
+//! pins while they decide which records to discard and until they have
+//! written, so a version can not be pinned once it is being discarded.
+//!
+//! Logically though, consecutive versions share most of their nodes, which
+//! `Merk::version_overlap` measures.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use rocksdb::{IteratorMode, WriteBatch, DEFAULT_COLUMN_FAMILY_NAME};
+
+use super::info::INFO_KEY;
+use super::reader::load_snapshot_root;
+use super::snapshot::SnapshotSource;
+use super::{
+    load_root, missing_separated_value, Merk, MerkReader, PendingWrite, INTERNAL_CF_NAME,
+    ROOT_KEY_KEY, UNDO_CF_NAME, VALUES_CF_NAME,
+};
+use crate::tree::{Fetch, FetchBytes, Tree, NULL_HASH};
+use crate::{Error, Hash, Result, HASH_LENGTH};
+
+/// The column family name, key and previous value of a key written by a
+/// commit.
+type UndoWrite = (String, Vec<u8>, Option<Vec<u8>>);
+
+/// The previous values of the keys written by a commit, along with the root
+/// hash before the commit.
+struct UndoRecord {
+    prev_root_hash: Hash,
+    writes: Vec<UndoWrite>,
+}
+
+fn write_bytes(output: &mut Vec<u8>, bytes: &[u8]) {
+    output.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    output.extend_from_slice(bytes);
+}
+
+fn read_bytes(input: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    input.read_exact(&mut len)?;
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Returns the root hash before the commit of an encoded undo record.
+fn decode_prev_root_hash(bytes: &[u8]) -> Result<Hash> {
+    bytes
+        .get(..HASH_LENGTH)
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| Error::Decode("Undo record is too short".into()))
+}
+
+/// Returns the writes which restore the version with root hash `root_hash`
+/// from the undo records `records` (ordered from newest to oldest), newest
+/// first, or fails with `Error::VersionNotFound` if no record leads to it.
+fn undo_writes<I, K, V>(records: I, root_hash: Hash) -> Result<Vec<UndoWrite>>
+where
+    I: IntoIterator<Item = (K, V)>,
+    V: AsRef<[u8]>,
+{
+    let mut writes = vec![];
+    for (_, bytes) in records {
+        let record = UndoRecord::decode(bytes.as_ref())?;
+        writes.extend(record.writes);
+        if record.prev_root_hash == root_hash {
+            return Ok(writes);
+        }
+    }
+    Err(Error::VersionNotFound(root_hash))
+}
+
+impl UndoRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut output = self.prev_root_hash.to_vec();
+        for (cf_name, key, maybe_value) in self.writes.iter() {
+            write_bytes(&mut output, cf_name.as_bytes());
+            write_bytes(&mut output, key);
+            match maybe_value {
+                Some(value) => {
+                    output.push(1);
+                    write_bytes(&mut output, value);
+                }
+                None => output.push(0),
+            }
+        }
+        output
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let input = &mut bytes;
+        let mut prev_root_hash = [0; HASH_LENGTH];
+        input.read_exact(&mut prev_root_hash)?;
+
+        let mut writes = vec![];
+        while !input.is_empty() {
+            let cf_name = String::from_utf8(read_bytes(input)?)
+                .map_err(|_| Error::Decode("Invalid column family name in undo record".into()))?;
+            let key = read_bytes(input)?;
+            let mut tag = [0];
+            input.read_exact(&mut tag)?;
+            let maybe_value = match tag[0] {
+                0 => None,
+                1 => Some(read_bytes(input)?),
+                byte => return Err(ed::Error::UnexpectedByte(byte).into()),
+            };
+            writes.push((cf_name, key, maybe_value));
+        }
+
+        Ok(UndoRecord {
+            prev_root_hash,
+            writes,
+        })
+    }
+}
+
+/// How many nodes two versions of the tree share, see
+/// `Merk::version_overlap`. Bytes are the lengths of the stored node records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VersionOverlap {
+    /// The number of nodes in the first version.
+    pub a_nodes: u64,
+    /// The number of bytes of nodes in the first version.
+    pub a_bytes: u64,
+    /// The number of nodes in the second version.
+    pub b_nodes: u64,
+    /// The number of bytes of nodes in the second version.
+    pub b_bytes: u64,
+    /// The number of nodes which are the same in both versions.
+    pub shared_nodes: u64,
+    /// The number of bytes of the shared nodes, as stored in the first
+    /// version.
+    pub shared_bytes: u64,
+}
+
+impl VersionOverlap {
+    /// Returns the number of bytes of nodes in the first version which are
+    /// not in the second, i.e. roughly what keeping only the second version
+    /// would save.
+    pub fn a_unique_bytes(&self) -> u64 {
+        self.a_bytes - self.shared_bytes
+    }
+
+    /// Returns the fraction of the first version's nodes which are also in
+    /// the second, or 1 if the first version is empty.
+    pub fn shared_fraction(&self) -> f64 {
+        if self.a_nodes == 0 {
+            return 1.0;
+        }
+        self.shared_nodes as f64 / self.a_nodes as f64
+    }
+}
+
+impl Merk {
+    /// Sets the number of commits which can be rolled back. Retaining versions
+    /// requires reading the previous value of every key written, so it is
+    /// disabled (set to 0) by default.
+    ///
+    /// Undo records beyond the new limit are discarded on the next commit.
+    /// Since versions can only be rolled back one after another, committing
+    /// while retention is disabled discards all undo records.
+    pub fn set_retained_versions(&mut self, versions: usize) {
+        self.retained_versions = versions;
+    }
+
+    /// Returns the number of commits which can currently be rolled back.
+    pub fn retained_versions(&self) -> usize {
+        let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+        self.db.iterator_cf(undo_cf, IteratorMode::Start).count()
+    }
+
+    /// Reverts the most recent commit, returning the root hash of the
+    /// restored tree. Fails with `Error::Replicated` if a replication log is
+    /// installed, since replicas would not follow the rollback.
+    pub fn rollback(&mut self) -> Result<Hash> {
+        self.check_not_replicated("roll back")?;
+        let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+        let (seq, record) = match self.db.iterator_cf(undo_cf, IteratorMode::End).next() {
+            Some((seq, bytes)) => (seq, UndoRecord::decode(&bytes)?),
+            None => return Err(Error::NothingToRollBack),
+        };
+
+        self.undo(vec![(seq.to_vec(), record)])?;
+        Ok(self.root_hash())
+    }
+
+    /// Reverts commits until the tree's root hash is `root_hash`. Fails
+    /// without changing anything if no retained version has that root hash,
+    /// or with `Error::Replicated` if a replication log is installed.
+    pub fn rollback_to(&mut self, root_hash: Hash) -> Result<()> {
+        self.check_not_replicated("roll back")?;
+        if self.root_hash() == root_hash {
+            return Ok(());
+        }
+
+        let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+        let mut records = vec![];
+        for (seq, bytes) in self.db.iterator_cf(undo_cf, IteratorMode::End) {
+            let record = UndoRecord::decode(&bytes)?;
+            let found = record.prev_root_hash == root_hash;
+            records.push((seq.to_vec(), record));
+            if found {
+                return self.undo(records);
+            }
+        }
+
+        Err(Error::VersionNotFound(root_hash))
+    }
+
+    /// Reports how many nodes the versions with root hashes `a` and `b` share.
+    /// Either may be the current root hash or the root hash of a retained
+    /// version (see `rollback_to`), otherwise `Error::VersionNotFound` is
+    /// returned.
+    ///
+    /// A node is shared if the node under its key has the same hash in both
+    /// versions, so its whole subtree is the same. This reads every node of
+    /// the current tree, reconstructing the older versions from the undo
+    /// records without writing anything.
+    pub fn version_overlap(&self, a: Hash, b: Hash) -> Result<VersionOverlap> {
+        self.check_not_offloaded("compute version overlap")?;
+        let a_nodes = self.version_nodes(a)?;
+        let b_nodes = self.version_nodes(b)?;
+
+        let mut keys: Vec<_> = a_nodes.keys().chain(b_nodes.keys()).cloned().collect();
+        keys.sort();
+        keys.dedup();
+
+        let mut overlap = VersionOverlap::default();
+        let mut count = |key: &[u8], a: Option<&[u8]>, b: Option<&[u8]>| -> Result<()> {
+            if let Some(a) = a {
+                overlap.a_nodes += 1;
+                overlap.a_bytes += a.len() as u64;
+            }
+            if let Some(b) = b {
+                overlap.b_nodes += 1;
+                overlap.b_bytes += b.len() as u64;
+            }
+            if let (Some(a), Some(b)) = (a, b) {
+                // the same record can be encoded differently, e.g. if a
+                // child was inlined in one version only
+                let shared = a == b
+                    || Tree::decode_lazy(key.to_vec(), a)?.hash()
+                        == Tree::decode_lazy(key.to_vec(), b)?.hash();
+                if shared {
+                    overlap.shared_nodes += 1;
+                    overlap.shared_bytes += a.len() as u64;
+                }
+            }
+            Ok(())
+        };
+
+        for (key, value) in self.db.iterator(IteratorMode::Start) {
+            let a = a_nodes.get(&*key).map_or(Some(&*value), Option::as_deref);
+            let b = b_nodes.get(&*key).map_or(Some(&*value), Option::as_deref);
+            count(&key, a, b)?;
+        }
+        // nodes which were deleted since the older versions
+        for key in keys {
+            if self.db.get_pinned(&key)?.is_none() {
+                let a = a_nodes.get(&key).cloned().flatten();
+                let b = b_nodes.get(&key).cloned().flatten();
+                count(&key, a.as_deref(), b.as_deref())?;
+            }
+        }
+
+        Ok(overlap)
+    }
+
+    /// Returns the nodes of the version with the given root hash which differ
+    /// from the current tree, mapping their keys to their records in that
+    /// version, or to `None` if they were not in it.
+    fn version_nodes(&self, root_hash: Hash) -> Result<BTreeMap<Vec<u8>, Option<Vec<u8>>>> {
+        let mut nodes = BTreeMap::new();
+        if self.root_hash() == root_hash {
+            return Ok(nodes);
+        }
+
+        let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+        let records = self.db.iterator_cf(undo_cf, IteratorMode::End);
+        // older records overwrite newer ones
+        for (cf_name, key, maybe_value) in undo_writes(records, root_hash)? {
+            if cf_name == DEFAULT_COLUMN_FAMILY_NAME {
+                nodes.insert(key, maybe_value);
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Writes back the previous values from the given undo records, which
+    /// must be ordered from newest to oldest, and reloads the tree. Fails with
+    /// `Error::VersionPinned` if a version which would be reverted is pinned.
+    fn undo(&mut self, records: Vec<(Vec<u8>, UndoRecord)>) -> Result<()> {
+        // held until the records are deleted, so the reverted versions can't
+        // be pinned in the meantime
+        let version_pins = self.version_pins.clone();
+        let pins = version_pins.lock();
+        let restored = records.last().map(|(_, record)| record.prev_root_hash);
+        let reverted = records[1..]
+            .iter()
+            .map(|(_, record)| record.prev_root_hash)
+            .chain(std::iter::once(self.root_hash()));
+        for root_hash in reverted {
+            if Some(root_hash) != restored && pins.contains_key(&root_hash) {
+                return Err(Error::VersionPinned(root_hash));
+            }
+        }
+
+        let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+        let mut batch = WriteBatch::default();
+        for (seq, record) in records {
+            for (cf_name, key, maybe_value) in record.writes {
+                let cf = self.db.cf_handle(&cf_name).unwrap();
+                match maybe_value {
+                    Some(value) if cf_name == INTERNAL_CF_NAME && key == INFO_KEY => {
+                        batch.put_cf(cf, key, self.rolled_back_info(&value)?)
+                    }
+                    Some(value) => batch.put_cf(cf, key, value),
+                    None => batch.delete_cf(cf, key),
+                }
+            }
+            batch.delete_cf(undo_cf, seq);
+        }
+
+        self.write(batch)?;
+        self.load_root()?;
+        self.refresh_quota_usage()
+    }
+
+    /// Stages an undo record holding the current values of the keys about to
+    /// be written by `writes`, discarding the oldest records beyond the
+    /// retention limit unless a version in `pins` needs them. The pins must
+    /// stay locked until the writes are written.
+    pub(crate) fn record_undo(&self, writes: &mut Vec<PendingWrite>, pins: &Pins) -> Result<()> {
+        let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+
+        let prev_root_hash = load_root(&self.db)?.map_or(NULL_HASH, |tree| tree.hash());
+        let mut record = UndoRecord {
+            prev_root_hash,
+            writes: Vec::with_capacity(writes.len()),
+        };
+        for (cf_name, key, _) in writes.iter() {
+            let cf = self.db.cf_handle(cf_name).unwrap();
+            let prev_value = self.db.get_cf(cf, key)?;
+            record
+                .writes
+                .push((cf_name.to_string(), key.clone(), prev_value));
+        }
+
+        let records: Vec<_> = self.db.iterator_cf(undo_cf, IteratorMode::Start).collect();
+        let seqs: Vec<_> = records.iter().map(|(seq, _)| seq).collect();
+        let next_seq = match seqs.last() {
+            Some(seq) => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(seq);
+                u64::from_be_bytes(bytes) + 1
+            }
+            None => 0,
+        };
+        let mut excess = (seqs.len() + 1).saturating_sub(self.retained_versions);
+        // a pinned version needs the record leading to it and all newer ones
+        for (i, (_, bytes)) in records.iter().enumerate().take(excess) {
+            if pins.contains_key(&decode_prev_root_hash(bytes)?) {
+                excess = i;
+                break;
+            }
+        }
+        for seq in seqs.iter().take(excess) {
+            writes.push((UNDO_CF_NAME, seq.to_vec(), None));
+        }
+
+        writes.push((
+            UNDO_CF_NAME,
+            next_seq.to_be_bytes().to_vec(),
+            Some(record.encode()),
+        ));
+        Ok(())
+    }
+
+    /// Stages deletes of all undo records.
+    pub(crate) fn discard_undo(&self, writes: &mut Vec<PendingWrite>) {
+        let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+        for (seq, _) in self.db.iterator_cf(undo_cf, IteratorMode::Start) {
+            writes.push((UNDO_CF_NAME, seq.to_vec(), None));
+        }
+    }
+}
+
+/// The number of references to each pinned version, by root hash.
+pub(crate) type Pins = BTreeMap<Hash, usize>;
+
+/// The versions pinned by the readers of a store, see
+/// `MerkReader::pin_version`.
+#[derive(Clone, Default)]
+pub(crate) struct VersionPins(Arc<Mutex<Pins>>);
+
+impl VersionPins {
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Pins> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl MerkReader {
+    /// Pins the version with root hash `root_hash`, which may be the last
+    /// commit or a retained version (see `Merk::rollback_to`), so it can be
+    /// read until the returned `PinnedVersion` is dropped. Fails with
+    /// `Error::VersionNotFound` if the version is not retained.
+    ///
+    /// While a version is pinned, commits keep the undo records needed to
+    /// read it even beyond the retention limit (see
+    /// `Merk::set_retained_versions`), and record undo records even if
+    /// retention is disabled, and rolling back past it fails with
+    /// `Error::VersionPinned`.
+    pub fn pin_version(&self, root_hash: Hash) -> Result<PinnedVersion> {
+        let mut pins = self.version_pins.lock();
+        let db = self.commit_lock.snapshot(|| self.db.snapshot());
+        let current = load_snapshot_root(&db, &self.db)?.map_or(NULL_HASH, |tree| tree.hash());
+        if current != root_hash {
+            let undo_cf = self.db.cf_handle(UNDO_CF_NAME).unwrap();
+            undo_writes(db.iterator_cf(undo_cf, IteratorMode::End), root_hash)?;
+        }
+        *pins.entry(root_hash).or_insert(0) += 1;
+
+        Ok(PinnedVersion {
+            reader: self.clone(),
+            root_hash,
+        })
+    }
+}
+
+/// A version of a store pinned by a reader, see `MerkReader::pin_version`.
+///
+/// Each read takes a snapshot of the database, and reads the version from the
+/// latest tree with the previous values of the newer commits' undo records
+/// overlaid, so reads are slower the more commits have been made since the
+/// version.
+pub struct PinnedVersion {
+    reader: MerkReader,
+    root_hash: Hash,
+}
+
+impl PinnedVersion {
+    /// Returns the root hash of the pinned version.
+    pub fn root_hash(&self) -> Hash {
+        self.root_hash
+    }
+
+    /// Gets the value for a key as of the pinned version.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let reader = &self.reader;
+        let db = reader.commit_lock.snapshot(|| reader.db.snapshot());
+        let mut version = Version::default();
+        let current = load_snapshot_root(&db, &reader.db)?.map_or(NULL_HASH, |tree| tree.hash());
+        if current != self.root_hash {
+            let undo_cf = reader.db.cf_handle(UNDO_CF_NAME).unwrap();
+            let records = db.iterator_cf(undo_cf, IteratorMode::End);
+            // older records overwrite newer ones
+            for (cf_name, key, maybe_value) in undo_writes(records, self.root_hash)? {
+                version.writes.insert((cf_name, key), maybe_value);
+            }
+        }
+
+        let source = VersionSource {
+            snapshot: SnapshotSource(&db, &reader.db),
+            version: &version,
+        };
+        let root_key = match version.get(INTERNAL_CF_NAME, ROOT_KEY_KEY) {
+            Some(root_key) => root_key.clone(),
+            None => {
+                let internal_cf = reader.db.cf_handle(INTERNAL_CF_NAME).unwrap();
+                db.get_cf(internal_cf, ROOT_KEY_KEY)?
+            }
+        };
+        match root_key {
+            Some(root_key) => {
+                let tree = source.fetch_by_key_expect(&root_key)?;
+                super::get(&tree, source, key)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for PinnedVersion {
+    fn drop(&mut self) {
+        let mut pins = self.reader.version_pins.lock();
+        if let Some(count) = pins.get_mut(&self.root_hash) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.root_hash);
+            }
+        }
+    }
+}
+
+/// The previous values which restore a version from the latest tree, by
+/// column family name and key.
+#[derive(Default)]
+struct Version {
+    writes: BTreeMap<(String, Vec<u8>), Option<Vec<u8>>>,
+}
+
+impl Version {
+    fn get(&self, cf_name: &str, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        self.writes.get(&(cf_name.to_string(), key.to_vec()))
+    }
+}
+
+/// Reads the nodes of a version, from its previous values or else from a
+/// snapshot of the latest tree.
+#[derive(Clone)]
+struct VersionSource<'a> {
+    snapshot: SnapshotSource<'a>,
+    version: &'a Version,
+}
+
+impl Fetch for VersionSource<'_> {
+    fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
+        self.fetch_bytes(key)?
+            .map(|bytes| Tree::decode_lazy(key.to_vec(), &bytes))
+            .transpose()
+    }
+
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        FetchBytes::fetch_value(self, key)
+    }
+}
+
+impl FetchBytes for VersionSource<'_> {
+    type Bytes = Vec<u8>;
+
+    fn fetch_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.version.get(DEFAULT_COLUMN_FAMILY_NAME, key) {
+            Some(record) => Ok(record.clone()),
+            None => self.snapshot.fetch_bytes(key),
+        }
+    }
+
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        match self.version.get(VALUES_CF_NAME, key) {
+            Some(value) => value.clone().ok_or_else(|| missing_separated_value(key)),
+            None => FetchBytes::fetch_value(&self.snapshot, key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use crate::test_utils::*;
+    use crate::tree::NULL_HASH;
+    use crate::{Error, Op};
+
+    #[test]
+    fn rollback_single_step() {
+        let mut merk = TempMerk::new().unwrap();
+        assert!(merk.rollback().is_err());
+        merk.set_retained_versions(2);
+
+        merk.apply(&make_batch_seq(0..100), &[(vec![1], Op::Put(vec![1]))])
+            .unwrap();
+        let root_hash = merk.root_hash();
+
+        merk.apply(&make_del_batch_seq(0..50), &[(vec![1], Op::Put(vec![2]))])
+            .unwrap();
+        assert_eq!(merk.retained_versions(), 2);
+
+        assert_eq!(merk.rollback().unwrap(), root_hash);
+        assert_eq!(merk.get(&seq_key(0)).unwrap(), Some(put_entry_value()));
+        assert_eq!(merk.get_aux(&[1]).unwrap(), Some(vec![1]));
+        assert_invariants(&merk);
+
+        assert_eq!(merk.rollback().unwrap(), NULL_HASH);
+        assert_eq!(merk.get_aux(&[1]).unwrap(), None);
+        assert!(merk.rollback().is_err());
+    }
+
+    #[test]
+    fn disabling_retention_discards_versions() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retained_versions(2);
+        merk.apply(&make_batch_seq(0..10), &[]).unwrap();
+        assert_eq!(merk.retained_versions(), 1);
+
+        merk.set_retained_versions(0);
+        merk.apply(&make_batch_seq(10..20), &[]).unwrap();
+        assert_eq!(merk.retained_versions(), 0);
+        assert!(merk.rollback().is_err());
+    }
+
+    #[test]
+    fn rollback_to_root() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retained_versions(3);
+
+        let mut root_hashes = vec![];
+        for i in 0..5 {
+            merk.apply(&make_batch_rand(20, i), &[]).unwrap();
+            root_hashes.push(merk.root_hash());
+        }
+        assert_eq!(merk.retained_versions(), 3);
+
+        // too old to be retained
+        assert!(matches!(
+            merk.rollback_to(root_hashes[0]),
+            Err(Error::VersionNotFound(_))
+        ));
+        assert_eq!(merk.root_hash(), root_hashes[4]);
+
+        merk.rollback_to(root_hashes[1]).unwrap();
+        assert_eq!(merk.root_hash(), root_hashes[1]);
+        assert_eq!(merk.retained_versions(), 0);
+        assert_invariants(&merk);
+
+        // continuing from the restored tree matches never having applied the
+        // reverted batches
+        merk.apply(&make_batch_rand(20, 4), &[]).unwrap();
+        let mut expected = TempMerk::new().unwrap();
+        for i in [0, 1, 4] {
+            expected.apply(&make_batch_rand(20, i), &[]).unwrap();
+        }
+        assert_eq!(merk.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn pruning_keeps_other_versions() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retained_versions(4);
+
+        // overlapping puts and deletes, so versions differ in the same nodes
+        let batches: Vec<_> = (0..6)
+            .map(|i| {
+                if i % 3 == 2 {
+                    make_del_batch_rand(10, i)
+                } else {
+                    make_batch_rand(40, i % 3)
+                }
+            })
+            .collect();
+        let mut root_hashes = vec![];
+        for batch in batches.iter() {
+            root_hashes.push(merk.root_hash());
+            merk.apply(batch, &[]).unwrap();
+        }
+        assert_eq!(merk.retained_versions(), 4);
+
+        // prunes the two oldest of the retained versions
+        let latest = merk.root_hash();
+        merk.set_retained_versions(2);
+        merk.apply(&make_batch_rand(40, 7), &[]).unwrap();
+        assert_eq!(merk.retained_versions(), 2);
+
+        assert_eq!(merk.rollback().unwrap(), latest);
+        assert_eq!(merk.rollback().unwrap(), root_hashes[5]);
+        assert!(merk.rollback().is_err());
+
+        let mut expected = TempMerk::new().unwrap();
+        for batch in batches[..5].iter() {
+            expected.apply(batch, &[]).unwrap();
+        }
+        assert_eq!(merk.root_hash(), expected.root_hash());
+        let puts: Vec<_> = (0..3).map(|seed| make_batch_rand(40, seed)).collect();
+        for i in 0..40 {
+            let key = &puts[i % 3][i].0;
+            assert_eq!(merk.get(key).unwrap(), expected.get(key).unwrap());
+        }
+        assert_invariants(&merk);
+    }
+
+    #[test]
+    fn version_overlap() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retained_versions(3);
+
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let first = merk.root_hash();
+        merk.apply(&make_batch_seq(100..110), &[]).unwrap();
+        let second = merk.root_hash();
+        merk.apply(&make_del_batch_seq(0..100), &[]).unwrap();
+        let current = merk.root_hash();
+
+        let overlap = merk.version_overlap(current, current).unwrap();
+        assert_eq!(overlap.a_nodes, 10);
+        assert_eq!(overlap.shared_nodes, 10);
+        assert_eq!(overlap.shared_bytes, overlap.a_bytes);
+        assert_eq!(overlap.a_unique_bytes(), 0);
+
+        // appending keys only changes the nodes along the right edge
+        let overlap = merk.version_overlap(first, second).unwrap();
+        assert_eq!((overlap.a_nodes, overlap.b_nodes), (100, 110));
+        assert!(overlap.shared_nodes > 80 && overlap.shared_nodes < 100);
+        assert!(overlap.shared_fraction() > 0.8);
+
+        assert_eq!(merk.version_overlap(first, current).unwrap().b_nodes, 10);
+        assert_eq!(merk.version_overlap(NULL_HASH, first).unwrap().a_nodes, 0);
+        assert!(matches!(
+            merk.version_overlap([1; 32], current),
+            Err(Error::VersionNotFound(_))
+        ));
+
+        // reading versions doesn't change anything
+        assert_eq!(merk.root_hash(), current);
+        assert_eq!(merk.rollback().unwrap(), second);
+    }
+
+    #[test]
+    fn pinned_versions_are_kept() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_separate_value_length(4);
+        merk.set_retained_versions(1);
+        let reader = merk.reader();
+
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let pinned = reader.pin_version(merk.root_hash()).unwrap();
+        let expected = merk.get(&seq_key(5)).unwrap();
+        assert!(expected.is_some());
+
+        // later commits keep the records the pinned version needs
+        merk.apply(&make_del_batch_seq(0..50), &[]).unwrap();
+        let second = merk.root_hash();
+        merk.apply(&[(seq_key(60), Op::Put(vec![1; 10]))], &[])
+            .unwrap();
+        assert_eq!(merk.retained_versions(), 2);
+        assert_eq!(pinned.get(&seq_key(5)).unwrap(), expected);
+        assert_eq!(pinned.get(&seq_key(60)).unwrap(), Some(put_entry_value()));
+        assert_eq!(pinned.get(&seq_key(200)).unwrap(), None);
+
+        // and rolling back past a pinned version fails
+        let third = merk.root_hash();
+        let pinned_third = reader.pin_version(third).unwrap();
+        assert!(matches!(
+            merk.rollback(),
+            Err(Error::VersionPinned(hash)) if hash == third
+        ));
+        drop(pinned_third);
+        assert_eq!(merk.rollback().unwrap(), second);
+        assert_eq!(merk.rollback().unwrap(), pinned.root_hash());
+        assert_eq!(pinned.get(&seq_key(5)).unwrap(), expected);
+
+        // unpinned versions are discarded by later commits
+        let first = pinned.root_hash();
+        drop(pinned);
+        merk.apply(&make_batch_seq(200..210), &[]).unwrap();
+        merk.apply(&make_batch_seq(210..220), &[]).unwrap();
+        assert_eq!(merk.retained_versions(), 1);
+        assert!(matches!(
+            reader.pin_version(first),
+            Err(Error::VersionNotFound(_))
+        ));
+
+        // versions are kept while pinned even if retention is disabled
+        merk.set_retained_versions(0);
+        let pinned = reader.pin_version(merk.root_hash()).unwrap();
+        merk.apply(&make_del_batch_seq(200..210), &[]).unwrap();
+        assert_eq!(pinned.get(&seq_key(205)).unwrap(), Some(put_entry_value()));
+        drop(pinned);
+        merk.apply(&make_batch_seq(300..301), &[]).unwrap();
+        assert_eq!(merk.retained_versions(), 0);
+    }
+
+    #[test]
+    fn prune_while_reading_pinned_versions() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_retained_versions(1);
+        let reader = merk.reader();
+        let done = AtomicBool::new(false);
+
+        // every key of the batch of commit `i` has value `i`, so a version
+        // reads back consistently if all of its keys have the same value
+        let batch = |i: u64| -> Vec<_> {
+            (0..20)
+                .map(|key| (seq_key(key), Op::Put(i.to_be_bytes().to_vec())))
+                .collect()
+        };
+        merk.apply(&batch(0), &[]).unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut pinned = 0;
+                while !done.load(Ordering::Acquire) {
+                    // the version may be pruned before it is pinned
+                    let root_hash = reader.root_hash().unwrap();
+                    let version = match reader.pin_version(root_hash) {
+                        Ok(version) => version,
+                        Err(Error::VersionNotFound(_)) => continue,
+                        Err(err) => panic!("{}", err),
+                    };
+                    let value = version.get(&seq_key(0)).unwrap().unwrap();
+                    // commits prune while the version is read
+                    for _ in 0..3 {
+                        for key in 0..20 {
+                            let read = version.get(&seq_key(key)).unwrap();
+                            assert_eq!(read.as_ref(), Some(&value));
+                        }
+                        thread::yield_now();
+                    }
+                    pinned += 1;
+                }
+                assert!(pinned > 0);
+            });
+
+            for i in 1..200 {
+                merk.apply(&batch(i), &[]).unwrap();
+            }
+            done.store(true, Ordering::Release);
+        });
+
+        // once the reader is done, retention is back to the limit
+        merk.apply(&batch(200), &[]).unwrap();
+        assert_eq!(merk.retained_versions(), 1);
+    }
+
+    fn assert_invariants(merk: &TempMerk) {
+        merk.walk(|maybe_walker| {
+            if let Some(walker) = maybe_walker {
+                assert_tree_invariants(walker.tree());
+            }
+        });
+    }
+}
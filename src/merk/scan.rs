@@ -0,0 +1,264 @@
+//! Scans over ranges of a store's entries, see `Merk::iter_filtered` and
+//! `Merk::par_scan`.
+
+use std::borrow::Cow;
+use std::ops::Range;
+use std::panic;
+use std::thread;
+
+use rocksdb::DBRawIterator;
+
+use super::{separated_value, Merk, MerkSource};
+use crate::tree::{Fetch, Tree, TreeRef};
+use crate::{Error, Result};
+
+/// An iterator over the entries of a range which pass a filter, see
+/// `Merk::iter_filtered`.
+pub struct FilteredIter<'a, F> {
+    merk: &'a Merk,
+    iter: DBRawIterator<'a>,
+    end: Option<Vec<u8>>,
+    filter: F,
+    done: bool,
+    /// Whether nodes are offloaded, which the scan would not see, see the
+    /// `tiered` module.
+    offloaded: bool,
+}
+
+/// Decodes a node read from the database and returns its entry if it passes
+/// `filter`.
+fn read<F>(
+    merk: &Merk,
+    key: &[u8],
+    bytes: &[u8],
+    filter: &mut F,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>>
+where
+    F: FnMut(&[u8], &[u8]) -> bool,
+{
+    let node = TreeRef::decode(key, bytes)?;
+    let value = match node.value() {
+        Some(value) => Cow::Borrowed(value),
+        None => Cow::Owned(separated_value(&merk.db, key)?),
+    };
+    Ok(filter(key, &value).then(|| (key.to_vec(), value.into_owned())))
+}
+
+impl<'a, F> Iterator for FilteredIter<'a, F>
+where
+    F: FnMut(&[u8], &[u8]) -> bool,
+{
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offloaded && !self.done {
+            self.done = true;
+            return Some(Err(Error::Offloaded("scan")));
+        }
+        while !self.done {
+            let result = match (self.iter.key(), self.iter.value()) {
+                (Some(key), Some(bytes)) if self.end.as_deref().is_none_or(|end| key < end) => {
+                    read(self.merk, key, bytes, &mut self.filter)
+                }
+                _ => break,
+            };
+            self.iter.next();
+            match result {
+                Ok(None) => continue,
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+impl Merk {
+    /// Iterates over the entries with keys in `range` in key order, yielding
+    /// only those for which `filter` returns `true`. The filter is called with
+    /// each key and value as they are read from the database, so values it
+    /// rejects are never decoded into a node or copied, which makes this much
+    /// cheaper than filtering a plain iteration when most entries are
+    /// rejected.
+    ///
+    /// Values stored apart from their nodes (see `set_separate_value_length`)
+    /// are read before the filter is called.
+    pub fn iter_filtered<F>(&self, range: Range<Vec<u8>>, filter: F) -> FilteredIter<'_, F>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.scan(&range.start, Some(range.end), filter)
+    }
+
+    fn scan<F>(&self, start: &[u8], end: Option<Vec<u8>>, filter: F) -> FilteredIter<'_, F> {
+        let mut iter = self.db.raw_iterator();
+        iter.seek(start);
+        FilteredIter {
+            merk: self,
+            iter,
+            end,
+            filter,
+            done: false,
+            offloaded: self.has_offloaded(),
+        }
+    }
+
+    /// Iterates over all of the entries from `start` up to `end`.
+    pub(super) fn scan_all(&self, start: &[u8], end: Option<Vec<u8>>) -> ScanIter<'_> {
+        self.scan(start, end, accept_all as AcceptAll)
+    }
+
+    /// Splits the keyspace into up to `partitions` ranges at the keys of the
+    /// nodes near the root, so the ranges hold similar numbers of entries, and
+    /// calls `f` with an iterator over each range's entries, each on its own
+    /// thread. Returns the results of the calls in key order, or the first
+    /// error.
+    ///
+    /// This is for jobs which read the whole store, such as building an
+    /// external index, and scale with the number of cores.
+    pub fn par_scan<F, T>(&self, partitions: usize, f: F) -> Result<Vec<T>>
+    where
+        F: Fn(ScanIter) -> Result<T> + Sync,
+        T: Send,
+    {
+        let boundaries = self.partition_boundaries(partitions)?;
+        let mut starts = vec![vec![]];
+        starts.extend(boundaries.iter().cloned());
+        let mut ends: Vec<_> = boundaries.into_iter().map(Some).collect();
+        ends.push(None);
+
+        let f = &f;
+        thread::scope(|scope| {
+            let handles: Vec<_> = starts
+                .into_iter()
+                .zip(ends)
+                .map(|(start, end)| scope.spawn(move || f(self.scan_all(&start, end))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err))
+                })
+                .collect()
+        })
+    }
+
+    /// Returns up to `partitions - 1` keys which split the keyspace into
+    /// ranges of similar size, taken from the top levels of the tree.
+    fn partition_boundaries(&self, partitions: usize) -> Result<Vec<Vec<u8>>> {
+        fn collect(
+            tree: &Tree,
+            source: &MerkSource,
+            depth: u32,
+            keys: &mut Vec<Vec<u8>>,
+        ) -> Result<()> {
+            if depth == 0 {
+                return Ok(());
+            }
+            for &left in [true, false].iter() {
+                match (tree.child(left), tree.link(left)) {
+                    (Some(child), _) => collect(child, source, depth - 1, keys)?,
+                    (None, Some(link)) => collect(&source.fetch(link)?, source, depth - 1, keys)?,
+                    (None, None) => {}
+                }
+            }
+            keys.push(tree.key().to_vec());
+            Ok(())
+        }
+
+        // the top `depth` levels hold up to 2^depth - 1 keys
+        let depth = usize::BITS - partitions.max(1).saturating_sub(1).leading_zeros();
+        let mut keys = vec![];
+        self.use_tree(|maybe_tree| match maybe_tree {
+            Some(tree) => collect(tree, &self.source(), depth, &mut keys),
+            None => Ok(()),
+        })?;
+        keys.sort();
+
+        // pick evenly spaced keys if there are more than needed
+        let count = keys.len();
+        let wanted = partitions.saturating_sub(1).min(count);
+        Ok((1..=wanted)
+            .map(|i| keys[i * (count + 1) / (wanted + 1) - 1].clone())
+            .collect())
+    }
+}
+
+type AcceptAll = fn(&[u8], &[u8]) -> bool;
+
+fn accept_all(_: &[u8], _: &[u8]) -> bool {
+    true
+}
+
+/// An iterator over the entries of one partition of `Merk::par_scan`.
+pub type ScanIter<'a> = FilteredIter<'a, AcceptAll>;
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::Result;
+
+    #[test]
+    fn iter_filtered() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_separate_value_length(9);
+        let batch: Vec<_> = (0..100u64)
+            .map(|i| {
+                let len = if i % 10 == 0 { 20 } else { 8 };
+                (seq_key(i), crate::Op::Put(vec![i as u8; len]))
+            })
+            .collect();
+        merk.apply(&batch, &[]).unwrap();
+
+        let mut calls = 0;
+        let entries = merk
+            .iter_filtered(seq_key(10)..seq_key(60), |key, value| {
+                calls += 1;
+                assert_eq!(key.len(), 8);
+                value[0] % 5 == 0
+            })
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(calls, 50);
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.clone()).collect();
+        let expected: Vec<_> = (10..60).step_by(5).map(seq_key).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(entries[0].1, vec![10; 20]);
+        assert_eq!(entries[1].1, vec![15; 8]);
+
+        let none = merk.iter_filtered(seq_key(200)..seq_key(300), |_, _| true);
+        assert_eq!(none.count(), 0);
+    }
+
+    #[test]
+    fn par_scan() {
+        let mut merk = TempMerk::with_opts(crate::Merk::default_db_opts(), 2).unwrap();
+        assert_eq!(merk.par_scan(4, |iter| Ok(iter.count())).unwrap(), vec![0]);
+
+        merk.apply(&make_batch_seq(0..10_000), &[]).unwrap();
+        merk.reopen().unwrap();
+        for &partitions in [1, 2, 3, 8, 100].iter() {
+            let counts = merk
+                .par_scan(partitions, |iter| {
+                    let keys = iter
+                        .map(|entry| entry.map(|(key, _)| key))
+                        .collect::<Result<Vec<_>>>()?;
+                    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                    Ok((keys.first().cloned(), keys.len()))
+                })
+                .unwrap();
+            assert_eq!(counts.len(), partitions);
+            assert_eq!(counts.iter().map(|(_, n)| n).sum::<usize>(), 10_000);
+            assert!(counts.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            // partitions at subtree boundaries are roughly even
+            let largest = counts.iter().map(|(_, n)| *n).max().unwrap();
+            assert!(largest <= 2 * 10_000 / partitions + 1, "{:?}", counts);
+        }
+    }
+}
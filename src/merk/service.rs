@@ -0,0 +1,468 @@
+//! Serves proofs for many concurrent requests from a pool of snapshots.
+//!
+//! A `ProofService` keeps several snapshots of the same state so proofs can be
+//! created in parallel, each reading through its own RocksDB snapshot, and
+//! when identical queries arrive while a proof for them is still being
+//! created, only one proof is created and shared between the callers.
+//!
+//! For queries from untrusted callers, `QueryLimits` bounds the work a single
+//! query can cause, and `ProofService::cost` estimates it ahead of time so
+//! callers can rate limit clients by the cost of their queries rather than
+//! their number.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use super::{resolve_limits, Merk, Snapshot};
+use crate::proofs::query::QueryItem;
+use crate::proofs::Query;
+use crate::{Error, Hash, Result};
+
+const KEY_TAG: u8 = 0;
+const RANGE_TAG: u8 = 1;
+const RANGE_INCLUSIVE_TAG: u8 = 2;
+const VALUE_LIMIT_TAG: u8 = 3;
+
+/// A proof being created by one caller, which other callers with the same
+/// query wait for.
+#[derive(Default)]
+struct Pending {
+    // `Some(None)` once the proof failed, in which case waiting callers create
+    // the proof themselves to get the error
+    result: Mutex<Option<Option<Vec<u8>>>>,
+    done: Condvar,
+}
+
+impl Pending {
+    fn wait(&self) -> Option<Vec<u8>> {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            match &*result {
+                Some(result) => return result.clone(),
+                None => result = self.done.wait(result).unwrap(),
+            }
+        }
+    }
+
+    fn finish(&self, proof: Option<Vec<u8>>) {
+        *self.result.lock().unwrap_or_else(PoisonError::into_inner) = Some(proof);
+        self.done.notify_all();
+    }
+}
+
+/// The caller creating a pending proof. When dropped, including when the
+/// caller panics, the proof is removed from the in-flight proofs and the
+/// callers waiting for it are woken, getting the proof if it was set with
+/// `finish`, or creating it themselves otherwise.
+struct Leader<'s> {
+    in_flight: &'s Mutex<HashMap<Vec<u8>, Arc<Pending>>>,
+    key: Vec<u8>,
+    pending: Arc<Pending>,
+    proof: Option<Vec<u8>>,
+}
+
+impl Leader<'_> {
+    fn finish(mut self, proof: Option<Vec<u8>>) {
+        self.proof = proof;
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+        self.pending.finish(self.proof.take());
+    }
+}
+
+/// Limits on the queries a `ProofService` proves, checked once the query's
+/// limited terms are resolved. Queries over a limit fail with
+/// `Error::QueryLimit`. Limits of `None` (the default) are not checked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// The most items (keys and ranges) in a query.
+    pub max_items: Option<usize>,
+    /// The most keys in the store any one range of a query may span.
+    pub max_range_keys: Option<usize>,
+    /// The longest proof returned. Proofs are measured once created, so this
+    /// bounds the response size rather than the work done, which the other
+    /// limits bound.
+    pub max_proof_bytes: Option<usize>,
+}
+
+/// Creates proofs against a fixed state of a `Merk`, using a pool of snapshots
+/// which are pinned for the lifetime of the service.
+///
+/// The service is `Sync`, so it can be shared between the threads handling
+/// requests. Since it borrows the `Merk`, the store can not be written to
+/// while the service exists; create a new service after each commit.
+pub struct ProofService<'a> {
+    snapshots: Vec<Snapshot<'a>>,
+    next: AtomicUsize,
+    in_flight: Mutex<HashMap<Vec<u8>, Arc<Pending>>>,
+    limits: QueryLimits,
+}
+
+impl<'a> ProofService<'a> {
+    /// Creates a service with `pool_size` snapshots of the current state of
+    /// `merk`, allowing up to `pool_size` proofs to be created in parallel.
+    pub fn new(merk: &'a Merk, pool_size: usize) -> Result<Self> {
+        let snapshots = (0..pool_size.max(1))
+            .map(|_| merk.snapshot())
+            .collect::<Result<_>>()?;
+
+        Ok(ProofService {
+            snapshots,
+            next: AtomicUsize::new(0),
+            in_flight: Mutex::new(HashMap::new()),
+            limits: QueryLimits::default(),
+        })
+    }
+
+    /// Sets the limits queries are checked against, see `QueryLimits`.
+    pub fn set_limits(&mut self, limits: QueryLimits) {
+        self.limits = limits;
+    }
+
+    /// Returns the cost of proving `query`, for rate limiting untrusted
+    /// callers: one for each item, plus one for each key a range spans. If
+    /// `max_range_keys` is set, ranges are only counted up to one past it, so
+    /// estimating the cost of a query is bounded as well.
+    pub fn cost(&self, query: &Query) -> Result<u64> {
+        let mut query = query.clone();
+        let snapshot = self.snapshot();
+        resolve_limits(&mut query, snapshot.raw_iter());
+        let cap = self.limits.max_range_keys.map(|max| max + 1);
+        Ok(query
+            .iter()
+            .map(|item| 1 + range_keys(snapshot, item, cap) as u64)
+            .sum())
+    }
+
+    /// Checks a query which has had its limits resolved against the item and
+    /// range limits.
+    fn check_query(&self, snapshot: &Snapshot, query: &Query) -> Result<()> {
+        if let Some(max) = self.limits.max_items {
+            if query.len() > max {
+                return Err(Error::QueryLimit(format!(
+                    "Query has {} items, over the limit of {}",
+                    query.len(),
+                    max
+                )));
+            }
+        }
+        if let Some(max) = self.limits.max_range_keys {
+            for item in query.iter() {
+                if range_keys(snapshot, item, Some(max + 1)) > max {
+                    return Err(Error::QueryLimit(format!(
+                        "Range spans more than the limit of {} keys",
+                        max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a proof against the proof length limit.
+    fn check_proof(&self, proof: &[u8]) -> Result<()> {
+        match self.limits.max_proof_bytes {
+            Some(max) if proof.len() > max => Err(Error::QueryLimit(format!(
+                "Proof is {} bytes, over the limit of {}",
+                proof.len(),
+                max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the number of snapshots in the pool.
+    pub fn pool_size(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Returns the root hash which the proofs are created against.
+    pub fn root_hash(&self) -> Hash {
+        self.snapshots[0].root_hash()
+    }
+
+    /// Creates a proof for `query`, like `Merk::prove`. If a proof for the
+    /// same query is already being created by another thread, waits for it
+    /// instead of creating it again.
+    pub fn prove(&self, mut query: Query) -> Result<Vec<u8>> {
+        let snapshot = self.snapshot();
+        resolve_limits(&mut query, snapshot.raw_iter());
+        self.check_query(snapshot, &query)?;
+        let key = query_key(&query);
+        let proof = self.prove_resolved(snapshot, key, query)?;
+        self.check_proof(&proof)?;
+        Ok(proof)
+    }
+
+    /// Creates a proof for a query which has had its limits resolved, or waits
+    /// for the proof if another caller is already creating one.
+    fn prove_resolved(&self, snapshot: &Snapshot, key: Vec<u8>, query: Query) -> Result<Vec<u8>> {
+        let leader = match self.lead(key) {
+            Ok(leader) => leader,
+            Err(pending) => {
+                if let Some(proof) = pending.wait() {
+                    return Ok(proof);
+                }
+                return snapshot.prove_resolved(query);
+            }
+        };
+
+        let result = snapshot.prove_resolved(query);
+        leader.finish(result.as_ref().ok().cloned());
+        result
+    }
+
+    /// Starts creating the proof for the query with the given key, or returns
+    /// the pending proof if another caller is already creating it.
+    fn lead(&self, key: Vec<u8>) -> std::result::Result<Leader<'_>, Arc<Pending>> {
+        let mut in_flight = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(pending) = in_flight.get(&key) {
+            return Err(pending.clone());
+        }
+        let pending = Arc::new(Pending::default());
+        in_flight.insert(key.clone(), pending.clone());
+        Ok(Leader {
+            in_flight: &self.in_flight,
+            key,
+            pending,
+            proof: None,
+        })
+    }
+
+    /// Creates a proof for each query in `queries`, returned in the same
+    /// order. Identical queries within the batch are only proven once.
+    pub fn prove_batch(&self, queries: Vec<Query>) -> Result<Vec<Vec<u8>>> {
+        let mut proofs: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        let mut keys = Vec::with_capacity(queries.len());
+        for mut query in queries {
+            let snapshot = self.snapshot();
+            resolve_limits(&mut query, snapshot.raw_iter());
+            self.check_query(snapshot, &query)?;
+            let key = query_key(&query);
+            if !proofs.contains_key(&key) {
+                let proof = self.prove_resolved(snapshot, key.clone(), query)?;
+                self.check_proof(&proof)?;
+                proofs.insert(key.clone(), proof);
+            }
+            keys.push(key);
+        }
+
+        Ok(keys.iter().map(|key| proofs[key].clone()).collect())
+    }
+
+    /// Picks the next snapshot from the pool, spreading concurrent callers
+    /// across them.
+    fn snapshot(&self) -> &Snapshot<'a> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.snapshots.len();
+        &self.snapshots[index]
+    }
+}
+
+/// Returns the number of keys in the store `item` spans if it is a range, up
+/// to `cap`, or 0 for a single key.
+fn range_keys(snapshot: &Snapshot, item: &QueryItem, cap: Option<usize>) -> usize {
+    if let QueryItem::Key(_) = item {
+        return 0;
+    }
+    let mut iter = snapshot.raw_iter();
+    iter.seek(item.lower_bound());
+    let mut keys = 0;
+    while cap.is_none_or(|cap| keys < cap) {
+        match iter.key() {
+            Some(key) if item.contains(key) => keys += 1,
+            _ => break,
+        }
+        iter.next();
+    }
+    keys
+}
+
+/// Encodes the items and value limit of a resolved query, so that identical
+/// queries can be found in a map. `QueryItem`'s own ordering treats
+/// overlapping items as equal, so it can not be used for this.
+pub(super) fn query_key(query: &Query) -> Vec<u8> {
+    let mut key = vec![];
+    let mut push = |tag, bytes: &[u8]| {
+        key.push(tag);
+        key.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        key.extend_from_slice(bytes);
+    };
+
+    for item in query.iter() {
+        match item {
+            QueryItem::Key(k) => push(KEY_TAG, k),
+            QueryItem::Range(range) => {
+                push(RANGE_TAG, &range.start);
+                push(RANGE_TAG, &range.end);
+            }
+            QueryItem::RangeInclusive(range) => {
+                push(RANGE_INCLUSIVE_TAG, range.start());
+                push(RANGE_INCLUSIVE_TAG, range.end());
+            }
+        }
+    }
+    if let Some(limit) = query.get_value_limit() {
+        push(VALUE_LIMIT_TAG, &(limit as u64).to_be_bytes());
+    }
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proofs::query::Term;
+    use crate::test_utils::*;
+    use crate::verify;
+    use std::thread;
+
+    #[test]
+    fn concurrent_proofs() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let root_hash = merk.root_hash();
+        let expected = merk
+            .prove(Term::key(5u64.to_be_bytes().to_vec()).into())
+            .unwrap();
+
+        let service = ProofService::new(&merk, 2).unwrap();
+        assert_eq!(service.pool_size(), 2);
+        assert_eq!(service.root_hash(), root_hash);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..20u64 {
+                        let key = (i % 10).to_be_bytes().to_vec();
+                        let proof = service.prove(Term::key(key.clone()).into()).unwrap();
+                        let map = verify(&proof, root_hash).unwrap();
+                        assert_eq!(map.get(&key).unwrap(), Some(&[123; 60][..]));
+                    }
+                });
+            }
+        });
+
+        let proof = service
+            .prove(Term::key(5u64.to_be_bytes().to_vec()).into())
+            .unwrap();
+        assert_eq!(proof, expected);
+        assert!(service.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn leader_panics() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let service = ProofService::new(&merk, 2).unwrap();
+        let query: Query = Term::key(seq_key(5)).into();
+        let leader = match service.lead(query_key(&query)) {
+            Ok(leader) => leader,
+            Err(_) => unreachable!(),
+        };
+        let pending = leader.pending.clone();
+
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| service.prove(query.clone()));
+            // the map, the leader, this thread and the waiter hold the proof
+            while Arc::strong_count(&pending) < 4 {
+                thread::yield_now();
+            }
+            let panicked = scope.spawn(move || {
+                let _leader = leader;
+                panic!("proof creation panicked");
+            });
+            assert!(panicked.join().is_err());
+
+            // the waiter creates the proof itself rather than blocking
+            let proof = waiter.join().unwrap().unwrap();
+            verify(&proof, service.root_hash()).unwrap();
+        });
+        assert!(service.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn prove_batch() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let service = ProofService::new(&merk, 1).unwrap();
+
+        let key = |i: u64| i.to_be_bytes().to_vec();
+        let queries = vec![
+            Term::key(key(1)).into(),
+            Term::range(key(10)..key(20)).limit(3).into(),
+            Term::key(key(1)).into(),
+        ];
+        let proofs = service.prove_batch(queries).unwrap();
+        assert_eq!(proofs.len(), 3);
+        assert_eq!(proofs[0], proofs[2]);
+
+        let map = verify(&proofs[1], service.root_hash()).unwrap();
+        let term = Term::range(key(10)..key(20)).limit(3);
+        assert_eq!(map.term(&term).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn query_limits() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let mut service = ProofService::new(&merk, 1).unwrap();
+        let query = |items: Vec<QueryItem>| Query::from(items);
+        let wide = || query(vec![QueryItem::Range(seq_key(10)..seq_key(30))]);
+        let keys = || query(vec![QueryItem::Key(seq_key(1)), QueryItem::Key(seq_key(2))]);
+
+        assert_eq!(service.cost(&wide()).unwrap(), 21);
+        assert_eq!(service.cost(&keys()).unwrap(), 2);
+        let limited = Term::range(seq_key(10)..seq_key(30)).limit(3).into();
+        assert_eq!(service.cost(&limited).unwrap(), 4);
+
+        service.set_limits(QueryLimits {
+            max_items: Some(1),
+            max_range_keys: Some(10),
+            max_proof_bytes: None,
+        });
+        assert_eq!(service.cost(&wide()).unwrap(), 12);
+        assert!(matches!(service.prove(wide()), Err(Error::QueryLimit(_))));
+        assert!(matches!(service.prove(keys()), Err(Error::QueryLimit(_))));
+        assert!(service.prove(limited).is_ok());
+        let narrow = query(vec![QueryItem::Range(seq_key(10)..seq_key(20))]);
+        let proof = service.prove(narrow.clone()).unwrap();
+
+        service.set_limits(QueryLimits {
+            max_proof_bytes: Some(proof.len() - 1),
+            ..Default::default()
+        });
+        assert!(matches!(service.prove(narrow), Err(Error::QueryLimit(_))));
+        assert!(service.prove_batch(vec![wide()]).is_err());
+    }
+
+    #[test]
+    fn query_keys() {
+        let key = |items: Vec<QueryItem>| query_key(&Query::from(items));
+        assert_eq!(
+            key(vec![QueryItem::Key(vec![1])]),
+            key(vec![QueryItem::Key(vec![1])])
+        );
+        assert_ne!(
+            key(vec![QueryItem::Range(vec![1]..vec![3])]),
+            key(vec![QueryItem::RangeInclusive(vec![1]..=vec![3])])
+        );
+        assert_ne!(
+            key(vec![QueryItem::Key(vec![1, 2])]),
+            key(vec![QueryItem::Key(vec![1]), QueryItem::Key(vec![2])])
+        );
+        assert_ne!(
+            query_key(&Query::from(vec![vec![1]])),
+            query_key(&Query::from(vec![vec![1]]).value_limit(10))
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Logging of slow operations, see `Merk::set_slow_op_threshold`.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::explain::Explain;
+use super::Merk;
+
+/// A function called with each operation which took longer than the slow
+/// operation threshold.
+pub type SlowOpLogger = Box<dyn Fn(&SlowOp) + Send + Sync>;
+
+/// The kind of a slow operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowOpKind {
+    Apply,
+    Prove,
+    Get,
+}
+
+impl SlowOpKind {
+    fn name(self) -> &'static str {
+        match self {
+            SlowOpKind::Apply => "apply",
+            SlowOpKind::Prove => "prove",
+            SlowOpKind::Get => "get",
+        }
+    }
+}
+
+/// An operation which took longer than the slow operation threshold, with
+/// stats about the work it did.
+///
+/// It displays as a single line of `key=value` fields, for log aggregators
+/// to parse.
+#[derive(Clone, Debug)]
+pub struct SlowOp {
+    pub kind: SlowOpKind,
+    /// How long the operation took.
+    pub duration: Duration,
+    /// The number of keys in the batch, the number of items in the query, or
+    /// 1 for a get.
+    pub keys: usize,
+    /// The number of bytes written by a batch, the length of the proof, or
+    /// the length of the value which was read.
+    pub bytes: usize,
+    /// The breakdown of the work done to apply a batch, or `None` for other
+    /// operations.
+    pub explain: Option<Explain>,
+}
+
+impl fmt::Display for SlowOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slow merk operation op={} duration_us={} keys={} bytes={}",
+            self.kind.name(),
+            self.duration.as_micros(),
+            self.keys,
+            self.bytes
+        )?;
+        if let Some(explain) = &self.explain {
+            write!(
+                f,
+                " nodes_loaded={} nodes_created={} nodes_written={} nodes_deleted={} \
+                 apply_us={} hash_us={} commit_us={} write_us={}",
+                explain.counts.nodes_loaded,
+                explain.counts.nodes_created,
+                explain.nodes_written,
+                explain.nodes_deleted,
+                explain.apply_time.as_micros(),
+                explain.hash_time.as_micros(),
+                explain.commit_time.as_micros(),
+                explain.write_time.as_micros()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Merk {
+    /// Sets the duration above which an `apply`, `prove` or `get` is logged
+    /// as a `SlowOp`, or `None` (the default) to log nothing. Slow operations
+    /// are written to standard error as warnings unless a logger is set with
+    /// `set_slow_op_logger`.
+    ///
+    /// While a threshold is set, batches record the same stats as
+    /// `apply_explain`, which are included in the log of slow batches.
+    pub fn set_slow_op_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_op_threshold = threshold;
+    }
+
+    /// Sets the function slow operations are logged with, see
+    /// `set_slow_op_threshold`.
+    pub fn set_slow_op_logger<F>(&mut self, logger: F)
+    where
+        F: Fn(&SlowOp) + Send + Sync + 'static,
+    {
+        self.slow_op_logger = Some(Box::new(logger));
+    }
+
+    /// Returns the start time of an operation if slow operations are logged.
+    pub(crate) fn slow_op_start(&self) -> Option<Instant> {
+        self.slow_op_threshold.map(|_| Instant::now())
+    }
+
+    /// Logs an operation started at `start` if it took longer than the
+    /// threshold, creating its stats with `op` from its duration.
+    pub(crate) fn log_if_slow(&self, start: Option<Instant>, op: impl FnOnce(Duration) -> SlowOp) {
+        let (start, threshold) = match (start, self.slow_op_threshold) {
+            (Some(start), Some(threshold)) => (start, threshold),
+            _ => return,
+        };
+        let duration = start.elapsed();
+        if duration <= threshold {
+            return;
+        }
+        let op = op(duration);
+        match &self.slow_op_logger {
+            Some(logger) => logger(&op),
+            None => eprintln!("warning: {}", op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::proofs::Query;
+    use crate::test_utils::*;
+
+    #[test]
+    fn slow_op_log() {
+        let mut merk = TempMerk::new().unwrap();
+        let logged = Arc::new(Mutex::new(vec![]));
+        let log = logged.clone();
+        merk.set_slow_op_logger(move |op| log.lock().unwrap().push(op.clone()));
+
+        // nothing is logged without a threshold
+        merk.apply(&make_batch_seq(0..10), &[]).unwrap();
+        assert!(logged.lock().unwrap().is_empty());
+
+        merk.set_slow_op_threshold(Some(Duration::ZERO));
+        merk.apply(&make_batch_seq(10..20), &[]).unwrap();
+        merk.get(&seq_key(5)).unwrap();
+        let mut query = Query::new();
+        query.insert_key(seq_key(5));
+        let proof = merk.prove(query).unwrap();
+
+        let logged = logged.lock().unwrap();
+        let kinds: Vec<_> = logged.iter().map(|op| op.kind).collect();
+        assert_eq!(
+            kinds,
+            [SlowOpKind::Apply, SlowOpKind::Get, SlowOpKind::Prove]
+        );
+        assert_eq!(logged[0].keys, 10);
+        assert_eq!(logged[0].explain.as_ref().unwrap().counts.nodes_created, 10);
+        assert_eq!(logged[1].bytes, 60);
+        assert_eq!(logged[2].bytes, proof.len());
+        assert!(logged[0]
+            .to_string()
+            .starts_with("slow merk operation op=apply duration_us="));
+    }
+}
@@ -1,21 +1,32 @@
-use std::cell::Cell;
+#[cfg(loom)]
+use loom::sync::RwLock;
+#[cfg(not(loom))]
+use std::sync::RwLock;
+
+use super::{
+    canonical_state_hash, missing_separated_value, read_lock, write_lock, TreeDb, VALUES_CF_NAME,
+};
 
 use crate::{
     proofs::{query::QueryItem, Query},
-    tree::{Fetch, RefWalker, Tree, NULL_HASH},
+    tree::{Fetch, FetchBytes, RefWalker, Tree, NULL_HASH},
     Hash, Result,
 };
 
 pub struct Snapshot<'a> {
     db: rocksdb::Snapshot<'a>,
-    tree: Cell<Option<Tree>>,
+    parent: &'a TreeDb,
+    tree: RwLock<Option<Tree>>,
 }
 
 impl<'a> Snapshot<'a> {
-    pub fn new(db: rocksdb::Snapshot<'a>, tree: Option<Tree>) -> Self {
+    /// Creates a snapshot view of a tree. `parent` is the database `db` was
+    /// taken from.
+    pub(crate) fn new(db: rocksdb::Snapshot<'a>, parent: &'a TreeDb, tree: Option<Tree>) -> Self {
         Snapshot {
             db,
-            tree: Cell::new(tree),
+            parent,
+            tree: RwLock::new(tree),
         }
     }
 
@@ -31,8 +42,24 @@ impl<'a> Snapshot<'a> {
         self.use_tree(|tree| tree.map_or(NULL_HASH, |tree| tree.hash()))
     }
 
-    pub fn prove(&self, query: Query) -> Result<Vec<u8>> {
-        self.prove_unchecked(query)
+    /// Returns the canonical state hash of the snapshot's entries, see
+    /// `Merk::canonical_state_hash`.
+    pub fn canonical_state_hash(&self) -> Result<Hash> {
+        canonical_state_hash(self.raw_iter())
+    }
+
+    pub fn prove(&self, mut query: Query) -> Result<Vec<u8>> {
+        super::resolve_limits(&mut query, self.raw_iter());
+        self.prove_resolved(query)
+    }
+
+    /// Creates a proof for a query which has had its limits resolved, with
+    /// its value limit.
+    pub(crate) fn prove_resolved(&self, query: Query) -> Result<Vec<u8>> {
+        let value_limit = query.get_value_limit();
+        self.use_tree(move |maybe_tree| {
+            super::prove_unchecked(maybe_tree, self.source(), query, value_limit)
+        })
     }
 
     pub fn prove_unchecked<Q, I>(&self, query: I) -> Result<Vec<u8>>
@@ -40,52 +67,61 @@ impl<'a> Snapshot<'a> {
         Q: Into<QueryItem>,
         I: IntoIterator<Item = Q>,
     {
-        self.use_tree_mut(move |maybe_tree| {
-            super::prove_unchecked(maybe_tree, self.source(), query.into_iter())
+        self.use_tree(move |maybe_tree| {
+            super::prove_unchecked(maybe_tree, self.source(), query, None)
         })
     }
 
     pub fn walk<T>(&self, f: impl FnOnce(Option<RefWalker<SnapshotSource>>) -> T) -> T {
-        let mut tree = self.tree.take();
+        let mut tree = write_lock(&self.tree);
         let maybe_walker = tree
             .as_mut()
             .map(|tree| RefWalker::new(tree, self.source()));
-        let res = f(maybe_walker);
-        self.tree.set(tree);
-        res
+        f(maybe_walker)
     }
 
     pub fn raw_iter(&self) -> rocksdb::DBRawIterator {
-        self.db.raw_iterator()
+        self.db.raw_iterator_cf(self.parent.nodes_cf())
     }
 
     fn source(&self) -> SnapshotSource {
-        SnapshotSource(&self.db)
+        SnapshotSource(&self.db, self.parent)
     }
 
     fn use_tree<T>(&self, f: impl FnOnce(Option<&Tree>) -> T) -> T {
-        let tree = self.tree.take();
-        let res = f(tree.as_ref());
-        self.tree.set(tree);
-        res
-    }
-
-    fn use_tree_mut<T>(&self, f: impl FnOnce(Option<&mut Tree>) -> T) -> T {
-        let mut tree = self.tree.take();
-        let res = f(tree.as_mut());
-        self.tree.set(tree);
-        res
+        f(read_lock(&self.tree).as_ref())
     }
 }
 
+/// Reads nodes from a snapshot. The second field is the database the snapshot
+/// was taken from, which is needed to read from the tree's column families.
 #[derive(Clone)]
-pub struct SnapshotSource<'a>(&'a rocksdb::Snapshot<'a>);
+pub struct SnapshotSource<'a>(pub(crate) &'a rocksdb::Snapshot<'a>, pub(crate) &'a TreeDb);
 
 impl<'a> Fetch for SnapshotSource<'a> {
     fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
-        Ok(self
-            .0
-            .get(key)?
-            .map(|bytes| Tree::decode(key.to_vec(), &bytes)))
+        self.0
+            .get_cf(self.1.nodes_cf(), key)?
+            .map(|bytes| Tree::decode_lazy(key.to_vec(), &bytes))
+            .transpose()
+    }
+
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        FetchBytes::fetch_value(self, key)
+    }
+}
+
+impl<'a> FetchBytes for SnapshotSource<'a> {
+    type Bytes = Vec<u8>;
+
+    fn fetch_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.get_cf(self.1.nodes_cf(), key)?)
+    }
+
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let values_cf = self.1.cf_handle(VALUES_CF_NAME).unwrap();
+        self.0
+            .get_cf(values_cf, key)?
+            .ok_or_else(|| missing_separated_value(key))
     }
 }
@@ -0,0 +1,468 @@
+//! A store with layers of pending changes which reads see, see `StagedMerk`.
+//!
+//! State machines typically apply many small changes while processing a
+//! block or transaction and read back their own writes along the way, then
+//! commit everything at once. `StagedMerk` keeps those changes in a pending
+//! batch in memory and overlays it on the committed state for `get` and
+//! `iter`, so nothing reaches the store until `commit`.
+//!
+//! Further layers can be stacked on top with `child`, e.g. one per message
+//! within a transaction: an `Overlay` sees the changes of the layers below
+//! it, and its own changes are written through to its parent when it is
+//! committed, or dropped along with it otherwise.
+
+use std::cmp::Ordering;
+use std::collections::btree_map::{self, BTreeMap};
+use std::iter::Peekable;
+use std::ops::Bound;
+
+use rocksdb::DBRawIterator;
+
+use super::{separated_value, Merk};
+use crate::tree::{BatchEntry, Tree};
+use crate::{Error, Hash, Op, Result};
+
+type Pending = BTreeMap<Vec<u8>, Op>;
+
+/// A layer which an `Overlay` can be stacked on.
+trait Layer {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn stage(&mut self, key: Vec<u8>, op: Op);
+
+    /// Pushes the pending changes of this layer and the layers below it onto
+    /// `layers`, lowest first, and returns the store at the bottom.
+    fn layers<'a>(&'a self, layers: &mut Vec<&'a Pending>) -> &'a Merk;
+}
+
+fn get_pending(pending: &Pending, key: &[u8]) -> Option<Option<Vec<u8>>> {
+    pending.get(key).map(|op| match op {
+        Op::Put(value) => Some(value.clone()),
+        Op::Delete => None,
+        Op::Move { .. } | Op::SetFlags(_) => unreachable!("only puts and deletes are staged"),
+    })
+}
+
+fn iter_layers<'a>(layer: &'a dyn Layer, start: &[u8], end: Option<&[u8]>) -> StagedIter<'a> {
+    let mut layers = vec![];
+    let merk = layer.layers(&mut layers);
+    let mut committed = merk.raw_iter();
+    committed.seek(start);
+    let upper = end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_vec()));
+    let pending = layers
+        .into_iter()
+        .map(|pending| {
+            pending
+                .range((Bound::Included(start.to_vec()), upper.clone()))
+                .peekable()
+        })
+        .collect();
+    StagedIter {
+        merk,
+        committed,
+        pending,
+        end: end.map(<[u8]>::to_vec),
+        offloaded: merk.has_offloaded(),
+    }
+}
+
+fn batch(pending: &Pending) -> Vec<BatchEntry> {
+    pending
+        .iter()
+        .map(|(key, op)| (key.clone(), op.clone()))
+        .collect()
+}
+
+/// Wraps a `Merk` so that puts and deletes accumulate in a pending batch, and
+/// reads see the pending changes on top of the committed state.
+///
+/// Unlike a `Transaction`, a `StagedMerk` owns its store and stays usable
+/// after committing, so it can be the long-lived handle to the store.
+pub struct StagedMerk {
+    merk: Merk,
+    pending: Pending,
+}
+
+impl Layer for StagedMerk {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match get_pending(&self.pending, key) {
+            Some(value) => Ok(value),
+            None => self.merk.get(key),
+        }
+    }
+
+    fn stage(&mut self, key: Vec<u8>, op: Op) {
+        self.pending.insert(key, op);
+    }
+
+    fn layers<'a>(&'a self, layers: &mut Vec<&'a Pending>) -> &'a Merk {
+        layers.push(&self.pending);
+        &self.merk
+    }
+}
+
+impl StagedMerk {
+    /// Wraps `merk`, with no pending changes.
+    pub fn new(merk: Merk) -> StagedMerk {
+        StagedMerk {
+            merk,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Gets a value for the given key, including the pending changes.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Layer::get(self, key)
+    }
+
+    /// Stages a put of `value` to `key`.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.stage(key, Op::Put(value));
+    }
+
+    /// Stages a delete of `key`.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.stage(key, Op::Delete);
+    }
+
+    /// Iterates over the entries with keys in `start..end` (or from `start`
+    /// to the last key if `end` is `None`) in key order, including the
+    /// pending changes.
+    pub fn iter(&self, start: &[u8], end: Option<&[u8]>) -> StagedIter<'_> {
+        iter_layers(self, start, end)
+    }
+
+    /// Stacks a new layer of changes on top of the pending changes, which
+    /// reaches them only if it is committed.
+    pub fn child(&mut self) -> Overlay<'_> {
+        Overlay::new(self)
+    }
+
+    /// Returns the pending changes as a sorted batch.
+    pub fn batch(&self) -> Vec<BatchEntry> {
+        batch(&self.pending)
+    }
+
+    /// Returns whether there are pending changes.
+    pub fn is_dirty(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Computes the root hash the store would have after committing the
+    /// pending changes, without writing anything.
+    pub fn root_hash(&self) -> Result<Hash> {
+        self.merk.simulate(&self.batch())
+    }
+
+    /// Applies the pending changes to the store in a single batch, along with
+    /// the given auxiliary batch. The pending changes are kept if applying
+    /// fails.
+    pub fn commit(&mut self, aux: &[BatchEntry]) -> Result<()> {
+        self.merk.apply(&self.batch(), aux)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Discards the pending changes.
+    pub fn discard(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Returns the wrapped store. Reads from it do not see the pending
+    /// changes.
+    pub fn merk(&self) -> &Merk {
+        &self.merk
+    }
+
+    /// Returns the wrapped store, discarding the pending changes.
+    pub fn into_inner(self) -> Merk {
+        self.merk
+    }
+}
+
+/// A layer of changes stacked on a `StagedMerk` or another `Overlay`, see
+/// `StagedMerk::child`. Reads see the changes of every layer below, and
+/// `commit` writes the overlay's changes through to its parent. Dropping the
+/// overlay without committing discards its changes.
+pub struct Overlay<'a> {
+    parent: &'a mut dyn Layer,
+    pending: Pending,
+}
+
+impl<'a> Layer for Overlay<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match get_pending(&self.pending, key) {
+            Some(value) => Ok(value),
+            None => self.parent.get(key),
+        }
+    }
+
+    fn stage(&mut self, key: Vec<u8>, op: Op) {
+        self.pending.insert(key, op);
+    }
+
+    fn layers<'b>(&'b self, layers: &mut Vec<&'b Pending>) -> &'b Merk {
+        let merk = self.parent.layers(layers);
+        layers.push(&self.pending);
+        merk
+    }
+}
+
+impl<'a> Overlay<'a> {
+    fn new(parent: &'a mut dyn Layer) -> Overlay<'a> {
+        Overlay {
+            parent,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Gets a value for the given key, including the changes of this overlay
+    /// and the layers below it.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Layer::get(self, key)
+    }
+
+    /// Stages a put of `value` to `key` in this overlay.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.stage(key, Op::Put(value));
+    }
+
+    /// Stages a delete of `key` in this overlay.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.stage(key, Op::Delete);
+    }
+
+    /// Iterates over the entries with keys in `start..end` like
+    /// `StagedMerk::iter`, including the changes of this overlay and the
+    /// layers below it.
+    pub fn iter(&self, start: &[u8], end: Option<&[u8]>) -> StagedIter<'_> {
+        iter_layers(self, start, end)
+    }
+
+    /// Stacks a new layer of changes on top of this overlay.
+    pub fn child(&mut self) -> Overlay<'_> {
+        Overlay::new(self)
+    }
+
+    /// Returns this overlay's changes as a sorted batch.
+    pub fn batch(&self) -> Vec<BatchEntry> {
+        batch(&self.pending)
+    }
+
+    /// Writes this overlay's changes through to its parent.
+    pub fn commit(self) {
+        for (key, op) in self.pending {
+            self.parent.stage(key, op);
+        }
+    }
+
+    /// Discards this overlay's changes, like dropping it.
+    pub fn discard(self) {}
+}
+
+/// An iterator over the entries of a `StagedMerk` or an `Overlay`, see
+/// `StagedMerk::iter`.
+pub struct StagedIter<'a> {
+    merk: &'a Merk,
+    committed: DBRawIterator<'a>,
+    /// The pending changes in range of each layer, lowest first.
+    pending: Vec<Peekable<btree_map::Range<'a, Vec<u8>, Op>>>,
+    end: Option<Vec<u8>>,
+    /// Whether nodes are offloaded, which the iterator would not see, see
+    /// the `tiered` module.
+    offloaded: bool,
+}
+
+impl<'a> StagedIter<'a> {
+    /// Returns the key of the next committed node, if it is in range.
+    fn committed_key(&self) -> Option<&[u8]> {
+        let key = self.committed.key()?;
+        match &self.end {
+            Some(end) if key >= end.as_slice() => None,
+            _ => Some(key),
+        }
+    }
+
+    fn next_committed(&mut self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let key = self.committed.key().unwrap().to_vec();
+        let bytes = self.committed.value().unwrap();
+        let db = &self.merk.db;
+        let tree = Tree::decode_with(key.clone(), bytes, |key| separated_value(db, key))?;
+        self.committed.next();
+        Ok((key, tree.value().to_vec()))
+    }
+}
+
+impl<'a> Iterator for StagedIter<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offloaded {
+            // fail once, then end
+            self.offloaded = false;
+            self.committed.seek_to_last();
+            self.committed.next();
+            self.pending.clear();
+            return Some(Err(Error::Offloaded("iterate a staged store")));
+        }
+        loop {
+            // the next key of any source, and the highest layer which has it
+            let mut next: Option<(Vec<u8>, Option<usize>)> =
+                self.committed_key().map(|key| (key.to_vec(), None));
+            for (i, layer) in self.pending.iter_mut().enumerate() {
+                let key = match layer.peek() {
+                    Some((key, _)) => key.as_slice(),
+                    None => continue,
+                };
+                let closer = match &next {
+                    None => true,
+                    Some((next_key, _)) => key.cmp(next_key) != Ordering::Greater,
+                };
+                if closer {
+                    next = Some((key.to_vec(), Some(i)));
+                }
+            }
+            let (key, top) = next?;
+
+            let top = match top {
+                None => return Some(self.next_committed()),
+                Some(top) => top,
+            };
+            // later changes to the key replace the committed entry and
+            // changes in lower layers
+            if self.committed_key() == Some(key.as_slice()) {
+                self.committed.next();
+            }
+            let mut op = None;
+            for (i, layer) in self.pending.iter_mut().enumerate() {
+                if layer.peek().map(|(k, _)| k.as_slice()) == Some(key.as_slice()) {
+                    let (_, layer_op) = layer.next().unwrap();
+                    if i == top {
+                        op = Some(layer_op);
+                    }
+                }
+            }
+            match op.unwrap() {
+                Op::Put(value) => return Some(Ok((key, value.clone()))),
+                Op::Delete => continue,
+                Op::Move { .. } | Op::SetFlags(_) => {
+                    unreachable!("only puts and deletes are staged")
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StagedIter, StagedMerk};
+    use crate::{Merk, Op, Result};
+    use tempdir::TempDir;
+
+    fn entries_of(iter: StagedIter) -> Vec<(Vec<u8>, Vec<u8>)> {
+        iter.collect::<Result<_>>().unwrap()
+    }
+
+    fn entries(staged: &StagedMerk, start: &[u8], end: Option<&[u8]>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        entries_of(staged.iter(start, end))
+    }
+
+    #[test]
+    fn overlays_pending_changes() {
+        let dir = TempDir::new("staged").unwrap();
+        let mut merk = Merk::open(dir.path()).unwrap();
+        let batch: Vec<_> = (0..10u8).map(|i| (vec![i * 2], Op::Put(vec![i]))).collect();
+        merk.apply(&batch, &[]).unwrap();
+        let mut staged = StagedMerk::new(merk);
+        let root_hash = staged.merk().root_hash();
+
+        staged.put(vec![3], vec![30]);
+        staged.put(vec![4], vec![40]);
+        staged.delete(vec![6]);
+        staged.delete(vec![7]);
+        staged.put(vec![100], vec![100]);
+        assert_eq!(staged.get(&[3]).unwrap(), Some(vec![30]));
+        assert_eq!(staged.get(&[4]).unwrap(), Some(vec![40]));
+        assert_eq!(staged.get(&[6]).unwrap(), None);
+        assert_eq!(staged.get(&[8]).unwrap(), Some(vec![4]));
+        assert_eq!(staged.merk().get(&[4]).unwrap(), Some(vec![2]));
+
+        assert_eq!(
+            entries(&staged, &[2], Some(&[10])),
+            vec![
+                (vec![2], vec![1]),
+                (vec![3], vec![30]),
+                (vec![4], vec![40]),
+                (vec![8], vec![4]),
+            ]
+        );
+        assert_eq!(entries(&staged, &[17], None).len(), 2);
+        assert_eq!(entries(&staged, &[0], None).len(), 11);
+
+        let expected = staged.root_hash().unwrap();
+        assert_eq!(staged.merk().root_hash(), root_hash);
+        staged.commit(&[]).unwrap();
+        assert!(!staged.is_dirty());
+        assert_eq!(staged.merk().root_hash(), expected);
+        assert_eq!(staged.get(&[6]).unwrap(), None);
+        assert_eq!(entries(&staged, &[0], None).len(), 11);
+
+        staged.put(vec![1], vec![1]);
+        staged.discard();
+        assert_eq!(staged.get(&[1]).unwrap(), None);
+
+        // nested overlays see the layers below them, and write through to
+        // their parent when committed
+        staged.put(vec![1], vec![1]);
+        {
+            let mut tx = staged.child();
+            tx.put(vec![2], vec![20]);
+            {
+                let mut msg = tx.child();
+                msg.delete(vec![1]);
+                msg.put(vec![5], vec![5]);
+                assert_eq!(msg.get(&[1]).unwrap(), None);
+                assert_eq!(msg.get(&[2]).unwrap(), Some(vec![20]));
+                assert_eq!(
+                    entries_of(msg.iter(&[0], Some(&[6]))),
+                    vec![
+                        (vec![0], vec![0]),
+                        (vec![2], vec![20]),
+                        (vec![3], vec![30]),
+                        (vec![4], vec![40]),
+                        (vec![5], vec![5]),
+                    ]
+                );
+                msg.commit();
+            }
+            {
+                let mut failed = tx.child();
+                failed.put(vec![1], vec![100]);
+                failed.delete(vec![0]);
+                failed.discard();
+            }
+            {
+                let mut dropped = tx.child();
+                dropped.delete(vec![2]);
+            }
+            assert_eq!(tx.get(&[0]).unwrap(), Some(vec![0]));
+            assert_eq!(tx.get(&[1]).unwrap(), None);
+            assert_eq!(tx.get(&[2]).unwrap(), Some(vec![20]));
+            tx.commit();
+        }
+        assert_eq!(staged.get(&[1]).unwrap(), None);
+        assert_eq!(staged.get(&[5]).unwrap(), Some(vec![5]));
+        {
+            let mut discarded = staged.child();
+            discarded.put(vec![9], vec![9]);
+        }
+        assert_eq!(staged.get(&[9]).unwrap(), None);
+        staged.commit(&[]).unwrap();
+        assert_eq!(staged.merk().get(&[2]).unwrap(), Some(vec![20]));
+        assert_eq!(staged.merk().get(&[1]).unwrap(), None);
+
+        staged.discard();
+        assert_eq!(staged.get(&[1]).unwrap(), None);
+        staged.into_inner().destroy().unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Notifications of changes to the store, so indexers and other consumers can
+//! react to state changes as they are committed without diffing snapshots.
+
+use std::collections::{HashSet, LinkedList};
+use std::sync::mpsc::{channel, Receiver};
+
+use super::Merk;
+use crate::tree::{Entry, OpRef};
+
+/// A change to a single key, emitted once the batch containing it has been
+/// committed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// The key was set to the given value.
+    Put(Vec<u8>, Vec<u8>),
+    /// The key was deleted.
+    Delete(Vec<u8>),
+}
+
+impl ChangeEvent {
+    /// The key which was changed.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            ChangeEvent::Put(key, _) => key,
+            ChangeEvent::Delete(key) => key,
+        }
+    }
+}
+
+impl Merk {
+    /// Subscribes to changes of keys starting with `prefix` (an empty prefix
+    /// matches every key). Events are sent in key order for each batch, after
+    /// the batch has been written to the database.
+    ///
+    /// The subscription ends when the returned `Receiver` is dropped.
+    pub fn subscribe(&mut self, prefix: &[u8]) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push((prefix.to_vec(), sender));
+        receiver
+    }
+
+    /// Sends the events for a committed batch to all matching subscribers,
+    /// dropping the subscriptions whose receiver has gone away. Deletes of
+    /// keys which were not in the tree (and so are not in `deleted_keys`) are
+    /// not reported.
+    pub(crate) fn notify<E: Entry>(&mut self, batch: &[E], deleted_keys: &LinkedList<Vec<u8>>) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        let deleted_keys: HashSet<&[u8]> = deleted_keys.iter().map(Vec::as_slice).collect();
+        self.subscribers.retain(|(prefix, sender)| {
+            batch
+                .iter()
+                .filter(|entry| entry.key().starts_with(prefix))
+                .filter_map(|entry| match entry.op() {
+                    OpRef::Put(value) => {
+                        Some(ChangeEvent::Put(entry.key().to_vec(), value.to_vec()))
+                    }
+                    OpRef::Delete if deleted_keys.contains(entry.key()) => {
+                        Some(ChangeEvent::Delete(entry.key().to_vec()))
+                    }
+                    OpRef::Delete => None,
+                    // moves are resolved into puts and deletes before commit
+                    OpRef::Move { .. } => None,
+                    // the value is unchanged
+                    OpRef::SetFlags(_) => None,
+                })
+                .all(|event| sender.send(event).is_ok())
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ChangeEvent;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn subscribe_prefix() {
+        let mut merk = TempMerk::new().unwrap();
+        let all = merk.subscribe(&[]);
+        let prefixed = merk.subscribe(&[1]);
+
+        merk.apply(
+            &[(vec![0], Op::Put(vec![0])), (vec![1, 1], Op::Put(vec![1]))],
+            &[],
+        )
+        .unwrap();
+        merk.apply(&[(vec![1, 1], Op::Delete)], &[]).unwrap();
+
+        assert_eq!(
+            all.try_iter().collect::<Vec<_>>(),
+            vec![
+                ChangeEvent::Put(vec![0], vec![0]),
+                ChangeEvent::Put(vec![1, 1], vec![1]),
+                ChangeEvent::Delete(vec![1, 1]),
+            ]
+        );
+        assert_eq!(
+            prefixed.try_iter().collect::<Vec<_>>(),
+            vec![
+                ChangeEvent::Put(vec![1, 1], vec![1]),
+                ChangeEvent::Delete(vec![1, 1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_events_for_missing_keys() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&[(vec![2], Op::Put(vec![2]))], &[]).unwrap();
+        let events = merk.subscribe(&[]);
+
+        merk.apply(&[(vec![1], Op::Delete)], &[]).unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn dropped_receiver_unsubscribes() {
+        let mut merk = TempMerk::new().unwrap();
+        drop(merk.subscribe(&[]));
+
+        merk.apply(&[(vec![1], Op::Put(vec![1]))], &[]).unwrap();
+        assert!(merk.subscribers.is_empty());
+    }
+}
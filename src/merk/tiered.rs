@@ -0,0 +1,586 @@
+//! Tiered storage, where rarely accessed subtrees are moved to a cold store
+//! (such as a bucket of an object storage service) and read back on demand.
+//!
+//! `Merk::offload` writes the nodes of a subtree to a `ColdStore` as a single
+//! object named after the subtree's hash, and replaces their local records
+//! with small records in the cold column family mapping each key to that hash.
+//! When a walk of the tree reaches a node which is not stored locally, the
+//! object is read back, checked against the hash (so a cold store can not
+//! change the state it returns), and the node is read from it. Reads do not
+//! write to the store: the objects read are kept in memory, and the next
+//! commit writes their nodes back into the store along with its changes, after
+//! which reads and writes of the subtree work as if it had never been
+//! offloaded.
+//!
+//! Only walks of the tree read from the cold store. Operations which read the
+//! node column family in key order instead, such as `Merk::chunks`, scans,
+//! snapshots and the canonical state hash, fail with `Error::Offloaded` while
+//! any node is offloaded, so call `Merk::rehydrate_all` before them
+//! (`Merk::raw_iter` does not check, and does not see offloaded entries).
+//! Objects are never deleted from the cold store, as checkpoints and backups
+//! of the store may still refer to them.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{DBPinnableSlice, DEFAULT_COLUMN_FAMILY_NAME};
+
+use super::{Merk, MerkSource, PendingWrite, TreeDb, COLD_CF_NAME, VALUES_CF_NAME};
+use crate::tree::{kv_hash_with_flags, FetchBytes, Hasher, TreeRef};
+use crate::{Error, Hash, Result};
+
+/// Somewhere offloaded subtrees are stored, see the `tiered` module. It is
+/// shared by the readers of a store, so it is called through a shared
+/// reference.
+pub trait ColdStore: Send + Sync {
+    /// Writes the object with the given name, replacing any existing object
+    /// of that name.
+    fn put(&self, name: &str, data: &[u8]) -> Result<()>;
+
+    /// Reads the object with the given name.
+    fn get(&self, name: &str) -> Result<Vec<u8>>;
+}
+
+/// A cold store which stores objects as files in a directory, for tests or
+/// for a slower local disk.
+pub struct DirectoryColdStore {
+    path: PathBuf,
+}
+
+impl DirectoryColdStore {
+    /// Uses the directory at `path`, creating it if it does not exist.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<DirectoryColdStore> {
+        fs::create_dir_all(&path)?;
+        Ok(DirectoryColdStore {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+}
+
+impl ColdStore for DirectoryColdStore {
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        // write to a temporary file first so objects are replaced atomically
+        let tmp_path = self.path.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, self.path.join(name))?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.path.join(name))?)
+    }
+}
+
+/// A node of an offloaded subtree: its key, its record, and its value if it
+/// is stored apart from the record.
+type ColdNode = (Vec<u8>, Vec<u8>, Option<Vec<u8>>);
+
+/// The record of an offloaded node, and its value if the value is stored apart
+/// from the record.
+type OffloadedNode = (Vec<u8>, Option<Vec<u8>>);
+
+/// The nodes of a checked offloaded subtree, by key.
+type ColdObject = BTreeMap<Vec<u8>, OffloadedNode>;
+
+/// The encoding of a node read by a `MerkSource`: pinned in the database's
+/// cache, or read from an offloaded subtree.
+pub enum NodeBytes<'a> {
+    Local(DBPinnableSlice<'a>),
+    Offloaded(Vec<u8>),
+}
+
+impl AsRef<[u8]> for NodeBytes<'_> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            NodeBytes::Local(bytes) => bytes,
+            NodeBytes::Offloaded(bytes) => bytes,
+        }
+    }
+}
+
+/// The cold store of a `Merk`, and the offloaded subtrees read from it since
+/// the last commit, which the next commit writes back into the store.
+#[derive(Default)]
+pub(crate) struct ColdTier {
+    store: Option<Arc<dyn ColdStore>>,
+    read: Mutex<HashMap<Hash, Arc<ColdObject>>>,
+}
+
+impl ColdTier {
+    /// Returns the checked subtree offloaded as the object named after
+    /// `hash`, reading it from the cold store unless it was read since the
+    /// last commit.
+    fn object(&self, hash: &Hash) -> Result<Arc<ColdObject>> {
+        if let Some(object) = self.read.lock().unwrap().get(hash) {
+            return Ok(object.clone());
+        }
+        let store = self
+            .store
+            .as_deref()
+            .ok_or_else(|| Error::Fetch("Nodes are offloaded, but no cold store is set".into()))?;
+        let object: ColdObject = load_subtree(store, hash)?
+            .into_iter()
+            .map(|(key, record, maybe_value)| (key, (record, maybe_value)))
+            .collect();
+        let object = Arc::new(object);
+        self.read.lock().unwrap().insert(*hash, object.clone());
+        Ok(object)
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn read_bytes(input: &mut &[u8]) -> Result<Vec<u8>> {
+    let invalid = || Error::Decode("Invalid offloaded subtree".into());
+    if input.len() < 4 {
+        return Err(invalid());
+    }
+    let (len, rest) = input.split_at(4);
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return Err(invalid());
+    }
+    let (bytes, rest) = rest.split_at(len);
+    *input = rest;
+    Ok(bytes.to_vec())
+}
+
+/// Encodes the nodes of a subtree, root first, as the length-prefixed key,
+/// record and (after a flag byte) separately stored value of each node.
+fn encode_nodes(nodes: &[ColdNode]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for (key, record, maybe_value) in nodes {
+        write_bytes(&mut bytes, key);
+        write_bytes(&mut bytes, record);
+        match maybe_value {
+            Some(value) => {
+                bytes.push(1);
+                write_bytes(&mut bytes, value);
+            }
+            None => bytes.push(0),
+        }
+    }
+    bytes
+}
+
+fn decode_nodes(mut bytes: &[u8]) -> Result<Vec<ColdNode>> {
+    let mut nodes = vec![];
+    while !bytes.is_empty() {
+        let key = read_bytes(&mut bytes)?;
+        let record = read_bytes(&mut bytes)?;
+        let (&flag, rest) = bytes
+            .split_first()
+            .ok_or_else(|| Error::Decode("Invalid offloaded subtree".into()))?;
+        bytes = rest;
+        let maybe_value = match flag {
+            0 => None,
+            _ => Some(read_bytes(&mut bytes)?),
+        };
+        nodes.push((key, record, maybe_value));
+    }
+    Ok(nodes)
+}
+
+/// Checks the subtree rooted at `nodes[index]` and returns its hash. Every
+/// child must be in `nodes`, and have the hash its parent links to.
+fn verify_subtree(nodes: &[ColdNode], index: usize, visited: &mut [bool]) -> Result<Hash> {
+    let (key, record, maybe_value) = &nodes[index];
+    visited[index] = true;
+    let node = TreeRef::decode(key, record)?;
+    let violation = |detail: &str| Error::InvariantViolation {
+        key: key.clone(),
+        detail: detail.into(),
+    };
+    let value = node
+        .value()
+        .or(maybe_value.as_deref())
+        .ok_or_else(|| violation("Offloaded value is missing"))?;
+    if kv_hash_with_flags::<Hasher>(key, value, node.flags())? != *node.kv_hash() {
+        return Err(violation("Key/value hash does not match the entry"));
+    }
+    for &left in [true, false].iter() {
+        if let Some(link) = node.link(left) {
+            let child = nodes
+                .binary_search_by(|(key, _, _)| key.as_slice().cmp(link.key()))
+                .map_err(|_| violation("Offloaded child is missing"))?;
+            if verify_subtree(nodes, child, visited)? != *link.hash() {
+                return Err(violation("Node hash does not match its link"));
+            }
+        }
+    }
+    Ok(node.hash())
+}
+
+/// Reads the subtree offloaded as the object named after `hash`, and checks
+/// that it has that hash.
+fn load_subtree(cold_store: &dyn ColdStore, hash: &Hash) -> Result<Vec<ColdNode>> {
+    let mut nodes = decode_nodes(&cold_store.get(&hex::encode(hash))?)?;
+    if nodes.is_empty() {
+        return Err(Error::Decode("Offloaded subtree is empty".into()));
+    }
+    // the root is first, then the rest in key order
+    let root = nodes.remove(0);
+    let root_index = nodes
+        .binary_search_by(|(key, _, _)| key.cmp(&root.0))
+        .unwrap_or_else(|index| index);
+    nodes.insert(root_index, root);
+    let mut visited = vec![false; nodes.len()];
+    let actual = verify_subtree(&nodes, root_index, &mut visited)?;
+    if actual != *hash {
+        return Err(Error::HashMismatch(*hash, actual));
+    }
+    if visited.contains(&false) {
+        return Err(Error::Decode("Offloaded subtree has extra nodes".into()));
+    }
+    Ok(nodes)
+}
+
+fn decode_cold_record(bytes: &[u8]) -> Result<Hash> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::Decode("Invalid cold record".into()))
+}
+
+/// Returns the hash of the subtree the node with the given key is offloaded
+/// in, if it is offloaded.
+fn offloaded_in(db: &TreeDb, key: &[u8]) -> Result<Option<Hash>> {
+    let cold_cf = db.cf_handle(COLD_CF_NAME).unwrap();
+    db.get_pinned_cf(cold_cf, key)?
+        .map(|bytes| decode_cold_record(&bytes))
+        .transpose()
+}
+
+/// Called when a node is not found locally: if it is offloaded, reads its
+/// record and separately stored value from its subtree, without writing to
+/// the store.
+pub(crate) fn offloaded_node(
+    db: &TreeDb,
+    cold: Option<&ColdTier>,
+    key: &[u8],
+) -> Result<Option<OffloadedNode>> {
+    let hash = match offloaded_in(db, key)? {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+    let cold = cold.ok_or_else(|| {
+        Error::Fetch(format!(
+            "Node {:?} is offloaded, but no cold store is set",
+            key
+        ))
+    })?;
+    match cold.object(&hash)?.get(key) {
+        Some(node) => Ok(Some(node.clone())),
+        None => Err(Error::Decode("Offloaded subtree is missing a node".into())),
+    }
+}
+
+impl Merk {
+    /// Sets the cold store subtrees are offloaded to and read back from, see
+    /// the `tiered` module. A store with offloaded subtrees needs it set
+    /// again each time the store is opened.
+    pub fn set_cold_store<S: ColdStore + 'static>(&mut self, cold_store: S) {
+        self.cold.store = Some(Arc::new(cold_store));
+    }
+
+    /// Offloads the subtree rooted at the node with the given key to the cold
+    /// store, and returns the number of nodes offloaded. Offloaded subtrees
+    /// within it are read through, and included in the new object.
+    ///
+    /// The root node can not be offloaded, as it is read whenever the store is
+    /// opened. The in-memory tree is reloaded from its root, so nodes cached
+    /// in memory are read again as they are needed.
+    pub fn offload(&mut self, key: &[u8]) -> Result<u64> {
+        let cold_store = self
+            .cold
+            .store
+            .clone()
+            .ok_or_else(|| Error::Fetch("No cold store is set".into()))?;
+        let is_root = self.use_tree(|maybe_tree| maybe_tree.is_some_and(|tree| tree.key() == key));
+        if is_root {
+            return Err(Error::Key("The root node can not be offloaded".into()));
+        }
+
+        let source = self.source();
+        let mut nodes: Vec<ColdNode> = vec![];
+        let mut hash = None;
+        let mut stack = vec![key.to_vec()];
+        while let Some(key) = stack.pop() {
+            let record = source
+                .fetch_bytes(&key)?
+                .ok_or_else(|| Error::KeyNotFound(format!("{:?}", key)))?
+                .as_ref()
+                .to_vec();
+            let node = TreeRef::decode(&key, &record)?;
+            hash.get_or_insert_with(|| node.hash());
+            let maybe_value = match node.value() {
+                Some(_) => None,
+                None => Some(source.fetch_value(&key)?),
+            };
+            for &left in [true, false].iter() {
+                if let Some(link) = node.link(left) {
+                    stack.push(link.key().to_vec());
+                }
+            }
+            nodes.push((key, record, maybe_value));
+        }
+        let hash = hash.unwrap();
+        // the root first, then the rest in key order
+        nodes[1..].sort_by(|a, b| a.0.cmp(&b.0));
+        cold_store.put(&hex::encode(hash), &encode_nodes(&nodes))?;
+
+        let cold_cf = self.db.cf_handle(COLD_CF_NAME).unwrap();
+        let values_cf = self.db.cf_handle(VALUES_CF_NAME).unwrap();
+        let mut batch = rocksdb::WriteBatch::default();
+        for (key, _, maybe_value) in nodes.iter() {
+            if maybe_value.is_some() {
+                batch.delete_cf(values_cf, key);
+            }
+            batch.delete_cf(self.db.nodes_cf(), key);
+            batch.put_cf(cold_cf, key, hash);
+        }
+        self.write(batch)?;
+        self.load_root()?;
+        Ok(nodes.len() as u64)
+    }
+
+    /// Returns the number of nodes which are offloaded to the cold store.
+    pub fn offloaded_count(&self) -> u64 {
+        let cold_cf = self.db.cf_handle(COLD_CF_NAME).unwrap();
+        self.db
+            .iterator_cf(cold_cf, rocksdb::IteratorMode::Start)
+            .count() as u64
+    }
+
+    /// Returns whether any node is offloaded to the cold store.
+    pub(crate) fn has_offloaded(&self) -> bool {
+        let cold_cf = self.db.cf_handle(COLD_CF_NAME).unwrap();
+        let mut iter = self.db.raw_iterator_cf(cold_cf);
+        iter.seek_to_first();
+        iter.valid()
+    }
+
+    /// Fails with `Error::Offloaded` if any node is offloaded, for the
+    /// operations which read the node column family in key order and so
+    /// would not see offloaded entries.
+    pub(crate) fn check_not_offloaded(&self, operation: &'static str) -> Result<()> {
+        if self.has_offloaded() {
+            return Err(Error::Offloaded(operation));
+        }
+        Ok(())
+    }
+
+    /// Reads every offloaded subtree back from the cold store, so the whole
+    /// state is stored locally again. The nodes are written in one batch,
+    /// under the commit lock, but not as a commit, since the tree does not
+    /// change.
+    pub fn rehydrate_all(&mut self) -> Result<()> {
+        let cold_cf = self.db.cf_handle(COLD_CF_NAME).unwrap();
+        let hashes = self
+            .db
+            .iterator_cf(cold_cf, rocksdb::IteratorMode::Start)
+            .map(|(_, hash)| decode_cold_record(&hash))
+            .collect::<Result<BTreeSet<_>>>()?;
+        let mut writes = vec![];
+        for hash in hashes {
+            let object = self.cold.object(&hash)?;
+            self.rehydration_writes_of(&hash, &object, &mut writes)?;
+        }
+        self.cold.read.lock().unwrap().clear();
+        let batch = self.write_batch(writes);
+        self.write(batch)
+    }
+
+    /// Stages writing the nodes of the subtrees read from the cold store since
+    /// the last commit back into the store, for the commit being made.
+    pub(crate) fn rehydration_writes(&self, writes: &mut Vec<PendingWrite>) -> Result<()> {
+        let read = std::mem::take(&mut *self.cold.read.lock().unwrap());
+        for (hash, object) in read {
+            self.rehydration_writes_of(&hash, &object, writes)?;
+        }
+        Ok(())
+    }
+
+    /// Stages writing the nodes of the subtree with the given hash back into
+    /// the store, skipping those which are no longer offloaded in it, e.g.
+    /// because it was offloaded again as part of a larger subtree.
+    fn rehydration_writes_of(
+        &self,
+        hash: &Hash,
+        object: &ColdObject,
+        writes: &mut Vec<PendingWrite>,
+    ) -> Result<()> {
+        for (key, (record, maybe_value)) in object.iter() {
+            if offloaded_in(&self.db, key)?.as_ref() != Some(hash) {
+                continue;
+            }
+            if let Some(value) = maybe_value {
+                writes.push((VALUES_CF_NAME, key.clone(), Some(value.clone())));
+            }
+            writes.push((
+                DEFAULT_COLUMN_FAMILY_NAME,
+                key.clone(),
+                Some(record.clone()),
+            ));
+            writes.push((COLD_CF_NAME, key.clone(), None));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> MerkSource<'a> {
+    /// Reads the record and separately stored value of `key` from its
+    /// subtree if it is offloaded.
+    pub(crate) fn offloaded_node(&self, key: &[u8]) -> Result<Option<OffloadedNode>> {
+        offloaded_node(self.db, self.cold, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::merk::staged::StagedMerk;
+    use crate::proofs::query::Term;
+    use crate::proofs::Query;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn offload_and_rehydrate() {
+        let dir = TempDir::new("merk_cold").unwrap();
+        let mut merk = TempMerk::new().unwrap();
+        merk.set_separate_value_length(1);
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        assert!(merk.offload(&seq_key(1)).is_err());
+        merk.set_cold_store(DirectoryColdStore::new(dir.path()).unwrap());
+
+        let root_key = merk.use_tree(|tree| tree.unwrap().key().to_vec());
+        assert!(merk.offload(&root_key).is_err());
+        let left_key = merk.use_tree(|tree| tree.unwrap().link(true).unwrap().key().to_vec());
+        let root_hash = merk.root_hash();
+        let offloaded = merk.offload(&left_key).unwrap();
+        assert!(offloaded > 1);
+        assert_eq!(merk.offloaded_count(), offloaded);
+        assert_eq!(merk.root_hash(), root_hash);
+
+        // reads and proofs of the subtree read it through without writing,
+        // and the next commit writes it back
+        assert!(merk.get(&seq_key(1)).unwrap().is_some());
+        let mut query = Query::new();
+        query.insert_key(seq_key(1));
+        merk.prove(query).unwrap();
+        assert_eq!(merk.offloaded_count(), offloaded);
+        merk.apply(&[(seq_key(200), Op::Put(vec![1]))], &[])
+            .unwrap();
+        assert_eq!(merk.offloaded_count(), 0);
+        assert_eq!(merk.verify_integrity().unwrap(), 101);
+
+        // writes to an offloaded subtree work as usual
+        merk.offload(&left_key).unwrap();
+        merk.apply(&[(seq_key(2), Op::Delete)], &[]).unwrap();
+        assert_eq!(merk.get(&seq_key(2)).unwrap(), None);
+        assert!(merk.get(&seq_key(3)).unwrap().is_some());
+
+        // a cold store can't change the state it returns
+        let left_key = merk.use_tree(|tree| tree.unwrap().link(true).unwrap().key().to_vec());
+        let offloaded = merk.offload(&left_key).unwrap();
+        let hash = merk.use_tree(|tree| *tree.unwrap().link(true).unwrap().hash());
+        let name = dir.path().join(hex::encode(hash));
+        let mut nodes = decode_nodes(&fs::read(&name).unwrap()).unwrap();
+        let value = nodes[0].2.as_mut().unwrap();
+        value[0] ^= 1;
+        fs::write(&name, encode_nodes(&nodes)).unwrap();
+        assert!(merk.get(&seq_key(3)).is_err());
+        assert_eq!(merk.offloaded_count(), offloaded);
+
+        nodes[0].2.as_mut().unwrap()[0] ^= 1;
+        fs::write(&name, encode_nodes(&nodes)).unwrap();
+        merk.rehydrate_all().unwrap();
+        assert_eq!(merk.offloaded_count(), 0);
+        assert_eq!(merk.verify_integrity().unwrap(), 100);
+    }
+
+    #[test]
+    fn key_order_operations_need_rehydration() {
+        fn is_offloaded<T>(result: Result<T>) -> bool {
+            matches!(result, Err(Error::Offloaded(_)))
+        }
+
+        fn offload_left(merk: &mut Merk, dir: &TempDir) {
+            merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+            merk.set_cold_store(DirectoryColdStore::new(dir.path()).unwrap());
+            let left_key = merk.use_tree(|tree| tree.unwrap().link(true).unwrap().key().to_vec());
+            merk.offload(&left_key).unwrap();
+        }
+
+        fn check(merk: &mut Merk, offloaded: bool) {
+            let root_hash = merk.root_hash();
+            let overlap = merk.version_overlap(root_hash, root_hash);
+            assert_eq!(is_offloaded(overlap), offloaded);
+            assert_eq!(is_offloaded(merk.canonical_state_hash()), offloaded);
+            let mut query = Query::new();
+            query.insert_term(Term::range(seq_key(0)..seq_key(50)).limit(3));
+            assert_eq!(is_offloaded(merk.prove(query)), offloaded);
+            let empty = merk.prove_empty_range(seq_key(1)..seq_key(1));
+            assert_eq!(is_offloaded(empty), offloaded);
+            assert_eq!(is_offloaded(merk.snapshot()), offloaded);
+            assert_eq!(is_offloaded(merk.flat_root()), offloaded);
+            assert_eq!(is_offloaded(merk.chunks()), offloaded);
+            assert_eq!(is_offloaded(merk.dump_nodes(.., vec![])), offloaded);
+            let scanned = merk.iter_filtered(vec![]..vec![255], |_, _| true).next();
+            assert_eq!(is_offloaded(scanned.unwrap()), offloaded);
+            let entry = merk.cursor(vec![]..vec![255]).entry().map(|_| ());
+            assert_eq!(is_offloaded(entry), offloaded);
+            let partitions = merk.par_scan(2, |iter| iter.collect::<Result<Vec<_>>>());
+            assert_eq!(is_offloaded(partitions), offloaded);
+            assert_eq!(is_offloaded(merk.rebuild_index("first")), offloaded);
+            assert_eq!(
+                is_offloaded(merk.copy_prefix(&seq_key(1), &[255])),
+                offloaded
+            );
+            assert_eq!(is_offloaded(merk.canonicalize()), offloaded);
+        }
+
+        let dir = TempDir::new("merk_cold").unwrap();
+        let path = TempDir::new("merk_tiered").unwrap();
+        let mut merk = Merk::open(path.path()).unwrap();
+        merk.register_index("first", |key, _| vec![key[..1].to_vec()])
+            .unwrap();
+        offload_left(&mut merk, &dir);
+        check(&mut merk, true);
+
+        // reads and commits still work, and the commit writes back what was read
+        assert!(merk.get(&seq_key(1)).unwrap().is_some());
+        merk.apply(&[(seq_key(200), Op::Put(vec![1]))], &[])
+            .unwrap();
+        assert_eq!(merk.offloaded_count(), 0);
+        let left_key = merk.use_tree(|tree| tree.unwrap().link(true).unwrap().key().to_vec());
+        merk.offload(&left_key).unwrap();
+
+        let staged = StagedMerk::new(merk);
+        let mut iter = staged.iter(&[], None);
+        assert!(is_offloaded(iter.next().unwrap()));
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        let mut merk = staged.into_inner();
+        merk.rehydrate_all().unwrap();
+        check(&mut merk, false);
+
+        drop(merk);
+        let path = TempDir::new("merk_repair").unwrap();
+        let mut merk = Merk::open(path.path()).unwrap();
+        offload_left(&mut merk, &dir);
+        assert!(is_offloaded(merk.repair()));
+    }
+}
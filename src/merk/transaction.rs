@@ -0,0 +1,192 @@
+//! Staged changes with nested savepoints.
+//!
+//! A `Transaction` buffers puts and deletes in memory on top of a `Merk`,
+//! reading through to the store for keys it has not touched. Savepoints can be
+//! nested so that a failing step of a larger state transition reverts only its
+//! own changes, and nothing reaches the store until the transaction is
+//! committed as a single batch.
+
+use std::collections::BTreeMap;
+
+use super::Merk;
+use crate::{tree::BatchEntry, Error, Hash, Op, Result};
+
+/// A set of staged changes to a `Merk`. Dropping the transaction without
+/// calling `commit` discards its changes.
+pub struct Transaction<'a> {
+    merk: &'a mut Merk,
+    pending: BTreeMap<Vec<u8>, Op>,
+    /// For each open savepoint, the pending operation each key had before it
+    /// was first changed after the savepoint was created.
+    savepoints: Vec<BTreeMap<Vec<u8>, Option<Op>>>,
+}
+
+impl Merk {
+    /// Starts a transaction which stages changes to this store in memory.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction {
+            merk: self,
+            pending: BTreeMap::new(),
+            savepoints: vec![],
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// Gets a value for the given key, including the staged changes.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.pending.get(key) {
+            Some(Op::Put(value)) => Ok(Some(value.clone())),
+            Some(Op::Delete) => Ok(None),
+            Some(Op::Move { .. }) | Some(Op::SetFlags(_)) => {
+                unreachable!("only puts and deletes are staged")
+            }
+            None => self.merk.get(key),
+        }
+    }
+
+    /// Stages a put of `value` to `key`.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.stage(key, Op::Put(value));
+    }
+
+    /// Stages a delete of `key`.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.stage(key, Op::Delete);
+    }
+
+    fn stage(&mut self, key: Vec<u8>, op: Op) {
+        let prev_op = self.pending.insert(key.clone(), op);
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint.entry(key).or_insert(prev_op);
+        }
+    }
+
+    /// Creates a savepoint which the staged changes can later be reverted to
+    /// with `rollback_to_savepoint`. Returns the number of open savepoints.
+    pub fn savepoint(&mut self) -> usize {
+        self.savepoints.push(BTreeMap::new());
+        self.savepoints.len()
+    }
+
+    /// Closes the most recent savepoint, keeping the changes staged since it
+    /// was created as part of the enclosing savepoint (or the transaction).
+    pub fn release(&mut self) -> Result<()> {
+        let savepoint = self.pop_savepoint()?;
+        if let Some(parent) = self.savepoints.last_mut() {
+            for (key, prev_op) in savepoint {
+                parent.entry(key).or_insert(prev_op);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reverts the changes staged since the most recent savepoint was
+    /// created, and closes it.
+    pub fn rollback_to_savepoint(&mut self) -> Result<()> {
+        for (key, prev_op) in self.pop_savepoint()? {
+            match prev_op {
+                Some(op) => self.pending.insert(key, op),
+                None => self.pending.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn pop_savepoint(&mut self) -> Result<BTreeMap<Vec<u8>, Option<Op>>> {
+        self.savepoints.pop().ok_or(Error::NoSavepoint)
+    }
+
+    /// Returns the staged changes as a sorted batch.
+    pub fn batch(&self) -> Vec<BatchEntry> {
+        self.pending
+            .iter()
+            .map(|(key, op)| (key.clone(), op.clone()))
+            .collect()
+    }
+
+    /// Computes the root hash the store would have if the transaction were
+    /// committed, without writing anything.
+    pub fn root_hash(&self) -> Result<Hash> {
+        self.merk.simulate(&self.batch())
+    }
+
+    /// Applies all staged changes to the store in a single batch, along with
+    /// the given auxiliary batch. Savepoints which are still open are
+    /// released.
+    pub fn commit(self, aux: &[BatchEntry]) -> Result<()> {
+        let batch = self.batch();
+        self.merk.apply(&batch, aux)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::{Error, Op};
+
+    #[test]
+    fn staged_reads_and_commit() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&[(vec![1], Op::Put(vec![1]))], &[]).unwrap();
+        let root_hash = merk.root_hash();
+
+        let mut tx = merk.transaction();
+        tx.put(vec![2], vec![2]);
+        tx.delete(vec![1]);
+        assert_eq!(tx.get(&[1]).unwrap(), None);
+        assert_eq!(tx.get(&[2]).unwrap(), Some(vec![2]));
+        let staged_root_hash = tx.root_hash().unwrap();
+        drop(tx);
+
+        // dropped without committing
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get(&[1]).unwrap(), Some(vec![1]));
+
+        let mut tx = merk.transaction();
+        tx.put(vec![2], vec![2]);
+        tx.delete(vec![1]);
+        tx.commit(&[]).unwrap();
+        assert_eq!(merk.root_hash(), staged_root_hash);
+        assert_eq!(merk.get(&[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn nested_savepoints() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&[(vec![1], Op::Put(vec![1]))], &[]).unwrap();
+
+        let mut tx = merk.transaction();
+        tx.put(vec![2], vec![2]);
+
+        assert_eq!(tx.savepoint(), 1);
+        tx.put(vec![2], vec![20]);
+        tx.put(vec![3], vec![3]);
+
+        assert_eq!(tx.savepoint(), 2);
+        tx.delete(vec![1]);
+        tx.put(vec![3], vec![30]);
+        tx.rollback_to_savepoint().unwrap();
+        assert_eq!(tx.get(&[1]).unwrap(), Some(vec![1]));
+        assert_eq!(tx.get(&[3]).unwrap(), Some(vec![3]));
+
+        assert_eq!(tx.savepoint(), 2);
+        tx.put(vec![4], vec![4]);
+        tx.release().unwrap();
+
+        // reverting the outer savepoint also reverts the released inner one
+        tx.rollback_to_savepoint().unwrap();
+        assert!(matches!(
+            tx.rollback_to_savepoint(),
+            Err(Error::NoSavepoint)
+        ));
+        assert_eq!(tx.get(&[2]).unwrap(), Some(vec![2]));
+        assert_eq!(tx.get(&[3]).unwrap(), None);
+        assert_eq!(tx.get(&[4]).unwrap(), None);
+
+        tx.commit(&[]).unwrap();
+        assert_eq!(merk.get(&[1]).unwrap(), Some(vec![1]));
+        assert_eq!(merk.get(&[2]).unwrap(), Some(vec![2]));
+        assert_eq!(merk.get(&[3]).unwrap(), None);
+    }
+}
@@ -0,0 +1,306 @@
+//! The database as seen by one of the trees of a store.
+//!
+//! A store holds its unnamed tree in the default column family and the column
+//! families named in `CF_NAMES`, and each tree opened with `Merk::open_named`
+//! in a column family of its own for each of those, named after the tree
+//! (`<tree name>/<column family>`). Nodes are stored under their keys, so
+//! trees which may hold the same keys cannot share a column family.
+//!
+//! `TreeDb` wraps the database with the subset of its API the store uses,
+//! mapping the column family names to those of the open tree, so the rest of
+//! the store reads and writes its own tree without knowing which one it is.
+//! Methods without a column family argument use the tree's node column family.
+
+use std::path::Path;
+
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, DBIterator, DBPinnableSlice, DBRawIterator, Error,
+    IteratorMode, Options, ReadOptions, Snapshot, WriteBatch, WriteOptions, DB,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+
+use super::CF_NAMES;
+use crate::Result;
+
+type DbResult<T> = std::result::Result<T, Error>;
+
+/// The suffix of the name of a named tree's internal column family, which
+/// identifies the tree in `Merk::tree_names`.
+const INTERNAL_CF_SUFFIX: &str = "/internal";
+
+fn tree_cf_name(tree_name: &str, cf_name: &str) -> String {
+    format!("{}/{}", tree_name, cf_name)
+}
+
+/// The column families of a tree, `DEFAULT_COLUMN_FAMILY_NAME` then those in
+/// `CF_NAMES`.
+fn tree_cf_names(tree_name: &str) -> Vec<String> {
+    std::iter::once(DEFAULT_COLUMN_FAMILY_NAME)
+        .chain(CF_NAMES.iter().copied())
+        .map(|cf_name| tree_cf_name(tree_name, cf_name))
+        .collect()
+}
+
+/// Returns the column families to open besides those of the unnamed tree:
+/// the ones of the tree named `tree_name`, and any other existing ones, since
+/// RocksDB only opens a database with all of its column families.
+pub(crate) fn extra_column_families(
+    opts: &Options,
+    path: &Path,
+    tree_name: Option<&str>,
+) -> Result<Vec<ColumnFamilyDescriptor>> {
+    let mut names = match tree_name {
+        Some(tree_name) => tree_cf_names(tree_name),
+        None => vec![],
+    };
+    if super::db_exists(path) {
+        for name in DB::list_cf(opts, path)? {
+            let is_unnamed = name == DEFAULT_COLUMN_FAMILY_NAME || CF_NAMES.contains(&&*name);
+            if !is_unnamed && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Ok(names
+        .into_iter()
+        .map(|name| ColumnFamilyDescriptor::new(name, opts.clone()))
+        .collect())
+}
+
+/// Returns the names of the named trees in the store at `path`.
+pub(crate) fn tree_names(opts: &Options, path: &Path) -> Result<Vec<String>> {
+    let mut names: Vec<_> = DB::list_cf(opts, path)?
+        .into_iter()
+        .filter_map(|name| {
+            name.strip_suffix(INTERNAL_CF_SUFFIX)
+                .map(|tree_name| tree_name.to_string())
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// The database of a store, as seen by one of its trees. See the module
+/// documentation.
+pub(crate) struct TreeDb {
+    db: DB,
+    tree_name: Option<String>,
+    /// The names of the tree's column families, in the order of
+    /// `tree_cf_names`, or `None` for the unnamed tree.
+    cf_names: Option<Vec<String>>,
+}
+
+impl TreeDb {
+    pub(crate) fn new(db: DB, tree_name: Option<&str>) -> TreeDb {
+        TreeDb {
+            db,
+            tree_name: tree_name.map(str::to_string),
+            cf_names: tree_name.map(tree_cf_names),
+        }
+    }
+
+    /// Returns the database itself, for store-wide operations.
+    pub(crate) fn inner(&self) -> &DB {
+        &self.db
+    }
+
+    pub(crate) fn tree_name(&self) -> Option<&str> {
+        self.tree_name.as_deref()
+    }
+
+    /// Drops the column families of a named tree, deleting its data.
+    pub(crate) fn drop_tree(mut self) -> Result<()> {
+        for name in self.cf_names.take().unwrap_or_default() {
+            self.db.drop_cf(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the tree's column family with the given unnamed tree name.
+    pub(crate) fn cf_handle(&self, name: &str) -> Option<&ColumnFamily> {
+        match &self.cf_names {
+            None => self.db.cf_handle(name),
+            Some(cf_names) => {
+                let index = std::iter::once(DEFAULT_COLUMN_FAMILY_NAME)
+                    .chain(CF_NAMES.iter().copied())
+                    .position(|cf_name| cf_name == name)?;
+                self.db.cf_handle(&cf_names[index])
+            }
+        }
+    }
+
+    /// Returns the column family holding the tree's nodes.
+    pub(crate) fn nodes_cf(&self) -> &ColumnFamily {
+        self.cf_handle(DEFAULT_COLUMN_FAMILY_NAME).unwrap()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get(&self, key: impl AsRef<[u8]>) -> DbResult<Option<Vec<u8>>> {
+        self.db.get_cf(self.nodes_cf(), key)
+    }
+
+    pub(crate) fn get_pinned(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> DbResult<Option<DBPinnableSlice<'_>>> {
+        self.db.get_pinned_cf(self.nodes_cf(), key)
+    }
+
+    pub(crate) fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> DbResult<()> {
+        self.db.put_cf(self.nodes_cf(), key, value)
+    }
+
+    pub(crate) fn iterator(&self, mode: IteratorMode) -> DBIterator<'_> {
+        self.db.iterator_cf(self.nodes_cf(), mode)
+    }
+
+    pub(crate) fn iterator_opt(&self, mode: IteratorMode, readopts: ReadOptions) -> DBIterator<'_> {
+        self.db.iterator_cf_opt(self.nodes_cf(), readopts, mode)
+    }
+
+    pub(crate) fn raw_iterator(&self) -> DBRawIterator<'_> {
+        self.db.raw_iterator_cf(self.nodes_cf())
+    }
+
+    pub(crate) fn flush(&self) -> DbResult<()> {
+        self.db.flush_cf(self.nodes_cf())
+    }
+
+    pub(crate) fn compact_range(&self) {
+        self.db
+            .compact_range_cf::<&[u8], &[u8]>(self.nodes_cf(), None, None);
+    }
+
+    pub(crate) fn get_cf(
+        &self,
+        cf: &ColumnFamily,
+        key: impl AsRef<[u8]>,
+    ) -> DbResult<Option<Vec<u8>>> {
+        self.db.get_cf(cf, key)
+    }
+
+    pub(crate) fn get_pinned_cf(
+        &self,
+        cf: &ColumnFamily,
+        key: impl AsRef<[u8]>,
+    ) -> DbResult<Option<DBPinnableSlice<'_>>> {
+        self.db.get_pinned_cf(cf, key)
+    }
+
+    pub(crate) fn put_cf(
+        &self,
+        cf: &ColumnFamily,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> DbResult<()> {
+        self.db.put_cf(cf, key, value)
+    }
+
+    pub(crate) fn iterator_cf(&self, cf: &ColumnFamily, mode: IteratorMode) -> DBIterator<'_> {
+        self.db.iterator_cf(cf, mode)
+    }
+
+    pub(crate) fn iterator_cf_opt(
+        &self,
+        cf: &ColumnFamily,
+        readopts: ReadOptions,
+        mode: IteratorMode,
+    ) -> DBIterator<'_> {
+        self.db.iterator_cf_opt(cf, readopts, mode)
+    }
+
+    pub(crate) fn raw_iterator_cf(&self, cf: &ColumnFamily) -> DBRawIterator<'_> {
+        self.db.raw_iterator_cf(cf)
+    }
+
+    pub(crate) fn raw_iterator_cf_opt(
+        &self,
+        cf: &ColumnFamily,
+        readopts: ReadOptions,
+    ) -> DBRawIterator<'_> {
+        self.db.raw_iterator_cf_opt(cf, readopts)
+    }
+
+    pub(crate) fn compact_range_cf(&self, cf: &ColumnFamily) {
+        self.db.compact_range_cf::<&[u8], &[u8]>(cf, None, None);
+    }
+
+    pub(crate) fn write_opt(&self, batch: WriteBatch, opts: &WriteOptions) -> DbResult<()> {
+        self.db.write_opt(batch, opts)
+    }
+
+    pub(crate) fn snapshot(&self) -> Snapshot<'_> {
+        self.db.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use crate::test_utils::*;
+    use crate::{Merk, Op};
+
+    #[test]
+    fn named_trees() {
+        let dir = TempDir::new("named_trees").unwrap();
+        let path = dir.path().join("store");
+        let batches = [
+            make_batch_seq(0..100),
+            make_batch_rand(100, 1),
+            make_batch_rand(100, 2),
+        ];
+        let mut root_hashes = vec![];
+        for (i, tree_name) in [None, Some("a"), Some("b")].iter().enumerate() {
+            let mut merk = match tree_name {
+                Some(tree_name) => Merk::open_named(&path, tree_name).unwrap(),
+                None => Merk::open(&path).unwrap(),
+            };
+            assert_eq!(merk.tree_name(), *tree_name);
+            let aux = [(vec![0], Op::Put(vec![i as u8]))];
+            merk.apply(&batches[i], &aux).unwrap();
+            root_hashes.push(merk.root_hash());
+        }
+        assert_eq!(Merk::tree_names(&path).unwrap(), vec!["a", "b"]);
+
+        for (i, tree_name) in ["a", "b"].iter().enumerate() {
+            let mut merk = Merk::open_named(&path, tree_name).unwrap();
+            assert_eq!(merk.root_hash(), root_hashes[i + 1]);
+            assert_eq!(merk.get_aux(&[0]).unwrap(), Some(vec![i as u8 + 1]));
+            merk.verify_integrity().unwrap();
+
+            merk.set_retained_versions(1);
+            merk.apply(&make_del_batch_rand(10, i as u64 + 1), &[])
+                .unwrap();
+            merk.rollback().unwrap();
+            assert_eq!(merk.root_hash(), root_hashes[i + 1]);
+        }
+        let merk = Merk::open(&path).unwrap();
+        assert_eq!(merk.root_hash(), root_hashes[0]);
+        assert_eq!(merk.get_aux(&[0]).unwrap(), Some(vec![0]));
+        assert!(merk.get(&seq_key(0)).unwrap().is_some());
+    }
+
+    #[test]
+    fn destroy_named_tree() {
+        let dir = TempDir::new("destroy_named_tree").unwrap();
+        let path = dir.path().join("store");
+        for tree_name in ["a", "b"].iter() {
+            let mut merk = Merk::open_named(&path, tree_name).unwrap();
+            merk.apply(&make_batch_seq(0..10), &[]).unwrap();
+        }
+
+        let merk = Merk::open_named(&path, "a").unwrap();
+        let checkpoint = merk.checkpoint(dir.path().join("checkpoint")).unwrap();
+        assert_eq!(checkpoint.tree_name(), Some("a"));
+        assert_eq!(checkpoint.root_hash(), merk.root_hash());
+        merk.destroy().unwrap();
+
+        assert_eq!(Merk::tree_names(&path).unwrap(), vec!["b"]);
+        let merk = Merk::open_named(&path, "b").unwrap();
+        assert!(merk.get(&seq_key(0)).unwrap().is_some());
+        drop(merk);
+        let merk = Merk::open_named(&path, "a").unwrap();
+        assert!(merk.get(&seq_key(0)).unwrap().is_none());
+    }
+}
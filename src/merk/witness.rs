@@ -0,0 +1,96 @@
+//! Recording the nodes a batch touches into a `Witness`, see `Merk::witness`.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::{check_batch_keys, load_root, Merk, MerkSource};
+use crate::proofs::witness::Witness;
+use crate::tree::{resolve_moves, Batch, Fetch, Tree, Walker};
+use crate::Result;
+
+/// A source which keeps the record of every node it fetches.
+#[derive(Clone)]
+struct RecordingSource<'a> {
+    inner: MerkSource<'a>,
+    nodes: &'a Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl RecordingSource<'_> {
+    fn record(&self, mut tree: Tree) -> Result<Tree> {
+        tree.load_value_with(|key| self.inner.fetch_value(key))?;
+        self.nodes
+            .lock()
+            .unwrap()
+            .entry(tree.key().to_vec())
+            .or_insert_with(|| tree.encode());
+        Ok(tree)
+    }
+}
+
+impl Fetch for RecordingSource<'_> {
+    fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
+        self.inner
+            .fetch_by_key(key)?
+            .map(|tree| self.record(tree))
+            .transpose()
+    }
+
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.inner.fetch_value(key)
+    }
+}
+
+impl Merk {
+    /// Returns a witness of the nodes applying `batch` reads, along with the
+    /// paths to the keys in `reads`, so the batch can be applied and the keys
+    /// read with `apply_stateless` and `Witness::get` by nodes which only know
+    /// the current root hash.
+    ///
+    /// The nodes are read from the database as of the last commit.
+    pub fn witness(&self, reads: &[&[u8]], batch: &Batch) -> Result<Witness> {
+        check_batch_keys(batch)?;
+        let nodes = Mutex::new(BTreeMap::new());
+        let source = RecordingSource {
+            inner: self.source(),
+            nodes: &nodes,
+        };
+        let root = match load_root(&self.db)? {
+            Some(root) => source.record(root)?,
+            None => return Ok(Witness::new(None, BTreeMap::new())),
+        };
+        let root_key = root.key().to_vec();
+
+        for key in reads {
+            record_path(&root, key, &source)?;
+        }
+        let resolved = resolve_moves(batch, |key| {
+            record_path(&root, key, &source)?;
+            self.get(key)
+        })?;
+        let batch = resolved.as_deref().unwrap_or(batch);
+        Walker::apply_to(Some(Walker::new(root, source.clone())), batch, source)?;
+
+        Ok(Witness::new(Some(root_key), nodes.into_inner().unwrap()))
+    }
+}
+
+/// Fetches the nodes on the path from `root` to `key` through `source`.
+fn record_path(root: &Tree, key: &[u8], source: &RecordingSource) -> Result<()> {
+    let mut next = child_key(root, key);
+    while let Some(child) = next {
+        let tree = source.fetch_by_key_expect(&child)?;
+        next = child_key(&tree, key);
+    }
+    Ok(())
+}
+
+/// Returns the key of the child of `tree` on the side of `key`, if `key` is
+/// not the node's own key.
+fn child_key(tree: &Tree, key: &[u8]) -> Option<Vec<u8>> {
+    let left = match tree.interned_key().cmp_bytes(key).reverse() {
+        Ordering::Equal => return None,
+        ordering => ordering == Ordering::Less,
+    };
+    tree.link(left).map(|link| link.key().to_vec())
+}
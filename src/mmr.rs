@@ -0,0 +1,351 @@
+//! A Merkle Mountain Range (MMR), an append-only accumulator for event logs.
+//!
+//! An MMR over `len` leaves is a list of perfect binary Merkle trees ("peaks"),
+//! one for each bit set in `len`, from the largest to the smallest. Appending a
+//! leaf only adds nodes on the right edge, merging peaks of equal height, so
+//! previously computed nodes never change. The root "bags" the peaks together
+//! with the number of leaves, and an inclusion proof for a leaf is the sibling
+//! hashes on its path up to its peak plus the other peaks, so proofs are
+//! logarithmic in the length of the log.
+//!
+//! Nodes are identified by their level (0 for leaves) and their index within
+//! that level, so a node at level `h` and index `i` is the root of the leaves
+//! `i * 2^h..(i + 1) * 2^h`. Hashes use the same hash function as the tree,
+//! with distinct prefixes so they cannot be confused with tree nodes.
+
+use std::collections::HashMap;
+
+use sha2::Digest;
+
+use crate::tree::{Hash, Hasher, HASH_LENGTH, NULL_HASH};
+use crate::{Error, Result};
+
+const LEAF_PREFIX: u8 = 2;
+const PARENT_PREFIX: u8 = 3;
+const ROOT_PREFIX: u8 = 4;
+
+/// The level and index of a node, see the module documentation.
+pub type NodeId = (u8, u64);
+
+/// Hashes a leaf of the log.
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Hasher::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Hashes an inner node from the hashes of its children.
+pub fn parent_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Hasher::new();
+    hasher.update([PARENT_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Computes the root of an MMR with `len` leaves from the hashes of its
+/// peaks, largest first. The root of an empty MMR is `NULL_HASH`.
+pub fn bag_peaks(len: u64, peaks: &[Hash]) -> Hash {
+    if len == 0 {
+        return NULL_HASH;
+    }
+    let mut hasher = Hasher::new();
+    hasher.update([ROOT_PREFIX]);
+    hasher.update(len.to_be_bytes());
+    for peak in peaks {
+        hasher.update(peak);
+    }
+    hasher.finalize().into()
+}
+
+/// Returns the peaks of an MMR with `len` leaves, largest first.
+pub fn peaks(len: u64) -> Vec<NodeId> {
+    (0..64u8)
+        .rev()
+        .filter(|level| len & (1 << level) != 0)
+        .map(|level| (level, (len >> level) - 1))
+        .collect()
+}
+
+/// Storage for the nodes of an MMR.
+pub trait MmrStore {
+    /// Returns the hash of the given node, or `None` if it has not been
+    /// written.
+    fn node(&self, id: NodeId) -> Result<Option<Hash>>;
+}
+
+fn get_node<S: MmrStore + ?Sized>(store: &S, id: NodeId) -> Result<Hash> {
+    store
+        .node(id)?
+        .ok_or_else(|| Error::Fetch(format!("Missing MMR node {:?}", id)))
+}
+
+/// Returns the nodes to write to append `leaves` to an MMR in `store` which
+/// has `len` leaves.
+pub fn append<S, I>(store: &S, len: u64, leaves: I) -> Result<Vec<(NodeId, Hash)>>
+where
+    S: MmrStore + ?Sized,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut written: HashMap<NodeId, Hash> = HashMap::new();
+    let mut nodes = vec![];
+    for (n, leaf) in (len..).zip(leaves) {
+        let mut id = (0, n);
+        let mut hash = leaf_hash(leaf.as_ref());
+        loop {
+            written.insert(id, hash);
+            nodes.push((id, hash));
+            let (level, index) = id;
+            if index % 2 == 0 {
+                break;
+            }
+            let sibling = (level, index - 1);
+            let left = match written.get(&sibling) {
+                Some(hash) => *hash,
+                None => get_node(store, sibling)?,
+            };
+            hash = parent_hash(&left, &hash);
+            id = (level + 1, index / 2);
+        }
+    }
+    Ok(nodes)
+}
+
+/// Returns the root of the MMR in `store` with `len` leaves.
+pub fn root<S: MmrStore + ?Sized>(store: &S, len: u64) -> Result<Hash> {
+    let peaks = peaks(len)
+        .into_iter()
+        .map(|id| get_node(store, id))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(bag_peaks(len, &peaks))
+}
+
+/// Creates a proof that the leaf at `index` is in the MMR in `store` with
+/// `len` leaves.
+pub fn prove<S: MmrStore + ?Sized>(store: &S, len: u64, index: u64) -> Result<InclusionProof> {
+    if index >= len {
+        return Err(Error::IndexOutOfBounds(format!(
+            "Leaf {} is not in a log of length {}",
+            index, len
+        )));
+    }
+
+    let (peak_level, _) = peak_of(len, index);
+    let siblings = (0..peak_level)
+        .map(|level| get_node(store, (level, (index >> level) ^ 1)))
+        .collect::<Result<_>>()?;
+    let peaks = peaks(len)
+        .into_iter()
+        .map(|id| get_node(store, id))
+        .collect::<Result<_>>()?;
+
+    Ok(InclusionProof {
+        index,
+        len,
+        siblings,
+        peaks,
+    })
+}
+
+/// Returns the level of the peak above the leaf at `index` and its position
+/// in the list of peaks.
+fn peak_of(len: u64, index: u64) -> (u8, usize) {
+    let mut start = 0;
+    for (position, (level, _)) in peaks(len).into_iter().enumerate() {
+        start += 1 << level;
+        if index < start {
+            return (level, position);
+        }
+    }
+    unreachable!("index is less than len")
+}
+
+/// A proof that a leaf is in an MMR with a given root, see `prove`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InclusionProof {
+    /// The index of the leaf.
+    pub index: u64,
+    /// The number of leaves in the MMR.
+    pub len: u64,
+    /// The siblings on the path from the leaf to its peak, lowest first.
+    pub siblings: Vec<Hash>,
+    /// The hashes of all the peaks, largest first.
+    pub peaks: Vec<Hash>,
+}
+
+impl InclusionProof {
+    /// Checks that `data` is the leaf at `self.index` of the MMR with root
+    /// `root`.
+    pub fn verify(&self, root: &Hash, data: &[u8]) -> Result<()> {
+        if self.index >= self.len {
+            return Err(Error::Proof("Leaf index is out of bounds".into()));
+        }
+        let (peak_level, position) = peak_of(self.len, self.index);
+        if self.siblings.len() != peak_level as usize || self.peaks.len() != peaks(self.len).len() {
+            return Err(Error::Proof("Proof has the wrong number of hashes".into()));
+        }
+
+        let mut hash = leaf_hash(data);
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            hash = if (self.index >> level) & 1 == 0 {
+                parent_hash(&hash, sibling)
+            } else {
+                parent_hash(sibling, &hash)
+            };
+        }
+        if hash != self.peaks[position] {
+            return Err(Error::Proof("Leaf is not under its peak".into()));
+        }
+
+        let actual = bag_peaks(self.len, &self.peaks);
+        if actual != *root {
+            return Err(Error::HashMismatch(*root, actual));
+        }
+        Ok(())
+    }
+
+    /// Encodes the proof into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let hashes = self.siblings.len() + self.peaks.len();
+        let mut bytes = Vec::with_capacity(17 + hashes * HASH_LENGTH);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes.push(self.siblings.len() as u8);
+        for hash in self.siblings.iter().chain(self.peaks.iter()) {
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    /// Decodes a proof from bytes.
+    pub fn decode(bytes: &[u8]) -> Result<InclusionProof> {
+        if bytes.len() < 17 || !(bytes.len() - 17).is_multiple_of(HASH_LENGTH) {
+            return Err(Error::Decode("Invalid MMR proof length".into()));
+        }
+        let mut int = [0; 8];
+        int.copy_from_slice(&bytes[..8]);
+        let index = u64::from_be_bytes(int);
+        int.copy_from_slice(&bytes[8..16]);
+        let len = u64::from_be_bytes(int);
+
+        let mut hashes: Vec<Hash> = bytes[17..]
+            .chunks(HASH_LENGTH)
+            .map(|chunk| {
+                let mut hash = NULL_HASH;
+                hash.copy_from_slice(chunk);
+                hash
+            })
+            .collect();
+        let sibling_count = bytes[16] as usize;
+        if sibling_count > hashes.len() {
+            return Err(Error::Decode("Invalid MMR proof length".into()));
+        }
+        let peaks = hashes.split_off(sibling_count);
+
+        Ok(InclusionProof {
+            index,
+            len,
+            siblings: hashes,
+            peaks,
+        })
+    }
+}
+
+/// An MMR held in memory.
+#[derive(Clone, Debug, Default)]
+pub struct Mmr {
+    nodes: HashMap<NodeId, Hash>,
+    len: u64,
+}
+
+impl Mmr {
+    pub fn new() -> Mmr {
+        Mmr::default()
+    }
+
+    /// Returns the number of leaves.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the MMR has no leaves.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends a leaf.
+    pub fn push(&mut self, data: &[u8]) {
+        let nodes = append(self, self.len, [data].iter()).expect("nodes are in memory");
+        self.nodes.extend(nodes);
+        self.len += 1;
+    }
+
+    /// Returns the root hash.
+    pub fn root(&self) -> Hash {
+        root(self, self.len).expect("nodes are in memory")
+    }
+
+    /// Creates a proof that the leaf at `index` is in the MMR.
+    pub fn prove(&self, index: u64) -> Result<InclusionProof> {
+        prove(self, self.len, index)
+    }
+}
+
+impl MmrStore for Mmr {
+    fn node(&self, id: NodeId) -> Result<Option<Hash>> {
+        Ok(self.nodes.get(&id).copied())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peaks_follow_bits() {
+        assert!(peaks(0).is_empty());
+        assert_eq!(peaks(1), vec![(0, 0)]);
+        assert_eq!(peaks(6), vec![(2, 0), (1, 2)]);
+        assert_eq!(peaks(7), vec![(2, 0), (1, 2), (0, 6)]);
+    }
+
+    #[test]
+    fn prove_and_verify() {
+        let mut mmr = Mmr::new();
+        assert_eq!(mmr.root(), NULL_HASH);
+
+        let mut roots = vec![];
+        for i in 0..37u64 {
+            mmr.push(&i.to_be_bytes());
+            roots.push(mmr.root());
+
+            for j in 0..=i {
+                let proof = mmr.prove(j).unwrap();
+                proof.verify(&mmr.root(), &j.to_be_bytes()).unwrap();
+                assert_eq!(InclusionProof::decode(&proof.encode()).unwrap(), proof);
+            }
+        }
+        roots.dedup();
+        assert_eq!(roots.len(), 37);
+
+        // 4 leaves merge into a single peak
+        let mut four = Mmr::new();
+        (0..4u64).for_each(|i| four.push(&i.to_be_bytes()));
+        let left = parent_hash(
+            &leaf_hash(&0u64.to_be_bytes()),
+            &leaf_hash(&1u64.to_be_bytes()),
+        );
+        let right = parent_hash(
+            &leaf_hash(&2u64.to_be_bytes()),
+            &leaf_hash(&3u64.to_be_bytes()),
+        );
+        assert_eq!(four.root(), bag_peaks(4, &[parent_hash(&left, &right)]));
+
+        let proof = mmr.prove(20).unwrap();
+        assert!(proof.verify(&mmr.root(), &21u64.to_be_bytes()).is_err());
+        assert!(proof.verify(&roots[20], &20u64.to_be_bytes()).is_err());
+        assert!(mmr.prove(37).is_err());
+    }
+}
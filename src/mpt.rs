@@ -0,0 +1,590 @@
+//! A parallel commitment to a store's entries in the format of Ethereum's
+//! Merkle Patricia trie (MPT), for bridging to light clients which only
+//! understand MPT proofs.
+//!
+//! Merk's AVL proofs cannot be re-expressed as MPT proofs, since the two
+//! commit to the same entries with different trees and hash functions, so
+//! the adapter builds the MPT of the entries itself. `Trie::from_entries`
+//! builds a trie in memory, `Trie::root_hash` is the root an Ethereum client
+//! would compute for the same entries, and `Trie::prove` returns the list of
+//! RLP-encoded nodes on a key's path, as in `eth_getProof`. `verify_proof`
+//! checks such a proof.
+//!
+//! Values are stored as they are, without being RLP-encoded first. With
+//! `hash_keys`, keys are hashed with Keccak-256 before insertion, like the
+//! "secure" tries Ethereum uses for its state and storage.
+//!
+//! Building the trie reads every entry, so `Merk::mpt` is meant for producing
+//! commitments at bridging checkpoints rather than on every commit.
+
+use std::collections::HashMap;
+
+use crate::tree::Hash;
+use crate::{Error, Result};
+
+/// The root hash of an empty trie, the Keccak-256 hash of the RLP encoding of
+/// an empty string.
+pub const EMPTY_ROOT: Hash = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+const EMPTY_STRING: u8 = 0x80;
+
+const ROUND_CONSTANTS: [u64; 24] = [
+    0x0000000000000001,
+    0x0000000000008082,
+    0x800000000000808a,
+    0x8000000080008000,
+    0x000000000000808b,
+    0x0000000080000001,
+    0x8000000080008081,
+    0x8000000000008009,
+    0x000000000000008a,
+    0x0000000000000088,
+    0x0000000080008009,
+    0x000000008000000a,
+    0x000000008000808b,
+    0x800000000000008b,
+    0x8000000000008089,
+    0x8000000000008003,
+    0x8000000000008002,
+    0x8000000000000080,
+    0x000000000000800a,
+    0x800000008000000a,
+    0x8000000080008081,
+    0x8000000000008080,
+    0x0000000080000001,
+    0x8000000080008008,
+];
+const ROTATIONS: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+const LANES: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+fn keccak_f(state: &mut [u64; 25]) {
+    for round_constant in ROUND_CONSTANTS.iter() {
+        let mut columns = [0u64; 5];
+        for (x, column) in columns.iter_mut().enumerate() {
+            *column = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+        }
+        for x in 0..5 {
+            let d = columns[(x + 4) % 5] ^ columns[(x + 1) % 5].rotate_left(1);
+            for y in 0..5 {
+                state[x + 5 * y] ^= d;
+            }
+        }
+
+        let mut last = state[1];
+        for (lane, rotation) in LANES.iter().zip(ROTATIONS.iter()) {
+            let next = state[*lane];
+            state[*lane] = last.rotate_left(*rotation);
+            last = next;
+        }
+
+        for y in 0..5 {
+            let mut row = [0u64; 5];
+            row.copy_from_slice(&state[5 * y..5 * y + 5]);
+            for x in 0..5 {
+                state[5 * y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+
+        state[0] ^= round_constant;
+    }
+}
+
+/// Hashes `data` with Keccak-256, the hash function of Ethereum (which differs
+/// from the standardized SHA3-256 in its padding).
+pub fn keccak256(data: &[u8]) -> Hash {
+    const RATE: usize = 136;
+
+    let mut state = [0u64; 25];
+    let mut absorb = |block: &[u8]| {
+        for (lane, bytes) in state.iter_mut().zip(block.chunks(8)) {
+            let mut word = [0; 8];
+            word.copy_from_slice(bytes);
+            *lane ^= u64::from_le_bytes(word);
+        }
+        keccak_f(&mut state);
+    };
+
+    let mut blocks = data.chunks_exact(RATE);
+    for block in &mut blocks {
+        absorb(block);
+    }
+    let remainder = blocks.remainder();
+    let mut last = [0u8; RATE];
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[remainder.len()] ^= 0x01;
+    last[RATE - 1] ^= 0x80;
+    absorb(&last);
+
+    let mut hash = [0; 32];
+    for (bytes, lane) in hash.chunks_mut(8).zip(state.iter()) {
+        bytes.copy_from_slice(&lane.to_le_bytes());
+    }
+    hash
+}
+
+fn rlp_length(output: &mut Vec<u8>, len: usize, offset: u8) {
+    if len < 56 {
+        output.push(offset + len as u8);
+    } else {
+        let bytes = (len as u64).to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+        output.push(offset + 55 + (8 - skip) as u8);
+        output.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+/// RLP-encodes a byte string.
+fn rlp_string(bytes: &[u8]) -> Vec<u8> {
+    if bytes.len() == 1 && bytes[0] < EMPTY_STRING {
+        return bytes.to_vec();
+    }
+    let mut output = Vec::with_capacity(bytes.len() + 9);
+    rlp_length(&mut output, bytes.len(), 0x80);
+    output.extend_from_slice(bytes);
+    output
+}
+
+/// RLP-encodes a list of already encoded items.
+fn rlp_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let len = items.iter().map(Vec::len).sum();
+    let mut output = Vec::with_capacity(len + 9);
+    rlp_length(&mut output, len, 0xc0);
+    for item in items {
+        output.extend_from_slice(item);
+    }
+    output
+}
+
+/// Returns the offset and length of the payload of the RLP item at the start
+/// of `bytes`, and whether it is a list.
+fn rlp_header(bytes: &[u8]) -> Result<(usize, usize, bool)> {
+    let invalid = || Error::Decode("Invalid RLP item".into());
+    let first = *bytes.first().ok_or_else(invalid)?;
+    let (offset, len, is_list) = match first {
+        0x00..=0x7f => (0, 1, false),
+        0x80..=0xb7 => (1, (first - 0x80) as usize, false),
+        0xc0..=0xf7 => (1, (first - 0xc0) as usize, true),
+        _ => {
+            let is_list = first >= 0xf8;
+            let len_len = (first - if is_list { 0xf7 } else { 0xb7 }) as usize;
+            let len_bytes = bytes.get(1..1 + len_len).ok_or_else(invalid)?;
+            if len_len > 8 {
+                return Err(invalid());
+            }
+            let len = len_bytes
+                .iter()
+                .fold(0u64, |len, byte| (len << 8) | *byte as u64);
+            (1 + len_len, len as usize, is_list)
+        }
+    };
+    if bytes.len() < offset + len {
+        return Err(invalid());
+    }
+    Ok((offset, len, is_list))
+}
+
+/// Decodes an RLP byte string.
+fn rlp_decode_string(bytes: &[u8]) -> Result<&[u8]> {
+    let (offset, len, is_list) = rlp_header(bytes)?;
+    if is_list {
+        return Err(Error::Decode("Expected an RLP string".into()));
+    }
+    Ok(&bytes[offset..offset + len])
+}
+
+/// Decodes an RLP list, returning the encodings of its items.
+fn rlp_decode_list(bytes: &[u8]) -> Result<Vec<&[u8]>> {
+    let (offset, len, is_list) = rlp_header(bytes)?;
+    if !is_list {
+        return Err(Error::Decode("Expected an RLP list".into()));
+    }
+    let mut payload = &bytes[offset..offset + len];
+    let mut items = vec![];
+    while !payload.is_empty() {
+        let (offset, len, _) = rlp_header(payload)?;
+        let (item, rest) = payload.split_at(offset + len);
+        items.push(item);
+        payload = rest;
+    }
+    Ok(items)
+}
+
+fn to_nibbles(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Encodes a nibble path with the hex-prefix encoding, which marks whether
+/// the path has an odd length and whether it ends at a leaf.
+fn hex_prefix(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 2 } else { 0 };
+    let mut output = Vec::with_capacity(nibbles.len() / 2 + 1);
+    let rest = if nibbles.len() % 2 == 1 {
+        output.push(((flag + 1) << 4) | nibbles[0]);
+        &nibbles[1..]
+    } else {
+        output.push(flag << 4);
+        nibbles
+    };
+    for pair in rest.chunks(2) {
+        output.push((pair[0] << 4) | pair[1]);
+    }
+    output
+}
+
+fn decode_hex_prefix(bytes: &[u8]) -> Result<(Vec<u8>, bool)> {
+    let first = *bytes
+        .first()
+        .ok_or_else(|| Error::Decode("Empty hex-prefix path".into()))?;
+    let flag = first >> 4;
+    if flag > 3 {
+        return Err(Error::Decode("Invalid hex-prefix flag".into()));
+    }
+    let mut nibbles = if flag % 2 == 1 {
+        vec![first & 0x0f]
+    } else {
+        vec![]
+    };
+    nibbles.extend(to_nibbles(&bytes[1..]));
+    Ok((nibbles, flag >= 2))
+}
+
+/// An MPT built in memory from a set of entries.
+pub struct Trie {
+    root_hash: Hash,
+    nodes: HashMap<Hash, Vec<u8>>,
+    hash_keys: bool,
+}
+
+impl Trie {
+    /// Builds the trie of `entries`, which must not contain duplicate keys.
+    /// With `hash_keys`, each key is hashed with Keccak-256 before insertion.
+    pub fn from_entries<I, K, V>(entries: I, hash_keys: bool) -> Trie
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut paths: Vec<(Vec<u8>, V)> = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let path = if hash_keys {
+                    to_nibbles(&keccak256(key.as_ref()))
+                } else {
+                    to_nibbles(key.as_ref())
+                };
+                (path, value)
+            })
+            .collect();
+        paths.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut nodes = HashMap::new();
+        let root = if paths.is_empty() {
+            vec![EMPTY_STRING]
+        } else {
+            encode_node(&paths, 0, &mut nodes)
+        };
+        let root_hash = keccak256(&root);
+        nodes.insert(root_hash, root);
+
+        Trie {
+            root_hash,
+            nodes,
+            hash_keys,
+        }
+    }
+
+    /// Returns the root hash of the trie.
+    pub fn root_hash(&self) -> Hash {
+        self.root_hash
+    }
+
+    /// Returns the RLP-encoded nodes on the path to `key`, starting with the
+    /// root, which prove its value or its absence.
+    pub fn prove(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut proof = vec![];
+        walk(
+            &self.root_hash,
+            &path(key, self.hash_keys),
+            |hash| self.nodes.get(hash).map(Vec::as_slice),
+            |node| proof.push(node.to_vec()),
+        )?;
+        Ok(proof)
+    }
+}
+
+fn path(key: &[u8], hash_keys: bool) -> Vec<u8> {
+    if hash_keys {
+        to_nibbles(&keccak256(key))
+    } else {
+        to_nibbles(key)
+    }
+}
+
+/// Encodes the node holding `entries`, whose paths are sorted and share their
+/// first `depth` nibbles. Nodes which are referenced by hash are added to
+/// `nodes`.
+fn encode_node<V: AsRef<[u8]>>(
+    entries: &[(Vec<u8>, V)],
+    depth: usize,
+    nodes: &mut HashMap<Hash, Vec<u8>>,
+) -> Vec<u8> {
+    if entries.len() == 1 {
+        let (path, value) = &entries[0];
+        return rlp_list(&[
+            rlp_string(&hex_prefix(&path[depth..], true)),
+            rlp_string(value.as_ref()),
+        ]);
+    }
+
+    // entries are sorted, so the first and last share the longest prefix
+    let first = &entries[0].0[depth..];
+    let last = &entries[entries.len() - 1].0[depth..];
+    let shared = first.iter().zip(last).take_while(|(a, b)| a == b).count();
+    if shared > 0 {
+        let child = encode_node(entries, depth + shared, nodes);
+        return rlp_list(&[
+            rlp_string(&hex_prefix(&first[..shared], false)),
+            reference(child, nodes),
+        ]);
+    }
+
+    let mut items = Vec::with_capacity(17);
+    let mut rest = entries;
+    let value = match rest.first() {
+        Some((path, value)) if path.len() == depth => {
+            rest = &rest[1..];
+            rlp_string(value.as_ref())
+        }
+        _ => vec![EMPTY_STRING],
+    };
+    for nibble in 0..16 {
+        let len = rest
+            .iter()
+            .take_while(|(path, _)| path[depth] == nibble)
+            .count();
+        let (children, tail) = rest.split_at(len);
+        rest = tail;
+        items.push(if children.is_empty() {
+            vec![EMPTY_STRING]
+        } else {
+            reference(encode_node(children, depth + 1, nodes), nodes)
+        });
+    }
+    items.push(value);
+    rlp_list(&items)
+}
+
+/// Returns the reference to a node from its parent: the node itself if its
+/// encoding is shorter than a hash, otherwise its hash.
+fn reference(node: Vec<u8>, nodes: &mut HashMap<Hash, Vec<u8>>) -> Vec<u8> {
+    if node.len() < 32 {
+        return node;
+    }
+    let hash = keccak256(&node);
+    nodes.insert(hash, node);
+    rlp_string(&hash)
+}
+
+/// Follows `path` from the root, calling `visit` with each node which is
+/// referenced by hash, and returns the value at the end of the path.
+fn walk<'a, F, V>(root_hash: &Hash, path: &[u8], lookup: F, mut visit: V) -> Result<Option<Vec<u8>>>
+where
+    F: Fn(&Hash) -> Option<&'a [u8]>,
+    V: FnMut(&[u8]),
+{
+    let missing = || Error::Proof("Missing MPT node".into());
+    let node = lookup(root_hash).ok_or_else(missing)?;
+    visit(node);
+    if node == [EMPTY_STRING] {
+        return Ok(None);
+    }
+
+    let mut node = node;
+    let mut position = 0;
+    loop {
+        let items = rlp_decode_list(node)?;
+        let child = match items.len() {
+            17 => {
+                if position == path.len() {
+                    let value = rlp_decode_string(items[16])?;
+                    return Ok(if value.is_empty() {
+                        None
+                    } else {
+                        Some(value.to_vec())
+                    });
+                }
+                position += 1;
+                items[path[position - 1] as usize]
+            }
+            2 => {
+                let (nibbles, leaf) = decode_hex_prefix(rlp_decode_string(items[0])?)?;
+                let rest = &path[position..];
+                if leaf {
+                    return Ok(if rest == nibbles.as_slice() {
+                        Some(rlp_decode_string(items[1])?.to_vec())
+                    } else {
+                        None
+                    });
+                }
+                if !rest.starts_with(&nibbles) {
+                    return Ok(None);
+                }
+                position += nibbles.len();
+                items[1]
+            }
+            _ => return Err(Error::Decode("Invalid MPT node".into())),
+        };
+
+        if child == [EMPTY_STRING] {
+            return Ok(None);
+        }
+        let (_, _, is_list) = rlp_header(child)?;
+        node = if is_list {
+            child
+        } else {
+            let hash = rlp_decode_string(child)?;
+            if hash.len() != 32 {
+                return Err(Error::Decode("Invalid MPT node reference".into()));
+            }
+            let mut key = [0; 32];
+            key.copy_from_slice(hash);
+            let node = lookup(&key).ok_or_else(missing)?;
+            visit(node);
+            node
+        };
+    }
+}
+
+/// Checks a proof created by `Trie::prove` against `root_hash`, returning the
+/// proven value of `key` (or `None` if it is proven absent).
+pub fn verify_proof(
+    root_hash: &Hash,
+    key: &[u8],
+    proof: &[Vec<u8>],
+    hash_keys: bool,
+) -> Result<Option<Vec<u8>>> {
+    let nodes: HashMap<Hash, &[u8]> = proof
+        .iter()
+        .map(|node| (keccak256(node), node.as_slice()))
+        .collect();
+    walk(
+        root_hash,
+        &path(key, hash_keys),
+        |hash| nodes.get(hash).copied(),
+        |_| (),
+    )
+}
+
+#[cfg(feature = "full")]
+impl crate::Merk {
+    /// Builds the MPT of the store's entries, see the `mpt` module.
+    pub fn mpt(&self, hash_keys: bool) -> Result<Trie> {
+        let mut node = crate::tree::Tree::new(vec![], vec![])?;
+        let entries = self
+            .db
+            .iterator(rocksdb::IteratorMode::Start)
+            .map(|(key, node_bytes)| {
+                node.decode_into_with(key.to_vec(), &node_bytes, |key| {
+                    crate::merk::separated_value(&self.db, key)
+                })?;
+                Ok((key.to_vec(), node.value().to_vec()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Trie::from_entries(entries, hash_keys))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keccak() {
+        assert_eq!(
+            hex::encode(keccak256(b"")),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        assert_eq!(
+            hex::encode(keccak256(b"abc")),
+            "4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45"
+        );
+        assert_eq!(keccak256(&[EMPTY_STRING]), EMPTY_ROOT);
+        // longer than one block
+        assert_eq!(keccak256(&[7; 300]), keccak256(&vec![7; 300]));
+        assert_ne!(keccak256(&[0; 136]), keccak256(&[0; 135]));
+    }
+
+    #[test]
+    fn ethereum_trie() {
+        assert_eq!(
+            Trie::from_entries(Vec::<(&[u8], &[u8])>::new(), false).root_hash(),
+            EMPTY_ROOT
+        );
+
+        // the example from the Ethereum wiki
+        let entries: Vec<(&[u8], &[u8])> = vec![
+            (b"doe", b"reindeer"),
+            (b"dog", b"puppy"),
+            (b"dogglesworth", b"cat"),
+        ];
+        let trie = Trie::from_entries(entries.clone(), false);
+        assert_eq!(
+            hex::encode(trie.root_hash()),
+            "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3"
+        );
+
+        for (key, value) in entries {
+            let proof = trie.prove(key).unwrap();
+            assert_eq!(
+                verify_proof(&trie.root_hash(), key, &proof, false).unwrap(),
+                Some(value.to_vec())
+            );
+        }
+        let proof = trie.prove(b"do").unwrap();
+        assert_eq!(
+            verify_proof(&trie.root_hash(), b"do", &proof, false).unwrap(),
+            None
+        );
+        assert!(verify_proof(&EMPTY_ROOT, b"dog", &proof, false).is_err());
+    }
+
+    #[cfg(feature = "full")]
+    #[test]
+    fn merk_mpt() {
+        use crate::test_utils::*;
+
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..200), &[]).unwrap();
+        let trie = merk.mpt(true).unwrap();
+
+        let key = 17u64.to_be_bytes();
+        let proof = trie.prove(&key).unwrap();
+        assert!(proof.len() > 1);
+        assert_eq!(
+            verify_proof(&trie.root_hash(), &key, &proof, true).unwrap(),
+            Some(vec![123; 60])
+        );
+        let key = 1000u64.to_be_bytes();
+        let proof = trie.prove(&key).unwrap();
+        assert_eq!(
+            verify_proof(&trie.root_hash(), &key, &proof, true).unwrap(),
+            None
+        );
+
+        let entries = (0..200u64).map(|n| (n.to_be_bytes(), vec![123; 60]));
+        assert_eq!(
+            Trie::from_entries(entries, true).root_hash(),
+            trie.root_hash()
+        );
+    }
+}
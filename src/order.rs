@@ -0,0 +1,253 @@
+//! Custom key orderings.
+//!
+//! The tree always sorts keys by their bytes. To sort keys by application
+//! semantics instead (e.g. by a timestamp embedded in the middle of the key),
+//! keys are stored in an encoding whose byte order is the desired order, given
+//! by a `KeyEncoding`. Queries and batches are written in terms of the
+//! application's keys and encoded on the way in, and ranges select the keys
+//! between their bounds in the custom order.
+//!
+//! Proofs contain the encoded keys, so they verify against the same root hash
+//! as any other proof, and a verifier using the same encoding can read them
+//! back in terms of the application's keys with `order::verify`.
+
+use std::ops::Range;
+
+use crate::error::{Error, Result};
+use crate::proofs::query::{Map, Query, QueryItem, Term};
+use crate::Hash;
+
+/// An encoding of keys into byte strings which sort in the desired order.
+///
+/// The encoding must be injective, so that distinct keys have distinct
+/// encodings, and `decode` must invert `encode`. Provers and verifiers must use
+/// the same encoding for proofs to be read correctly.
+pub trait KeyEncoding {
+    /// Encodes a key into the form stored in the tree.
+    fn encode(&self, key: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decodes a key stored in the tree into the application's form.
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>>;
+
+    /// Encodes the bounds of a query item.
+    fn encode_item(&self, item: &QueryItem) -> Result<QueryItem> {
+        Ok(match item {
+            QueryItem::Key(key) => QueryItem::Key(self.encode(key)?),
+            QueryItem::Range(range) => {
+                QueryItem::Range(self.encode(&range.start)?..self.encode(&range.end)?)
+            }
+            QueryItem::RangeInclusive(range) => {
+                QueryItem::RangeInclusive(self.encode(range.start())?..=self.encode(range.end())?)
+            }
+        })
+    }
+
+    /// Encodes the bounds of a term, keeping its limit and direction.
+    fn encode_term(&self, term: &Term) -> Result<Term> {
+        Ok(Term {
+            item: self.encode_item(&term.item)?,
+            limit: term.limit,
+            direction: term.direction,
+        })
+    }
+
+    /// Encodes every item and term of a query.
+    fn encode_query(&self, query: &Query) -> Result<Query> {
+        let mut encoded = Query::new();
+        for item in query.iter() {
+            encoded.insert_item(self.encode_item(item)?);
+        }
+        for term in query.terms() {
+            encoded.insert_term(self.encode_term(term)?);
+        }
+        Ok(encoded)
+    }
+}
+
+/// The tree's native ordering, which sorts keys by their bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bytewise;
+
+impl KeyEncoding for Bytewise {
+    fn encode(&self, key: &[u8]) -> Result<Vec<u8>> {
+        Ok(key.to_vec())
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>> {
+        Ok(encoded.to_vec())
+    }
+}
+
+/// Sorts keys by fixed-position fields before the rest of the key.
+///
+/// The fields are byte ranges of the key, compared in the order given, after
+/// which the remaining bytes are compared in their original order. For
+/// example, keys made of a 4-byte account id followed by an 8-byte big-endian
+/// timestamp sort by time with `FieldOrder::new(vec![4..12])`.
+///
+/// Every key (and every query bound) must be long enough to contain all of
+/// the fields.
+#[derive(Clone, Debug)]
+pub struct FieldOrder {
+    fields: Vec<Range<usize>>,
+    min_length: usize,
+}
+
+impl FieldOrder {
+    /// Creates an ordering by the given fields. Fails if any of the fields are
+    /// empty or overlap.
+    pub fn new(fields: Vec<Range<usize>>) -> Result<Self> {
+        let mut sorted = fields.clone();
+        sorted.sort_by_key(|field| field.start);
+        for (i, field) in sorted.iter().enumerate() {
+            if field.is_empty() {
+                return Err(Error::Key(format!("Empty key field {:?}", field)));
+            }
+            if let Some(next) = sorted.get(i + 1) {
+                if next.start < field.end {
+                    return Err(Error::Key(format!(
+                        "Key fields {:?} and {:?} overlap",
+                        field, next
+                    )));
+                }
+            }
+        }
+
+        let min_length = sorted.last().map_or(0, |field| field.end);
+        Ok(FieldOrder { fields, min_length })
+    }
+
+    fn check_length(&self, key: &[u8]) -> Result<()> {
+        if key.len() < self.min_length {
+            return Err(Error::Key(format!(
+                "Key of length {} is too short for fields ending at {}",
+                key.len(),
+                self.min_length
+            )));
+        }
+        Ok(())
+    }
+
+    fn in_field(&self, i: usize) -> bool {
+        self.fields.iter().any(|field| field.contains(&i))
+    }
+}
+
+impl KeyEncoding for FieldOrder {
+    fn encode(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.check_length(key)?;
+
+        let mut encoded = Vec::with_capacity(key.len());
+        for field in self.fields.iter() {
+            encoded.extend_from_slice(&key[field.clone()]);
+        }
+        let rest = (0..key.len()).filter(|i| !self.in_field(*i));
+        encoded.extend(rest.map(|i| key[i]));
+        Ok(encoded)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>> {
+        self.check_length(encoded)?;
+
+        let mut key = vec![0; encoded.len()];
+        let mut input = encoded.iter().copied();
+        for field in self.fields.iter() {
+            for (byte, value) in key[field.clone()].iter_mut().zip(&mut input) {
+                *byte = value;
+            }
+        }
+        for (i, byte) in key.iter_mut().enumerate() {
+            if !self.in_field(i) {
+                *byte = input.next().unwrap_or_default();
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// The entries of a verified proof, read in terms of the application's keys.
+pub struct OrderedMap<E> {
+    map: Map,
+    encoding: E,
+}
+
+impl<E: KeyEncoding> OrderedMap<E> {
+    /// Gets the value for a single key, or `None` if the key was proven to not
+    /// exist, like `Map::get`.
+    pub fn get(&self, key: &[u8]) -> Result<Option<&[u8]>> {
+        self.map.get(&self.encoding.encode(key)?)
+    }
+
+    /// Returns the entries with keys between the bounds of `range` in the
+    /// custom order, like `Map::range`.
+    pub fn range(&self, range: Range<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = self.encoding.encode(&range.start)?;
+        let end = self.encoding.encode(&range.end)?;
+        self.map
+            .range(start.as_slice()..end.as_slice())
+            .map(|entry| self.decode_entry(entry?))
+            .collect()
+    }
+
+    /// Returns the entries selected by `term`, like `Map::term`.
+    pub fn term(&self, term: &Term) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let term = self.encoding.encode_term(term)?;
+        self.map
+            .term(&term)?
+            .into_iter()
+            .map(|entry| self.decode_entry(entry))
+            .collect()
+    }
+
+    /// Returns the underlying map, keyed by the encoded keys.
+    pub fn inner(&self) -> &Map {
+        &self.map
+    }
+
+    fn decode_entry(&self, (key, value): (&[u8], &[u8])) -> Result<(Vec<u8>, Vec<u8>)> {
+        Ok((self.encoding.decode(key)?, value.to_vec()))
+    }
+}
+
+/// Verifies an encoded proof of a store using `encoding`, like
+/// `proofs::query::verify`.
+pub fn verify<E: KeyEncoding>(
+    bytes: &[u8],
+    expected_hash: Hash,
+    encoding: E,
+) -> Result<OrderedMap<E>> {
+    let map = crate::proofs::query::verify(bytes, expected_hash)?;
+    Ok(OrderedMap { map, encoding })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn field_order_roundtrip() {
+        let order = FieldOrder::new(vec![4..6, 1..2]).unwrap();
+        let key = [0, 1, 2, 3, 4, 5, 6];
+        let encoded = order.encode(&key).unwrap();
+        assert_eq!(encoded, vec![4, 5, 1, 0, 2, 3, 6]);
+        assert_eq!(order.decode(&encoded).unwrap(), key.to_vec());
+        assert!(order.encode(&[0; 5]).is_err());
+    }
+
+    #[test]
+    fn field_order_sorts_by_fields() {
+        let field = 1..3;
+        let order = FieldOrder::new(vec![field]).unwrap();
+        let mut keys = vec![vec![0, 9, 9], vec![1, 0, 1], vec![2, 0, 0, 5]];
+        keys.sort_by_key(|key| order.encode(key).unwrap());
+        assert_eq!(keys, vec![vec![2, 0, 0, 5], vec![1, 0, 1], vec![0, 9, 9]]);
+    }
+
+    #[test]
+    fn invalid_fields() {
+        assert!(FieldOrder::new(vec![0..4, 3..5]).is_err());
+        let empty = 2..2;
+        assert!(FieldOrder::new(vec![empty]).is_err());
+        assert!(FieldOrder::new(vec![]).is_ok());
+    }
+}
@@ -1,14 +1,20 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
 #[cfg(feature = "full")]
-use {
-    super::tree::{execute, Tree as ProofTree},
-    crate::tree::Hash,
-    crate::tree::Tree,
-    rocksdb::DBRawIterator,
-};
-
-use super::{Node, Op};
+use {crate::tree::Tree, rocksdb::DBRawIterator};
+
+use super::tree::{execute, Tree as ProofTree};
+use super::{Decoder, Node, Op};
 use crate::error::{Error, Result};
-use crate::tree::{Fetch, RefWalker};
+use crate::tree::{Fetch, Hash, RefWalker};
 
 /// The minimum number of layers the trunk will be guaranteed to have before
 /// splitting into multiple chunks. If the tree's height is less than double
@@ -94,14 +100,16 @@ where
         }
 
         // traverse left
-        let has_left_child = self.tree().link(true).is_some();
-        if has_left_child {
-            let mut left = self.walk(true)?.unwrap();
-            left.traverse_for_trunk(proof, remaining_depth - 1, is_leftmost)?;
-        }
+        let has_left_child = match self.walk(true)? {
+            Some(mut left) => {
+                left.traverse_for_trunk(proof, remaining_depth - 1, is_leftmost)?;
+                true
+            }
+            None => false,
+        };
 
         // add this node's data
-        proof.push(Op::Push(self.to_kv_node()));
+        proof.push(Op::Push(self.to_kv_node()?));
 
         if has_left_child {
             proof.push(Op::Parent);
@@ -121,25 +129,34 @@ where
 /// when a node with key `end_key` is encountered.
 ///
 /// Advances the iterator for all nodes in the chunk and the `end_key` (if any).
+/// Values which are stored apart from their nodes are read with `load_value`.
 #[cfg(feature = "full")]
-pub(crate) fn get_next_chunk(iter: &mut DBRawIterator, end_key: Option<&[u8]>) -> Result<Vec<Op>> {
+pub(crate) fn get_next_chunk<F>(
+    iter: &mut DBRawIterator,
+    end_key: Option<&[u8]>,
+    load_value: F,
+) -> Result<Vec<Op>>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>>,
+{
     let mut chunk = Vec::with_capacity(512);
     let mut stack = Vec::with_capacity(32);
     let mut node = Tree::new(vec![], vec![])?;
 
-    while iter.valid() {
-        let key = iter.key().unwrap();
-
+    while let Some((key, encoded_node)) = iter.key().zip(iter.value()) {
         if let Some(end_key) = end_key {
             if key == end_key {
                 break;
             }
         }
 
-        let encoded_node = iter.value().unwrap();
-        Tree::decode_into(&mut node, vec![], encoded_node);
+        node.decode_into_with(key.to_vec(), encoded_node, &load_value)?;
 
-        let kv = Node::KV(key.to_vec(), node.value().to_vec());
+        let kv = if node.flags() != 0 {
+            Node::KVFlags(key.to_vec(), node.value().to_vec(), node.flags())
+        } else {
+            Node::KV(key.to_vec(), node.value().to_vec())
+        };
         chunk.push(Op::Push(kv));
 
         if node.link(true).is_some() {
@@ -168,22 +185,59 @@ pub(crate) fn get_next_chunk(iter: &mut DBRawIterator, end_key: Option<&[u8]>) -
     Ok(chunk)
 }
 
+/// An encoded chunk proof, as returned by `ChunkProducer::chunk`.
+///
+/// The first chunk of a tree is its trunk, which proves the upper levels of
+/// the tree and hashes to the root hash, and the rest are leaf chunks, each
+/// proving a whole subtree which hashes to one of the `Hash` nodes at the
+/// bottom of the trunk, in key order. Both are encoded the same way as query
+/// proofs (see `encoding`) and executed by the same verification core.
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkProof<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ChunkProof<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        ChunkProof { bytes }
+    }
+
+    /// Returns the encoded proof.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Returns an iterator decoding the proof's operators.
+    pub fn ops(&self) -> Decoder<'a> {
+        Decoder::new(self.bytes)
+    }
+
+    /// Verifies the proof as a trunk chunk, see `verify_trunk`. The caller
+    /// checks the hash of the returned tree against the expected root hash.
+    pub fn verify_trunk(&self) -> Result<(ProofTree, usize)> {
+        verify_trunk(self.ops())
+    }
+
+    /// Verifies the proof as a leaf chunk which hashes to `expected_hash`,
+    /// see `verify_leaf`.
+    pub fn verify_leaf(&self, expected_hash: Hash) -> Result<ProofTree> {
+        verify_leaf(self.ops(), expected_hash)
+    }
+}
+
 /// Verifies a leaf chunk proof by executing its operators. Checks that there
 /// were no abridged nodes (Hash or KVHash) and the proof hashes to
 /// `expected_hash`.
-#[cfg(feature = "full")]
 pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
     ops: I,
     expected_hash: Hash,
 ) -> Result<ProofTree> {
     let tree = execute(ops, false, |node| match node {
-        Node::KV(_, _) => Ok(()),
+        Node::KV(_, _) | Node::KVFlags(..) => Ok(()),
         _ => Err(Error::Tree("Leaf chunks must contain full subtree".into())),
     })?;
 
-    if tree.hash()? != expected_hash {
-        return Err(Error::HashMismatch(expected_hash, tree.hash()?));
-    }
+    tree.check_hash(expected_hash)?;
 
     Ok(tree)
 }
@@ -192,7 +246,6 @@ pub(crate) fn verify_leaf<I: Iterator<Item = Result<Op>>>(
 /// resulting tree contains a valid height proof, the trunk is the correct
 /// height, and all of its inner nodes are not abridged. Returns the tree and
 /// the height given by the height proof.
-#[cfg(feature = "full")]
 pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(ProofTree, usize)> {
     fn verify_height_proof(tree: &ProofTree) -> Result<usize> {
         Ok(match tree.child(true) {
@@ -218,7 +271,7 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
 
         if remaining_depth > 0 {
             match tree.node {
-                Node::KV(_, _) => {}
+                Node::KV(_, _) | Node::KVFlags(..) => {}
                 _ => {
                     return Err(Error::UnexpectedNode(
                         "Expected trunk inner nodes to contain keys and values".into(),
@@ -246,7 +299,7 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
 
     let mut kv_only = true;
     let tree = execute(ops, false, |node| {
-        kv_only &= matches!(node, Node::KV(_, _));
+        kv_only &= matches!(node, Node::KV(_, _) | Node::KVFlags(..));
         Ok(())
     })?;
 
@@ -264,7 +317,7 @@ pub(crate) fn verify_trunk<I: Iterator<Item = Result<Op>>>(ops: I) -> Result<(Pr
     Ok((tree, height))
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "full"))]
 mod tests {
     use std::usize;
 
@@ -287,7 +340,8 @@ mod tests {
             match node {
                 Node::Hash(_) => counts.hash += 1,
                 Node::KVHash(_) => counts.kvhash += 1,
-                Node::KV(_, _) => counts.kv += 1,
+                Node::KV(_, _) | Node::KVFlags(..) => counts.kv += 1,
+                Node::KVDigest(..) => unreachable!("chunks contain no digests"),
             };
         });
 
@@ -415,14 +469,12 @@ mod tests {
         let batch = make_batch_seq(0..31);
         merk.apply(batch.as_slice(), &[]).unwrap();
 
-        let root_node = merk.tree.take();
-        let root_key = root_node.as_ref().unwrap().key().to_vec();
-        merk.tree.set(root_node);
+        let root_key = merk.tree.read().unwrap().as_ref().unwrap().key().to_vec();
 
         // whole tree as 1 leaf
         let mut iter = merk.db.raw_iterator();
         iter.seek_to_first();
-        let chunk = get_next_chunk(&mut iter, None).unwrap();
+        let chunk = get_next_chunk(&mut iter, None, |_| unreachable!()).unwrap();
         let ops = chunk.into_iter().map(Ok);
         let chunk = verify_leaf(ops, merk.root_hash()).unwrap();
         let counts = count_node_types(chunk);
@@ -435,7 +487,8 @@ mod tests {
         iter.seek_to_first();
 
         // left leaf
-        let chunk = get_next_chunk(&mut iter, Some(root_key.as_slice())).unwrap();
+        let chunk =
+            get_next_chunk(&mut iter, Some(root_key.as_slice()), |_| unreachable!()).unwrap();
         let ops = chunk.into_iter().map(Ok);
         let chunk = verify_leaf(
             ops,
@@ -451,7 +504,7 @@ mod tests {
         assert_eq!(counts.kvhash, 0);
 
         // right leaf
-        let chunk = get_next_chunk(&mut iter, None).unwrap();
+        let chunk = get_next_chunk(&mut iter, None, |_| unreachable!()).unwrap();
         let ops = chunk.into_iter().map(Ok);
         let chunk = verify_leaf(
             ops,
@@ -0,0 +1,178 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
+//! Human-readable printing of encoded proofs, for debugging proofs which do
+//! not verify.
+
+use std::fmt::Write;
+
+use ed::Encode;
+
+use super::{Node, Op};
+
+/// The number of bytes shown of each hash.
+const HASH_PREFIX_LENGTH: usize = 4;
+
+/// The number of bytes shown of keys and values before they are truncated.
+const BYTES_PREFIX_LENGTH: usize = 16;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hash_prefix(hash: &[u8]) -> String {
+    hex(hash.get(..HASH_PREFIX_LENGTH).unwrap_or(hash))
+}
+
+fn truncated_hex(bytes: &[u8]) -> String {
+    match bytes.get(..BYTES_PREFIX_LENGTH) {
+        Some(prefix) if bytes.len() > BYTES_PREFIX_LENGTH => {
+            format!("{}… ({} bytes)", hex(prefix), bytes.len())
+        }
+        _ => hex(bytes),
+    }
+}
+
+fn op_to_string(op: &Op) -> String {
+    match op {
+        Op::Push(Node::Hash(hash)) => format!("Push(Hash({}…))", hash_prefix(hash)),
+        Op::Push(Node::KVHash(hash)) => {
+            format!("Push(KVHash({}…))", hash_prefix(hash))
+        }
+        Op::Push(Node::KV(key, value)) => {
+            format!("Push(KV({}, {}))", truncated_hex(key), truncated_hex(value))
+        }
+        Op::Push(Node::KVFlags(key, value, flags)) => format!(
+            "Push(KVFlags({}, {}, {:#04x}))",
+            truncated_hex(key),
+            truncated_hex(value),
+            flags
+        ),
+        Op::Push(Node::KVDigest(key, hash)) => format!(
+            "Push(KVDigest({}, {}…))",
+            truncated_hex(key),
+            hash_prefix(hash)
+        ),
+        Op::Parent => "Parent".to_string(),
+        Op::Child => "Child".to_string(),
+    }
+}
+
+/// Decodes an encoded proof and formats its operators one per line, along
+/// with the byte offset of each operator and the height of the verification
+/// stack after executing it. Hashes are truncated, as are long keys and
+/// values.
+///
+/// Unlike verification, this never fails: if the proof can not be decoded or
+/// executed, the problem is noted on the line where it occurs.
+///
+/// # Example
+/// ```
+/// use merkdb::proofs::{debug::decode_to_string, encode_into, Node, Op};
+///
+/// let mut bytes = vec![];
+/// encode_into([Op::Push(Node::KV(vec![1], vec![2]))].iter(), &mut bytes);
+/// assert_eq!(decode_to_string(&bytes), "     0  1  Push(KV(01, 02))\n");
+/// ```
+pub fn decode_to_string(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    let mut offset = 0;
+    let mut height: usize = 0;
+
+    // writing to a `String` never fails, so the results of `writeln!` are
+    // ignored
+    while let Some(rest) = bytes.get(offset..).filter(|rest| !rest.is_empty()) {
+        let op = match Op::decode(rest).and_then(|op| Ok((op.encoding_length()?, op))) {
+            Ok(op) => op,
+            Err(err) => {
+                let _ = writeln!(output, "{:>6}  error: {}", offset, err);
+                return output;
+            }
+        };
+        let (length, op) = op;
+
+        let note = match op {
+            Op::Push(_) => {
+                height += 1;
+                ""
+            }
+            Op::Parent | Op::Child if height < 2 => "  (stack underflow)",
+            Op::Parent | Op::Child => {
+                height -= 1;
+                ""
+            }
+        };
+        let _ = writeln!(
+            output,
+            "{:>6}  {}  {}{}",
+            offset,
+            height,
+            op_to_string(&op),
+            note
+        );
+
+        offset += length;
+    }
+
+    if height != 1 {
+        let _ = writeln!(
+            output,
+            "{:>6}  error: proof ends with {} stack items, expected 1",
+            offset, height
+        );
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::encode_into;
+    use super::*;
+
+    #[test]
+    fn prints_ops() {
+        let ops = [
+            Op::Push(Node::Hash([0xab; 32])),
+            Op::Push(Node::KV(vec![1, 2, 3], vec![0xcd; 20])),
+            Op::Parent,
+            Op::Push(Node::KVHash([0x12; 32])),
+            Op::Child,
+        ];
+        let mut bytes = vec![];
+        encode_into(ops.iter(), &mut bytes);
+
+        assert_eq!(
+            decode_to_string(&bytes),
+            "     0  1  Push(Hash(abababab…))\n\
+             \x20   33  2  Push(KV(010203, cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd… (20 bytes)))\n\
+             \x20   60  1  Parent\n\
+             \x20   61  2  Push(KVHash(12121212…))\n\
+             \x20   94  1  Child\n"
+        );
+    }
+
+    #[test]
+    fn prints_errors() {
+        let mut bytes = vec![];
+        encode_into(
+            [Op::Push(Node::KV(vec![1], vec![2])), Op::Parent].iter(),
+            &mut bytes,
+        );
+        bytes.push(0xff);
+
+        assert_eq!(
+            decode_to_string(&bytes),
+            "     0  1  Push(KV(01, 02))\n\
+             \x20    6  1  Parent  (stack underflow)\n\
+             \x20    7  error: Unexpected byte: 255\n"
+        );
+    }
+}
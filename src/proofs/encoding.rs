@@ -1,3 +1,13 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
 use std::io::{Read, Write};
 
 use ed::{Decode, Encode, Terminated};
@@ -26,6 +36,23 @@ impl Encode for Op {
                 (value.len() as u16).encode_into(dest)?;
                 dest.write_all(value)?;
             }
+            Op::Push(Node::KVDigest(key, kv_hash)) => {
+                debug_assert!(key.len() < 256);
+
+                dest.write_all(&[0x04, key.len() as u8])?;
+                dest.write_all(key)?;
+                dest.write_all(kv_hash)?;
+            }
+            Op::Push(Node::KVFlags(key, value, flags)) => {
+                debug_assert!(key.len() < 256);
+                debug_assert!(value.len() < 65536);
+
+                dest.write_all(&[0x05, key.len() as u8])?;
+                dest.write_all(key)?;
+                (value.len() as u16).encode_into(dest)?;
+                dest.write_all(value)?;
+                dest.write_all(&[*flags])?;
+            }
             Op::Parent => dest.write_all(&[0x10])?,
             Op::Child => dest.write_all(&[0x11])?,
         };
@@ -33,13 +60,31 @@ impl Encode for Op {
     }
 
     fn encoding_length(&self) -> ed::Result<usize> {
-        Ok(match self {
+        Ok(Op::encoding_length(self))
+    }
+}
+
+impl Terminated for Op {}
+
+impl Op {
+    fn encoding_length(&self) -> usize {
+        match self {
             Op::Push(Node::Hash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KVHash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KV(key, value)) => 4 + key.len() + value.len(),
+            Op::Push(Node::KVDigest(key, _)) => 2 + key.len() + HASH_LENGTH,
+            Op::Push(Node::KVFlags(key, value, _)) => 5 + key.len() + value.len(),
             Op::Parent => 1,
             Op::Child => 1,
-        })
+        }
+    }
+
+    fn encode_into<W: Write>(&self, dest: &mut W) -> Result<()> {
+        Ok(Encode::encode_into(self, dest)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(Decode::decode(bytes)?)
     }
 }
 
@@ -69,6 +114,27 @@ impl Decode for Op {
 
                 Op::Push(Node::KV(key, value))
             }
+            0x04 => {
+                let key_len: u8 = Decode::decode(&mut input)?;
+                let mut key = vec![0; key_len as usize];
+                input.read_exact(key.as_mut_slice())?;
+
+                let mut hash = [0; HASH_LENGTH];
+                input.read_exact(&mut hash)?;
+                Op::Push(Node::KVDigest(key, hash))
+            }
+            0x05 => {
+                let key_len: u8 = Decode::decode(&mut input)?;
+                let mut key = vec![0; key_len as usize];
+                input.read_exact(key.as_mut_slice())?;
+
+                let value_len: u16 = Decode::decode(&mut input)?;
+                let mut value = vec![0; value_len as usize];
+                input.read_exact(value.as_mut_slice())?;
+
+                let flags: u8 = Decode::decode(&mut input)?;
+                Op::Push(Node::KVFlags(key, value, flags))
+            }
             0x10 => Op::Parent,
             0x11 => Op::Child,
             byte => {
@@ -78,22 +144,8 @@ impl Decode for Op {
     }
 }
 
-impl Terminated for Op {}
-
-impl Op {
-    fn encode_into<W: Write>(&self, dest: &mut W) -> Result<()> {
-        Ok(Encode::encode_into(self, dest)?)
-    }
-
-    fn encoding_length(&self) -> usize {
-        Encode::encoding_length(self).unwrap()
-    }
-
-    pub fn decode(bytes: &[u8]) -> Result<Self> {
-        Ok(Decode::decode(bytes)?)
-    }
-}
-
+// writing to a `Vec` never fails
+#[allow(clippy::unwrap_used)]
 pub fn encode_into<'a, T: Iterator<Item = &'a Op>>(ops: T, output: &mut Vec<u8>) {
     for op in ops {
         op.encode_into(output).unwrap();
@@ -122,12 +174,19 @@ impl<'a> Iterator for Decoder<'a> {
             return None;
         }
 
-        Some((|| {
-            let bytes = &self.bytes[self.offset..];
-            let op = Op::decode(bytes)?;
-            self.offset += op.encoding_length();
-            Ok(op)
-        })())
+        let bytes = self.bytes.get(self.offset..)?;
+        match Op::decode(bytes) {
+            Ok(op) => {
+                self.offset += op.encoding_length();
+                Some(Ok(op))
+            }
+            Err(err) => {
+                // the rest of the input can not be decoded once an operator is
+                // invalid, so stop after the first error
+                self.offset = self.bytes.len();
+                Some(Err(err))
+            }
+        }
     }
 }
 
@@ -180,6 +239,29 @@ mod test {
         assert_eq!(bytes, vec![0x03, 3, 1, 2, 3, 0, 3, 4, 5, 6]);
     }
 
+    #[test]
+    fn encode_push_kvdigest() {
+        let op = Op::Push(Node::KVDigest(vec![1, 2, 3], [123; HASH_LENGTH]));
+        assert_eq!(op.encoding_length(), 5 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        assert_eq!(&bytes[..5], &[0x04, 3, 1, 2, 3]);
+        assert_eq!(&bytes[5..], &[123; HASH_LENGTH]);
+        assert_eq!(Op::decode(bytes.as_slice()).unwrap(), op);
+    }
+
+    #[test]
+    fn encode_push_kvflags() {
+        let op = Op::Push(Node::KVFlags(vec![1, 2, 3], vec![4, 5], 1));
+        assert_eq!(op.encoding_length(), 10);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![0x05, 3, 1, 2, 3, 0, 2, 4, 5, 1]);
+        assert_eq!(Op::decode(bytes.as_slice()).unwrap(), op);
+    }
+
     #[test]
     fn encode_parent() {
         let op = Op::Parent;
@@ -0,0 +1,93 @@
+#![cfg(all(test, feature = "full"))]
+
+use super::chunk::{verify_leaf, verify_trunk};
+use super::debug::decode_to_string;
+use super::query::{verify, verify_empty_range, verify_iter, QueryItem};
+use super::{encode_into, Decoder};
+use crate::test_utils::*;
+use crate::tree::{PanicSource, RefWalker, Tree};
+use rand::prelude::*;
+
+const ITERATIONS: usize = 2_000;
+
+#[test]
+fn fuzz() {
+    let mut rng = thread_rng();
+
+    for _ in 0..ITERATIONS {
+        let seed = rng.gen::<u64>();
+        fuzz_case(seed);
+    }
+}
+
+#[test]
+fn corpus() {
+    let corpus: &[&[u8]] = &[
+        &[],
+        &[0x00],
+        &[0x01],
+        &[0x02, 0x00],
+        &[0x03, 0xff, 0xff, 0xff],
+        &[0x03, 0x01, 0x00, 0xff, 0x01],
+        &[0x10, 0x11],
+        &[0x10; 64],
+        &[0x11; 64],
+        &[0xff; 64],
+    ];
+
+    for bytes in corpus {
+        check_input(bytes);
+    }
+}
+
+/// Creates a valid proof, then checks that mutated copies of it are rejected
+/// with an error rather than a panic.
+fn fuzz_case(seed: u64) {
+    let mut rng: SmallRng = SeedableRng::seed_from_u64(seed);
+    let node_count = (rng.gen::<u64>() % 20) + 1;
+    let mut tree = make_tree_seq(node_count);
+
+    let start = rng.gen::<u64>() % (node_count + 2);
+    let end = start + rng.gen::<u64>() % 4 + 1;
+    let query = vec![QueryItem::Range(
+        start.to_be_bytes().to_vec()..end.to_be_bytes().to_vec(),
+    )];
+    let mut walker = RefWalker::new(&mut tree, PanicSource {});
+    let (proof, _) = walker.create_proof(query.as_slice()).unwrap();
+    let mut bytes = vec![];
+    encode_into(proof.iter(), &mut bytes);
+
+    let mut input = bytes.clone();
+    match rng.gen_range(0..4) {
+        0 => input.truncate(rng.gen_range(0..bytes.len())),
+        1 => {
+            let i = rng.gen_range(0..input.len());
+            input[i] ^= 1 << rng.gen_range(0..8);
+        }
+        2 => {
+            let i = rng.gen_range(0..input.len());
+            input[i] = rng.gen();
+        }
+        _ => {
+            input = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+        }
+    }
+
+    println!("SEED: {}", seed);
+    println!("{:?}", input);
+    check_input(&input);
+}
+
+/// Feeds `input` to every decoder and verifier which accepts untrusted bytes.
+/// Returning at all means the test passed, any panic fails it.
+fn check_input(input: &[u8]) {
+    let hash = [0; 32];
+    let _ = verify(input, hash);
+    let _ = verify_iter(input, hash).count();
+    let _ = verify_empty_range(input, &(vec![1]..vec![2]), hash);
+    let _ = decode_to_string(input);
+    let _ = Decoder::new(input).count();
+    let _ = verify_leaf(Decoder::new(input), hash);
+    let _ = verify_trunk(Decoder::new(input));
+    let _ = Tree::decode(vec![0], input);
+}
@@ -0,0 +1,245 @@
+//! A client which keeps track of the root hash it trusts and checks proofs
+//! against it, see `LightClient`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Bound;
+
+use super::query::{verify, Map, Query, QueryItem};
+use crate::error::{Error, Result};
+use crate::tree::Hash;
+
+/// The number of superseded roots a client remembers, so proofs against them
+/// fail with `Error::StaleRoot` rather than `Error::HashMismatch`.
+const SUPERSEDED_ROOTS: usize = 16;
+
+/// Checks the header a new root comes with, given the root's height and hash,
+/// e.g. by decoding the header as an `attest::RootAttestation` and checking
+/// that it attests to the root with a trusted `attest::Verifier`.
+pub type HeaderCheck = Box<dyn Fn(u64, &Hash, &[u8]) -> bool + Send + Sync>;
+
+/// The verifier side of a store: a trusted root hash, at a height (e.g. the
+/// number of commits of the store, or a block height), which is only moved
+/// forward, and against which proofs of queries are checked.
+///
+/// Proofs against roots the client has moved past fail with
+/// `Error::StaleRoot`, so a client can tell a server which is behind from one
+/// which serves invalid proofs.
+pub struct LightClient {
+    height: u64,
+    root_hash: Hash,
+    superseded: VecDeque<(u64, Hash)>,
+    header_check: Option<HeaderCheck>,
+}
+
+impl LightClient {
+    /// Creates a client which trusts `root_hash` at `height`.
+    pub fn new(height: u64, root_hash: Hash) -> LightClient {
+        LightClient {
+            height,
+            root_hash,
+            superseded: VecDeque::with_capacity(SUPERSEDED_ROOTS),
+            header_check: None,
+        }
+    }
+
+    /// Requires each new root passed to `update_root` to come with a header
+    /// which `check` accepts.
+    pub fn with_header_check<F>(mut self, check: F) -> LightClient
+    where
+        F: Fn(u64, &Hash, &[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.header_check = Some(Box::new(check));
+        self
+    }
+
+    /// Returns the height of the trusted root.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Returns the trusted root hash.
+    pub fn root_hash(&self) -> Hash {
+        self.root_hash
+    }
+
+    /// Trusts `root_hash` at `height` from now on. `header` is checked with the
+    /// client's header check, if it has one, and is ignored otherwise.
+    ///
+    /// Fails with `Error::StaleRoot` if `height` is below the trusted height,
+    /// with `Error::HashMismatch` if it is the trusted height but `root_hash`
+    /// is not the trusted root, or with `Error::InvalidSignature` if the
+    /// header check rejects the header. The trusted root is unchanged if it
+    /// fails.
+    pub fn update_root(&mut self, height: u64, root_hash: Hash, header: &[u8]) -> Result<()> {
+        if height < self.height {
+            return Err(Error::StaleRoot {
+                height,
+                trusted: self.height,
+            });
+        }
+        if height == self.height && root_hash != self.root_hash {
+            return Err(Error::HashMismatch(self.root_hash, root_hash));
+        }
+        if let Some(check) = &self.header_check {
+            if !check(height, &root_hash, header) {
+                return Err(Error::InvalidSignature(height));
+            }
+        }
+        if height == self.height {
+            return Ok(());
+        }
+
+        if self.superseded.len() == SUPERSEDED_ROOTS {
+            self.superseded.pop_front();
+        }
+        self.superseded.push_back((self.height, self.root_hash));
+        self.height = height;
+        self.root_hash = root_hash;
+        Ok(())
+    }
+
+    /// Verifies a proof of `query` (see `Merk::prove`) against the trusted
+    /// root, returning a map of the entries it proves.
+    ///
+    /// Unlike `verify`, this also checks that the proof covers every item of
+    /// the query, failing with `Error::MissingData` if it does not, so every
+    /// key in the query can be read from the map. Fails with
+    /// `Error::StaleRoot` if the proof is against a root the client has moved
+    /// past.
+    pub fn verify_query(&self, bytes: &[u8], query: &Query) -> Result<Map> {
+        let map = match verify(bytes, self.root_hash) {
+            Err(Error::HashMismatch(_, actual)) => {
+                return Err(self
+                    .stale(&actual)
+                    .unwrap_or(Error::HashMismatch(self.root_hash, actual)))
+            }
+            result => result?,
+        };
+        for item in query.iter() {
+            if let QueryItem::Key(key) = item {
+                map.get(key)?;
+                continue;
+            }
+            let end = match item.upper_bound() {
+                (end, true) => Bound::Included(end),
+                (end, false) => Bound::Excluded(end),
+            };
+            for entry in map.range((Bound::Included(item.lower_bound()), end)) {
+                entry?;
+            }
+        }
+        Ok(map)
+    }
+
+    /// Returns `Error::StaleRoot` if `root_hash` is a superseded root.
+    fn stale(&self, root_hash: &Hash) -> Option<Error> {
+        self.superseded
+            .iter()
+            .rev()
+            .find(|(_, hash)| hash == root_hash)
+            .map(|(height, _)| Error::StaleRoot {
+                height: *height,
+                trusted: self.height,
+            })
+    }
+}
+
+impl fmt::Debug for LightClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LightClient")
+            .field("height", &self.height)
+            .field("root_hash", &self.root_hash)
+            .field("header_check", &self.header_check.is_some())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "full"))]
+mod test {
+    use super::*;
+    use crate::attest::{HmacSha256, RootAttestation, Verifier};
+    use crate::test_utils::*;
+
+    fn prove(merk: &TempMerk, keys: &[u64]) -> (Vec<u8>, Query) {
+        let mut query = Query::new();
+        for key in keys {
+            query.insert_key(seq_key(*key));
+        }
+        (merk.prove(query.clone()).unwrap(), query)
+    }
+
+    #[test]
+    fn light_client() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let key = HmacSha256::new(b"key");
+        let mut client =
+            LightClient::new(1, merk.root_hash()).with_header_check(move |height, root, header| {
+                RootAttestation::decode(header).map_or(false, |attestation| {
+                    attestation.height == height
+                        && &attestation.root_hash == root
+                        && attestation.verify(&key as &dyn Verifier).is_ok()
+                })
+            });
+
+        let (old_proof, query) = prove(&merk, &[10, 200]);
+        let map = client.verify_query(&old_proof, &query).unwrap();
+        assert_eq!(map.get(&seq_key(10)).unwrap(), Some(&[123; 60][..]));
+        assert_eq!(map.get(&seq_key(200)).unwrap(), None);
+
+        // proofs must cover the whole query
+        let (proof, _) = prove(&merk, &[10]);
+        let (_, query) = prove(&merk, &[10, 50]);
+        assert!(matches!(
+            client.verify_query(&proof, &query),
+            Err(Error::MissingData)
+        ));
+
+        // new roots must come with a valid header
+        merk.apply(&make_batch_seq(100..110), &[]).unwrap();
+        let root_hash = merk.root_hash();
+        let signer = HmacSha256::new(b"key");
+        let header = RootAttestation::sign(2, root_hash, &signer).encode();
+        assert!(matches!(
+            client.update_root(2, root_hash, &header[..header.len() - 1]),
+            Err(Error::InvalidSignature(2))
+        ));
+        assert!(matches!(
+            client.update_root(3, root_hash, &header),
+            Err(Error::InvalidSignature(3))
+        ));
+        assert_eq!(client.height(), 1);
+        client.update_root(2, root_hash, &header).unwrap();
+        assert_eq!(client.root_hash(), root_hash);
+
+        // proofs against the old root, and updates to it, are stale
+        let (_, query) = prove(&merk, &[10, 200]);
+        assert!(matches!(
+            client.verify_query(&old_proof, &query),
+            Err(Error::StaleRoot {
+                height: 1,
+                trusted: 2
+            })
+        ));
+        assert!(matches!(
+            client.update_root(1, [0; 32], &[]),
+            Err(Error::StaleRoot { .. })
+        ));
+        assert!(matches!(
+            client.update_root(2, [0; 32], &header),
+            Err(Error::HashMismatch(..))
+        ));
+        let (proof, query) = prove(&merk, &[10, 105]);
+        client.verify_query(&proof, &query).unwrap();
+
+        // proofs against roots the client never trusted are invalid
+        let mut other = TempMerk::new().unwrap();
+        other.apply(&make_batch_seq(0..10), &[]).unwrap();
+        let (proof, query) = prove(&other, &[5]);
+        assert!(matches!(
+            client.verify_query(&proof, &query),
+            Err(Error::HashMismatch(..))
+        ));
+    }
+}
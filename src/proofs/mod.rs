@@ -1,13 +1,27 @@
 pub mod chunk;
+pub mod debug;
 pub mod encoding;
+pub mod light_client;
+pub mod partial;
+#[cfg(feature = "proto")]
+pub mod proto;
 pub mod query;
 pub mod tree;
+pub mod visitor;
+pub mod witness;
+
+mod fuzz_tests;
 
 use crate::tree::Hash;
 
+pub use chunk::ChunkProof;
 pub use encoding::{encode_into, Decoder};
-pub use query::Query;
+pub use light_client::LightClient;
+pub use partial::PartialTree;
+pub use query::{Query, QueryProof};
 pub use tree::Tree;
+pub use visitor::ProofVisitor;
+pub use witness::{apply_stateless, Witness};
 
 /// A proof operator, executed to verify the data in a Merkle proof.
 #[derive(Debug, PartialEq)]
@@ -38,4 +52,13 @@ pub enum Node {
 
     /// Represents the key and value of a tree node.
     KV(Vec<u8>, Vec<u8>),
+
+    /// Represents the key of a tree node and the hash of its key/value pair,
+    /// for entries whose value is withheld from the proof (see
+    /// `Query::value_limit`).
+    KVDigest(Vec<u8>, Hash),
+
+    /// Represents the key, value and flags of a tree node whose flags are
+    /// not zero (see `Op::SetFlags`).
+    KVFlags(Vec<u8>, Vec<u8>, u8),
 }
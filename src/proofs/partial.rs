@@ -0,0 +1,333 @@
+//! Partial trees built up from verified proofs, for clients which keep the
+//! part of a store's state they use without keeping the store itself.
+
+use std::cmp::Ordering;
+
+use super::tree::{execute, Tree as ProofTree};
+use super::{Decoder, Node};
+use crate::error::{Error, Result};
+use crate::tree::{flags::FROZEN, Entry, Hash, OpRef};
+
+/// The part of a tree revealed by proofs against its root hash.
+///
+/// A partial tree starts out knowing only the root hash (`PartialTree::new`),
+/// and each proof added with `extend` reveals the nodes it contains. The keys
+/// the proofs cover can then be read with `get`, which fails for keys they
+/// don't cover, and updates of the values of revealed keys can be applied
+/// with `apply` to predict the root hash the store will have after them.
+///
+/// Proofs do not include the heights of the subtrees they leave out, so batches
+/// which insert or delete keys, and so may rebalance the tree, can't be
+/// applied.
+#[derive(Debug)]
+pub struct PartialTree {
+    root: ProofTree,
+}
+
+impl PartialTree {
+    /// Creates a partial tree which knows nothing but its root hash.
+    pub fn new(root_hash: Hash) -> PartialTree {
+        PartialTree {
+            root: Node::Hash(root_hash).into(),
+        }
+    }
+
+    /// Creates a partial tree from a proof (of any kind), failing if the proof
+    /// is not for `root_hash`.
+    pub fn from_proof(bytes: &[u8], root_hash: Hash) -> Result<PartialTree> {
+        let mut tree = PartialTree::new(root_hash);
+        tree.extend(bytes)?;
+        Ok(tree)
+    }
+
+    /// Returns the root hash of the tree, including the updates applied with
+    /// `apply`.
+    pub fn root_hash(&self) -> Result<Hash> {
+        self.root.hash()
+    }
+
+    /// Adds the nodes revealed by a proof against the current root hash, so
+    /// their keys can be read. Fails with `Error::HashMismatch`, leaving the
+    /// tree unchanged, if the proof is for a different root hash.
+    pub fn extend(&mut self, bytes: &[u8]) -> Result<()> {
+        let proof = execute(Decoder::new(bytes), false, |_| Ok(()))?;
+        proof.check_hash(self.root_hash()?)?;
+        merge(&mut self.root, proof);
+        Ok(())
+    }
+
+    /// Gets the value for a key, or `None` if it is proven to be absent.
+    ///
+    /// Fails with `Error::MissingData` if the proofs added so far don't cover
+    /// the key, or with `Error::ValueWithheld` if they prove the key exists
+    /// but withheld its value.
+    pub fn get(&self, key: &[u8]) -> Result<Option<&[u8]>> {
+        // whether a node which is not revealed comes after the last revealed
+        // key before `key`, so `key` could be in the tree between them
+        let mut gap = false;
+        search(&self.root, key, &mut gap).unwrap_or_else(|| absent(gap))
+    }
+
+    /// Applies a batch of puts and flag changes to keys revealed by the proofs
+    /// added so far, updating the root hash. Nothing is applied if the batch
+    /// fails: with `Error::ShapeChange` if it has other operations or puts to
+    /// absent keys, which change the shape of the tree, with
+    /// `Error::MissingData` or `Error::ValueWithheld` for keys whose values
+    /// are not known, or with `Error::Frozen` for puts to frozen entries.
+    pub fn apply<E: Entry>(&mut self, batch: &[E]) -> Result<()> {
+        let paths = batch
+            .iter()
+            .map(|entry| {
+                let path = self.path_to(entry.key())?;
+                let node = path.iter().fold(&self.root, |tree, left| {
+                    &tree.child(*left).expect("Path leads to a child").tree
+                });
+                updated_node(&node.node, entry.key(), entry.op())?;
+                Ok(path)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (entry, path) in batch.iter().zip(paths) {
+            update(&mut self.root, &path, entry.key(), entry.op())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the sides taken from the root to reach the node for `key`.
+    fn path_to(&self, key: &[u8]) -> Result<Vec<bool>> {
+        match path_to(&self.root, key) {
+            Some(mut path) => {
+                path.reverse();
+                Ok(path)
+            }
+            None => match self.get(key)? {
+                None => Err(shape_change(key)),
+                Some(_) => unreachable!("Revealed keys have a path"),
+            },
+        }
+    }
+}
+
+/// The result of looking up a key.
+type Lookup<'a> = Result<Option<&'a [u8]>>;
+
+fn absent(gap: bool) -> Lookup<'static> {
+    if gap {
+        Err(Error::MissingData)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Looks for `key` in the nodes of `tree` in key order, returning `None` if
+/// it is after all of them. Path nodes of proofs are often revealed only by
+/// their key/value hash, so the search can't always tell which side to take.
+fn search<'a>(tree: &'a ProofTree, key: &[u8], gap: &mut bool) -> Option<Lookup<'a>> {
+    let search_child = |left, gap: &mut bool| {
+        let child = tree.child(left)?;
+        search(&child.tree, key, gap)
+    };
+    let (node_key, value) = match &tree.node {
+        Node::KV(node_key, value) | Node::KVFlags(node_key, value, _) => {
+            (node_key, Some(value.as_slice()))
+        }
+        Node::KVDigest(node_key, _) => (node_key, None),
+        Node::KVHash(_) => {
+            if let Some(result) = search_child(true, gap) {
+                return Some(result);
+            }
+            *gap = true;
+            return search_child(false, gap);
+        }
+        Node::Hash(_) => {
+            *gap = true;
+            return None;
+        }
+    };
+    match node_key.as_slice().cmp(key) {
+        Ordering::Equal => Some(
+            value
+                .map(Some)
+                .ok_or_else(|| Error::ValueWithheld(key.to_vec())),
+        ),
+        Ordering::Less => {
+            *gap = false;
+            search_child(false, gap)
+        }
+        Ordering::Greater => search_child(true, gap).or_else(|| Some(absent(*gap))),
+    }
+}
+
+/// Returns the sides taken to reach the node for `key` from `tree`, from the
+/// node up.
+fn path_to(tree: &ProofTree, key: &[u8]) -> Option<Vec<bool>> {
+    let path_through = |left| {
+        let child = tree.child(left)?;
+        let mut path = path_to(&child.tree, key)?;
+        path.push(left);
+        Some(path)
+    };
+    match &tree.node {
+        Node::KV(node_key, _) | Node::KVFlags(node_key, ..) | Node::KVDigest(node_key, _) => {
+            match node_key.as_slice().cmp(key) {
+                Ordering::Equal => Some(vec![]),
+                ordering => path_through(ordering == Ordering::Greater),
+            }
+        }
+        Node::KVHash(_) => path_through(true).or_else(|| path_through(false)),
+        Node::Hash(_) => None,
+    }
+}
+
+fn shape_change(key: &[u8]) -> Error {
+    Error::ShapeChange { key: key.to_vec() }
+}
+
+/// Returns the node for `key` after applying `op` to `node`.
+fn updated_node(node: &Node, key: &[u8], op: OpRef) -> Result<Node> {
+    let (value, flags) = match node {
+        Node::KV(_, value) => (value, 0),
+        Node::KVFlags(_, value, flags) => (value, *flags),
+        // the flags are part of the withheld entry
+        _ => return Err(Error::ValueWithheld(key.to_vec())),
+    };
+    let (value, flags) = match op {
+        OpRef::Put(_) if flags & FROZEN != 0 => return Err(Error::Frozen(key.to_vec())),
+        OpRef::Put(value) => (value.to_vec(), flags),
+        OpRef::SetFlags(flags) => (value.clone(), flags),
+        OpRef::Delete | OpRef::Move { .. } => return Err(shape_change(key)),
+    };
+    Ok(match flags {
+        0 => Node::KV(key.to_vec(), value),
+        flags => Node::KVFlags(key.to_vec(), value, flags),
+    })
+}
+
+/// Applies `op` to the node for `key`, at the end of `path` from `tree`,
+/// updating the hashes of the children on the way.
+fn update(tree: &mut ProofTree, path: &[bool], key: &[u8], op: OpRef) -> Result<()> {
+    let (left, rest) = match path.split_first() {
+        None => {
+            tree.node = updated_node(&tree.node, key, op)?;
+            return Ok(());
+        }
+        Some((left, rest)) => (*left, rest),
+    };
+    let child = tree
+        .child_mut(left)
+        .as_mut()
+        .expect("Path leads to a child");
+    update(&mut child.tree, rest, key, op)?;
+    child.hash = child.tree.hash()?;
+    Ok(())
+}
+
+/// How much of a node a proof reveals.
+fn revealed(node: &Node) -> u8 {
+    match node {
+        Node::Hash(_) => 0,
+        Node::KVHash(_) => 1,
+        Node::KVDigest(..) => 2,
+        Node::KV(..) | Node::KVFlags(..) => 3,
+    }
+}
+
+/// Merges the nodes of `other` into `tree`. Both must have the same hash, so
+/// nodes at the same position are the same node of the full tree.
+fn merge(tree: &mut ProofTree, other: ProofTree) {
+    if let Node::Hash(_) = tree.node {
+        *tree = other;
+        return;
+    }
+    let ProofTree {
+        node, left, right, ..
+    } = other;
+    if revealed(&node) > revealed(&tree.node) {
+        tree.node = node;
+    }
+    for (is_left, other_child) in [(true, left), (false, right)] {
+        if let (Some(child), Some(other_child)) = (tree.child_mut(is_left), other_child) {
+            merge(&mut child.tree, *other_child.tree);
+        }
+    }
+    tree.height = 1 + [true, false]
+        .iter()
+        .filter_map(|left| tree.child(*left))
+        .map(|child| child.tree.height)
+        .max()
+        .unwrap_or(0);
+}
+
+#[cfg(all(test, feature = "full"))]
+mod test {
+    use super::*;
+    use crate::proofs::Query;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    fn prove(merk: &TempMerk, keys: &[u64]) -> Vec<u8> {
+        let mut query = Query::new();
+        for key in keys {
+            query.insert_key(seq_key(*key));
+        }
+        merk.prove(query).unwrap()
+    }
+
+    #[test]
+    fn partial_tree() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let root_hash = merk.root_hash();
+
+        let mut tree = PartialTree::from_proof(&prove(&merk, &[10, 200]), root_hash).unwrap();
+        assert_eq!(tree.get(&seq_key(10)).unwrap(), Some(&[123; 60][..]));
+        assert_eq!(tree.get(&seq_key(200)).unwrap(), None);
+        assert!(matches!(tree.get(&seq_key(50)), Err(Error::MissingData)));
+
+        // proofs against other roots are rejected
+        let mut other = TempMerk::new().unwrap();
+        other.apply(&make_batch_seq(0..10), &[]).unwrap();
+        assert!(matches!(
+            tree.extend(&prove(&other, &[5])),
+            Err(Error::HashMismatch(..))
+        ));
+
+        tree.extend(&prove(&merk, &[50, 51])).unwrap();
+        assert_eq!(tree.root_hash().unwrap(), root_hash);
+        assert_eq!(tree.get(&seq_key(10)).unwrap(), Some(&[123; 60][..]));
+        assert!(tree.get(&seq_key(51)).unwrap().is_some());
+
+        // updates of revealed keys predict the store's next root
+        let batch = [
+            (seq_key(10), Op::Put(vec![1])),
+            (seq_key(50), Op::SetFlags(FROZEN)),
+        ];
+        tree.apply(&batch).unwrap();
+        merk.apply(&batch, &[]).unwrap();
+        assert_eq!(tree.root_hash().unwrap(), merk.root_hash());
+        assert_eq!(tree.get(&seq_key(10)).unwrap(), Some(&[1][..]));
+
+        // batches which change the shape of the tree, or touch keys which are
+        // not revealed, fail without changing it
+        let root_hash = merk.root_hash();
+        for (batch, expected) in [
+            (
+                vec![
+                    (seq_key(10), Op::Put(vec![2])),
+                    (seq_key(11), Op::Put(vec![])),
+                ],
+                "missing",
+            ),
+            (vec![(seq_key(10), Op::Delete)], "shape"),
+            (vec![(seq_key(200), Op::Put(vec![]))], "shape"),
+            (vec![(seq_key(50), Op::Put(vec![]))], "frozen"),
+        ] {
+            let result = tree.apply(&batch);
+            match expected {
+                "missing" => assert!(matches!(result, Err(Error::MissingData))),
+                "shape" => assert!(matches!(result, Err(Error::ShapeChange { .. }))),
+                _ => assert!(matches!(result, Err(Error::Frozen(_)))),
+            }
+            assert_eq!(tree.root_hash().unwrap(), root_hash);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Protobuf messages for proofs and queries, generated with prost from
+//! `proto/merk.proto`, so they can be embedded in gRPC responses, transactions
+//! or other prost messages as typed messages rather than opaque byte blobs.
+//!
+//! The messages are in the `merk` module, named as in the schema (e.g.
+//! `merk::Proof` for `merk.Proof`), and are read and written with
+//! `prost::Message`. The functions here convert them to and from proofs in
+//! the usual encoding and `Query`s.
+
+use std::convert::TryInto;
+
+use super::query::{Direction, Query, QueryItem, Term};
+use super::{encode_into, Decoder, Node, Op};
+use crate::tree::HASH_LENGTH;
+use crate::{Error, Result};
+
+/// The messages of `proto/merk.proto`.
+pub mod merk {
+    include!(concat!(env!("OUT_DIR"), "/merk.rs"));
+}
+
+use merk::op::Op as ProtoOp;
+use merk::query_item::Item;
+
+/// Converts a proof in the usual encoding (as returned by `Merk::prove`) into
+/// a `merk.Proof` message.
+pub fn proof_to_proto(proof: &[u8]) -> Result<merk::Proof> {
+    let ops = Decoder::new(proof)
+        .map(|op| {
+            let op = match op? {
+                Op::Push(Node::Hash(hash)) => ProtoOp::PushHash(hash.to_vec()),
+                Op::Push(Node::KVHash(hash)) => ProtoOp::PushKvHash(hash.to_vec()),
+                Op::Push(Node::KV(key, value)) => ProtoOp::PushKv(merk::Kv {
+                    key,
+                    value,
+                    flags: 0,
+                }),
+                Op::Push(Node::KVFlags(key, value, flags)) => ProtoOp::PushKv(merk::Kv {
+                    key,
+                    value,
+                    flags: flags as u32,
+                }),
+                Op::Push(Node::KVDigest(key, hash)) => ProtoOp::PushKvDigest(merk::KvDigest {
+                    key,
+                    kv_hash: hash.to_vec(),
+                }),
+                Op::Parent => ProtoOp::Parent(merk::Empty {}),
+                Op::Child => ProtoOp::Child(merk::Empty {}),
+            };
+            Ok(merk::Op { op: Some(op) })
+        })
+        .collect::<Result<_>>()?;
+    Ok(merk::Proof { ops })
+}
+
+/// Converts a `merk.Proof` message into a proof in the usual encoding, which
+/// can then be verified with `verify`.
+pub fn proof_from_proto(proof: &merk::Proof) -> Result<Vec<u8>> {
+    let ops = proof
+        .ops
+        .iter()
+        .map(|op| {
+            let op = op
+                .op
+                .as_ref()
+                .ok_or_else(|| Error::Decode("Proof operator has no operation".into()))?;
+            Ok(match op {
+                ProtoOp::PushHash(hash) => Op::Push(Node::Hash(decode_hash(hash)?)),
+                ProtoOp::PushKvHash(hash) => Op::Push(Node::KVHash(decode_hash(hash)?)),
+                ProtoOp::PushKv(kv) => {
+                    let (key, value) = (kv.key.clone(), kv.value.clone());
+                    Op::Push(match kv.flags {
+                        0 => Node::KV(key, value),
+                        flags => Node::KVFlags(
+                            key,
+                            value,
+                            flags
+                                .try_into()
+                                .map_err(|_| Error::Decode("Invalid entry flags".into()))?,
+                        ),
+                    })
+                }
+                ProtoOp::PushKvDigest(digest) => Op::Push(Node::KVDigest(
+                    digest.key.clone(),
+                    decode_hash(&digest.kv_hash)?,
+                )),
+                ProtoOp::Parent(_) => Op::Parent,
+                ProtoOp::Child(_) => Op::Child,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut bytes = vec![];
+    encode_into(ops.iter(), &mut bytes);
+    Ok(bytes)
+}
+
+fn decode_hash(bytes: &[u8]) -> Result<[u8; HASH_LENGTH]> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::Decode("Invalid hash length".into()))
+}
+
+/// Converts a query into a `merk.Query` message.
+pub fn query_to_proto(query: &Query) -> merk::Query {
+    merk::Query {
+        items: query.iter().map(encode_item).collect(),
+        terms: query
+            .terms()
+            .iter()
+            .map(|term| merk::Term {
+                item: Some(encode_item(&term.item)),
+                limit: term.limit.map(|limit| limit as u64),
+                descending: term.direction == Direction::Descending,
+            })
+            .collect(),
+        value_limit: query.get_value_limit().map(|limit| limit as u64),
+    }
+}
+
+/// Converts a `merk.Query` message into a query.
+pub fn query_from_proto(message: &merk::Query) -> Result<Query> {
+    let mut query = Query::new();
+    for item in message.items.iter() {
+        query.insert_item(decode_item(item)?);
+    }
+    for term in message.terms.iter() {
+        let item = term
+            .item
+            .as_ref()
+            .ok_or_else(|| Error::Decode("Query term has no item".into()))?;
+        query.push_term(Term {
+            item: decode_item(item)?,
+            limit: term.limit.map(|limit| limit as usize),
+            direction: match term.descending {
+                true => Direction::Descending,
+                false => Direction::Ascending,
+            },
+        });
+    }
+    if let Some(limit) = message.value_limit {
+        query = query.value_limit(limit as usize);
+    }
+    Ok(query)
+}
+
+fn encode_item(item: &QueryItem) -> merk::QueryItem {
+    let range = |start: &[u8], end: &[u8]| merk::Range {
+        start: start.to_vec(),
+        end: end.to_vec(),
+    };
+    let item = match item {
+        QueryItem::Key(key) => Item::Key(key.clone()),
+        QueryItem::Range(r) => Item::Range(range(&r.start, &r.end)),
+        QueryItem::RangeInclusive(r) => Item::RangeInclusive(range(r.start(), r.end())),
+    };
+    merk::QueryItem { item: Some(item) }
+}
+
+fn decode_item(item: &merk::QueryItem) -> Result<QueryItem> {
+    match &item.item {
+        Some(Item::Key(key)) => Ok(QueryItem::Key(key.clone())),
+        Some(Item::Range(range)) => Ok(QueryItem::Range(range.start.clone()..range.end.clone())),
+        Some(Item::RangeInclusive(range)) => Ok(QueryItem::RangeInclusive(
+            range.start.clone()..=range.end.clone(),
+        )),
+        None => Err(Error::Decode("Query item has no key or range".into())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use super::*;
+    use crate::proofs::query::verify;
+    use crate::test_utils::make_tree_seq;
+    use crate::tree::{PanicSource, RefWalker};
+
+    #[test]
+    fn proof_and_query_roundtrip() {
+        let mut query = Term::key(vec![0, 0, 0, 0, 0, 0, 0, 3])
+            | Term::range(vec![0, 0, 0, 0, 0, 0, 0, 5]..vec![0, 0, 0, 0, 0, 0, 0, 8]);
+        query.insert_term(Term::prefix(vec![0]).limit(2).descending());
+        let message = query_to_proto(&query);
+        let decoded = merk::Query::decode(&message.encode_to_vec()[..]).unwrap();
+        let decoded = query_from_proto(&decoded).unwrap();
+        assert_eq!(query_to_proto(&decoded), message);
+        assert_eq!(decoded.terms()[2].limit, Some(2));
+        let limited = query_from_proto(&query_to_proto(&query.clone().value_limit(10))).unwrap();
+        assert_eq!(limited.get_value_limit(), Some(10));
+
+        let mut tree = make_tree_seq(10);
+        let root_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let items: Vec<_> = query.iter().cloned().collect();
+        let (ops, _) = walker.create_proof(&items).unwrap();
+        let mut proof = vec![];
+        encode_into(ops.iter(), &mut proof);
+
+        let bytes = proof_to_proto(&proof).unwrap().encode_to_vec();
+        let message = merk::Proof::decode(&bytes[..]).unwrap();
+        assert_eq!(proof_from_proto(&message).unwrap(), proof);
+        let map = verify(&proof_from_proto(&message).unwrap(), root_hash).unwrap();
+        assert!(map.get(&[0, 0, 0, 0, 0, 0, 0, 6]).unwrap().is_some());
+
+        assert!(merk::Proof::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn wire_format() {
+        // a `Proof` with a single `parent` operator
+        let mut proof = vec![];
+        encode_into([Op::Parent].iter(), &mut proof);
+        assert_eq!(
+            proof_to_proto(&proof).unwrap().encode_to_vec(),
+            vec![0x0a, 0x02, 0x22, 0x00]
+        );
+
+        // unknown fields are skipped
+        let message = merk::Proof::decode(&[0x10, 0x96, 0x01, 0x0a, 0x02, 0x22, 0x00][..]).unwrap();
+        assert_eq!(proof_from_proto(&message).unwrap(), proof);
+
+        // flags are a field of the `KV` message
+        let mut proof = vec![];
+        encode_into(
+            [Op::Push(Node::KVFlags(vec![1], vec![2], 1))].iter(),
+            &mut proof,
+        );
+        let message = proof_to_proto(&proof).unwrap();
+        assert_eq!(
+            message.encode_to_vec(),
+            vec![0x0a, 0x0a, 0x1a, 0x08, 0x0a, 0x01, 1, 0x12, 0x01, 2, 0x18, 1]
+        );
+        assert_eq!(proof_from_proto(&message).unwrap(), proof);
+
+        // operators without an operation, and invalid hashes, are rejected
+        let empty = merk::Proof {
+            ops: vec![merk::Op { op: None }],
+        };
+        assert!(proof_from_proto(&empty).is_err());
+        let short = merk::Proof {
+            ops: vec![merk::Op {
+                op: Some(ProtoOp::PushHash(vec![0; 31])),
+            }],
+        };
+        assert!(proof_from_proto(&short).is_err());
+    }
+}
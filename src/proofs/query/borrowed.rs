@@ -0,0 +1,245 @@
+//! Proof creation which reads pruned nodes in place as `TreeRef`s.
+//!
+//! `RefWalker::create_proof` loads every node it visits into the in-memory
+//! tree, so each fetched node is decoded into a `Tree` with its own
+//! allocations, and the tree has to be borrowed mutably. Here the in-memory
+//! tree is only read, and nodes below it are fetched as encoded bytes and read
+//! without being decoded. The resulting proofs are identical.
+
+use std::borrow::Cow;
+use std::collections::LinkedList;
+
+use super::{split_query, QueryItem};
+use crate::error::{Error, Result};
+use crate::proofs::{Node, Op};
+use crate::tree::{FetchBytes, Hash, Tree, TreeRef};
+
+/// A node which a proof is being created from.
+enum NodeView<'a> {
+    /// A node of the in-memory tree.
+    Tree(&'a Tree),
+    /// A node read from its encoding.
+    Ref(TreeRef<'a>),
+}
+
+impl<'a> NodeView<'a> {
+    fn key(&self) -> Cow<'_, [u8]> {
+        match self {
+            NodeView::Tree(tree) => tree.key(),
+            NodeView::Ref(node) => Cow::Borrowed(node.key()),
+        }
+    }
+
+    /// Creates a `Node::KV` from the node's key and value (a `Node::KVFlags`
+    /// if it has flags), or a `Node::KVDigest` if the value is longer than
+    /// `value_limit`.
+    fn to_kv_node<S: FetchBytes>(&self, source: &S, value_limit: Option<usize>) -> Result<Node> {
+        let value = match self {
+            NodeView::Tree(tree) if tree.value_loaded() => tree.value().to_vec(),
+            NodeView::Tree(tree) => source.fetch_value(&tree.key())?,
+            NodeView::Ref(node) => match node.value() {
+                Some(value) => value.to_vec(),
+                None => source.fetch_value(node.key())?,
+            },
+        };
+        if value_limit.is_some_and(|limit| value.len() > limit) {
+            return Ok(Node::KVDigest(self.key().to_vec(), *self.kv_hash()));
+        }
+        let flags = match self {
+            NodeView::Tree(tree) => tree.flags(),
+            NodeView::Ref(node) => node.flags(),
+        };
+        if flags != 0 {
+            return Ok(Node::KVFlags(self.key().to_vec(), value, flags));
+        }
+        Ok(Node::KV(self.key().to_vec(), value))
+    }
+
+    fn kv_hash(&self) -> &Hash {
+        match self {
+            NodeView::Tree(tree) => tree.kv_hash(),
+            NodeView::Ref(node) => node.kv_hash(),
+        }
+    }
+
+    fn to_kvhash_node(&self) -> Node {
+        Node::KVHash(*self.kv_hash())
+    }
+
+    fn link_hash(&self, left: bool) -> Option<Hash> {
+        match self {
+            NodeView::Tree(tree) => tree.link(left).map(|link| *link.hash()),
+            NodeView::Ref(node) => node.link(left).map(|link| *link.hash()),
+        }
+    }
+
+    /// Calls `f` with the child on the given side, fetching it from `source`
+    /// if it is not in memory.
+    fn with_child<S, T, F>(&self, left: bool, source: &S, f: F) -> Result<T>
+    where
+        S: FetchBytes,
+        F: FnOnce(Option<&NodeView>) -> Result<T>,
+    {
+        let (key, inlined) = match self {
+            NodeView::Tree(tree) => match tree.link(left) {
+                None => return f(None),
+                Some(link) => match link.tree() {
+                    Some(child) => return f(Some(&NodeView::Tree(child))),
+                    None => (link.key(), None),
+                },
+            },
+            NodeView::Ref(node) => match node.link(left) {
+                None => return f(None),
+                Some(link) => (Cow::Borrowed(link.key()), node.inlined(left)),
+            },
+        };
+
+        if let Some(bytes) = inlined {
+            return f(Some(&NodeView::Ref(TreeRef::decode(&key, bytes)?)));
+        }
+
+        let bytes = source
+            .fetch_bytes(&key)?
+            .ok_or_else(|| Error::InvariantViolation {
+                key: key.to_vec(),
+                detail: "Referenced node does not exist".into(),
+            })?;
+        f(Some(&NodeView::Ref(TreeRef::decode(&key, bytes.as_ref())?)))
+    }
+}
+
+/// Generates a proof for the list of queried keys like
+/// `RefWalker::create_proof`, without modifying `tree`. Values longer than
+/// `value_limit` are withheld from the proof (see `Query::value_limit`).
+pub(crate) fn create_proof<S: FetchBytes>(
+    tree: &Tree,
+    query: &[QueryItem],
+    source: &S,
+    value_limit: Option<usize>,
+) -> Result<(LinkedList<Op>, (bool, bool))> {
+    create_node_proof(&NodeView::Tree(tree), query, source, value_limit)
+}
+
+fn create_node_proof<S: FetchBytes>(
+    node: &NodeView,
+    query: &[QueryItem],
+    source: &S,
+    value_limit: Option<usize>,
+) -> Result<(LinkedList<Op>, (bool, bool))> {
+    let (search, left_items, right_items) = split_query(query, &node.key());
+
+    let (mut proof, left_absence) =
+        create_child_proof(node, true, left_items, source, value_limit)?;
+    let (mut right_proof, right_absence) =
+        create_child_proof(node, false, right_items, source, value_limit)?;
+
+    let (has_left, has_right) = (!proof.is_empty(), !right_proof.is_empty());
+
+    proof.push_back(match search {
+        Ok(_) => Op::Push(node.to_kv_node(source, value_limit)?),
+        Err(_) => {
+            if left_absence.1 || right_absence.0 {
+                Op::Push(node.to_kv_node(source, value_limit)?)
+            } else {
+                Op::Push(node.to_kvhash_node())
+            }
+        }
+    });
+
+    if has_left {
+        proof.push_back(Op::Parent);
+    }
+
+    if has_right {
+        proof.append(&mut right_proof);
+        proof.push_back(Op::Child);
+    }
+
+    Ok((proof, (left_absence.0, right_absence.1)))
+}
+
+fn create_child_proof<S: FetchBytes>(
+    node: &NodeView,
+    left: bool,
+    query: &[QueryItem],
+    source: &S,
+    value_limit: Option<usize>,
+) -> Result<(LinkedList<Op>, (bool, bool))> {
+    if !query.is_empty() {
+        return node.with_child(left, source, |maybe_child| match maybe_child {
+            Some(child) => create_node_proof(child, query, source, value_limit),
+            None => Ok((LinkedList::new(), (true, true))),
+        });
+    }
+
+    let mut proof = LinkedList::new();
+    match node.link_hash(left) {
+        Some(hash) => {
+            proof.push_back(Op::Push(Node::Hash(hash)));
+            Ok((proof, (false, false)))
+        }
+        None => Ok((proof, (false, false))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::tree::{Commit, NoopCommit, PanicSource, RefWalker};
+    use std::collections::HashMap;
+
+    /// Keeps the encodings of every committed node, and prunes all of them.
+    #[derive(Default)]
+    struct MapStore(HashMap<Vec<u8>, Vec<u8>>);
+
+    impl Commit for MapStore {
+        fn write(&mut self, tree: &Tree) -> Result<()> {
+            self.0.insert(tree.key().to_vec(), tree.encode());
+            Ok(())
+        }
+
+        fn prune(&self, tree: &Tree) -> (bool, bool) {
+            (tree.height() < 3, tree.height() < 3)
+        }
+    }
+
+    impl FetchBytes for MapStore {
+        type Bytes = Vec<u8>;
+
+        fn fetch_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(key).cloned())
+        }
+
+        fn fetch_value(&self, _: &[u8]) -> Result<Vec<u8>> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn matches_walker_proofs() {
+        let mut tree = make_tree_seq(100);
+        let mut store = MapStore::default();
+        tree.commit(&mut NoopCommit {}).unwrap();
+        let mut pruned = make_tree_seq(100);
+        pruned.commit(&mut store).unwrap();
+
+        let key = |n: u64| n.to_be_bytes().to_vec();
+        let queries = vec![
+            vec![QueryItem::Key(key(5))],
+            vec![QueryItem::Key(key(1000))],
+            vec![
+                QueryItem::Range(key(10)..key(20)),
+                QueryItem::RangeInclusive(key(50)..=key(60)),
+            ],
+            vec![QueryItem::Range(key(0)..key(100))],
+        ];
+
+        for query in queries {
+            let mut walker = RefWalker::new(&mut tree, PanicSource {});
+            let expected = walker.create_proof(query.as_slice()).unwrap();
+            let actual = create_proof(&pruned, query.as_slice(), &store, None).unwrap();
+            assert_eq!(actual, expected);
+        }
+    }
+}
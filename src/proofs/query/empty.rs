@@ -0,0 +1,137 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
+use std::ops::Range;
+
+use super::super::tree::execute;
+use super::super::{Decoder, Node};
+use crate::error::{Error, Result};
+use crate::tree::Hash;
+
+/// Verifies an encoded proof that the tree contains no entries with keys in
+/// `range`, as created by `Merk::prove_empty_range`.
+///
+/// Rather than including every entry in the range, the proof only has to
+/// include the entries directly before and after it (or show that the range
+/// extends past the edge of the tree), so it stays the size of a single key
+/// proof.
+///
+/// Returns `Err` if the proof does not match `expected_hash`, if it includes
+/// an entry in the range, or if it leaves out data which could contain an
+/// entry in the range.
+pub fn verify_empty_range(bytes: &[u8], range: &Range<Vec<u8>>, expected_hash: Hash) -> Result<()> {
+    let ops = Decoder::new(bytes);
+
+    let mut last_push: Option<Node> = None;
+    let mut proven = false;
+
+    let root = execute(ops, true, |node| {
+        if let Node::KV(key, _) | Node::KVDigest(key, _) | Node::KVFlags(key, ..) = node {
+            if range.contains(key) {
+                return Err(Error::Proof(format!(
+                    "Range is not empty, contains key {:?}",
+                    key
+                )));
+            }
+
+            // the first entry after the range must directly follow the entry
+            // before it, or be the leftmost node of the tree
+            if !proven && key >= &range.end {
+                match last_push {
+                    None
+                    | Some(Node::KV(..))
+                    | Some(Node::KVDigest(..))
+                    | Some(Node::KVFlags(..)) => proven = true,
+                    Some(_) => return Err(Error::MissingData),
+                }
+            }
+        }
+
+        last_push = Some(node.clone());
+        Ok(())
+    })?;
+
+    // no entry after the range, so the entry before it must be the rightmost
+    // node of the tree
+    if !proven
+        && !matches!(
+            last_push,
+            Some(Node::KV(..)) | Some(Node::KVDigest(..)) | Some(Node::KVFlags(..))
+        )
+    {
+        return Err(Error::MissingData);
+    }
+
+    root.check_hash(expected_hash)?;
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "full"))]
+mod test {
+    use super::super::super::encoding::encode_into;
+    use super::super::super::Op;
+    use super::super::QueryItem;
+    use super::*;
+    use crate::test_utils::make_tree_seq;
+    use crate::tree::{PanicSource, RefWalker};
+
+    fn prove(tree: &mut crate::tree::Tree, range: Range<Vec<u8>>) -> Vec<u8> {
+        let mut walker = RefWalker::new(tree, PanicSource {});
+        let (proof, _) = walker.create_proof(&[QueryItem::Range(range)]).unwrap();
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn empty_range() {
+        let mut tree = make_tree_seq(10);
+        let root_hash = tree.hash();
+
+        let gap = vec![0, 0, 0, 0, 0, 0, 0, 4, 1]..vec![0, 0, 0, 0, 0, 0, 0, 5];
+        let bytes = prove(&mut tree, gap.clone());
+        verify_empty_range(&bytes, &gap, root_hash).unwrap();
+        assert!(matches!(
+            verify_empty_range(&bytes, &gap, [1; 32]),
+            Err(Error::HashMismatch(..))
+        ));
+
+        // the proof for the gap does not prove a wider range
+        let wide = vec![0, 0, 0, 0, 0, 0, 0, 4, 1]..vec![0, 0, 0, 0, 0, 0, 0, 7];
+        assert!(verify_empty_range(&bytes, &wide, root_hash).is_err());
+
+        // past the right edge of the tree
+        let tail = vec![1]..vec![2];
+        let bytes = prove(&mut tree, tail.clone());
+        verify_empty_range(&bytes, &tail, root_hash).unwrap();
+    }
+
+    #[test]
+    fn non_empty_range() {
+        let mut tree = make_tree_seq(10);
+        let root_hash = tree.hash();
+
+        let range = vec![0, 0, 0, 0, 0, 0, 0, 4]..vec![0, 0, 0, 0, 0, 0, 0, 5];
+        let bytes = prove(&mut tree, range.clone());
+        assert!(matches!(
+            verify_empty_range(&bytes, &range, root_hash),
+            Err(Error::Proof(_))
+        ));
+
+        // abridged proof which leaves out the entries around the range
+        let mut bytes = vec![];
+        encode_into([Op::Push(Node::Hash(root_hash))].iter(), &mut bytes);
+        assert!(matches!(
+            verify_empty_range(&bytes, &range, root_hash),
+            Err(Error::MissingData)
+        ));
+    }
+}
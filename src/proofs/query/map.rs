@@ -1,4 +1,15 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
 use super::super::Node;
+use crate::tree::{kv_hash, Hash, Hasher};
 use crate::{Error, Result};
 use std::collections::btree_map;
 use std::collections::BTreeMap;
@@ -13,16 +24,21 @@ impl MapBuilder {
     pub fn new() -> Self {
         MapBuilder(Map {
             entries: Default::default(),
+            withheld: Default::default(),
             right_edge: true,
         })
     }
 
-    /// Adds the node's data to the uncerlying `Map` (if node is type `KV`), or
-    /// makes a note of non-contiguous data (if node is type `KVHash` or
-    /// `Hash`).
+    /// Adds the node's data to the uncerlying `Map` (if node is type `KV`,
+    /// `KVFlags` or `KVDigest`), or makes a note of non-contiguous data (if node is type
+    /// `KVHash` or `Hash`).
     pub fn insert(&mut self, node: &Node) -> Result<()> {
         match node {
-            Node::KV(key, value) => {
+            Node::KVDigest(key, kv_hash) => {
+                self.insert(&Node::KV(key.clone(), vec![]))?;
+                self.0.withheld.insert(key.clone(), *kv_hash);
+            }
+            Node::KV(key, value) | Node::KVFlags(key, value, _) => {
                 if let Some((prev_key, _)) = self.0.entries.last_key_value() {
                     if key <= prev_key {
                         return Err(Error::Key(
@@ -51,8 +67,12 @@ impl MapBuilder {
 /// against a known root hash), and allows a consumer to access the data by
 /// looking up individual keys using the `get` method, or iterating over ranges
 /// using the `range` method.
+#[derive(Clone)]
 pub struct Map {
     entries: BTreeMap<Vec<u8>, (bool, Vec<u8>)>,
+    /// The hashes of the key/value pairs of the entries whose values were
+    /// withheld from the proof, which are in `entries` with empty values.
+    withheld: BTreeMap<Vec<u8>, Hash>,
     right_edge: bool,
 }
 
@@ -61,7 +81,14 @@ impl Map {
     /// exist in the tree. If the proof does not include the data and also does
     /// not prove that the key is absent in the tree (meaning the proof is not
     /// valid), an error will be returned.
-    pub fn get<'a>(&'a self, key: &'a [u8]) -> Result<Option<&'a [u8]>> {
+    ///
+    /// Fails with `Error::ValueWithheld` if the key was proven to exist but its
+    /// value was withheld from the proof, see `verify_withheld`.
+    pub fn get<'a>(&'a self, key: &[u8]) -> Result<Option<&'a [u8]>> {
+        if self.withheld.contains_key(key) {
+            return Err(Error::ValueWithheld(key.to_vec()));
+        }
+
         // if key is in proof just get from entries
         if let Some((_, value)) = self.entries.get(key) {
             return Ok(Some(value.as_slice()));
@@ -81,7 +108,7 @@ impl Map {
     /// of keys. If during iteration we encounter a gap in the data (e.g. the
     /// proof did not include all nodes within the range), the iterator will
     /// yield an error.
-    pub fn range<'a, R: RangeBounds<&'a [u8]>>(&'a self, bounds: R) -> Range {
+    pub fn range<'a, 'b, R: RangeBounds<&'b [u8]>>(&'a self, bounds: R) -> Range<'a> {
         let start_key = bound_to_inner(bounds.start_bound()).map(|x| (*x).into());
         let bounds = bounds_to_vec(bounds);
 
@@ -92,6 +119,61 @@ impl Map {
             iter: self.entries.range(bounds),
         }
     }
+
+    /// Checks a value fetched separately for a key whose value was withheld
+    /// from the proof (see `Query::value_limit`) against the hash of the
+    /// entry in the proof. Fails with `Error::ValueNotWithheld` if the proof
+    /// does not include the key with a withheld value, or
+    /// `Error::HashMismatch` if the value is not the one in the tree.
+    pub fn verify_withheld(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let expected = self
+            .withheld
+            .get(key)
+            .ok_or_else(|| Error::ValueNotWithheld(key.to_vec()))?;
+        let actual = kv_hash::<Hasher>(key, value)?;
+        if actual != *expected {
+            return Err(Error::HashMismatch(*expected, actual));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the proof shows `key` to hold `value`, comparing it to
+    /// the hash of the entry if its value was withheld (see
+    /// `Query::hashes_only`). Returns false if the key holds a different value
+    /// or was proven to not exist, and fails if the proof does not include
+    /// the key or prove its absence.
+    pub fn matches_value(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        if let Some(expected) = self.withheld.get(key) {
+            return Ok(kv_hash::<Hasher>(key, value)? == *expected);
+        }
+        Ok(self.get(key)? == Some(value))
+    }
+
+    /// Returns the number of entries in the requested range of keys, including
+    /// those whose values were withheld from the proof. Fails with
+    /// `Error::MissingData` if the proof does not include every key in the
+    /// range.
+    pub fn count<'b, R: RangeBounds<&'b [u8]>>(&self, bounds: R) -> Result<u64> {
+        let mut count = 0;
+        for entry in self.range(bounds) {
+            match entry {
+                Ok(_) | Err(Error::ValueWithheld(_)) => count += 1,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns the keys of the entries in the proof within the given range,
+    /// without checking that the proof includes every key in the range.
+    pub(crate) fn keys<'a, R: RangeBounds<&'a [u8]>>(
+        &'a self,
+        bounds: R,
+    ) -> impl DoubleEndedIterator<Item = &'a [u8]> {
+        self.entries
+            .range(bounds_to_vec(bounds))
+            .map(|(key, _)| key.as_slice())
+    }
 }
 
 /// Returns `None` for `Bound::Unbounded`, or the inner key value for
@@ -195,6 +277,10 @@ impl<'a> Iterator for Range<'a> {
             return Some(Err(Error::MissingData));
         }
 
+        if self.map.withheld.contains_key(key) {
+            return Some(Err(Error::ValueWithheld(key.clone())));
+        }
+
         // passed checks, return entry
         Some(Ok((key.as_slice(), value.as_slice())))
     }
@@ -1,23 +1,45 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
+#[cfg(feature = "full")]
+mod borrowed;
+mod empty;
 mod map;
+mod stream;
+mod term;
 
 #[cfg(feature = "full")]
-use {super::Op, std::collections::LinkedList};
+use std::collections::LinkedList;
 
-use super::tree::execute;
-use super::{Decoder, Node};
+use super::tree::{execute, execute_with};
+use super::{Decoder, Node, Op, ProofVisitor};
 use crate::error::{Error, Result};
 use crate::tree::{Fetch, Hash, Link, RefWalker};
 use std::cmp::{max, min, Ordering};
-use std::collections::BTreeSet;
-use std::ops::{Range, RangeInclusive};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::{self, Bound, RangeInclusive};
 
+#[cfg(feature = "full")]
+pub(crate) use borrowed::create_proof;
+pub use empty::verify_empty_range;
 pub use map::*;
+pub use stream::{verify_iter, VerifyIter};
+pub use term::{Direction, Term};
 
 /// `Query` represents one or more keys or ranges of keys, which can be used to
 /// resolve a proof which will include all of the requested values.
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Query {
     items: BTreeSet<QueryItem>,
+    terms: Vec<Term>,
+    value_limit: Option<usize>,
 }
 
 impl Query {
@@ -52,7 +74,7 @@ impl Query {
     /// If a range including the range already exists in the query, this will
     /// have no effect. If the query already includes a range that overlaps with
     /// the range, the ranges will be joined together.
-    pub fn insert_range(&mut self, range: Range<Vec<u8>>) {
+    pub fn insert_range(&mut self, range: ops::Range<Vec<u8>>) {
         let range = QueryItem::Range(range);
         self.insert_item(range);
     }
@@ -83,12 +105,64 @@ impl Query {
 
         self.items.insert(item);
     }
+
+    /// Adds a `Term` to the query. Terms without a limit are added as a
+    /// `QueryItem`, while limited terms are narrowed down to the entries they
+    /// select when the proof is created.
+    ///
+    /// The terms are kept in the order they were added, so the entries
+    /// selected by each can be read back from the verified proof.
+    pub fn insert_term(&mut self, term: Term) {
+        if term.limit.is_none() {
+            self.insert_item(term.item.clone());
+        }
+        self.terms.push(term);
+    }
+
+    /// Adds a `Term` to the query's terms without adding its item, for
+    /// queries decoded with their items already merged in.
+    #[cfg(feature = "proto")]
+    pub(crate) fn push_term(&mut self, term: Term) {
+        self.terms.push(term);
+    }
+
+    /// Returns the terms added to the query with `insert_term`.
+    pub fn terms(&self) -> &[Term] {
+        &self.terms
+    }
+
+    /// Withholds values longer than `bytes` from the resulting proof, which
+    /// then only includes the hash of the key/value pair of those entries
+    /// (`Node::KVDigest`). The verifier learns that the entries exist, and
+    /// checks values fetched separately against the proof with
+    /// `Map::verify_withheld`. This keeps proofs small when values are huge.
+    pub fn value_limit(mut self, bytes: usize) -> Self {
+        self.value_limit = Some(bytes);
+        self
+    }
+
+    /// Withholds every non-empty value from the resulting proof, so it only
+    /// includes the hashes of the key/value pairs of the queried entries,
+    /// like `value_limit(0)`. For clients which only need to confirm that
+    /// their cached values have not changed, see `Map::matches_value`.
+    pub fn hashes_only(self) -> Self {
+        self.value_limit(0)
+    }
+
+    /// Returns the limit set with `value_limit`, if any.
+    pub fn get_value_limit(&self) -> Option<usize> {
+        self.value_limit
+    }
 }
 
 impl<Q: Into<QueryItem>> From<Vec<Q>> for Query {
     fn from(other: Vec<Q>) -> Self {
         let items = other.into_iter().map(Into::into).collect();
-        Query { items }
+        Query {
+            items,
+            terms: vec![],
+            value_limit: None,
+        }
     }
 }
 
@@ -111,7 +185,7 @@ impl IntoIterator for Query {
 #[derive(Clone, Debug)]
 pub enum QueryItem {
     Key(Vec<u8>),
-    Range(Range<Vec<u8>>),
+    Range(ops::Range<Vec<u8>>),
     RangeInclusive(RangeInclusive<Vec<u8>>),
 }
 
@@ -134,7 +208,7 @@ impl QueryItem {
 
     pub fn contains(&self, key: &[u8]) -> bool {
         let (bound, inclusive) = self.upper_bound();
-        return key >= self.lower_bound() && (key < bound || (key == bound && inclusive));
+        key >= self.lower_bound() && (key < bound || (key == bound && inclusive))
     }
 
     fn merge(self, other: QueryItem) -> QueryItem {
@@ -144,7 +218,7 @@ impl QueryItem {
         if end.1 {
             QueryItem::RangeInclusive(RangeInclusive::new(start, end.0.to_vec()))
         } else {
-            QueryItem::Range(Range {
+            QueryItem::Range(ops::Range {
                 start,
                 end: end.0.to_vec(),
             })
@@ -211,7 +285,7 @@ impl From<Vec<u8>> for QueryItem {
 impl Link {
     /// Creates a `Node::Hash` from this link. Panics if the link is of variant
     /// `Link::Modified` since its hash has not yet been computed.
-    #[cfg(feature = "full")]
+    #[cfg(all(test, feature = "full"))]
     fn to_hash_node(&self) -> Node {
         let hash = match self {
             Link::Reference { hash, .. } => hash,
@@ -225,13 +299,73 @@ impl Link {
     }
 }
 
+/// Finds the query item which contains `node_key` (if any), and splits the
+/// query into the items which the node's left and right subtrees have to be
+/// searched for.
+#[cfg(feature = "full")]
+pub(crate) fn split_query<'q>(
+    query: &'q [QueryItem],
+    node_key: &[u8],
+) -> (
+    std::result::Result<usize, usize>,
+    &'q [QueryItem],
+    &'q [QueryItem],
+) {
+    // TODO: don't copy into vec, support comparing QI to byte slice
+    let node_item = QueryItem::Key(node_key.to_vec());
+    let search = query.binary_search_by(|key| key.cmp(&node_item));
+
+    let (left_items, right_items) = match search.map(|index| (index, query.get(index))) {
+        Ok((index, Some(item))) => {
+            let left_bound = item.lower_bound();
+            let right_bound = item.upper_bound().0;
+
+            // if range starts before this node's key, include it in left
+            // child's query
+            let left_query = if left_bound < node_key {
+                query.get(..=index)
+            } else {
+                query.get(..index)
+            };
+
+            // if range ends after this node's key, include it in right
+            // child's query
+            let right_query = if right_bound > node_key {
+                query.get(index..)
+            } else {
+                query.get(index + 1..)
+            };
+
+            (left_query, right_query)
+        }
+        Ok((index, None)) | Err(index) => (query.get(..index), query.get(index..)),
+    };
+    let (left_items, right_items) = (
+        left_items.unwrap_or_default(),
+        right_items.unwrap_or_default(),
+    );
+
+    (search, left_items, right_items)
+}
+
 impl<'a, S> RefWalker<'a, S>
 where
     S: Fetch + Sized + Send + Clone,
 {
-    /// Creates a `Node::KV` from the key/value pair of the root node.
-    pub(crate) fn to_kv_node(&self) -> Node {
-        Node::KV(self.tree().key().to_vec(), self.tree().value().to_vec())
+    /// Creates a `Node::KV` (or `Node::KVFlags` if it has flags) from the
+    /// key/value pair of the root node, fetching the value if it is not
+    /// loaded.
+    pub(crate) fn to_kv_node(&self) -> Result<Node> {
+        let tree = self.tree();
+        let value = if tree.value_loaded() {
+            tree.value().to_vec()
+        } else {
+            self.source().fetch_value(&tree.key())?
+        };
+        if tree.flags() != 0 {
+            return Ok(Node::KVFlags(tree.key().to_vec(), value, tree.flags()));
+        }
+        Ok(Node::KV(tree.key().to_vec(), value))
     }
 
     /// Creates a `Node::KVHash` from the hash of the key/value pair of the root
@@ -247,35 +381,11 @@ where
 
     #[cfg(feature = "full")]
     pub(crate) fn execute_query(&mut self, query: &[QueryItem]) -> Result<LinkedList<Op>> {
-        let node_key = QueryItem::Key(self.tree().key().to_vec());
-        let search = query.binary_search_by(|key| key.cmp(&node_key));
-        let (left_items, right_items) = match search {
-            Ok(index) => {
-                let item = &query[index];
-                let left_bound = item.lower_bound();
-                let right_bound = item.upper_bound().0;
-                // if range starts before this node's key, include it in left
-                // child's query
-                let left_query = if left_bound < self.tree().key() {
-                    &query[..=index]
-                } else {
-                    &query[..index]
-                };
-                // if range ends after this node's key, include it in right
-                // child's query
-                let right_query = if right_bound > self.tree().key() {
-                    &query[index..]
-                } else {
-                    &query[index + 1..]
-                };
-                (left_query, right_query)
-            }
-            Err(index) => (&query[..index], &query[index..]),
-        };
+        let (search, left_items, right_items) = split_query(query, &self.tree().key());
         let mut left_ops = self.execute_child_query(true, left_items)?;
         let mut right_ops = self.execute_child_query(false, right_items)?;
         if search.is_ok() {
-            left_ops.push_back(Op::Push(self.to_kv_node()));
+            left_ops.push_back(Op::Push(self.to_kv_node()?));
         }
         left_ops.append(&mut right_ops);
         Ok(left_ops)
@@ -297,41 +407,15 @@ where
     /// containing the generated proof operators, and a tuple representing if
     /// any keys were queried were less than the left edge or greater than the
     /// right edge, respectively.
-    #[cfg(feature = "full")]
+    ///
+    /// Stores create proofs with `query::create_proof`, which does not load
+    /// nodes into the tree; this is kept as the reference it is tested against.
+    #[cfg(all(test, feature = "full"))]
     pub(crate) fn create_proof(
         &mut self,
         query: &[QueryItem],
     ) -> Result<(LinkedList<Op>, (bool, bool))> {
-        // TODO: don't copy into vec, support comparing QI to byte slice
-        let node_key = QueryItem::Key(self.tree().key().to_vec());
-        let search = query.binary_search_by(|key| key.cmp(&node_key));
-
-        let (left_items, right_items) = match search {
-            Ok(index) => {
-                let item = &query[index];
-                let left_bound = item.lower_bound();
-                let right_bound = item.upper_bound().0;
-
-                // if range starts before this node's key, include it in left
-                // child's query
-                let left_query = if left_bound < self.tree().key() {
-                    &query[..=index]
-                } else {
-                    &query[..index]
-                };
-
-                // if range ends after this node's key, include it in right
-                // child's query
-                let right_query = if right_bound > self.tree().key() {
-                    &query[index..]
-                } else {
-                    &query[index + 1..]
-                };
-
-                (left_query, right_query)
-            }
-            Err(index) => (&query[..index], &query[index..]),
-        };
+        let (search, left_items, right_items) = split_query(query, &self.tree().key());
 
         let (mut proof, left_absence) = self.create_child_proof(true, left_items)?;
         let (mut right_proof, right_absence) = self.create_child_proof(false, right_items)?;
@@ -339,10 +423,10 @@ where
         let (has_left, has_right) = (!proof.is_empty(), !right_proof.is_empty());
 
         proof.push_back(match search {
-            Ok(_) => Op::Push(self.to_kv_node()),
+            Ok(_) => Op::Push(self.to_kv_node()?),
             Err(_) => {
                 if left_absence.1 || right_absence.0 {
-                    Op::Push(self.to_kv_node())
+                    Op::Push(self.to_kv_node()?)
                 } else {
                     Op::Push(self.to_kvhash_node())
                 }
@@ -363,7 +447,7 @@ where
 
     /// Similar to `create_proof`. Recurses into the child on the given side and
     /// generates a proof for the queried keys.
-    #[cfg(feature = "full")]
+    #[cfg(all(test, feature = "full"))]
     fn create_child_proof(
         &mut self,
         left: bool,
@@ -385,17 +469,138 @@ where
     }
 }
 
+/// An encoded query proof, as returned by `Merk::prove`.
+///
+/// The proof is a sequence of operators (see `encoding`) which rebuild the
+/// parts of the tree needed to prove the queried entries, and hashes to the
+/// root hash of the tree. It is executed by the same verification core as
+/// chunk proofs, with the entries it contains collected along the way.
+#[derive(Clone, Copy, Debug)]
+pub struct QueryProof<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> QueryProof<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        QueryProof { bytes }
+    }
+
+    /// Returns the encoded proof.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Returns an iterator decoding the proof's operators.
+    pub fn ops(&self) -> Decoder<'a> {
+        Decoder::new(self.bytes)
+    }
+
+    /// Verifies the proof against `expected_hash`, returning a map of the
+    /// entries it proves.
+    pub fn verify(&self, expected_hash: Hash) -> Result<Map> {
+        self.verify_with(expected_hash, &mut ())
+    }
+
+    /// Verifies the proof like `verify`, calling `visitor` for every operator
+    /// and pushed node along the way.
+    pub fn verify_with<V: ProofVisitor + ?Sized>(
+        &self,
+        expected_hash: Hash,
+        visitor: &mut V,
+    ) -> Result<Map> {
+        struct Collect<'v, V: ?Sized> {
+            visitor: &'v mut V,
+            map_builder: MapBuilder,
+        }
+
+        impl<'v, V: ProofVisitor + ?Sized> ProofVisitor for Collect<'v, V> {
+            fn visit_op(&mut self, op: &Op) -> Result<()> {
+                self.visitor.visit_op(op)
+            }
+
+            fn visit_node(&mut self, node: &Node) -> Result<()> {
+                self.visitor.visit_node(node)?;
+                self.map_builder.insert(node)
+            }
+        }
+
+        let mut collect = Collect {
+            visitor,
+            map_builder: MapBuilder::new(),
+        };
+        execute_with(self.ops(), true, &mut collect)?.check_hash(expected_hash)?;
+
+        Ok(collect.map_builder.build())
+    }
+
+    /// Verifies the proof against `expected_hash`, yielding the entries it
+    /// contains, see `verify_iter`.
+    pub fn verify_iter(&self, expected_hash: Hash) -> VerifyIter<'a> {
+        verify_iter(self.bytes, expected_hash)
+    }
+
+    /// Verifies the proof as a proof that the tree has no entries in `range`,
+    /// see `verify_empty_range`.
+    pub fn verify_empty_range(
+        &self,
+        range: &ops::Range<Vec<u8>>,
+        expected_hash: Hash,
+    ) -> Result<()> {
+        verify_empty_range(self.bytes, range, expected_hash)
+    }
+}
+
 pub fn verify(bytes: &[u8], expected_hash: Hash) -> Result<Map> {
-    let ops = Decoder::new(bytes);
-    let mut map_builder = MapBuilder::new();
+    QueryProof::new(bytes).verify(expected_hash)
+}
 
-    let root = execute(ops, true, |node| map_builder.insert(node))?;
+/// Verifies a proof created by `Merk::prove_count` against `expected_hash`,
+/// returning the number of keys in the tree within `item`.
+pub fn verify_count(bytes: &[u8], item: &QueryItem, expected_hash: Hash) -> Result<u64> {
+    let map = verify(bytes, expected_hash)?;
+    let end = match item.upper_bound() {
+        (end, true) => Bound::Included(end),
+        (end, false) => Bound::Excluded(end),
+    };
+    map.count((Bound::Included(item.lower_bound()), end))
+}
 
-    if root.hash()? != expected_hash {
-        return Err(Error::HashMismatch(expected_hash, root.hash()?));
+/// Verifies many encoded proofs against the same root hash, returning their
+/// maps in the same order, for servers checking the proofs submitted by many
+/// clients at once. Identical proofs, which are common when many clients
+/// prove the same popular keys, are only verified once.
+///
+/// Stops at the first invalid proof, failing with `Error::BatchProof` with
+/// its index and the error it failed with.
+pub fn verify_batch<P: AsRef<[u8]>>(expected_hash: Hash, proofs: &[P]) -> Result<Vec<Map>> {
+    let mut verified: HashMap<&[u8], usize> = HashMap::new();
+    let mut maps: Vec<Map> = Vec::with_capacity(proofs.len());
+    for (index, proof) in proofs.iter().enumerate() {
+        let bytes = proof.as_ref();
+        if let Some(map) = verified.get(bytes).and_then(|&first| maps.get(first)) {
+            let map = map.clone();
+            maps.push(map);
+            continue;
+        }
+
+        let map = verify(bytes, expected_hash).map_err(|err| Error::BatchProof {
+            index,
+            source: Box::new(err),
+        })?;
+        verified.insert(bytes, index);
+        maps.push(map);
     }
+    Ok(maps)
+}
 
-    Ok(map_builder.build())
+/// Verifies the encoded proof like `verify`, calling `visitor` for every
+/// operator and pushed node along the way.
+pub fn verify_with<V: ProofVisitor + ?Sized>(
+    bytes: &[u8],
+    expected_hash: Hash,
+    visitor: &mut V,
+) -> Result<Map> {
+    QueryProof::new(bytes).verify_with(expected_hash, visitor)
 }
 
 /// Verifies the encoded proof with the given query and expected hash.
@@ -422,7 +627,12 @@ pub fn verify_query(
     let ops = Decoder::new(bytes);
 
     let root = execute(ops, true, |node| {
-        if let Node::KV(key, value) = node {
+        let entry = match node {
+            Node::KV(key, value) | Node::KVFlags(key, value, _) => Some((key, Some(value))),
+            Node::KVDigest(key, _) => Some((key, None)),
+            _ => None,
+        };
+        if let Some((key, value)) = entry {
             while let Some(item) = query.peek() {
                 // get next item in query
                 let query_item = *item;
@@ -445,7 +655,8 @@ pub fn verify_query(
 
                         // lower bound is proven - the preceding tree node
                         // is lower than the bound
-                        Some(Node::KV(_, _)) => {}
+                        Some(Node::KV(..)) | Some(Node::KVDigest(..)) | Some(Node::KVFlags(..)) => {
+                        }
 
                         // cannot verify lower bound - we have an abridged
                         // tree so we cannot tell what the preceding key was
@@ -473,6 +684,7 @@ pub fn verify_query(
                 // this push matches the queried item
                 if query_item.contains(key) {
                     // add data to output
+                    let value = value.ok_or_else(|| Error::ValueWithheld(key.clone()))?;
                     output.push((key.clone(), value.clone()));
 
                     // continue to next push
@@ -497,7 +709,7 @@ pub fn verify_query(
     if query.peek().is_some() {
         match last_push {
             // last node in tree was less than queried item
-            Some(Node::KV(_, _)) => {}
+            Some(Node::KV(..)) | Some(Node::KVDigest(..)) | Some(Node::KVFlags(..)) => {}
 
             // proof contains abridged data so we cannot verify absence of
             // remaining query items
@@ -507,15 +719,13 @@ pub fn verify_query(
         }
     }
 
-    if root.hash()? != expected_hash {
-        return Err(Error::HashMismatch(expected_hash, root.hash()?));
-    }
+    root.check_hash(expected_hash)?;
 
     Ok(output)
 }
 
 #[allow(deprecated)]
-#[cfg(test)]
+#[cfg(all(test, feature = "full"))]
 mod test {
     use super::super::encoding::encode_into;
     use super::super::*;
@@ -1482,6 +1692,106 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn verify_with_visitor() -> Result<()> {
+        use crate::proofs::visitor::GasMeter;
+
+        struct Recorder(Vec<Vec<u8>>, usize);
+        impl ProofVisitor for Recorder {
+            fn visit_op(&mut self, _op: &Op) -> Result<()> {
+                self.1 += 1;
+                Ok(())
+            }
+            fn visit_node(&mut self, node: &Node) -> Result<()> {
+                if let Node::KV(key, _) = node {
+                    self.0.push(key.clone());
+                }
+                Ok(())
+            }
+        }
+
+        let mut tree = make_3_node_tree()?;
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let (proof, _) =
+            walker.create_proof(&[QueryItem::Key(vec![3]), QueryItem::Key(vec![7])])?;
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let mut recorder = Recorder(vec![], 0);
+        let map = verify_with(&bytes, tree.hash(), &mut recorder)?;
+        assert_eq!(recorder.0, vec![vec![3], vec![7]]);
+        assert_eq!(recorder.1, proof.len());
+        assert_eq!(map.get(&[7])?, Some(&[7][..]));
+
+        let mut meter = GasMeter::new(1_000, 10, 1);
+        verify_with(&bytes, tree.hash(), &mut meter)?;
+        assert!(meter.used() > 0);
+        let mut meter = GasMeter::new(meter.used() - 1, 10, 1);
+        assert!(verify_with(&bytes, tree.hash(), &mut meter).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_batch_proofs() -> Result<()> {
+        let mut tree = make_3_node_tree()?;
+        let root_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let mut prove = |key: u8| -> Result<Vec<u8>> {
+            let (ops, _) = walker.create_proof(&[QueryItem::Key(vec![key])])?;
+            let mut bytes = vec![];
+            encode_into(ops.iter(), &mut bytes);
+            Ok(bytes)
+        };
+        let proofs = vec![prove(3)?, prove(7)?, prove(3)?, prove(4)?];
+
+        let maps = verify_batch(root_hash, &proofs)?;
+        assert_eq!(maps.len(), 4);
+        assert_eq!(maps[0].get(&[3])?, Some(&[3][..]));
+        assert_eq!(maps[1].get(&[7])?, Some(&[7][..]));
+        assert_eq!(maps[2].get(&[3])?, Some(&[3][..]));
+        assert_eq!(maps[3].get(&[4])?, None);
+
+        let mut invalid = proofs.clone();
+        invalid[2].pop();
+        assert!(matches!(
+            verify_batch(root_hash, &invalid),
+            Err(Error::BatchProof { index: 2, .. })
+        ));
+        assert!(matches!(
+            verify_batch([1; 32], &proofs),
+            Err(Error::BatchProof { index: 0, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn query_proof() -> Result<()> {
+        let mut tree = make_3_node_tree()?;
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let (ops, _) = walker.create_proof(&[QueryItem::RangeInclusive(vec![4]..=vec![7])])?;
+        let mut bytes = vec![];
+        encode_into(ops.iter(), &mut bytes);
+
+        let proof = QueryProof::new(&bytes);
+        assert_eq!(proof.ops().count(), ops.len());
+        let map = proof.verify(tree.hash())?;
+        assert_eq!(map.get(&[5])?, Some(&[5][..]));
+        let entries = proof.verify_iter(tree.hash()).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            entries,
+            vec![(vec![3], vec![3]), (vec![5], vec![5]), (vec![7], vec![7])]
+        );
+        proof.verify_empty_range(&(vec![6]..vec![7]), tree.hash())?;
+        assert!(proof
+            .verify_empty_range(&(vec![4]..vec![6]), tree.hash())
+            .is_err());
+        assert!(matches!(
+            proof.verify([42; 32]),
+            Err(Error::HashMismatch(..))
+        ));
+        Ok(())
+    }
+
     #[test]
     #[should_panic(expected = "verify failed")]
     fn verify_ops_mismatched_hash() {
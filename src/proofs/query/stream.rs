@@ -0,0 +1,111 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
+use super::super::tree::Executor;
+use super::super::{Decoder, Node};
+use crate::error::{Error, Result};
+use crate::tree::Hash;
+
+/// Verifies the encoded proof against the expected hash, yielding the
+/// key/value entries it contains in key order rather than collecting them into
+/// a `Map`.
+///
+/// Only the verification stack is kept in memory, but the root hash can only
+/// be checked once every operator has been executed, so the entries yielded
+/// are not trusted until the iterator has finished without yielding an error.
+/// If the proof is invalid, the last item yielded is an `Err`, as it is for
+/// an entry whose value is withheld from the proof (`Error::ValueWithheld`).
+pub fn verify_iter(bytes: &[u8], expected_hash: Hash) -> VerifyIter<'_> {
+    VerifyIter {
+        ops: Decoder::new(bytes),
+        executor: Some(Executor::new(true)),
+        expected_hash,
+    }
+}
+
+/// An iterator over the entries in a proof, created by `verify_iter`.
+pub struct VerifyIter<'a> {
+    ops: Decoder<'a>,
+    executor: Option<Executor>,
+    expected_hash: Hash,
+}
+
+impl<'a> VerifyIter<'a> {
+    fn finish(&mut self, executor: Executor) -> Result<()> {
+        executor.finish()?.check_hash(self.expected_hash)
+    }
+}
+
+impl<'a> Iterator for VerifyIter<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut executor = self.executor.take()?;
+
+        for op in self.ops.by_ref() {
+            let result = match op {
+                Ok(op) => executor.execute_op(op),
+                Err(err) => Err(err),
+            };
+            let entry = match result {
+                Ok(Some(Node::KV(key, value))) | Ok(Some(Node::KVFlags(key, value, _))) => {
+                    (key.clone(), value.clone())
+                }
+                Ok(Some(Node::KVDigest(key, _))) => {
+                    return Some(Err(Error::ValueWithheld(key.clone())));
+                }
+                Ok(_) => continue,
+                // stop after the first error
+                Err(err) => return Some(Err(err)),
+            };
+
+            self.executor = Some(executor);
+            return Some(Ok(entry));
+        }
+
+        self.finish(executor).err().map(Err)
+    }
+}
+
+#[cfg(all(test, feature = "full"))]
+mod test {
+    use super::super::super::encoding::encode_into;
+    use super::*;
+    use crate::test_utils::make_tree_seq;
+    use crate::tree::{PanicSource, RefWalker};
+
+    #[test]
+    fn streams_entries() {
+        let mut tree = make_tree_seq(10);
+        let root_hash = tree.hash();
+        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let (proof, _) = walker
+            .create_proof(&[vec![0, 0, 0, 0, 0, 0, 0, 5].into()])
+            .unwrap();
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let entries: Vec<_> = verify_iter(&bytes, root_hash)
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(entries, vec![(vec![0, 0, 0, 0, 0, 0, 0, 5], vec![123; 60])]);
+
+        // the entries are yielded before the hash mismatch is detected
+        let mut iter = verify_iter(&bytes, [1; 32]);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(matches!(iter.next(), Some(Err(Error::HashMismatch(..)))));
+        assert!(iter.next().is_none());
+
+        let mut iter = verify_iter(&bytes[..bytes.len() - 1], root_hash);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+}
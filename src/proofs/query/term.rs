@@ -0,0 +1,225 @@
+use super::{Map, Query, QueryItem};
+use crate::error::Result;
+use std::ops::{BitOr, Bound, Range, RangeInclusive};
+
+/// The maximum length of a key which can be included in a proof.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// The order in which a `Term` selects entries, which determines the entries
+/// selected when the term has a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+/// A `Term` selects a key, a range of keys or all keys with a given prefix,
+/// optionally limited to the first (or last) `n` entries.
+///
+/// Terms are combined into a `Query` with `|` (or `Query::insert_term`), so
+/// that a single proof can be created for all of them. The entries selected by
+/// each term can then be read back from the verified proof with `Map::term`.
+///
+/// # Example
+/// ```
+/// use merkdb::proofs::query::Term;
+///
+/// let query = Term::key(vec![1])
+///     | Term::prefix(b"withdrawals/".to_vec()).limit(10).descending()
+///     | Term::range(vec![5]..vec![8]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Term {
+    pub(crate) item: QueryItem,
+    pub(crate) limit: Option<usize>,
+    pub(crate) direction: Direction,
+}
+
+impl Term {
+    fn new(item: QueryItem) -> Self {
+        Term {
+            item,
+            limit: None,
+            direction: Direction::Ascending,
+        }
+    }
+
+    /// Creates a term which selects an individual key (or proves its absence).
+    pub fn key(key: Vec<u8>) -> Self {
+        Term::new(QueryItem::Key(key))
+    }
+
+    /// Creates a term which selects all entries with keys in the range.
+    pub fn range(range: Range<Vec<u8>>) -> Self {
+        Term::new(QueryItem::Range(range))
+    }
+
+    /// Creates a term which selects all entries with keys in the inclusive
+    /// range.
+    pub fn range_inclusive(range: RangeInclusive<Vec<u8>>) -> Self {
+        Term::new(QueryItem::RangeInclusive(range))
+    }
+
+    /// Creates a term which selects all entries with keys starting with
+    /// `prefix`.
+    pub fn prefix(prefix: Vec<u8>) -> Self {
+        // the smallest key greater than all keys with the prefix is the prefix
+        // with its last byte incremented, ignoring trailing 0xff bytes
+        let mut end = prefix.clone();
+        while end.last() == Some(&0xff) {
+            end.pop();
+        }
+
+        let item = match end.last_mut() {
+            Some(byte) => {
+                *byte += 1;
+                QueryItem::Range(prefix..end)
+            }
+            // no such key, so include everything up to the greatest key
+            None => QueryItem::RangeInclusive(prefix..=vec![0xff; MAX_KEY_LENGTH]),
+        };
+        Term::new(item)
+    }
+
+    /// Limits the term to selecting at most `limit` entries, starting from the
+    /// lower bound (or from the upper bound if the term is descending).
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Makes the term select entries from its upper bound downwards.
+    pub fn descending(mut self) -> Self {
+        self.direction = Direction::Descending;
+        self
+    }
+
+    /// Returns the key or range of keys the term selects from.
+    pub fn item(&self) -> &QueryItem {
+        &self.item
+    }
+
+    pub fn get_limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        let (end, inclusive) = self.item.upper_bound();
+        let end = if inclusive {
+            Bound::Included(end)
+        } else {
+            Bound::Excluded(end)
+        };
+        (Bound::Included(self.item.lower_bound()), end)
+    }
+}
+
+impl BitOr for Term {
+    type Output = Query;
+
+    fn bitor(self, other: Term) -> Query {
+        let mut query = Query::new();
+        query.insert_term(self);
+        query.insert_term(other);
+        query
+    }
+}
+
+impl BitOr<Term> for Query {
+    type Output = Query;
+
+    fn bitor(mut self, term: Term) -> Query {
+        self.insert_term(term);
+        self
+    }
+}
+
+impl From<Term> for Query {
+    fn from(term: Term) -> Self {
+        let mut query = Query::new();
+        query.insert_term(term);
+        query
+    }
+}
+
+impl Map {
+    /// Returns the entries selected by `term`, in the term's direction. If the
+    /// proof does not include all of the selected entries, or does not prove
+    /// that there are no more entries before the limit is reached, an error
+    /// will be returned.
+    pub fn term<'a>(&'a self, term: &'a Term) -> Result<Vec<(&'a [u8], &'a [u8])>> {
+        let limit = term.limit.unwrap_or(usize::MAX);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let (start, end) = term.bounds();
+
+        // an entry at an inclusive upper bound proves the end of the range, so
+        // stop there rather than checking for data after it
+        let len = |start| match end {
+            Bound::Included(key) if self.keys((end, end)).next().is_some() => {
+                self.keys((start, Bound::Included(key))).count()
+            }
+            _ => usize::MAX,
+        };
+
+        match term.direction {
+            Direction::Ascending => self
+                .range((start, end))
+                .take(limit.min(len(start)))
+                .collect(),
+            Direction::Descending => {
+                // find where the last `limit` entries start, then check the
+                // proof includes every entry from there to the upper bound
+                let keys: Vec<_> = self.keys((start, end)).rev().take(limit).collect();
+                let start = match keys.last() {
+                    Some(key) if keys.len() == limit => Bound::Included(*key),
+                    _ => start,
+                };
+
+                let mut entries = self
+                    .range((start, end))
+                    .take(len(start))
+                    .collect::<Result<Vec<_>>>()?;
+                entries.reverse();
+                Ok(entries)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefix_bounds() {
+        let item = Term::prefix(vec![1, 2]).item;
+        assert_eq!(item.lower_bound(), &[1, 2]);
+        assert_eq!(item.upper_bound(), (&[1, 3][..], false));
+        let item = Term::prefix(vec![1, 0xff]).item;
+        assert_eq!(item.upper_bound(), (&[2][..], false));
+        assert!(item.contains(&[1, 0xff, 0xff, 0xff]));
+        assert!(!item.contains(&[2]));
+
+        let item = Term::prefix(vec![]).item;
+        assert!(item.contains(&[]));
+        assert!(item.contains(&[0xff; MAX_KEY_LENGTH]));
+    }
+
+    #[test]
+    fn combine_terms() {
+        let query = Term::key(vec![1]) | Term::range(vec![5]..vec![8]).limit(2);
+        assert_eq!(query.terms().len(), 2);
+
+        // only unlimited terms can be added without resolving them against
+        // the tree
+        let items: Vec<QueryItem> = query.into();
+        assert_eq!(items, vec![QueryItem::Key(vec![1])]);
+    }
+}
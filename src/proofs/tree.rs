@@ -1,6 +1,16 @@
-use super::{Node, Op};
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
+use super::{Node, Op, ProofVisitor};
 use crate::error::{Error, Result};
-use crate::tree::{kv_hash, node_hash, Hash, Hasher, NULL_HASH};
+use crate::tree::{kv_hash, kv_hash_with_flags, node_hash, Hash, Hasher, NULL_HASH};
 
 /// Contains a tree's child node and its hash. The hash can always be assumed to
 /// be up-to-date.
@@ -54,12 +64,29 @@ impl Tree {
             Node::KV(key, value) => kv_hash::<Hasher>(key.as_slice(), value.as_slice())
                 .map(|kv_hash| compute_hash(self, kv_hash))
                 .map_err(Into::into),
+            Node::KVDigest(_, kv_hash) => Ok(compute_hash(self, *kv_hash)),
+            Node::KVFlags(key, value, flags) => {
+                kv_hash_with_flags::<Hasher>(key.as_slice(), value.as_slice(), *flags)
+                    .map(|kv_hash| compute_hash(self, kv_hash))
+                    .map_err(Into::into)
+            }
         }
     }
 
+    /// Returns an error unless the tree hashes to `expected_hash`, the final
+    /// check of every proof verification.
+    pub fn check_hash(&self, expected_hash: Hash) -> Result<()> {
+        let hash = self.hash()?;
+        if hash != expected_hash {
+            return Err(Error::HashMismatch(expected_hash, hash));
+        }
+        Ok(())
+    }
+
     /// Creates an iterator that yields the in-order traversal of the nodes at
-    /// the given depth.
-    pub fn layer(&self, depth: usize) -> LayerIter {
+    /// the given depth. If the tree is not complete down to that depth, the
+    /// iterator stops at the first missing node.
+    pub fn layer(&self, depth: usize) -> LayerIter<'_> {
         LayerIter::new(self, depth)
     }
 
@@ -143,10 +170,12 @@ impl Tree {
     }
 
     #[cfg(feature = "full")]
-    pub(crate) fn key(&self) -> &[u8] {
+    pub(crate) fn key(&self) -> Result<&[u8]> {
         match self.node {
-            Node::KV(ref key, _) => key,
-            _ => panic!("Expected node to be type KV"),
+            Node::KV(ref key, _) | Node::KVFlags(ref key, ..) => Ok(key),
+            _ => Err(Error::UnexpectedNode(
+                "Expected node to be type KV".to_string(),
+            )),
         }
     }
 }
@@ -181,7 +210,7 @@ impl<'a> LayerIter<'a> {
         if let Some(child) = tree.child(true) {
             self.traverse_to_start(&child.tree, remaining_depth - 1)
         } else {
-            panic!("Could not traverse to given layer")
+            self.stack.clear();
         }
     }
 }
@@ -194,21 +223,29 @@ impl<'a> Iterator for LayerIter<'a> {
         let mut popped = item;
 
         loop {
-            if self.stack.is_empty() {
-                return item;
-            }
-
-            let parent = self.stack.last().unwrap();
-            let left_child = parent.child(true).unwrap();
-            let right_child = parent.child(false).unwrap();
+            let parent = match self.stack.last() {
+                Some(parent) => *parent,
+                None => return item,
+            };
+            let (left_child, right_child) = match (parent.child(true), parent.child(false)) {
+                (Some(left_child), Some(right_child)) => (left_child, right_child),
+                _ => {
+                    self.stack.clear();
+                    return item;
+                }
+            };
 
-            if left_child.tree.as_ref() == popped.unwrap() {
+            if Some(left_child.tree.as_ref()) == popped {
                 self.stack.push(&right_child.tree);
 
-                while self.stack.len() - 1 < self.depth {
-                    let parent = self.stack.last().unwrap();
-                    let left_child = parent.child(true).unwrap();
-                    self.stack.push(&left_child.tree);
+                while self.stack.len() <= self.depth {
+                    match self.stack.last().and_then(|parent| parent.child(true)) {
+                        Some(left_child) => self.stack.push(&left_child.tree),
+                        None => {
+                            self.stack.clear();
+                            return item;
+                        }
+                    }
                 }
 
                 return item;
@@ -231,74 +268,126 @@ impl<'a> Iterator for LayerIter<'a> {
 /// `visit_node` will be called once for every push operation in the proof, in
 /// key-order. If `visit_node` returns an `Err` result, it will halt the
 /// execution and `execute` will return the error.
-pub(crate) fn execute<I, F>(ops: I, collapse: bool, mut visit_node: F) -> Result<Tree>
+pub(crate) fn execute<I, F>(ops: I, collapse: bool, visit_node: F) -> Result<Tree>
 where
     I: IntoIterator<Item = Result<Op>>,
     F: FnMut(&Node) -> Result<()>,
 {
-    let mut stack: Vec<Tree> = Vec::with_capacity(32);
-    let mut maybe_last_key = None;
+    struct VisitNodes<F>(F);
+
+    impl<F: FnMut(&Node) -> Result<()>> ProofVisitor for VisitNodes<F> {
+        fn visit_node(&mut self, node: &Node) -> Result<()> {
+            (self.0)(node)
+        }
+    }
+
+    execute_with(ops, collapse, &mut VisitNodes(visit_node))
+}
+
+/// Executes a proof like `execute`, calling `visitor` for every operator
+/// before it is executed and for every pushed node. Every kind of proof is
+/// verified through this function, with the checks specific to the kind of
+/// proof made by the visitor and on the resulting tree.
+pub(crate) fn execute_with<I, V>(ops: I, collapse: bool, visitor: &mut V) -> Result<Tree>
+where
+    I: IntoIterator<Item = Result<Op>>,
+    V: ProofVisitor + ?Sized,
+{
+    let mut executor = Executor::new(collapse);
+
+    for op in ops {
+        let op = op?;
+        visitor.visit_op(&op)?;
+        if let Some(node) = executor.execute_op(op)? {
+            visitor.visit_node(node)?;
+        }
+    }
 
-    fn try_pop(stack: &mut Vec<Tree>) -> Result<Tree> {
-        match stack.pop() {
+    executor.finish()
+}
+
+/// The verification stack of a proof being executed one operator at a time,
+/// as done by `execute`.
+pub(crate) struct Executor {
+    stack: Vec<Tree>,
+    maybe_last_key: Option<Vec<u8>>,
+    collapse: bool,
+}
+
+impl Executor {
+    pub(crate) fn new(collapse: bool) -> Self {
+        Executor {
+            stack: Vec::with_capacity(32),
+            maybe_last_key: None,
+            collapse,
+        }
+    }
+
+    fn try_pop(&mut self) -> Result<Tree> {
+        match self.stack.pop() {
             None => Err(Error::StackUnderflow),
             Some(tree) => Ok(tree),
         }
     }
 
-    for op in ops {
-        match op? {
+    /// Executes a single operator. Returns the pushed node if the operator is
+    /// a push.
+    pub(crate) fn execute_op(&mut self, op: Op) -> Result<Option<&Node>> {
+        match op {
             Op::Parent => {
-                let (mut parent, child) = (try_pop(&mut stack)?, try_pop(&mut stack)?);
+                let (mut parent, child) = (self.try_pop()?, self.try_pop()?);
                 parent.attach(
                     true,
-                    if collapse {
+                    if self.collapse {
                         child.try_into_hash()?
                     } else {
                         child
                     },
                 )?;
-                stack.push(parent);
+                self.stack.push(parent);
             }
             Op::Child => {
-                let (child, mut parent) = (try_pop(&mut stack)?, try_pop(&mut stack)?);
+                let (child, mut parent) = (self.try_pop()?, self.try_pop()?);
                 parent.attach(
                     false,
-                    if collapse {
+                    if self.collapse {
                         child.try_into_hash()?
                     } else {
                         child
                     },
                 )?;
-                stack.push(parent);
+                self.stack.push(parent);
             }
             Op::Push(node) => {
-                if let Node::KV(key, _) = &node {
+                if let Node::KV(key, _) | Node::KVFlags(key, ..) = &node {
                     // keys should always increase
-                    if let Some(last_key) = &maybe_last_key {
+                    if let Some(last_key) = &self.maybe_last_key {
                         if key <= last_key {
                             return Err(Error::Key("Incorrect key ordering".into()));
                         }
                     }
 
-                    maybe_last_key = Some(key.clone());
+                    self.maybe_last_key = Some(key.clone());
                 }
 
-                visit_node(&node)?;
-
-                let tree: Tree = node.into();
-                stack.push(tree);
+                self.stack.push(node.into());
+                return Ok(self.stack.last().map(|tree| &tree.node));
             }
         }
-    }
 
-    if stack.len() != 1 {
-        return Err(Error::Proof(
-            "Expected proof to result in exactly on stack item".into(),
-        ));
+        Ok(None)
     }
 
-    Ok(stack.pop().unwrap())
+    /// Returns the resulting tree once all operators have been executed.
+    pub(crate) fn finish(mut self) -> Result<Tree> {
+        if self.stack.len() != 1 {
+            return Err(Error::Proof(
+                "Expected proof to result in exactly on stack item".into(),
+            ));
+        }
+
+        self.try_pop()
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,84 @@
+//! Hooks into proof verification, see `ProofVisitor`.
+
+use super::{Node, Op};
+use crate::{Error, Result};
+
+/// Observes a proof while it is being verified by `verify_with`, for
+/// verifiers which need more than the resulting map of proven entries, such
+/// as logging, metering the cost of verification, or accumulating their own
+/// commitments over the proven data.
+///
+/// Returning an error from any method halts verification with that error.
+pub trait ProofVisitor {
+    /// Called for every operator of the proof as it is decoded, before it is
+    /// executed.
+    fn visit_op(&mut self, _op: &Op) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every node pushed by the proof once it is on the
+    /// verification stack, in key order.
+    fn visit_node(&mut self, _node: &Node) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A visitor which does nothing.
+impl ProofVisitor for () {}
+
+/// Meters the cost of verifying a proof, failing verification once it
+/// exceeds a limit, so callers verifying untrusted proofs can bound the work
+/// they do the same way they bound other work in their runtime.
+///
+/// Every operator costs `op_cost`, and pushed nodes additionally cost
+/// `byte_cost` for each byte of key, value or hash they contain.
+#[derive(Clone, Debug)]
+pub struct GasMeter {
+    limit: u64,
+    used: u64,
+    op_cost: u64,
+    byte_cost: u64,
+}
+
+impl GasMeter {
+    pub fn new(limit: u64, op_cost: u64, byte_cost: u64) -> GasMeter {
+        GasMeter {
+            limit,
+            used: 0,
+            op_cost,
+            byte_cost,
+        }
+    }
+
+    /// Returns the gas used so far.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    fn charge(&mut self, amount: u64) -> Result<()> {
+        self.used = self.used.saturating_add(amount);
+        if self.used > self.limit {
+            return Err(Error::Proof(format!(
+                "Verification used more than {} gas",
+                self.limit
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ProofVisitor for GasMeter {
+    fn visit_op(&mut self, op: &Op) -> Result<()> {
+        let bytes = match op {
+            Op::Push(Node::KV(key, value)) => key.len() + value.len(),
+            Op::Push(Node::KVFlags(key, value, _)) => key.len() + value.len() + 1,
+            Op::Push(Node::KVDigest(key, _)) => key.len() + 32,
+            Op::Push(_) => 32,
+            Op::Parent | Op::Child => 0,
+        };
+        let cost = self
+            .op_cost
+            .saturating_add(self.byte_cost.saturating_mul(bytes as u64));
+        self.charge(cost)
+    }
+}
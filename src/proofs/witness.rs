@@ -0,0 +1,272 @@
+//! Witnesses of the nodes a batch touches, so batches can be applied by nodes
+//! which do not keep the store's state, see `apply_stateless`.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use crate::error::{Error, Result};
+use crate::tree::{
+    check_sorted, kv_hash_with_flags, resolve_moves, Entry, Fetch, Hash, Hasher, Link, NoopCommit,
+    OpRef, Tree, Walker, NULL_HASH,
+};
+
+/// The nodes of a store which a batch reads, with their child links and
+/// heights, see `Merk::witness`.
+///
+/// Unlike a proof, a witness includes the heights of the subtrees it leaves
+/// out, so batches which insert or delete keys, and so rebalance the tree, can
+/// be applied to it. Every node is checked against the root hash before it is
+/// used. The heights are not part of the root hash, so they are only checked
+/// to agree with each other.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Witness {
+    root: Option<Vec<u8>>,
+    nodes: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Witness {
+    /// Creates a witness of the records in `nodes` (encoded with their
+    /// values, by key), for a store whose root node has the key `root`, or
+    /// for an empty store.
+    pub(crate) fn new(root: Option<Vec<u8>>, nodes: BTreeMap<Vec<u8>, Vec<u8>>) -> Witness {
+        Witness { root, nodes }
+    }
+
+    /// Returns the number of nodes in the witness.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if the witness has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Encodes the witness: the root key, then the key and record of each
+    /// node, each preceded by its length.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match &self.root {
+            None => bytes.push(0),
+            Some(key) => {
+                bytes.extend_from_slice(&[1, key.len() as u8]);
+                bytes.extend_from_slice(key);
+            }
+        }
+        for (key, record) in self.nodes.iter() {
+            bytes.push(key.len() as u8);
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(record);
+        }
+        bytes
+    }
+
+    /// Decodes a witness encoded with `encode`.
+    pub fn decode(mut bytes: &[u8]) -> Result<Witness> {
+        let input = &mut bytes;
+        let root = match take(input, 1)?[0] {
+            0 => None,
+            1 => Some(take_key(input)?.to_vec()),
+            _ => return Err(Error::Decode("Invalid witness root tag".into())),
+        };
+        let mut nodes = BTreeMap::new();
+        while !input.is_empty() {
+            let key = take_key(input)?.to_vec();
+            let length = u32::from_be_bytes(take(input, 4)?.try_into().unwrap());
+            nodes.insert(key, take(input, length as usize)?.to_vec());
+        }
+        Ok(Witness::new(root, nodes))
+    }
+
+    /// Gets the value for a key, or `None` if it is proven to be absent, from
+    /// the nodes on its path, checking them against `root_hash`. Fails with
+    /// `Error::MissingData` if the witness does not have them.
+    pub fn get(&self, root_hash: Hash, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut tree = match self.root(root_hash)? {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        loop {
+            let link = match tree.interned_key().cmp_bytes(key).reverse() {
+                Ordering::Equal => return Ok(Some(tree.value().to_vec())),
+                ordering => match tree.link(ordering == Ordering::Less) {
+                    Some(link) => link,
+                    None => return Ok(None),
+                },
+            };
+            tree = self.node(&link.key(), link.hash())?;
+        }
+    }
+
+    /// Applies a batch to the store the witness is for, whose root hash is
+    /// `prev_root`, and returns the root hash the store has after it. Fails
+    /// with `Error::MissingData` if the batch touches nodes which are not in
+    /// the witness, or with `Error::HashMismatch` if a node does not match
+    /// `prev_root`.
+    ///
+    /// Keys in the batch must be sorted and unique.
+    pub fn apply<E: Entry>(&self, prev_root: Hash, batch: &[E]) -> Result<Hash> {
+        check_sorted(batch)?;
+        if batch
+            .iter()
+            .any(|entry| matches!(entry.op(), OpRef::Move { .. }))
+        {
+            let resolved = resolve_moves(&E::to_batch(batch), |key| self.get(prev_root, key))?
+                .expect("Batch has moves");
+            return self.apply_resolved(prev_root, &resolved);
+        }
+        self.apply_resolved(prev_root, batch)
+    }
+
+    fn apply_resolved<E: Entry>(&self, prev_root: Hash, batch: &[E]) -> Result<Hash> {
+        let source = WitnessSource(self);
+        let maybe_walker = self.root(prev_root)?.map(|tree| Walker::new(tree, source));
+        let (maybe_tree, _) = Walker::apply_to(maybe_walker, batch, source)?;
+        Ok(match maybe_tree {
+            Some(mut tree) => {
+                tree.commit(&mut NoopCommit {})?;
+                tree.hash()
+            }
+            None => NULL_HASH,
+        })
+    }
+
+    /// Returns the root node, checked against `root_hash`.
+    fn root(&self, root_hash: Hash) -> Result<Option<Tree>> {
+        match &self.root {
+            Some(key) => self.node(key, &root_hash).map(Some),
+            None if root_hash == NULL_HASH => Ok(None),
+            None => Err(Error::HashMismatch(root_hash, NULL_HASH)),
+        }
+    }
+
+    /// Decodes the node with the given key, checking that its key/value hash
+    /// is that of its key, value and flags, and that its hash is `hash`.
+    fn node(&self, key: &[u8], hash: &Hash) -> Result<Tree> {
+        let record = self.nodes.get(key).ok_or(Error::MissingData)?;
+        let tree = Tree::decode(key.to_vec(), record)?;
+        let kv_hash = kv_hash_with_flags::<Hasher>(key, tree.value(), tree.flags())?;
+        if &kv_hash != tree.kv_hash() {
+            return Err(Error::HashMismatch(*tree.kv_hash(), kv_hash));
+        }
+        if &tree.hash() != hash {
+            return Err(Error::HashMismatch(*hash, tree.hash()));
+        }
+        Ok(tree)
+    }
+}
+
+/// Applies a batch to a store without its state, given a witness (encoded
+/// with `Witness::encode`) of the nodes the batch touches, and returns the
+/// store's root hash after the batch. Every node read from the witness is
+/// checked against `prev_root`, so a validator can execute a block given only
+/// the previous root and witnesses from untrusted peers, see `Witness::apply`.
+///
+/// Keys in the batch must be sorted and unique.
+pub fn apply_stateless<E: Entry>(prev_root: Hash, witness: &[u8], batch: &[E]) -> Result<Hash> {
+    Witness::decode(witness)?.apply(prev_root, batch)
+}
+
+/// A source of the nodes of a witness, which are checked against the links
+/// they are fetched through.
+#[derive(Clone, Copy)]
+struct WitnessSource<'a>(&'a Witness);
+
+impl Fetch for WitnessSource<'_> {
+    fn fetch_by_key(&self, _: &[u8]) -> Result<Option<Tree>> {
+        Err(Error::Fetch(
+            "Witness nodes can only be fetched through a link".into(),
+        ))
+    }
+
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        let tree = self.0.node(&link.key(), link.hash())?;
+        if tree.child_heights() != link.child_heights() {
+            return Err(Error::InvariantViolation {
+                key: link.key().to_vec(),
+                detail: "Witness node has different heights than its link".into(),
+            });
+        }
+        Ok(tree)
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if input.len() < length {
+        return Err(Error::Decode("Unexpected end of witness".into()));
+    }
+    let (bytes, rest) = input.split_at(length);
+    *input = rest;
+    Ok(bytes)
+}
+
+fn take_key<'a>(input: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let length = take(input, 1)?[0];
+    take(input, length as usize)
+}
+
+#[cfg(all(test, feature = "full"))]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::Op;
+
+    #[test]
+    fn stateless_apply() {
+        let mut merk = TempMerk::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let prev_root = merk.root_hash();
+        let batch = vec![
+            (seq_key(10), Op::Put(vec![1])),
+            (seq_key(50), Op::Delete),
+            (seq_key(60), Op::Move { to: seq_key(300) }),
+            (seq_key(200), Op::Put(vec![2])),
+        ];
+
+        let witness = merk.witness(&[&seq_key(20)], &batch).unwrap();
+        assert!(witness.len() < 100);
+        let bytes = witness.encode();
+        assert_eq!(Witness::decode(&bytes).unwrap(), witness);
+        assert_eq!(
+            witness.get(prev_root, &seq_key(20)).unwrap(),
+            merk.get(&seq_key(20)).unwrap()
+        );
+        let new_root = apply_stateless(prev_root, &bytes, &batch).unwrap();
+        merk.apply(&batch, &[]).unwrap();
+        assert_eq!(new_root, merk.root_hash());
+
+        // witnesses which do not match the root are rejected
+        assert!(matches!(
+            apply_stateless(new_root, &bytes, &batch),
+            Err(Error::HashMismatch(..))
+        ));
+        let mut tampered = witness.clone();
+        *tampered
+            .nodes
+            .get_mut(&seq_key(10))
+            .unwrap()
+            .last_mut()
+            .unwrap() ^= 1;
+        assert!(matches!(
+            tampered.apply(prev_root, &batch),
+            Err(Error::HashMismatch(..))
+        ));
+        let mut partial = witness;
+        partial.nodes.remove(&seq_key(10));
+        assert!(matches!(
+            partial.apply(prev_root, &batch),
+            Err(Error::MissingData)
+        ));
+
+        // an empty store needs no nodes
+        let empty = TempMerk::new().unwrap();
+        let witness = empty.witness(&[], &batch[..1]).unwrap();
+        assert!(witness.is_empty());
+        let root = witness.apply(NULL_HASH, &batch[..1]).unwrap();
+        let mut empty = empty;
+        empty.apply(&batch[..1], &[]).unwrap();
+        assert_eq!(root, empty.root_hash());
+    }
+}
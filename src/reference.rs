@@ -0,0 +1,377 @@
+//! A reference implementation of the tree, for differential testing.
+//!
+//! `Reference` is a sorted map of keys to values kept in a plain boxed
+//! binary tree, which applies batches with the same AVL algorithm as the
+//! engine but none of its machinery (walkers, links, pruning, fetching or
+//! cached hashes), and recomputes every hash from scratch whenever the root
+//! hash is requested. The root hash commits to the shape of the tree as well
+//! as its contents, so the shape follows the engine's rules exactly: batches
+//! are built into balanced subtrees around their middle entry, deleted nodes
+//! are replaced by the nearest entry from their taller subtree, and subtrees
+//! are rebalanced with rotations on the way back up.
+//!
+//! Applying the same batches to a `Merk` and a `Reference` must produce the
+//! same root hashes, and the `Merk`'s proofs must verify against the
+//! reference's root hash with the reference's values.
+
+use std::cmp::Ordering;
+use std::ops::RangeBounds;
+
+use crate::tree::{
+    check_sorted, flags::FROZEN, kv_hash_with_flags, node_hash, resolve_moves, Batch, Hash, Hasher,
+    Op, NULL_HASH,
+};
+use crate::{Error, Result};
+
+struct Node {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    flags: u8,
+    left: Option<Box<Node>>,
+    right: Option<Box<Node>>,
+}
+
+type Link = Option<Box<Node>>;
+
+fn height(link: &Link) -> i8 {
+    link.as_ref()
+        .map_or(0, |node| 1 + height(&node.left).max(height(&node.right)))
+}
+
+fn hash(link: &Link) -> Hash {
+    match link {
+        None => NULL_HASH,
+        Some(node) => {
+            let kv = kv_hash_with_flags::<Hasher>(&node.key, &node.value, node.flags)
+                .expect("key or value is too long");
+            node_hash::<Hasher>(&kv, &hash(&node.left), &hash(&node.right))
+        }
+    }
+}
+
+impl Node {
+    fn new(key: &[u8], value: &[u8]) -> Box<Node> {
+        Box::new(Node {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            flags: 0,
+            left: None,
+            right: None,
+        })
+    }
+
+    fn child(&mut self, left: bool) -> &mut Link {
+        if left {
+            &mut self.left
+        } else {
+            &mut self.right
+        }
+    }
+
+    fn balance_factor(&self) -> i8 {
+        height(&self.right) - height(&self.left)
+    }
+}
+
+fn apply_to(link: Link, batch: &Batch) -> Link {
+    if batch.is_empty() {
+        return link;
+    }
+    match link {
+        None => build(batch),
+        Some(node) => apply(node, batch),
+    }
+}
+
+fn build(batch: &Batch) -> Link {
+    if batch.is_empty() {
+        return None;
+    }
+    let mid = batch.len() / 2;
+    match &batch[mid] {
+        (_, Op::Delete) => match build(&batch[..mid]) {
+            Some(left) => apply(left, &batch[mid + 1..]),
+            None => build(&batch[mid + 1..]),
+        },
+        (key, Op::Put(value)) => recurse(Node::new(key, value), batch, mid, true),
+        (_, Op::Move { .. }) => unreachable!("moves are resolved by Reference::apply"),
+        (_, Op::SetFlags(_)) => {
+            unreachable!("flags of missing keys are rejected by Reference::apply")
+        }
+    }
+}
+
+fn apply(mut node: Box<Node>, batch: &Batch) -> Link {
+    match batch.binary_search_by(|(key, _)| key.as_slice().cmp(&node.key)) {
+        Ok(index) => match &batch[index].1 {
+            Op::Put(value) => {
+                node.value = value.clone();
+                recurse(node, batch, index, true)
+            }
+            Op::Delete => {
+                node.left = apply_to(node.left.take(), &batch[..index]);
+                node.right = apply_to(node.right.take(), &batch[index + 1..]);
+                remove(*node).map(balance)
+            }
+            Op::SetFlags(flags) => {
+                node.flags = *flags;
+                recurse(node, batch, index, true)
+            }
+            Op::Move { .. } => unreachable!("moves are resolved by Reference::apply"),
+        },
+        Err(index) => recurse(node, batch, index, false),
+    }
+}
+
+fn recurse(mut node: Box<Node>, batch: &Batch, mid: usize, exclusive: bool) -> Link {
+    let left_batch = &batch[..mid];
+    let right_batch = if exclusive {
+        &batch[mid + 1..]
+    } else {
+        &batch[mid..]
+    };
+    if !left_batch.is_empty() {
+        node.left = apply_to(node.left.take(), left_batch);
+    }
+    if !right_batch.is_empty() {
+        node.right = apply_to(node.right.take(), right_batch);
+    }
+    Some(balance(node))
+}
+
+fn balance(mut node: Box<Node>) -> Box<Node> {
+    let balance_factor = node.balance_factor();
+    if balance_factor.abs() <= 1 {
+        return node;
+    }
+    let left = balance_factor < 0;
+
+    let child = node.child(left).take().expect("taller side has a child");
+    let child = if left == (child.balance_factor() > 0) {
+        rotate(child, !left)
+    } else {
+        child
+    };
+    *node.child(left) = Some(child);
+
+    rotate(node, left)
+}
+
+fn rotate(mut node: Box<Node>, left: bool) -> Box<Node> {
+    let mut child = node.child(left).take().expect("rotated node has a child");
+    *node.child(left) = child.child(!left).take();
+    *child.child(!left) = Some(balance(node));
+    balance(child)
+}
+
+fn remove(mut node: Node) -> Link {
+    let left = height(&node.left) > height(&node.right);
+    match (node.left.is_some(), node.right.is_some()) {
+        (true, true) => {
+            let tall = node.child(left).take().unwrap();
+            let short = node.child(!left).take().unwrap();
+            Some(promote_edge(tall, !left, short))
+        }
+        (false, false) => None,
+        _ => node.child(left).take(),
+    }
+}
+
+fn promote_edge(node: Box<Node>, left: bool, attach: Box<Node>) -> Box<Node> {
+    let (mut edge, maybe_child) = remove_edge(node, left);
+    *edge.child(!left) = maybe_child;
+    *edge.child(left) = Some(attach);
+    balance(edge)
+}
+
+fn remove_edge(mut node: Box<Node>, left: bool) -> (Box<Node>, Link) {
+    match node.child(left).take() {
+        Some(child) => {
+            let (edge, maybe_child) = remove_edge(child, left);
+            *node.child(left) = maybe_child;
+            (edge, Some(balance(node)))
+        }
+        None => {
+            let maybe_child = node.child(!left).take();
+            (node, maybe_child)
+        }
+    }
+}
+
+fn collect<'a, R: RangeBounds<[u8]>>(
+    link: &'a Link,
+    bounds: &R,
+    out: &mut Vec<(&'a [u8], &'a [u8])>,
+) {
+    if let Some(node) = link {
+        collect(&node.left, bounds, out);
+        if bounds.contains(node.key.as_slice()) {
+            out.push((&node.key, &node.value));
+        }
+        collect(&node.right, bounds, out);
+    }
+}
+
+/// A reference implementation of the tree, see the module documentation.
+#[derive(Default)]
+pub struct Reference {
+    root: Link,
+}
+
+impl Reference {
+    pub fn new() -> Reference {
+        Reference::default()
+    }
+
+    /// Applies a batch of operations. Keys must be sorted and unique.
+    pub fn apply(&mut self, batch: &Batch) -> Result<()> {
+        check_sorted(batch)?;
+        let resolved = resolve_moves(batch, |key| Ok(self.get(key).map(<[u8]>::to_vec)))?;
+        let batch = resolved.as_deref().unwrap_or(batch);
+        for (key, op) in batch {
+            match (self.find(key), op) {
+                (Some(node), op) if node.flags & FROZEN != 0 && !matches!(op, Op::SetFlags(_)) => {
+                    return Err(Error::Frozen(key.clone()));
+                }
+                (None, Op::SetFlags(_)) => {
+                    return Err(Error::KeySetFlags(key.clone()));
+                }
+                _ => {}
+            }
+        }
+        self.root = apply_to(self.root.take(), batch);
+        Ok(())
+    }
+
+    fn find(&self, key: &[u8]) -> Option<&Node> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            link = match key.cmp(&node.key) {
+                Ordering::Less => &node.left,
+                Ordering::Greater => &node.right,
+                Ordering::Equal => return Some(node),
+            };
+        }
+        None
+    }
+
+    /// Returns the value of `key`, if it exists.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.find(key).map(|node| node.value.as_slice())
+    }
+
+    /// Returns the flags of `key`, if it exists.
+    pub fn flags(&self, key: &[u8]) -> Option<u8> {
+        self.find(key).map(|node| node.flags)
+    }
+
+    /// Returns the entries with keys in `bounds`, in key order.
+    pub fn range<R: RangeBounds<[u8]>>(&self, bounds: R) -> Vec<(&[u8], &[u8])> {
+        let mut entries = vec![];
+        collect(&self.root, &bounds, &mut entries);
+        entries
+    }
+
+    /// Returns the root hash, or `NULL_HASH` if the tree is empty.
+    pub fn root_hash(&self) -> Hash {
+        hash(&self.root)
+    }
+
+    /// Returns the height of the tree.
+    pub fn height(&self) -> u8 {
+        height(&self.root) as u8
+    }
+}
+
+#[cfg(all(test, feature = "full"))]
+mod test {
+    use std::ops::Bound;
+
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::proofs::Query;
+    use crate::test_utils::{seq_key, TempMerk};
+    use crate::verify;
+
+    const ITERATIONS: usize = 200;
+
+    fn random_batch(rng: &mut SmallRng, key_count: u64) -> Vec<(Vec<u8>, Op)> {
+        let size = match rng.gen_range(0..10) {
+            0 => 0,
+            1 => key_count as usize,
+            _ => rng.gen_range(1..20),
+        };
+        let mut batch: Vec<_> = (0..size)
+            .map(|_| {
+                let key = seq_key(rng.gen_range(0..key_count));
+                let op = if rng.gen_bool(0.4) {
+                    Op::Delete
+                } else {
+                    Op::Put(vec![rng.gen(); rng.gen_range(0..8)])
+                };
+                (key, op)
+            })
+            .collect();
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+        batch.dedup_by(|a, b| a.0 == b.0);
+        batch
+    }
+
+    #[test]
+    fn matches_merk() {
+        let seed = thread_rng().gen();
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut merk = TempMerk::new().unwrap();
+        let mut reference = Reference::new();
+        let key_count = 100;
+
+        for i in 0..ITERATIONS {
+            let batch = random_batch(&mut rng, key_count);
+            merk.apply(&batch, &[]).unwrap();
+            reference.apply(&batch).unwrap();
+            assert_eq!(
+                merk.root_hash(),
+                reference.root_hash(),
+                "root mismatch at iteration {} with seed {}",
+                i,
+                seed
+            );
+
+            let key = seq_key(rng.gen_range(0..key_count));
+            assert_eq!(merk.get(&key).unwrap().as_deref(), reference.get(&key));
+
+            if reference.root_hash() == NULL_HASH {
+                continue;
+            }
+            let (start, end) = (
+                seq_key(rng.gen_range(0..key_count)),
+                seq_key(rng.gen_range(0..key_count)),
+            );
+            if start >= end {
+                continue;
+            }
+            let mut query = Query::new();
+            query.insert_range(start.clone()..end.clone());
+            let proof = merk.prove(query).unwrap();
+            let map = verify(&proof, reference.root_hash()).unwrap();
+            let proven = map
+                .range(start.as_slice()..end.as_slice())
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let bounds = (
+                Bound::Included(start.as_slice()),
+                Bound::Excluded(end.as_slice()),
+            );
+            assert_eq!(proven, reference.range(bounds), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn rejects_unsorted_batches() {
+        let mut reference = Reference::new();
+        let batch = [(vec![2], Op::Delete), (vec![1], Op::Delete)];
+        assert!(reference.apply(&batch).is_err());
+        assert_eq!(reference.root_hash(), NULL_HASH);
+    }
+}
@@ -0,0 +1,508 @@
+//! `Serialize` and `Deserialize` implementations for public types, behind the
+//! `serde` feature.
+//!
+//! Batch operations, proof operators and change events are serialized as
+//! enums, with byte strings for keys, values and hashes (batch entries are
+//! tuples of a key and an operation). Types with a binary encoding of their
+//! own, such as `ReplicatedBatch` or `InclusionProof`, are serialized as that
+//! encoding, so they look the same whichever format carries them.
+
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::ser::{SerializeStructVariant, SerializeTupleVariant};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::proofs::{Node, Op as ProofOp};
+use crate::{Hash, Op, HASH_LENGTH};
+
+/// Serializes a byte slice as a byte string rather than a sequence.
+struct BytesRef<'a>(&'a [u8]);
+
+impl<'a> Serialize for BytesRef<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Deserializes a byte string, or a sequence of bytes for formats without
+/// byte strings.
+struct Bytes(Vec<u8>);
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Bytes, E> {
+        Ok(Bytes(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+impl Bytes {
+    fn into_hash<E: de::Error>(self) -> Result<Hash, E> {
+        let mut hash = [0; HASH_LENGTH];
+        if self.0.len() != HASH_LENGTH {
+            return Err(E::invalid_length(self.0.len(), &"a 32-byte hash"));
+        }
+        hash.copy_from_slice(&self.0);
+        Ok(hash)
+    }
+}
+
+/// Deserializes a pair of byte strings, the contents of the tuple variants.
+struct BytesPair;
+
+impl<'de> Visitor<'de> for BytesPair {
+    type Value = (Vec<u8>, Vec<u8>);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a key and a value")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let key: Bytes = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let value: Bytes = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok((key.0, value.0))
+    }
+}
+
+/// Deserializes the key, value and flags of a `Node::KVFlags`.
+struct FlaggedPair;
+
+impl<'de> Visitor<'de> for FlaggedPair {
+    type Value = (Vec<u8>, Vec<u8>, u8);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a key, a value and flags")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let (key, value) = BytesPair.visit_seq(&mut seq)?;
+        let flags = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        Ok((key, value, flags))
+    }
+}
+
+/// Deserializes the index of an enum variant from its name or index.
+struct VariantSeed(&'static [&'static str]);
+
+impl<'de> Visitor<'de> for VariantSeed {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "one of {:?}", self.0)
+    }
+
+    fn visit_u64<E: de::Error>(self, index: u64) -> Result<usize, E> {
+        if index >= self.0.len() as u64 {
+            return Err(E::invalid_value(de::Unexpected::Unsigned(index), &self));
+        }
+        Ok(index as usize)
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<usize, E> {
+        self.0
+            .iter()
+            .position(|variant| *variant == name)
+            .ok_or_else(|| E::unknown_variant(name, self.0))
+    }
+
+    fn visit_bytes<E: de::Error>(self, name: &[u8]) -> Result<usize, E> {
+        match std::str::from_utf8(name) {
+            Ok(name) => self.visit_str(name),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(name), &self)),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for VariantSeed {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+const OP_VARIANTS: &[&str] = &["Put", "Delete", "Move", "SetFlags"];
+const MOVE_FIELDS: &[&str] = &["to"];
+
+impl Serialize for Op {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Op::Put(value) => {
+                serializer.serialize_newtype_variant("Op", 0, "Put", &BytesRef(value))
+            }
+            Op::Delete => serializer.serialize_unit_variant("Op", 1, "Delete"),
+            Op::Move { to } => {
+                let mut variant = serializer.serialize_struct_variant("Op", 2, "Move", 1)?;
+                variant.serialize_field("to", &BytesRef(to))?;
+                variant.end()
+            }
+            Op::SetFlags(flags) => serializer.serialize_newtype_variant("Op", 3, "SetFlags", flags),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Op {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OpVisitor;
+
+        /// Visits the fields of a `Move`, as a sequence or a map.
+        struct MoveVisitor;
+
+        impl<'de> Visitor<'de> for MoveVisitor {
+            type Value = Op;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("the fields of a move")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Op, A::Error> {
+                let to = seq
+                    .next_element::<Bytes>()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                Ok(Op::Move { to: to.0 })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Op, A::Error> {
+                let mut to = None;
+                // "to" is the only field
+                while map.next_key_seed(VariantSeed(MOVE_FIELDS))?.is_some() {
+                    if to.is_some() {
+                        return Err(de::Error::duplicate_field("to"));
+                    }
+                    to = Some(map.next_value::<Bytes>()?.0);
+                }
+                let to = to.ok_or_else(|| de::Error::missing_field("to"))?;
+                Ok(Op::Move { to })
+            }
+        }
+
+        impl<'de> Visitor<'de> for OpVisitor {
+            type Value = Op;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a batch operation")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Op, A::Error> {
+                let (index, variant) = data.variant_seed(VariantSeed(OP_VARIANTS))?;
+                Ok(match index {
+                    0 => Op::Put(variant.newtype_variant::<Bytes>()?.0),
+                    1 => {
+                        variant.unit_variant()?;
+                        Op::Delete
+                    }
+                    2 => variant.struct_variant(MOVE_FIELDS, MoveVisitor)?,
+                    _ => Op::SetFlags(variant.newtype_variant::<u8>()?),
+                })
+            }
+        }
+
+        deserializer.deserialize_enum("Op", OP_VARIANTS, OpVisitor)
+    }
+}
+
+const NODE_VARIANTS: &[&str] = &["Hash", "KVHash", "KV", "KVDigest", "KVFlags"];
+
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Node::Hash(hash) => {
+                serializer.serialize_newtype_variant("Node", 0, "Hash", &BytesRef(hash))
+            }
+            Node::KVHash(hash) => {
+                serializer.serialize_newtype_variant("Node", 1, "KVHash", &BytesRef(hash))
+            }
+            Node::KV(key, value) => {
+                let mut variant = serializer.serialize_tuple_variant("Node", 2, "KV", 2)?;
+                variant.serialize_field(&BytesRef(key))?;
+                variant.serialize_field(&BytesRef(value))?;
+                variant.end()
+            }
+            Node::KVDigest(key, hash) => {
+                let mut variant = serializer.serialize_tuple_variant("Node", 3, "KVDigest", 2)?;
+                variant.serialize_field(&BytesRef(key))?;
+                variant.serialize_field(&BytesRef(hash))?;
+                variant.end()
+            }
+            Node::KVFlags(key, value, flags) => {
+                let mut variant = serializer.serialize_tuple_variant("Node", 4, "KVFlags", 3)?;
+                variant.serialize_field(&BytesRef(key))?;
+                variant.serialize_field(&BytesRef(value))?;
+                variant.serialize_field(flags)?;
+                variant.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodeVisitor;
+
+        impl<'de> Visitor<'de> for NodeVisitor {
+            type Value = Node;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a proof node")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Node, A::Error> {
+                let (index, variant) = data.variant_seed(VariantSeed(NODE_VARIANTS))?;
+                Ok(match index {
+                    0 => Node::Hash(variant.newtype_variant::<Bytes>()?.into_hash()?),
+                    1 => Node::KVHash(variant.newtype_variant::<Bytes>()?.into_hash()?),
+                    2 => {
+                        let (key, value) = variant.tuple_variant(2, BytesPair)?;
+                        Node::KV(key, value)
+                    }
+                    3 => {
+                        let (key, hash) = variant.tuple_variant(2, BytesPair)?;
+                        Node::KVDigest(key, Bytes(hash).into_hash()?)
+                    }
+                    _ => {
+                        let (key, value, flags) = variant.tuple_variant(3, FlaggedPair)?;
+                        Node::KVFlags(key, value, flags)
+                    }
+                })
+            }
+        }
+
+        deserializer.deserialize_enum("Node", NODE_VARIANTS, NodeVisitor)
+    }
+}
+
+const PROOF_OP_VARIANTS: &[&str] = &["Push", "Parent", "Child"];
+
+impl Serialize for ProofOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ProofOp::Push(node) => serializer.serialize_newtype_variant("Op", 0, "Push", node),
+            ProofOp::Parent => serializer.serialize_unit_variant("Op", 1, "Parent"),
+            ProofOp::Child => serializer.serialize_unit_variant("Op", 2, "Child"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ProofOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ProofOpVisitor;
+
+        impl<'de> Visitor<'de> for ProofOpVisitor {
+            type Value = ProofOp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a proof operator")
+            }
+
+            fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<ProofOp, A::Error> {
+                let (index, variant) = data.variant_seed(VariantSeed(PROOF_OP_VARIANTS))?;
+                Ok(match index {
+                    0 => ProofOp::Push(variant.newtype_variant()?),
+                    1 => {
+                        variant.unit_variant()?;
+                        ProofOp::Parent
+                    }
+                    _ => {
+                        variant.unit_variant()?;
+                        ProofOp::Child
+                    }
+                })
+            }
+        }
+
+        deserializer.deserialize_enum("Op", PROOF_OP_VARIANTS, ProofOpVisitor)
+    }
+}
+
+#[cfg(feature = "full")]
+mod full {
+    use super::*;
+    use crate::subscribe::ChangeEvent;
+
+    const CHANGE_EVENT_VARIANTS: &[&str] = &["Put", "Delete"];
+
+    impl Serialize for ChangeEvent {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                ChangeEvent::Put(key, value) => {
+                    let mut variant =
+                        serializer.serialize_tuple_variant("ChangeEvent", 0, "Put", 2)?;
+                    variant.serialize_field(&BytesRef(key))?;
+                    variant.serialize_field(&BytesRef(value))?;
+                    variant.end()
+                }
+                ChangeEvent::Delete(key) => {
+                    serializer.serialize_newtype_variant("ChangeEvent", 1, "Delete", &BytesRef(key))
+                }
+            }
+        }
+    }
+
+    impl<'de> Deserialize<'de> for ChangeEvent {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct ChangeEventVisitor;
+
+            impl<'de> Visitor<'de> for ChangeEventVisitor {
+                type Value = ChangeEvent;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a change event")
+                }
+
+                fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<ChangeEvent, A::Error> {
+                    let (index, variant) = data.variant_seed(VariantSeed(CHANGE_EVENT_VARIANTS))?;
+                    Ok(match index {
+                        0 => {
+                            let (key, value) = variant.tuple_variant(2, BytesPair)?;
+                            ChangeEvent::Put(key, value)
+                        }
+                        _ => ChangeEvent::Delete(variant.newtype_variant::<Bytes>()?.0),
+                    })
+                }
+            }
+
+            deserializer.deserialize_enum("ChangeEvent", CHANGE_EVENT_VARIANTS, ChangeEventVisitor)
+        }
+    }
+}
+
+/// Implements `Serialize` and `Deserialize` for a type as a byte string of
+/// its binary encoding.
+macro_rules! encoded {
+    ($(#[$attr:meta])* $type:ty, $decode:expr) => {
+        $(#[$attr])*
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(&self.encode())
+            }
+        }
+
+        $(#[$attr])*
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let bytes = Bytes::deserialize(deserializer)?;
+                ($decode)(&bytes.0[..]).map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+encoded!(
+    crate::mmr::InclusionProof,
+    crate::mmr::InclusionProof::decode
+);
+encoded!(
+    #[cfg(feature = "full")]
+    crate::replication::ReplicatedBatch,
+    crate::replication::ReplicatedBatch::decode
+);
+encoded!(
+    #[cfg(feature = "full")]
+    crate::attest::RootAttestation,
+    crate::attest::RootAttestation::decode
+);
+encoded!(
+    #[cfg(feature = "full")]
+    crate::backup::BackupManifest,
+    crate::backup::BackupManifest::decode
+);
+encoded!(
+    #[cfg(feature = "smt")]
+    crate::smt::SmtProof,
+    |mut bytes: &[u8]| match crate::smt::SmtProof::decode_from(&mut bytes)? {
+        _ if !bytes.is_empty() => Err(crate::Error::Decode("Unexpected trailing bytes".into())),
+        proof => Ok(proof),
+    }
+);
+encoded!(
+    #[cfg(feature = "evm")]
+    crate::evm::Account,
+    crate::evm::Account::decode
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::de::value::{
+        BytesDeserializer, Error, MapAccessDeserializer, MapDeserializer, SeqDeserializer,
+        StrDeserializer,
+    };
+    use serde::de::IntoDeserializer;
+
+    fn variant<'a>(
+        name: &'static str,
+        value: &'a [u8],
+    ) -> MapAccessDeserializer<MapDeserializer<'a, std::iter::Once<(&'static str, &'a [u8])>, Error>>
+    {
+        MapAccessDeserializer::new(MapDeserializer::new(std::iter::once((name, value))))
+    }
+
+    #[test]
+    fn deserialize_ops() {
+        let op = Op::deserialize(variant("Put", b"value")).unwrap();
+        assert_eq!(op, Op::Put(b"value".to_vec()));
+        let op = Op::deserialize(StrDeserializer::<Error>::new("Delete")).unwrap();
+        assert_eq!(op, Op::Delete);
+        assert!(Op::deserialize(StrDeserializer::<Error>::new("Merge")).is_err());
+        let fields = MapDeserializer::<_, Error>::new(std::iter::once(("Move", vec![vec![9u8]])));
+        let op = Op::deserialize(MapAccessDeserializer::new(fields)).unwrap();
+        assert_eq!(op, Op::Move { to: vec![9] });
+
+        let node = Node::deserialize(variant("Hash", &[7; 32])).unwrap();
+        assert_eq!(node, Node::Hash([7; 32]));
+        assert!(Node::deserialize(variant("KVHash", &[7; 31])).is_err());
+        let op = ProofOp::deserialize(StrDeserializer::<Error>::new("Parent")).unwrap();
+        assert_eq!(op, ProofOp::Parent);
+
+        // byte strings can also be sequences of bytes
+        let seq: SeqDeserializer<_, Error> = vec![1u8, 2, 3].into_deserializer();
+        assert_eq!(Bytes::deserialize(seq).unwrap().0, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn deserialize_encoded() {
+        let mut mmr = crate::mmr::Mmr::new();
+        (0..5u8).for_each(|i| mmr.push(&[i]));
+        let proof = mmr.prove(3).unwrap();
+        let encoded = proof.encode();
+        let bytes = BytesDeserializer::<Error>::new(&encoded);
+        assert_eq!(
+            crate::mmr::InclusionProof::deserialize(bytes).unwrap(),
+            proof
+        );
+
+        let bytes = BytesDeserializer::<Error>::new(&encoded[1..]);
+        assert!(crate::mmr::InclusionProof::deserialize(bytes).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+//! Deterministic sharding of a key space across several stores.
+//!
+//! A `ShardedMerk` splits the key space into `K` contiguous ranges at `K - 1`
+//! split keys, and keeps the entries of each range in a `Merk` of its own, so
+//! the shards can live on different disks and batches can be applied to all
+//! of them in parallel. Shard `i` holds the keys from split `i - 1`
+//! (inclusive, or the start of the key space for the first shard) up to split
+//! `i` (exclusive, or the end of the key space for the last shard).
+//!
+//! The combined root hash is the root of a Merkle Mountain Range (see `mmr`)
+//! whose leaves are the shards in order, each committing to the shard's root
+//! hash and the range of keys it covers. A proof of a query holds, for each
+//! shard the query touches, the inclusion proof of the shard's leaf and an
+//! ordinary query proof against the shard's root hash, so a verifier which
+//! only knows the combined root hash can check that an entry is, or is not,
+//! in the sharded store. The ranges are part of the leaves, so a proof cannot
+//! answer for a key from a shard which does not cover it.
+
+#[cfg(feature = "full")]
+use std::ops::{Range, RangeInclusive};
+
+use crate::mmr::InclusionProof;
+#[cfg(feature = "full")]
+use crate::proofs::query::QueryItem;
+use crate::proofs::query::{verify, Map};
+use crate::tree::{Hash, HASH_LENGTH, NULL_HASH};
+use crate::{Error, Result};
+
+/// Returns the MMR leaf of a shard with root hash `root_hash` covering the
+/// keys from `start` up to `end`: the root hash, then the length-prefixed
+/// start key, then the length-prefixed end key if there is one.
+pub fn shard_leaf(root_hash: &Hash, start: &[u8], end: Option<&[u8]>) -> Vec<u8> {
+    let mut leaf = Vec::with_capacity(HASH_LENGTH + 9 + start.len() + end.map_or(0, <[u8]>::len));
+    leaf.extend_from_slice(root_hash);
+    write_bytes(&mut leaf, start);
+    match end {
+        Some(end) => {
+            leaf.push(1);
+            write_bytes(&mut leaf, end);
+        }
+        None => leaf.push(0),
+    }
+    leaf
+}
+
+fn covers(start: &[u8], end: Option<&[u8]>, key: &[u8]) -> bool {
+    key >= start && end.is_none_or(|end| key < end)
+}
+
+/// Returns the part of `item` from `start` up to `end`, or `None` if they do
+/// not overlap.
+#[cfg(feature = "full")]
+fn clip(item: &QueryItem, start: &[u8], end: Option<&[u8]>) -> Option<QueryItem> {
+    if let QueryItem::Key(key) = item {
+        return covers(start, end, key).then(|| item.clone());
+    }
+    let lower = item.lower_bound().max(start).to_vec();
+    let (upper, inclusive) = match (item.upper_bound(), end) {
+        ((upper, inclusive), Some(end)) if upper < end => (upper, inclusive),
+        ((upper, false), Some(end)) if upper == end => (upper, false),
+        (_, Some(end)) => (end, false),
+        ((upper, inclusive), None) => (upper, inclusive),
+    };
+    if inclusive {
+        (lower.as_slice() <= upper)
+            .then(|| QueryItem::RangeInclusive(RangeInclusive::new(lower, upper.to_vec())))
+    } else {
+        (lower.as_slice() < upper).then(|| {
+            QueryItem::Range(Range {
+                start: lower,
+                end: upper.to_vec(),
+            })
+        })
+    }
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+fn read_bytes<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let invalid = || Error::Decode("Invalid sharded proof".into());
+    let mut len = [0; 4];
+    len.copy_from_slice(bytes.get(..4).ok_or_else(invalid)?);
+    let end = 4 + u32::from_be_bytes(len) as usize;
+    let data = bytes.get(4..end).ok_or_else(invalid)?;
+    *bytes = &bytes[end..];
+    Ok(data)
+}
+
+/// The proof of a query's entries in one shard, see `ShardedProof`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardProof {
+    /// The first key of the shard.
+    pub start: Vec<u8>,
+    /// The key after the last key of the shard, or `None` for the last shard.
+    pub end: Option<Vec<u8>>,
+    /// The root hash of the shard.
+    pub root_hash: Hash,
+    /// The proof of the shard's leaf in the combined root.
+    pub inclusion: InclusionProof,
+    /// The query proof of the entries against the shard's root hash.
+    pub proof: Vec<u8>,
+}
+
+/// A proof of a query against the combined root hash of a `ShardedMerk`,
+/// with one `ShardProof` for each shard the query touches.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShardedProof {
+    pub shards: Vec<ShardProof>,
+}
+
+impl ShardedProof {
+    /// Verifies the proof against the combined root hash, returning the
+    /// proven entries of each shard.
+    pub fn verify(&self, expected_root: &Hash) -> Result<ShardedMap> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let leaf = shard_leaf(&shard.root_hash, &shard.start, shard.end.as_deref());
+                shard.inclusion.verify(expected_root, &leaf)?;
+                let map = verify(&shard.proof, shard.root_hash)?;
+                Ok((shard.start.clone(), shard.end.clone(), map))
+            })
+            .collect::<Result<_>>()?;
+        Ok(ShardedMap { shards })
+    }
+
+    /// Encodes the proof into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&(self.shards.len() as u32).to_be_bytes());
+        for shard in self.shards.iter() {
+            write_bytes(&mut bytes, &shard.start);
+            match &shard.end {
+                Some(end) => {
+                    bytes.push(1);
+                    write_bytes(&mut bytes, end);
+                }
+                None => bytes.push(0),
+            }
+            bytes.extend_from_slice(&shard.root_hash);
+            write_bytes(&mut bytes, &shard.inclusion.encode());
+            write_bytes(&mut bytes, &shard.proof);
+        }
+        bytes
+    }
+
+    /// Decodes a proof from bytes.
+    pub fn decode(mut bytes: &[u8]) -> Result<ShardedProof> {
+        let invalid = || Error::Decode("Invalid sharded proof".into());
+        let mut count = [0; 4];
+        count.copy_from_slice(bytes.get(..4).ok_or_else(invalid)?);
+        bytes = &bytes[4..];
+
+        let mut shards = vec![];
+        for _ in 0..u32::from_be_bytes(count) {
+            let start = read_bytes(&mut bytes)?.to_vec();
+            let (&has_end, rest) = bytes.split_first().ok_or_else(invalid)?;
+            bytes = rest;
+            let end = match has_end {
+                0 => None,
+                1 => Some(read_bytes(&mut bytes)?.to_vec()),
+                _ => return Err(invalid()),
+            };
+            let mut root_hash = NULL_HASH;
+            root_hash.copy_from_slice(bytes.get(..HASH_LENGTH).ok_or_else(invalid)?);
+            bytes = &bytes[HASH_LENGTH..];
+            let inclusion = InclusionProof::decode(read_bytes(&mut bytes)?)?;
+            let proof = read_bytes(&mut bytes)?.to_vec();
+            shards.push(ShardProof {
+                start,
+                end,
+                root_hash,
+                inclusion,
+                proof,
+            });
+        }
+        if !bytes.is_empty() {
+            return Err(Error::Decode("Unexpected bytes after sharded proof".into()));
+        }
+        Ok(ShardedProof { shards })
+    }
+}
+
+/// The entries proven by a `ShardedProof`, by shard.
+#[derive(Clone)]
+pub struct ShardedMap {
+    shards: Vec<(Vec<u8>, Option<Vec<u8>>, Map)>,
+}
+
+impl ShardedMap {
+    /// Gets the value of `key` from the proven shard covering it, or `None`
+    /// if the proof shows the key is absent. Returns `Error::MissingData` if
+    /// no proven shard covers the key, or if its shard's proof does not
+    /// include it.
+    pub fn get(&self, key: &[u8]) -> Result<Option<&[u8]>> {
+        let (_, _, map) = self
+            .shards
+            .iter()
+            .find(|(start, end, _)| covers(start, end.as_deref(), key))
+            .ok_or(Error::MissingData)?;
+        map.get(key)
+    }
+
+    /// Returns the verified proofs of each shard, with the first key of the
+    /// shard and the key after its last key.
+    pub fn shards(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>, &Map)> {
+        self.shards
+            .iter()
+            .map(|(start, end, map)| (start.as_slice(), end.as_deref(), map))
+    }
+}
+
+#[cfg(feature = "full")]
+pub use store::ShardedMerk;
+
+#[cfg(feature = "full")]
+mod store {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mmr::Mmr;
+    use crate::proofs::Query;
+    use crate::tree::{check_sorted, Batch};
+    use crate::Merk;
+
+    /// A key/value store sharded by key range across several `Merk` stores,
+    /// see the module documentation.
+    pub struct ShardedMerk {
+        shards: Vec<Merk>,
+        splits: Vec<Vec<u8>>,
+    }
+
+    impl ShardedMerk {
+        /// Opens a sharded store with one shard at each of `paths`, split at
+        /// the keys in `splits`, which must be sorted and unique and one
+        /// fewer than the paths. Shards which do not exist are created.
+        ///
+        /// The splits are not stored, so the same splits must be passed each
+        /// time the store is opened. This fails if a shard holds keys outside
+        /// of its range.
+        pub fn open<P: AsRef<Path>>(paths: &[P], splits: Vec<Vec<u8>>) -> Result<ShardedMerk> {
+            if paths.len() != splits.len() + 1 {
+                return Err(Error::Path(format!(
+                    "Expected {} shard paths for {} splits, got {}",
+                    splits.len() + 1,
+                    splits.len(),
+                    paths.len()
+                )));
+            }
+            if splits.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(Error::Key("Splits must be sorted and unique".into()));
+            }
+
+            let shards = paths.iter().map(Merk::open).collect::<Result<Vec<_>>>()?;
+            let sharded = ShardedMerk { shards, splits };
+            for (index, shard) in sharded.shards.iter().enumerate() {
+                let (start, end) = sharded.range(index);
+                let mut iter = shard.raw_iter();
+                iter.seek_to_first();
+                let first = iter.key().map(<[u8]>::to_vec);
+                iter.seek_to_last();
+                let last = iter.key().map(<[u8]>::to_vec);
+                for key in first.iter().chain(last.iter()) {
+                    if !covers(start, end, key) {
+                        return Err(Error::ShardRange {
+                            index,
+                            key: key.clone(),
+                        });
+                    }
+                }
+            }
+            Ok(sharded)
+        }
+
+        /// Opens a sharded store split at the keys in `splits`, placing its
+        /// shards across the directories in `dirs` in turn, so shard `i` is
+        /// at `shard-<i>` in directory `i % dirs.len()`. With a directory on
+        /// each disk, this spreads the shards and their I/O evenly across the
+        /// disks. See `open`.
+        pub fn open_in<P: AsRef<Path>>(dirs: &[P], splits: Vec<Vec<u8>>) -> Result<ShardedMerk> {
+            if dirs.is_empty() {
+                return Err(Error::Path("No directories to place shards in".into()));
+            }
+            let paths: Vec<_> = (0..=splits.len())
+                .map(|i| dirs[i % dirs.len()].as_ref().join(format!("shard-{}", i)))
+                .collect();
+            ShardedMerk::open(&paths, splits)
+        }
+
+        /// Returns the number of shards.
+        pub fn shard_count(&self) -> usize {
+            self.shards.len()
+        }
+
+        /// Returns the shard at `index`.
+        pub fn shard(&self, index: usize) -> &Merk {
+            &self.shards[index]
+        }
+
+        /// Returns the index of the shard holding `key`.
+        pub fn shard_for(&self, key: &[u8]) -> usize {
+            self.splits.partition_point(|split| split.as_slice() <= key)
+        }
+
+        /// Returns the first key of the shard at `index`, and the key after
+        /// its last key or `None` for the last shard.
+        pub fn range(&self, index: usize) -> (&[u8], Option<&[u8]>) {
+            let start = match index {
+                0 => &[][..],
+                _ => self.splits[index - 1].as_slice(),
+            };
+            (start, self.splits.get(index).map(Vec::as_slice))
+        }
+
+        /// Gets the value of `key` from its shard.
+        pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.shards[self.shard_for(key)].get(key)
+        }
+
+        /// Returns the combined root hash, see the module documentation.
+        pub fn root_hash(&self) -> Hash {
+            self.mmr().root()
+        }
+
+        fn mmr(&self) -> Mmr {
+            let mut mmr = Mmr::new();
+            for (index, shard) in self.shards.iter().enumerate() {
+                let (start, end) = self.range(index);
+                mmr.push(&shard_leaf(&shard.root_hash(), start, end));
+            }
+            mmr
+        }
+
+        /// Applies a batch of operations, which must be sorted and unique, by
+        /// splitting it by shard and applying each part to its shard in
+        /// parallel. Auxiliary data is written to the first shard.
+        ///
+        /// The batch is not applied atomically across shards: if applying it
+        /// to one shard fails, it may still have been applied to others.
+        pub fn apply(&mut self, batch: &Batch, aux: &Batch) -> Result<()> {
+            check_sorted(batch)?;
+
+            let mut parts = Vec::with_capacity(self.shards.len());
+            let mut rest = batch;
+            for split in self.splits.iter() {
+                let (part, next) = rest.split_at(rest.partition_point(|(key, _)| key < split));
+                parts.push(part);
+                rest = next;
+            }
+            parts.push(rest);
+
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = self
+                    .shards
+                    .iter_mut()
+                    .zip(parts)
+                    .enumerate()
+                    .filter(|(index, (_, part))| *index == 0 || !part.is_empty())
+                    .map(|(index, (shard, part))| {
+                        let aux: &Batch = if index == 0 { aux } else { &[] };
+                        scope.spawn(move || shard.apply(part, aux))
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .try_for_each(|handle| handle.join().expect("shard apply panicked"))
+            })
+        }
+
+        /// Gets auxiliary data from the first shard.
+        pub fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.shards[0].get_aux(key)
+        }
+
+        /// Creates a proof of `query` against the combined root hash, proving
+        /// the part of each item in each shard it overlaps. Fails if the query
+        /// has limited terms, which can't be split across shards.
+        pub fn prove(&self, query: Query) -> Result<ShardedProof> {
+            if query.terms().iter().any(|term| term.limit.is_some()) {
+                return Err(Error::Key(
+                    "Limited terms are not supported by sharded proofs".into(),
+                ));
+            }
+            let value_limit = query.get_value_limit();
+            let items: Vec<QueryItem> = query.into();
+
+            let mmr = self.mmr();
+            let mut shards = vec![];
+            for (index, shard) in self.shards.iter().enumerate() {
+                let (start, end) = self.range(index);
+                let mut shard_query = Query::new();
+                for item in items.iter() {
+                    if let Some(item) = clip(item, start, end) {
+                        shard_query.insert_item(item);
+                    }
+                }
+                if shard_query.len() == 0 {
+                    continue;
+                }
+                if let Some(bytes) = value_limit {
+                    shard_query = shard_query.value_limit(bytes);
+                }
+                shards.push(ShardProof {
+                    start: start.to_vec(),
+                    end: end.map(<[u8]>::to_vec),
+                    root_hash: shard.root_hash(),
+                    inclusion: mmr.prove(index as u64)?,
+                    proof: shard.prove(shard_query)?,
+                });
+            }
+            Ok(ShardedProof { shards })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use tempdir::TempDir;
+
+        use super::*;
+        use crate::test_utils::*;
+
+        fn open(dir: &TempDir) -> ShardedMerk {
+            let paths: Vec<_> = (0..3).map(|i| dir.path().join(i.to_string())).collect();
+            ShardedMerk::open(&paths, vec![seq_key(30), seq_key(60)]).unwrap()
+        }
+
+        #[test]
+        fn apply_and_prove() {
+            let dir = TempDir::new("merk_sharded").unwrap();
+            let mut sharded = open(&dir);
+            let empty_root = sharded.root_hash();
+            sharded.apply(&make_batch_seq(0..100), &[]).unwrap();
+            assert_ne!(sharded.root_hash(), empty_root);
+            assert_eq!(sharded.shard_for(&seq_key(29)), 0);
+            assert_eq!(sharded.shard_for(&seq_key(30)), 1);
+            assert_eq!(sharded.shard_for(&seq_key(99)), 2);
+            assert!(sharded.shard(1).get(&seq_key(45)).unwrap().is_some());
+            assert!(sharded.shard(0).get(&seq_key(45)).unwrap().is_none());
+
+            let root_hash = sharded.root_hash();
+            drop(sharded);
+            let sharded = open(&dir);
+            assert_eq!(sharded.root_hash(), root_hash);
+            assert!(sharded.get(&seq_key(70)).unwrap().is_some());
+
+            let mut query = Query::new();
+            query.insert_key(seq_key(5));
+            query.insert_range(seq_key(25)..seq_key(35));
+            query.insert_key(seq_key(500));
+            let proof = sharded.prove(query).unwrap();
+            // the range spans the first two shards, and the absent key is in
+            // the last one
+            assert_eq!(proof.shards.len(), 3);
+            let proof = ShardedProof::decode(&proof.encode()).unwrap();
+
+            let map = proof.verify(&root_hash).unwrap();
+            assert_eq!(map.get(&seq_key(5)).unwrap(), Some(&[123; 60][..]));
+            assert!(map.get(&seq_key(31)).unwrap().is_some());
+            assert_eq!(map.get(&seq_key(500)).unwrap(), None);
+            assert!(proof.verify(&empty_root).is_err());
+
+            // a shard's proof can't be passed off as covering another range
+            let mut moved = proof.clone();
+            moved.shards[0].end = Some(seq_key(40));
+            assert!(moved.verify(&root_hash).is_err());
+        }
+
+        #[test]
+        fn open_checks_ranges() {
+            let dir = TempDir::new("merk_sharded").unwrap();
+            let mut sharded = open(&dir);
+            sharded.apply(&make_batch_seq(0..100), &[]).unwrap();
+            drop(sharded);
+
+            let paths: Vec<_> = (0..3).map(|i| dir.path().join(i.to_string())).collect();
+            assert!(ShardedMerk::open(&paths, vec![seq_key(20), seq_key(60)]).is_err());
+            assert!(ShardedMerk::open(&paths, vec![seq_key(30)]).is_err());
+            assert!(ShardedMerk::open(&paths, vec![seq_key(60), seq_key(30)]).is_err());
+        }
+
+        #[test]
+        fn open_in() {
+            let dirs = [
+                TempDir::new("merk_sharded_a").unwrap(),
+                TempDir::new("merk_sharded_b").unwrap(),
+            ];
+            let dirs = [dirs[0].path(), dirs[1].path()];
+            let splits = vec![seq_key(30), seq_key(60)];
+            let mut sharded = ShardedMerk::open_in(&dirs, splits.clone()).unwrap();
+            sharded.apply(&make_batch_seq(0..100), &[]).unwrap();
+            let root_hash = sharded.root_hash();
+            drop(sharded);
+
+            assert!(dirs[0].join("shard-0").exists());
+            assert!(dirs[1].join("shard-1").exists());
+            assert!(dirs[0].join("shard-2").exists());
+            let sharded = ShardedMerk::open_in(&dirs, splits).unwrap();
+            assert_eq!(sharded.root_hash(), root_hash);
+            assert!(ShardedMerk::open_in::<&Path>(&[], vec![]).is_err());
+        }
+    }
+}
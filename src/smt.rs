@@ -0,0 +1,486 @@
+//! A sparse Merkle tree (SMT) mode, for verifiers which need fixed-shape
+//! proofs.
+//!
+//! Keys are hashed, and each key's hash is the path from the root to its leaf
+//! in a binary tree of depth `DEPTH`, so every proof has exactly `DEPTH`
+//! siblings regardless of how many keys are stored or where they are, and a
+//! verifier can check it with a fixed loop. The trade-off is that keys are not
+//! ordered, so there are no range queries, and every write updates `DEPTH`
+//! nodes.
+//!
+//! Empty subtrees hash to `NULL_HASH`, and a node whose children are both
+//! empty is empty itself, so only the nodes above stored keys are kept.
+//! Leaves are the `kv_hash` of their key and value, and inner nodes hash
+//! their children with a prefix distinct from the AVL tree's node hashes.
+//!
+//! `SparseMerk` is a store with the same shape of API as `Merk` (`open`,
+//! `apply`, `get`, `root_hash`, `prove`), backed by RocksDB.
+
+use sha2::Digest;
+
+use crate::tree::{kv_hash, Hash, Hasher, HASH_LENGTH, NULL_HASH};
+use crate::{Error, Result};
+
+/// The depth of the tree, which is the number of bits in a key's hash.
+pub const DEPTH: usize = HASH_LENGTH * 8;
+
+const PARENT_PREFIX: u8 = 8;
+
+/// Returns the path of a key's leaf, which is the hash of the key.
+pub fn key_path(key: &[u8]) -> Hash {
+    Hasher::digest(key).into()
+}
+
+/// Returns `true` if the path goes right at the given depth.
+fn bit(path: &Hash, depth: usize) -> bool {
+    (path[depth / 8] >> (7 - depth % 8)) & 1 == 1
+}
+
+/// Hashes a leaf holding `value` at `key`.
+pub fn leaf_hash(key: &[u8], value: &[u8]) -> Result<Hash> {
+    Ok(kv_hash::<Hasher>(key, value)?)
+}
+
+/// Hashes an inner node from the hashes of its children. A node with two
+/// empty children is empty.
+pub fn parent_hash(left: &Hash, right: &Hash) -> Hash {
+    if *left == NULL_HASH && *right == NULL_HASH {
+        return NULL_HASH;
+    }
+    let mut hasher = Hasher::new();
+    hasher.update([PARENT_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// A proof of the value of a single key, or of its absence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmtProof {
+    /// The siblings of the nodes on the key's path, `DEPTH` of them, from the
+    /// root's children down to the leaf's sibling.
+    pub siblings: Vec<Hash>,
+    /// The key's value, or `None` if the key is absent.
+    pub value: Option<Vec<u8>>,
+}
+
+impl SmtProof {
+    /// Returns the root hash of the tree the proof is for, given the key it
+    /// was created for.
+    pub fn root(&self, key: &[u8]) -> Result<Hash> {
+        if self.siblings.len() != DEPTH {
+            return Err(Error::Proof(format!(
+                "Expected {} siblings, got {}",
+                DEPTH,
+                self.siblings.len()
+            )));
+        }
+
+        let path = key_path(key);
+        let mut hash = match &self.value {
+            Some(value) => leaf_hash(key, value)?,
+            None => NULL_HASH,
+        };
+        for depth in (0..DEPTH).rev() {
+            let sibling = &self.siblings[depth];
+            hash = if bit(&path, depth) {
+                parent_hash(sibling, &hash)
+            } else {
+                parent_hash(&hash, sibling)
+            };
+        }
+        Ok(hash)
+    }
+
+    /// Checks the proof against `expected_root` and returns the proven value
+    /// of `key`.
+    pub fn verify(&self, key: &[u8], expected_root: &Hash) -> Result<Option<&[u8]>> {
+        let root = self.root(key)?;
+        if root != *expected_root {
+            return Err(Error::HashMismatch(*expected_root, root));
+        }
+        Ok(self.value.as_deref())
+    }
+
+    /// Encodes the proof into `output`. Empty siblings are omitted, with a
+    /// bitmap marking which siblings are present.
+    pub fn encode_into(&self, output: &mut Vec<u8>) {
+        let mut bitmap = [0u8; DEPTH / 8];
+        for (depth, sibling) in self.siblings.iter().enumerate() {
+            if *sibling != NULL_HASH {
+                bitmap[depth / 8] |= 1 << (7 - depth % 8);
+            }
+        }
+        output.extend_from_slice(&bitmap);
+        for sibling in self.siblings.iter().filter(|hash| **hash != NULL_HASH) {
+            output.extend_from_slice(sibling);
+        }
+        match &self.value {
+            None => output.push(0),
+            Some(value) => {
+                output.push(1);
+                output.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                output.extend_from_slice(value);
+            }
+        }
+    }
+
+    /// Encodes the proof into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = vec![];
+        self.encode_into(&mut output);
+        output
+    }
+
+    /// Decodes a proof from the start of `bytes`, advancing it past the proof.
+    pub fn decode_from(bytes: &mut &[u8]) -> Result<SmtProof> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+            if bytes.len() < len {
+                return Err(Error::Decode("Unexpected end of SMT proof".into()));
+            }
+            let (head, tail) = bytes.split_at(len);
+            *bytes = tail;
+            Ok(head)
+        }
+
+        let mut bitmap = [0u8; DEPTH / 8];
+        bitmap.copy_from_slice(take(bytes, DEPTH / 8)?);
+        let mut siblings = Vec::with_capacity(DEPTH);
+        for depth in 0..DEPTH {
+            let mut hash = NULL_HASH;
+            if bit(&bitmap, depth) {
+                hash.copy_from_slice(take(bytes, HASH_LENGTH)?);
+            }
+            siblings.push(hash);
+        }
+
+        let value = match take(bytes, 1)?[0] {
+            0 => None,
+            1 => {
+                let mut len = [0; 4];
+                len.copy_from_slice(take(bytes, 4)?);
+                Some(take(bytes, u32::from_be_bytes(len) as usize)?.to_vec())
+            }
+            tag => return Err(Error::Decode(format!("Unknown SMT proof tag {}", tag))),
+        };
+
+        Ok(SmtProof { siblings, value })
+    }
+}
+
+/// Verifies a proof created by `SparseMerk::prove` for `keys` against
+/// `expected_root`, returning the proven value of each key.
+pub fn verify(
+    mut bytes: &[u8],
+    keys: &[Vec<u8>],
+    expected_root: &Hash,
+) -> Result<Vec<Option<Vec<u8>>>> {
+    let mut values = Vec::with_capacity(keys.len());
+    for key in keys {
+        let proof = SmtProof::decode_from(&mut bytes)?;
+        values.push(
+            proof
+                .verify(key, expected_root)?
+                .map(|value| value.to_vec()),
+        );
+    }
+    if !bytes.is_empty() {
+        return Err(Error::Proof("Unexpected bytes after SMT proofs".into()));
+    }
+    Ok(values)
+}
+
+#[cfg(feature = "full")]
+pub use store::SparseMerk;
+
+#[cfg(feature = "full")]
+mod store {
+    use std::path::{Path, PathBuf};
+
+    use rocksdb::{ColumnFamilyDescriptor, WriteBatch, DB};
+
+    use super::*;
+    use crate::tree::{Batch, Op};
+    use crate::Merk;
+
+    const AUX_CF_NAME: &str = "aux";
+    const LEAVES_CF_NAME: &str = "leaves";
+
+    /// Returns the database key of the node at `depth` on `path`: the depth
+    /// followed by the path's first `depth` bits.
+    fn node_key(depth: usize, path: &Hash) -> Vec<u8> {
+        let len = depth.div_ceil(8);
+        let mut key = Vec::with_capacity(2 + len);
+        key.extend_from_slice(&(depth as u16).to_be_bytes());
+        key.extend_from_slice(&path[..len]);
+        if !depth.is_multiple_of(8) {
+            *key.last_mut().unwrap() &= 0xff << (8 - depth % 8);
+        }
+        key
+    }
+
+    /// Returns `path` with the bit at `depth` set to `right`.
+    fn with_bit(path: &Hash, depth: usize, right: bool) -> Hash {
+        let mut path = *path;
+        let mask = 1 << (7 - depth % 8);
+        if right {
+            path[depth / 8] |= mask;
+        } else {
+            path[depth / 8] &= !mask;
+        }
+        path
+    }
+
+    fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + key.len() + value.len());
+        bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(key);
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    fn decode_leaf(bytes: &[u8]) -> Result<(&[u8], &[u8])> {
+        let invalid = || Error::Decode("Invalid SMT leaf".into());
+        let mut len = [0; 4];
+        len.copy_from_slice(bytes.get(..4).ok_or_else(invalid)?);
+        let key_end = 4 + u32::from_be_bytes(len) as usize;
+        let key = bytes.get(4..key_end).ok_or_else(invalid)?;
+        Ok((key, &bytes[key_end..]))
+    }
+
+    /// A key/value store authenticated by a sparse Merkle tree, see the module
+    /// documentation.
+    pub struct SparseMerk {
+        db: DB,
+        path: PathBuf,
+    }
+
+    impl SparseMerk {
+        /// Opens a store with the specified file path. If no store exists at
+        /// that path, one will be created.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<SparseMerk> {
+            let column_families = vec![
+                ColumnFamilyDescriptor::new(AUX_CF_NAME, Merk::default_db_opts()),
+                ColumnFamilyDescriptor::new(LEAVES_CF_NAME, Merk::default_db_opts()),
+            ];
+            let db = DB::open_cf_descriptors(&Merk::default_db_opts(), &path, column_families)?;
+            Ok(SparseMerk {
+                db,
+                path: path.as_ref().to_path_buf(),
+            })
+        }
+
+        /// Closes the store and deletes all data from disk.
+        pub fn destroy(self) -> Result<()> {
+            let path = self.path.clone();
+            drop(self);
+            DB::destroy(&Merk::default_db_opts(), path)?;
+            Ok(())
+        }
+
+        /// Gets a value for the given key. If the key is not found, `None` is
+        /// returned.
+        pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            let leaves_cf = self.db.cf_handle(LEAVES_CF_NAME).unwrap();
+            match self.db.get_pinned_cf(leaves_cf, key_path(key))? {
+                None => Ok(None),
+                Some(bytes) => Ok(Some(decode_leaf(&bytes)?.1.to_vec())),
+            }
+        }
+
+        /// Gets an auxiliary value.
+        pub fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            let aux_cf = self.db.cf_handle(AUX_CF_NAME).unwrap();
+            Ok(self.db.get_cf(aux_cf, key)?)
+        }
+
+        /// Returns the root hash of the tree, which is `NULL_HASH` if the
+        /// store is empty.
+        pub fn root_hash(&self) -> Result<Hash> {
+            self.node(0, &NULL_HASH)
+        }
+
+        fn node(&self, depth: usize, path: &Hash) -> Result<Hash> {
+            match self.db.get_pinned(node_key(depth, path))? {
+                None => Ok(NULL_HASH),
+                Some(bytes) => {
+                    let mut hash = NULL_HASH;
+                    if bytes.len() != HASH_LENGTH {
+                        return Err(Error::Decode("Invalid SMT node".into()));
+                    }
+                    hash.copy_from_slice(&bytes);
+                    Ok(hash)
+                }
+            }
+        }
+
+        /// Applies a batch of operations (puts and deletes) to the tree, and
+        /// writes `aux` to the auxiliary data, like `Merk::apply`.
+        pub fn apply(&mut self, batch: &Batch, aux: &Batch) -> Result<()> {
+            crate::merk::check_batch_keys(batch)?;
+
+            let leaves_cf = self.db.cf_handle(LEAVES_CF_NAME).unwrap();
+            let aux_cf = self.db.cf_handle(AUX_CF_NAME).unwrap();
+            let mut write_batch = WriteBatch::default();
+
+            let mut leaves = Vec::with_capacity(batch.len());
+            for (key, op) in batch {
+                let path = key_path(key);
+                let hash = match op {
+                    Op::Put(value) => {
+                        write_batch.put_cf(leaves_cf, path, encode_leaf(key, value));
+                        leaf_hash(key, value)?
+                    }
+                    Op::Move { .. } => {
+                        return Err(Error::BatchKey(
+                            "Moves are not supported by sparse Merkle trees".into(),
+                        ));
+                    }
+                    Op::SetFlags(_) => {
+                        return Err(Error::BatchKey(
+                            "Flags are not supported by sparse Merkle trees".into(),
+                        ));
+                    }
+                    Op::Delete => {
+                        if self.db.get_pinned_cf(leaves_cf, path)?.is_none() {
+                            return Err(Error::KeyDelete(key.clone()));
+                        }
+                        write_batch.delete_cf(leaves_cf, path);
+                        NULL_HASH
+                    }
+                };
+                leaves.push((path, hash));
+            }
+            leaves.sort_by_key(|(path, _)| *path);
+
+            if !leaves.is_empty() {
+                self.update(0, &leaves, &mut write_batch)?;
+            }
+
+            for (key, op) in aux {
+                match op {
+                    Op::Put(value) => write_batch.put_cf(aux_cf, key, value),
+                    Op::Delete => write_batch.delete_cf(aux_cf, key),
+                    Op::Move { .. } => {
+                        return Err(Error::BatchKey("Aux batches cannot contain moves".into()));
+                    }
+                    Op::SetFlags(_) => {
+                        return Err(Error::BatchKey("Aux entries cannot have flags".into()));
+                    }
+                }
+            }
+
+            self.db.write(write_batch)?;
+            Ok(())
+        }
+
+        /// Updates the subtree at `depth` containing the paths of `leaves`,
+        /// which are sorted and not empty, returning its new hash.
+        fn update(
+            &self,
+            depth: usize,
+            leaves: &[(Hash, Hash)],
+            write_batch: &mut WriteBatch,
+        ) -> Result<Hash> {
+            let path = &leaves[0].0;
+            let hash = if depth == DEPTH {
+                leaves[0].1
+            } else {
+                let split = leaves.partition_point(|(path, _)| !bit(path, depth));
+                let (left, right) = leaves.split_at(split);
+                let mut child = |leaves: &[(Hash, Hash)], right: bool| -> Result<Hash> {
+                    if leaves.is_empty() {
+                        self.node(depth + 1, &with_bit(path, depth, right))
+                    } else {
+                        self.update(depth + 1, leaves, write_batch)
+                    }
+                };
+                let left_hash = child(left, false)?;
+                let right_hash = child(right, true)?;
+                parent_hash(&left_hash, &right_hash)
+            };
+
+            if hash == NULL_HASH {
+                write_batch.delete(node_key(depth, path));
+            } else {
+                write_batch.put(node_key(depth, path), hash);
+            }
+            Ok(hash)
+        }
+
+        /// Creates a proof of the value of `key`, or of its absence.
+        pub fn prove_key(&self, key: &[u8]) -> Result<SmtProof> {
+            let path = key_path(key);
+            let siblings = (0..DEPTH)
+                .map(|depth| self.node(depth + 1, &with_bit(&path, depth, !bit(&path, depth))))
+                .collect::<Result<_>>()?;
+            Ok(SmtProof {
+                siblings,
+                value: self.get(key)?,
+            })
+        }
+
+        /// Creates a proof of the values of `keys`, which can be checked with
+        /// `smt::verify`.
+        pub fn prove(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+            let mut output = vec![];
+            for key in keys {
+                self.prove_key(key)?.encode_into(&mut output);
+            }
+            Ok(output)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::test_utils::*;
+
+        #[test]
+        fn apply_and_prove() {
+            let tmp_dir = tempdir::TempDir::new("merk_smt").unwrap();
+            let mut smt = SparseMerk::open(tmp_dir.path().join("a")).unwrap();
+            assert_eq!(smt.root_hash().unwrap(), NULL_HASH);
+
+            smt.apply(&make_batch_seq(0..100), &[(vec![1], Op::Put(vec![2]))])
+                .unwrap();
+            let root_hash = smt.root_hash().unwrap();
+            assert_ne!(root_hash, NULL_HASH);
+            assert_eq!(smt.get_aux(&[1]).unwrap(), Some(vec![2]));
+
+            // the root does not depend on how keys were batched
+            let mut other = SparseMerk::open(tmp_dir.path().join("b")).unwrap();
+            other.apply(&make_batch_seq(50..100), &[]).unwrap();
+            other.apply(&make_batch_seq(0..50), &[]).unwrap();
+            assert_eq!(other.root_hash().unwrap(), root_hash);
+
+            let present = 5u64.to_be_bytes().to_vec();
+            let absent = 500u64.to_be_bytes().to_vec();
+            let proof = smt.prove_key(&present).unwrap();
+            assert_eq!(proof.siblings.len(), DEPTH);
+            assert_eq!(
+                proof.verify(&present, &root_hash).unwrap(),
+                Some(&[123; 60][..])
+            );
+            assert!(proof.verify(&absent, &root_hash).is_err());
+
+            let keys = vec![present, absent];
+            let bytes = smt.prove(&keys).unwrap();
+            assert_eq!(
+                verify(&bytes, &keys, &root_hash).unwrap(),
+                vec![Some(vec![123; 60]), None]
+            );
+            assert!(verify(&bytes, &keys, &NULL_HASH).is_err());
+            assert!(verify(&bytes[1..], &keys, &root_hash).is_err());
+
+            assert!(matches!(
+                smt.apply(&make_del_batch_seq(100..101), &[]),
+                Err(Error::KeyDelete(_))
+            ));
+            smt.apply(&make_del_batch_seq(0..100), &[]).unwrap();
+            assert_eq!(smt.root_hash().unwrap(), NULL_HASH);
+            assert_eq!(smt.get(&keys[0]).unwrap(), None);
+            assert_eq!(smt.db.iterator(rocksdb::IteratorMode::Start).count(), 0);
+        }
+    }
+}
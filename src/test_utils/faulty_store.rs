@@ -0,0 +1,223 @@
+use crate::{Merk, Result};
+use std::env::temp_dir;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Faults to inject into the reads and writes of a `Merk`, shared between a
+/// `FaultyStore` and the store it wraps. Every setting can be changed at any
+/// time, including from other threads.
+///
+/// Reads are those of tree nodes, separated values and auxiliary data which
+/// go to the database; writes are the commits of `apply` and the other methods
+/// which write to the database.
+#[derive(Default)]
+pub struct Faults {
+    failing_reads: AtomicUsize,
+    failing_writes: AtomicUsize,
+    torn_writes: AtomicUsize,
+    latency_micros: AtomicU64,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+/// What should happen to a write, as decided by `Faults::write`.
+pub(crate) enum WriteFault {
+    None,
+    /// Fail without writing anything.
+    Fail,
+    /// Write only the first part of the write, then fail.
+    Tear,
+}
+
+fn injected(kind: &str) -> crate::Error {
+    io::Error::other(format!("Injected {} fault", kind)).into()
+}
+
+fn take_one(counter: &AtomicUsize) -> bool {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+impl Faults {
+    /// Makes the next `count` reads fail with an I/O error.
+    pub fn fail_reads(&self, count: usize) {
+        self.failing_reads.store(count, Ordering::SeqCst);
+    }
+
+    /// Makes the next `count` writes fail with an I/O error, without writing
+    /// anything.
+    pub fn fail_writes(&self, count: usize) {
+        self.failing_writes.store(count, Ordering::SeqCst);
+    }
+
+    /// Makes the next `count` commits write only the first half of their
+    /// changes and then fail with an I/O error, as a torn write would. The
+    /// store is corrupted afterwards; this is for testing that corruption is
+    /// detected.
+    pub fn tear_writes(&self, count: usize) {
+        self.torn_writes.store(count, Ordering::SeqCst);
+    }
+
+    /// Delays every read and write by `latency`.
+    pub fn set_latency(&self, latency: Duration) {
+        self.latency_micros
+            .store(latency.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Stops injecting faults and latency.
+    pub fn clear(&self) {
+        self.fail_reads(0);
+        self.fail_writes(0);
+        self.tear_writes(0);
+        self.set_latency(Duration::ZERO);
+    }
+
+    /// Returns the number of reads so far, including failed ones.
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of writes so far, including failed ones.
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    fn delay(&self) {
+        let latency = self.latency_micros.load(Ordering::SeqCst);
+        if latency > 0 {
+            thread::sleep(Duration::from_micros(latency));
+        }
+    }
+
+    /// Called before every read.
+    pub(crate) fn read(&self) -> Result<()> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.delay();
+        if take_one(&self.failing_reads) {
+            return Err(injected("read"));
+        }
+        Ok(())
+    }
+
+    /// Called before every write, returning the fault to inject. `tearable`
+    /// is whether the caller can write part of the write.
+    pub(crate) fn write(&self, tearable: bool) -> WriteFault {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.delay();
+        if take_one(&self.failing_writes) {
+            WriteFault::Fail
+        } else if tearable && take_one(&self.torn_writes) {
+            WriteFault::Tear
+        } else {
+            WriteFault::None
+        }
+    }
+
+    pub(crate) fn write_error() -> crate::Error {
+        injected("write")
+    }
+}
+
+/// Wraps a temporary `Merk` whose reads and writes fail or are delayed on
+/// demand, see `Faults`, so the error handling around store operations can be
+/// tested. Only the top level of the tree is kept in memory, so reads go to
+/// the database.
+///
+/// The store is deleted from disk once it goes out of scope.
+pub struct FaultyStore {
+    inner: Option<Merk>,
+    faults: Arc<Faults>,
+}
+
+impl FaultyStore {
+    /// Opens a `FaultyStore` at an autogenerated, temporary file path.
+    pub fn new() -> Result<FaultyStore> {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let mut path = temp_dir();
+        path.push(format!("merk-faulty-{time}"));
+
+        let mut merk = Merk::open_opt(path, Merk::default_db_opts(), 1)?;
+        let faults = Arc::new(Faults::default());
+        merk.faults = Some(faults.clone());
+        Ok(FaultyStore {
+            inner: Some(merk),
+            faults,
+        })
+    }
+
+    /// Returns the faults injected into the store.
+    pub fn faults(&self) -> &Faults {
+        &self.faults
+    }
+}
+
+impl Drop for FaultyStore {
+    fn drop(&mut self) {
+        self.inner
+            .take()
+            .unwrap()
+            .destroy()
+            .expect("failed to delete db");
+    }
+}
+
+impl Deref for FaultyStore {
+    type Target = Merk;
+
+    fn deref(&self) -> &Merk {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for FaultyStore {
+    fn deref_mut(&mut self) -> &mut Merk {
+        self.inner.as_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FaultyStore;
+    use crate::test_utils::make_batch_seq;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn injects_faults() {
+        let mut merk = FaultyStore::new().unwrap();
+        merk.apply(&make_batch_seq(0..100), &[]).unwrap();
+        let key = make_batch_seq(5..6).remove(0).0;
+        let root_hash = merk.root_hash();
+
+        merk.faults().fail_reads(1);
+        assert!(merk.get(&key).is_err());
+        assert!(merk.get(&key).unwrap().is_some());
+        merk.faults().fail_reads(1);
+        assert!(merk.get_flags(&key).is_err());
+
+        merk.faults().fail_writes(1);
+        assert!(merk.apply(&make_batch_seq(100..110), &[]).is_err());
+        merk.faults().clear();
+
+        let reads = merk.faults().reads();
+        merk.faults().set_latency(Duration::from_millis(20));
+        let start = Instant::now();
+        merk.get(&key).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(merk.faults().reads() > reads);
+        merk.faults().clear();
+
+        // a torn write leaves the store inconsistent with its root
+        merk.faults().tear_writes(1);
+        assert!(merk.apply(&make_batch_seq(200..300), &[]).is_err());
+        assert!(merk.faults().writes() >= 3);
+        assert_ne!(merk.root_hash(), root_hash);
+    }
+}
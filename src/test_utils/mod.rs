@@ -1,4 +1,6 @@
 mod crash_merk;
+#[cfg(any(test, feature = "faults"))]
+mod faulty_store;
 mod temp_merk;
 
 use crate::tree::{Batch, BatchEntry, NoopCommit, Op, PanicSource, Tree, Walker};
@@ -8,6 +10,10 @@ use std::convert::TryInto;
 use std::ops::Range;
 
 pub use crash_merk::CrashMerk;
+#[cfg(any(test, feature = "faults"))]
+pub(crate) use faulty_store::WriteFault;
+#[cfg(any(test, feature = "faults"))]
+pub use faulty_store::{Faults, FaultyStore};
 pub use temp_merk::TempMerk;
 
 pub fn assert_tree_invariants(tree: &Tree) {
@@ -1,41 +1,108 @@
 use crate::{Merk, Result};
 use std::env::temp_dir;
 use std::ops::{Deref, DerefMut};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 /// Wraps a Merk instance and deletes it from disk it once it goes out of scope.
+///
+/// Before deleting the store, checks it with `Merk::verify_integrity` and
+/// panics if it is corrupt, unless the check is turned off with
+/// `skip_integrity_check` or the thread is already panicking.
 pub struct TempMerk {
     inner: Option<Merk>,
+    path: PathBuf,
+    db_opts: rocksdb::Options,
+    levels: u8,
+    check_integrity: bool,
+}
+
+fn temp_path() -> PathBuf {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let mut path = temp_dir();
+    path.push(format!("merk-temp–{time}"));
+    path
 }
 
 impl TempMerk {
     /// Opens a `TempMerk` at the given file path, creating a new one if it does
     /// not exist.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<TempMerk> {
-        let inner = Some(Merk::open(path)?);
-        Ok(TempMerk { inner })
+        TempMerk::open_opt(path, Merk::default_db_opts(), 100)
+    }
+
+    /// Opens a `TempMerk` at the given file path with the given database
+    /// options, keeping up to `levels` levels of the tree in memory (see
+    /// `Merk::open_opt`).
+    pub fn open_opt<P: AsRef<Path>>(
+        path: P,
+        db_opts: rocksdb::Options,
+        levels: u8,
+    ) -> Result<TempMerk> {
+        let path = path.as_ref().to_path_buf();
+        let inner = Some(Merk::open_opt(&path, db_opts.clone(), levels)?);
+        Ok(TempMerk {
+            inner,
+            path,
+            db_opts,
+            levels,
+            check_integrity: true,
+        })
     }
 
     /// Opens a `TempMerk` at an autogenerated, temporary file path.
     pub fn new() -> Result<TempMerk> {
-        let time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        let mut path = temp_dir();
-        path.push(format!("merk-temp–{time}"));
-        TempMerk::open(path)
+        TempMerk::open(temp_path())
+    }
+
+    /// Opens a `TempMerk` at an autogenerated, temporary file path with the
+    /// given database options, e.g. to size its block cache, keeping up to
+    /// `levels` levels of the tree in memory.
+    pub fn with_opts(db_opts: rocksdb::Options, levels: u8) -> Result<TempMerk> {
+        TempMerk::open_opt(temp_path(), db_opts, levels)
+    }
+
+    /// Returns the path of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Closes the store and opens it again with the same options, to test
+    /// that its data persists. Settings made through the `Merk`'s setters
+    /// (e.g. `set_sync_mode`) are not kept.
+    pub fn reopen(&mut self) -> Result<()> {
+        drop(self.inner.take());
+        self.inner = Some(Merk::open_opt(
+            &self.path,
+            self.db_opts.clone(),
+            self.levels,
+        )?);
+        Ok(())
+    }
+
+    /// Turns off the integrity check when the store is dropped, for tests
+    /// which corrupt it on purpose.
+    pub fn skip_integrity_check(&mut self) {
+        self.check_integrity = false;
     }
 }
 
 impl Drop for TempMerk {
     fn drop(&mut self) {
-        self.inner
-            .take()
-            .unwrap()
-            .destroy()
-            .expect("failed to delete db");
+        let merk = match self.inner.take() {
+            Some(merk) => merk,
+            // reopening failed, so there is no store to check
+            None => return,
+        };
+        if self.check_integrity && !std::thread::panicking() {
+            if let Err(err) = merk.verify_integrity() {
+                panic!("store at {} is corrupt: {}", self.path.display(), err);
+            }
+        }
+        merk.destroy().expect("failed to delete db");
     }
 }
 
@@ -52,3 +119,27 @@ impl DerefMut for TempMerk {
         self.inner.as_mut().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::TempMerk;
+    use crate::test_utils::make_batch_seq;
+    use crate::Merk;
+
+    #[test]
+    fn reopen() {
+        let mut merk = TempMerk::with_opts(Merk::default_db_opts(), 1).unwrap();
+        merk.apply(
+            &make_batch_seq(0..100),
+            &[(vec![1], crate::Op::Put(vec![2]))],
+        )
+        .unwrap();
+        let root_hash = merk.root_hash();
+
+        merk.reopen().unwrap();
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get_aux(&[1]).unwrap(), Some(vec![2]));
+        assert_eq!(merk.get_max_levels_in_memory(), 1);
+        assert!(merk.path().exists());
+    }
+}
@@ -8,6 +8,13 @@ pub trait Commit {
     /// backing store or cache.
     fn write(&mut self, tree: &Tree) -> Result<()>;
 
+    /// Returns `true` if `write` stores the node's value apart from the node,
+    /// so the node remembers where its value is stored while it stays in
+    /// memory.
+    fn separate_value(&self, _tree: &Tree) -> bool {
+        false
+    }
+
     /// Called once per node after writing a node and its children. The returned
     /// tuple specifies whether or not to prune the left and right child nodes,
     /// respectively. For example, returning `(true, true)` will prune both
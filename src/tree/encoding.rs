@@ -1,39 +1,520 @@
-use super::Tree;
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
+use std::convert::TryInto;
+
+use super::{Link, Tree};
+use crate::error::{Error, Result};
 use ed::{Decode, Encode};
 
+/// The first byte of a node encoding which is followed by the encodings of
+/// some of its children. Plain node encodings start with the `Option` tag of
+/// the left link, which is 0 or 1.
+pub(super) const INLINED_TAG: u8 = 2;
+
+/// The first byte of a node encoding which leaves out the node's value.
+pub(super) const SEPARATED_TAG: u8 = 3;
+
+/// The first byte of a compact node encoding, with the presence of the left
+/// and right links in the two lowest bits (so compact encodings start with
+/// 4 to 7).
+pub(super) const COMPACT_TAG: u8 = 4;
+
+/// The first byte of a record which starts with a CRC32C checksum, see
+/// `RecordFormat::checksum`.
+pub(super) const CHECKSUM_TAG: u8 = 8;
+
+/// The first byte of a record (after any checksum) of a node with flags,
+/// followed by the flags byte. Nodes without flags are encoded without it, so
+/// their records are the same as before flags existed.
+pub(super) const FLAGS_TAG: u8 = 9;
+
+/// How a node is encoded into the record stored under its key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordFormat {
+    /// Children which are in memory and encode to at most this many bytes are
+    /// encoded along with the node, see `Tree::encode_inlined_into`. 0 means
+    /// no children are inlined.
+    pub inline_child_length: usize,
+    /// Leaves out the node's value, see `Tree::encode_separated_into`.
+    pub separate_value: bool,
+    /// Uses the compact node encoding, see `Tree::encode_compact_into`.
+    pub compact: bool,
+    /// Starts the record with a CRC32C checksum of the node's key and the rest
+    /// of the record, which is checked whenever the record is decoded.
+    pub checksum: bool,
+}
+
+/// The CRC32C (Castagnoli) lookup table.
+// evaluated at compile time, where indexing out of bounds fails the build
+#[allow(clippy::indexing_slicing)]
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC32C checksum of the concatenation of `parts`.
+// table indices are masked to 8 bits
+#[allow(clippy::indexing_slicing)]
+fn crc32c(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for &byte in part.iter() {
+            crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
+
+/// Checks and strips the checksum of a record which starts with one, see
+/// `RecordFormat::checksum`. Records without a checksum are returned as they
+/// are.
+pub(super) fn strip_checksum<'a>(key: &[u8], input: &'a [u8]) -> Result<&'a [u8]> {
+    let mut rest = match input.split_first() {
+        Some((&CHECKSUM_TAG, rest)) => rest,
+        _ => return Ok(input),
+    };
+    let expected = take(&mut rest, 4)?;
+    if crc32c(&[key, rest]).to_be_bytes() != expected {
+        return Err(Error::ChecksumMismatch(key.to_vec()));
+    }
+    Ok(rest)
+}
+
+/// Strips the flags of a record which starts with them (after any checksum),
+/// returning the flags, or 0 for records without them.
+pub(super) fn strip_flags(input: &[u8]) -> Result<(u8, &[u8])> {
+    match input.split_first() {
+        Some((&FLAGS_TAG, mut rest)) => {
+            let [flags] = take_array(&mut rest)?;
+            Ok((flags, rest))
+        }
+        _ => Ok((0, input)),
+    }
+}
+
 impl Tree {
     #[inline]
     pub fn encode(&self) -> Vec<u8> {
-        // operation is infallible so it's ok to unwrap
-        Encode::encode(self).unwrap()
+        let mut bytes = Vec::with_capacity(self.encoding_length());
+        self.encode_into(&mut bytes);
+        bytes
     }
 
+    /// Encodes the node, preceded by its flags if it has any.
     #[inline]
+    #[allow(clippy::unwrap_used)]
     pub fn encode_into(&self, dest: &mut Vec<u8>) {
+        if self.flags() != 0 {
+            dest.extend_from_slice(&[FLAGS_TAG, self.flags()]);
+        }
         // operation is infallible so it's ok to unwrap
         Encode::encode_into(self, dest).unwrap()
     }
 
     #[inline]
+    #[allow(clippy::unwrap_used)]
     pub fn encoding_length(&self) -> usize {
+        let flags_length = if self.flags() != 0 { 2 } else { 0 };
         // operation is infallible so it's ok to unwrap
-        Encode::encoding_length(self).unwrap()
+        flags_length + Encode::encoding_length(self).unwrap()
     }
 
-    #[inline]
-    pub fn decode_into(&mut self, key: Vec<u8>, input: &[u8]) {
+    /// Encodes the node like `encode_into`, but with varint lengths and with
+    /// the keys of its children stored as a suffix of the key prefix they
+    /// share with the node. This saves a few bytes per node, which adds up in
+    /// stores of many small values.
+    ///
+    /// The encoding needs the node's key to be decoded, which `decode` is
+    /// always given.
+    pub fn encode_compact_into(&self, dest: &mut Vec<u8>) {
+        self.encode_node(true, true, dest)
+    }
+
+    /// Encodes the node like `encode_into`, followed by the encodings of its
+    /// children which are in memory and encode to at most `max_child_length`
+    /// bytes. When the node is decoded, those children are loaded along with
+    /// it rather than having to be fetched separately.
+    ///
+    /// The children must still be stored under their own keys, since they are
+    /// only inlined for as long as the parent node is not rewritten.
+    pub fn encode_inlined_into(&self, max_child_length: usize, dest: &mut Vec<u8>) {
+        let format = RecordFormat {
+            inline_child_length: max_child_length,
+            ..Default::default()
+        };
+        self.encode_record_into(format, dest)
+    }
+
+    /// Encodes the node like `encode_inlined_into`, but without its value. The
+    /// value has to be stored elsewhere and passed back in when decoding with
+    /// `decode_with`.
+    pub fn encode_separated_into(&self, max_child_length: usize, dest: &mut Vec<u8>) {
+        let format = RecordFormat {
+            inline_child_length: max_child_length,
+            separate_value: true,
+            ..Default::default()
+        };
+        self.encode_record_into(format, dest)
+    }
+
+    /// Encodes the node in the given format. Records in any format can be
+    /// decoded with `decode_with`.
+    pub fn encode_record_into(&self, format: RecordFormat, dest: &mut Vec<u8>) {
+        if format.checksum {
+            let mut record = vec![];
+            self.encode_record_into(
+                RecordFormat {
+                    checksum: false,
+                    ..format
+                },
+                &mut record,
+            );
+            let crc = crc32c(&[&self.key(), &record]);
+            dest.push(CHECKSUM_TAG);
+            dest.extend_from_slice(&crc.to_be_bytes());
+            dest.extend_from_slice(&record);
+            return;
+        }
+
+        if self.flags() != 0 {
+            dest.extend_from_slice(&[FLAGS_TAG, self.flags()]);
+        }
+
+        // a value which is not loaded can only be left out of the record
+        let format = RecordFormat {
+            separate_value: format.separate_value || !self.value_loaded(),
+            ..format
+        };
+        if format.separate_value {
+            dest.push(SEPARATED_TAG);
+        }
+
+        let inlined: Vec<_> = [true, false]
+            .iter()
+            .filter_map(|&left| {
+                let child = self.link(left)?.tree()?;
+                let length = child.encoding_length();
+                // inlined children are encoded without their flags
+                (child.value_loaded() && child.flags() == 0 && length <= format.inline_child_length)
+                    .then_some((left, child))
+            })
+            .collect();
+
+        if inlined.is_empty() {
+            return self.encode_node(!format.separate_value, format.compact, dest);
+        }
+
+        dest.push(INLINED_TAG);
+        write_prefixed(dest, |dest| {
+            self.encode_node(!format.separate_value, format.compact, dest)
+        });
+        for (left, child) in inlined {
+            dest.push(left as u8);
+            write_prefixed(dest, |dest| child.encode_node(true, format.compact, dest));
+        }
+    }
+
+    #[allow(clippy::unwrap_used)]
+    fn encode_node(&self, with_value: bool, compact: bool, dest: &mut Vec<u8>) {
+        if compact {
+            return self.encode_compact_node(with_value, dest);
+        }
+        if with_value {
+            // operation is infallible so it's ok to unwrap
+            return Encode::encode_into(self, dest).unwrap();
+        }
+
+        // the same as the derived encoding, with an empty value
         // operation is infallible so it's ok to unwrap
-        Decode::decode_into(self, input).unwrap();
-        self.inner.kv.key = key;
+        Encode::encode_into(&self.inner.left, dest).unwrap();
+        Encode::encode_into(&self.inner.right, dest).unwrap();
+        dest.extend_from_slice(self.kv_hash());
+    }
+
+    fn encode_compact_node(&self, with_value: bool, dest: &mut Vec<u8>) {
+        let left = self.inner.left.is_some() as u8;
+        let right = self.inner.right.is_some() as u8;
+        dest.push(COMPACT_TAG | left | right << 1);
+
+        for link in [self.link(true), self.link(false)].iter().flatten() {
+            let key = link.key();
+            let shared = self
+                .interned_key()
+                .bytes()
+                .zip(key.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            write_varint(dest, shared);
+            write_varint(dest, key.len() - shared);
+            dest.extend_from_slice(key.get(shared..).unwrap_or_default());
+            dest.extend_from_slice(link.hash());
+            let (left_height, right_height) = link.child_heights();
+            dest.extend_from_slice(&[left_height, right_height]);
+        }
+
+        dest.extend_from_slice(self.kv_hash());
+        if with_value {
+            dest.extend_from_slice(self.value());
+        }
     }
 
+    /// Decodes a node from `input` into `self`, reusing its allocations.
+    /// Returns an error if `input` is not a valid node encoding, or if it was
+    /// encoded without its value.
     #[inline]
-    pub fn decode(key: Vec<u8>, input: &[u8]) -> Tree {
-        // operation is infallible so it's ok to unwrap
-        let mut tree: Tree = Decode::decode(input).unwrap();
-        tree.inner.kv.key = key;
-        tree
+    pub fn decode_into(&mut self, key: Vec<u8>, input: &[u8]) -> Result<()> {
+        self.decode_into_with(key, input, value_not_given)
+    }
+
+    /// Decodes a node from `input` into `self` like `decode_into`. If the node
+    /// was encoded without its value, `load_value` is called with the node's
+    /// key to get it.
+    pub fn decode_into_with<F>(&mut self, key: Vec<u8>, input: &[u8], load_value: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let (flags, input) = strip_flags(strip_checksum(&key, input)?)?;
+        self.inner.kv.lazy = false;
+        let input = match input.split_first() {
+            Some((&SEPARATED_TAG, rest)) => rest,
+            _ => {
+                self.decode_record(key, input)?;
+                self.inner.kv.flags = flags;
+                return Ok(());
+            }
+        };
+
+        self.decode_record(key, input)?;
+        self.inner.kv.flags = flags;
+        self.inner.kv.separated = true;
+        self.inner.kv.value = load_value(&self.key())?;
+        Ok(())
+    }
+
+    /// Decodes a node from `input` like `decode`, but if the node was encoded
+    /// without its value, leaves the value unloaded instead of loading it, so
+    /// walks which pass through the node do not read or hold the value. It
+    /// can be loaded later with `load_value_with`.
+    ///
+    /// A node whose value is not loaded can still be committed, since its
+    /// value is already stored apart from it, but it is always written
+    /// without its value, and is not inlined into its parent.
+    pub fn decode_lazy(key: Vec<u8>, input: &[u8]) -> Result<Tree> {
+        let mut lazy = false;
+        let mut tree = Tree::decode_with(key, input, |_| {
+            lazy = true;
+            Ok(vec![])
+        })?;
+        tree.inner.kv.lazy = lazy;
+        Ok(tree)
+    }
+
+    /// Decodes a node from `input`. Returns an error if `input` is not a valid
+    /// node encoding, or if it was encoded without its value.
+    #[inline]
+    pub fn decode(key: Vec<u8>, input: &[u8]) -> Result<Tree> {
+        Tree::decode_with(key, input, value_not_given)
+    }
+
+    /// Decodes a node from `input` like `decode`. If the node was encoded
+    /// without its value, `load_value` is called with the node's key to get
+    /// it.
+    pub fn decode_with<F>(key: Vec<u8>, input: &[u8], load_value: F) -> Result<Tree>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let input = strip_checksum(&key, input)?;
+        if matches!(input.first(), Some(0) | Some(1)) {
+            let mut tree: Tree = Decode::decode(input)?;
+            tree.inner.kv.key = super::key::intern(key);
+            return Ok(tree);
+        }
+
+        let mut tree = super::pool::take()
+            .unwrap_or_else(|| Tree::from_fields(vec![], vec![], Default::default(), None, None));
+        tree.decode_into_with(key, input, load_value)?;
+        Ok(tree)
+    }
+
+    /// Decodes a node which may be followed by inlined children.
+    fn decode_record(&mut self, key: Vec<u8>, input: &[u8]) -> Result<()> {
+        let mut input = match input.split_first() {
+            Some((&INLINED_TAG, rest)) => rest,
+            _ => return self.decode_node(key, input),
+        };
+        self.decode_node(key, read_slice(&mut input)?)?;
+        while let Some((&side, rest)) = input.split_first() {
+            input = rest;
+            let bytes = read_slice(&mut input)?;
+            self.attach_inlined(side != 0, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes a plain or compact node encoding.
+    fn decode_node(&mut self, key: Vec<u8>, input: &[u8]) -> Result<()> {
+        match input.first() {
+            Some(tag) if tag & !3 == COMPACT_TAG => self.decode_compact_node(key, input),
+            _ => {
+                Decode::decode_into(self, input)?;
+                self.inner.kv.key = super::key::intern(key);
+                Ok(())
+            }
+        }
+    }
+
+    fn decode_compact_node(&mut self, key: Vec<u8>, input: &[u8]) -> Result<()> {
+        let (&tag, mut input) = input
+            .split_first()
+            .ok_or_else(|| Error::Decode("Empty compact node encoding".into()))?;
+
+        for (left, present) in [(true, tag & 1 != 0), (false, tag & 2 != 0)] {
+            let link = if present {
+                let shared = read_varint(&mut input)?;
+                let suffix_length = read_varint(&mut input)?;
+                let mut link_key = key
+                    .get(..shared)
+                    .ok_or_else(|| Error::Decode("Invalid shared key prefix length".into()))?
+                    .to_vec();
+                link_key.extend_from_slice(take(&mut input, suffix_length)?);
+                let hash = read_hash(&mut input)?;
+                let [left_height, right_height] = take_array(&mut input)?;
+                Some(Link::Reference {
+                    key: super::key::intern(link_key),
+                    hash,
+                    child_heights: (left_height, right_height),
+                })
+            } else {
+                None
+            };
+            *self.slot_mut(left) = link;
+        }
+
+        self.inner.kv.hash = read_hash(&mut input)?;
+        self.inner.kv.key = super::key::intern(key);
+        self.inner.kv.value.clear();
+        self.inner.kv.value.extend_from_slice(input);
+        self.inner.kv.stored = true;
+        self.inner.kv.separated = false;
+        Ok(())
     }
+
+    /// Replaces the reference to the child on the given side with the child
+    /// decoded from its inlined encoding.
+    fn attach_inlined(&mut self, left: bool, bytes: &[u8]) -> Result<()> {
+        let slot = self.slot_mut(left);
+        let (key, hash, child_heights) = match slot.take() {
+            Some(Link::Reference {
+                key,
+                hash,
+                child_heights,
+            }) => (key, hash, child_heights),
+            _ => {
+                return Err(Error::Decode(
+                    "Inlined child does not match a child link".into(),
+                ))
+            }
+        };
+
+        *slot = Some(Link::Loaded {
+            tree: Tree::decode(key.into_vec(), bytes)?,
+            hash,
+            child_heights,
+        });
+        Ok(())
+    }
+}
+
+fn value_not_given(key: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Decode(format!(
+        "Value of node {:?} is stored separately",
+        key
+    )))
+}
+
+/// Writes the output of `encode` prefixed with its length.
+fn write_prefixed(dest: &mut Vec<u8>, encode: impl FnOnce(&mut Vec<u8>)) {
+    let start = dest.len();
+    dest.extend_from_slice(&[0; 4]);
+    encode(dest);
+    let length = (dest.len() - start - 4) as u32;
+    if let Some(prefix) = dest.get_mut(start..start + 4) {
+        prefix.copy_from_slice(&length.to_be_bytes());
+    }
+}
+
+/// Reads a length-prefixed slice from the front of `input`.
+pub(super) fn read_slice<'a>(input: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let length = u32::from_be_bytes(take_array(input)?) as usize;
+    take(input, length)
+}
+
+/// Splits `length` bytes off the front of `input`.
+pub(super) fn take<'a>(input: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if input.len() < length {
+        return Err(Error::Decode("Truncated node encoding".into()));
+    }
+    let (bytes, rest) = input.split_at(length);
+    *input = rest;
+    Ok(bytes)
+}
+
+/// Splits `N` bytes off the front of `input`.
+pub(super) fn take_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N]> {
+    take(input, N)?
+        .try_into()
+        .map_err(|_| Error::Decode("Truncated node encoding".into()))
+}
+
+fn read_hash(input: &mut &[u8]) -> Result<[u8; 32]> {
+    take_array(input)
+}
+
+/// Writes an unsigned LEB128 varint.
+fn write_varint(dest: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        dest.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    dest.push(value as u8);
+}
+
+/// Reads an unsigned LEB128 varint from the front of `input`.
+pub(super) fn read_varint(input: &mut &[u8]) -> Result<usize> {
+    let mut value: usize = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let [byte] = take_array(input)?;
+        value |= ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .ok_or_else(|| Error::Decode("Varint overflow".into()))?;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::Decode("Varint overflow".into()))
 }
 
 #[cfg(test)]
@@ -42,6 +523,38 @@ mod tests {
     use super::*;
     use crate::error::Result;
 
+    #[test]
+    fn decode_lazy() {
+        let tree = Tree::from_fields(vec![0], vec![1, 2, 3], [55; 32], None, None);
+        let mut bytes = vec![];
+        let format = RecordFormat {
+            separate_value: true,
+            ..Default::default()
+        };
+        tree.encode_record_into(format, &mut bytes);
+
+        let mut lazy = Tree::decode_lazy(vec![0], &bytes).unwrap();
+        assert!(!lazy.value_loaded());
+        assert_eq!(lazy.hash(), tree.hash());
+        assert!(Tree::decode(vec![0], &bytes).is_err());
+
+        // a node which is not loaded is re-encoded without its value
+        let mut reencoded = vec![];
+        lazy.encode_record_into(Default::default(), &mut reencoded);
+        assert_eq!(reencoded, bytes);
+
+        lazy.load_value_with(|key| {
+            assert_eq!(key, [0]);
+            Ok(vec![1, 2, 3])
+        })
+        .unwrap();
+        assert!(lazy.value_loaded());
+        assert_eq!(lazy.value(), &[1, 2, 3]);
+
+        let inline = Tree::decode_lazy(vec![0], &tree.encode()).unwrap();
+        assert!(inline.value_loaded());
+    }
+
     #[test]
     fn encode_leaf_tree() {
         let tree = Tree::from_fields(vec![0], vec![1], [55; 32], None, None);
@@ -131,7 +644,7 @@ mod tests {
             Some(Link::Reference {
                 hash: [66; 32],
                 child_heights: (123, 124),
-                key: vec![2],
+                key: vec![2].into(),
             }),
             None,
         );
@@ -153,8 +666,8 @@ mod tests {
             0, 0, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
             1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
         ];
-        let tree = Tree::decode(vec![0], bytes.as_slice());
-        assert_eq!(tree.key(), &[0]);
+        let tree = Tree::decode(vec![0], bytes.as_slice()).unwrap();
+        assert_eq!(tree.key().as_ref(), &[0]);
         assert_eq!(tree.value(), &[1]);
     }
 
@@ -166,8 +679,8 @@ mod tests {
             55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55,
             55, 55, 55, 55, 55, 1,
         ];
-        let tree = Tree::decode(vec![0], bytes.as_slice());
-        assert_eq!(tree.key(), &[0]);
+        let tree = Tree::decode(vec![0], bytes.as_slice()).unwrap();
+        assert_eq!(tree.key().as_ref(), &[0]);
         assert_eq!(tree.value(), &[1]);
         if let Some(Link::Reference {
             key,
@@ -175,11 +688,201 @@ mod tests {
             hash,
         }) = tree.link(true)
         {
-            assert_eq!(*key, [2]);
+            assert_eq!(key.as_bytes().as_ref(), [2]);
             assert_eq!(*child_heights, (123_u8, 124_u8));
             assert_eq!(*hash, [66_u8; 32]);
         } else {
             panic!("Expected Link::Reference");
         }
     }
+
+    #[test]
+    fn inlined_children() {
+        let mut tree = Tree::new(vec![1], vec![2])
+            .unwrap()
+            .attach(true, Some(Tree::new(vec![0], vec![3; 10]).unwrap()))
+            .attach(false, Some(Tree::new(vec![2], vec![4; 100]).unwrap()));
+        tree.commit(&mut crate::tree::NoopCommit {}).unwrap();
+
+        // nothing is small enough to inline
+        let mut bytes = vec![];
+        tree.encode_inlined_into(10, &mut bytes);
+        assert_eq!(bytes, tree.encode());
+
+        let mut bytes = vec![];
+        tree.encode_inlined_into(50, &mut bytes);
+        assert_eq!(bytes[0], INLINED_TAG);
+
+        let decoded = Tree::decode(vec![1], bytes.as_slice()).unwrap();
+        assert_eq!(decoded.hash(), tree.hash());
+        assert_eq!(decoded.value(), &[2]);
+        match decoded.link(true) {
+            Some(Link::Loaded { tree, .. }) => {
+                assert_eq!(tree.key().as_ref(), &[0]);
+                assert_eq!(tree.value(), &[3; 10]);
+            }
+            _ => panic!("Expected left child to be loaded"),
+        }
+        assert!(decoded.link(false).unwrap().is_reference());
+
+        assert!(Tree::decode(vec![1], &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn separated_value() {
+        let mut tree = Tree::new(vec![1], vec![2; 100])
+            .unwrap()
+            .attach(true, Some(Tree::new(vec![0], vec![3]).unwrap()));
+        tree.commit(&mut crate::tree::NoopCommit {}).unwrap();
+
+        for max_child_length in [0, 50] {
+            let mut bytes = vec![];
+            tree.encode_separated_into(max_child_length, &mut bytes);
+            assert_eq!(bytes[0], SEPARATED_TAG);
+            assert!(bytes.len() < tree.encoding_length());
+
+            assert!(Tree::decode(vec![1], bytes.as_slice()).is_err());
+            let decoded = Tree::decode_with(vec![1], bytes.as_slice(), |key| {
+                assert_eq!(key, &[1]);
+                Ok(vec![2; 100])
+            })
+            .unwrap();
+            assert_eq!(decoded.hash(), tree.hash());
+            assert_eq!(decoded.value(), &[2; 100]);
+            assert_eq!(
+                decoded.link(true).unwrap().tree().is_some(),
+                max_child_length > 0
+            );
+        }
+    }
+
+    #[test]
+    fn compact_encoding() {
+        let mut tree = Tree::new(b"account/10".to_vec(), vec![2])
+            .unwrap()
+            .attach(
+                true,
+                Some(Tree::new(b"account/09".to_vec(), vec![3; 10]).unwrap()),
+            )
+            .attach(false, Some(Tree::new(b"b".to_vec(), vec![4]).unwrap()));
+        tree.commit(&mut crate::tree::NoopCommit {}).unwrap();
+
+        let mut bytes = vec![];
+        tree.encode_compact_into(&mut bytes);
+        assert_eq!(bytes[0], COMPACT_TAG | 3);
+        // the left child's key shares its first 8 bytes with the parent's
+        assert_eq!(&bytes[1..5], &[8, 2, b'0', b'9']);
+        assert_eq!(bytes.len(), tree.encoding_length() - 7);
+
+        let decoded = Tree::decode(b"account/10".to_vec(), bytes.as_slice()).unwrap();
+        assert_eq!(decoded.hash(), tree.hash());
+        assert_eq!(decoded.value(), &[2]);
+        assert_eq!(decoded.link(true).unwrap().key().as_ref(), b"account/09");
+        assert_eq!(decoded.link(false).unwrap().key().as_ref(), b"b");
+        assert_eq!(decoded.link(true).unwrap().child_heights(), (0, 0));
+
+        for len in 0..bytes.len() - 1 {
+            assert!(Tree::decode(b"account/10".to_vec(), &bytes[..len]).is_err());
+        }
+        // the shared prefix can not be longer than the node's key
+        assert!(Tree::decode(b"a".to_vec(), bytes.as_slice()).is_err());
+
+        let leaf = Tree::new(vec![1], vec![2]).unwrap();
+        let mut bytes = vec![];
+        leaf.encode_compact_into(&mut bytes);
+        assert_eq!(bytes.len(), leaf.encoding_length() - 1);
+    }
+
+    #[test]
+    fn compact_record_formats() {
+        let mut tree = Tree::new(vec![1], vec![2; 100])
+            .unwrap()
+            .attach(true, Some(Tree::new(vec![0], vec![3]).unwrap()));
+        tree.commit(&mut crate::tree::NoopCommit {}).unwrap();
+
+        for separate_value in [false, true] {
+            let format = RecordFormat {
+                inline_child_length: 50,
+                separate_value,
+                compact: true,
+                ..Default::default()
+            };
+            let mut bytes = vec![];
+            tree.encode_record_into(format, &mut bytes);
+
+            let decoded =
+                Tree::decode_with(vec![1], bytes.as_slice(), |_| Ok(vec![2; 100])).unwrap();
+            assert_eq!(decoded.hash(), tree.hash());
+            assert_eq!(decoded.value(), &[2; 100]);
+            let child = decoded.link(true).unwrap().tree().unwrap();
+            assert_eq!(child.value(), &[3]);
+        }
+    }
+
+    #[test]
+    fn checksummed_records() {
+        assert_eq!(crc32c(&[b"123456789"]), 0xe306_9283);
+        assert_eq!(crc32c(&[b"1234", b"56789"]), 0xe306_9283);
+
+        let mut tree = Tree::new(vec![1], vec![2; 10])
+            .unwrap()
+            .attach(true, Some(Tree::new(vec![0], vec![3]).unwrap()));
+        tree.commit(&mut crate::tree::NoopCommit {}).unwrap();
+        let format = RecordFormat {
+            inline_child_length: 100,
+            checksum: true,
+            ..Default::default()
+        };
+        let mut bytes = vec![];
+        tree.encode_record_into(format, &mut bytes);
+        assert_eq!(bytes[0], CHECKSUM_TAG);
+
+        let decoded = Tree::decode(vec![1], bytes.as_slice()).unwrap();
+        assert_eq!(decoded.hash(), tree.hash());
+
+        // a flipped bit anywhere in the record, or the record under another
+        // key, fails the check
+        for i in 1..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 1;
+            assert!(matches!(
+                Tree::decode(vec![1], corrupt.as_slice()),
+                Err(Error::ChecksumMismatch(key)) if key == vec![1]
+            ));
+        }
+        assert!(matches!(
+            Tree::decode(vec![5], bytes.as_slice()),
+            Err(Error::ChecksumMismatch(_))
+        ));
+        assert!(Tree::decode(vec![1], &bytes[..3]).is_err());
+    }
+
+    #[test]
+    fn varints() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as usize, usize::MAX] {
+            let mut bytes = vec![];
+            write_varint(&mut bytes, value);
+            let mut input = bytes.as_slice();
+            assert_eq!(read_varint(&mut input).unwrap(), value);
+            assert!(input.is_empty());
+        }
+        assert_eq!(
+            {
+                let mut bytes = vec![];
+                write_varint(&mut bytes, 300);
+                bytes
+            },
+            vec![0xac, 0x02]
+        );
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+    }
+
+    #[test]
+    fn decode_invalid_tree() {
+        // truncated in the middle of the kv hash
+        assert!(Tree::decode(vec![0], &[0, 0, 55, 55]).is_err());
+        // invalid link prefix
+        assert!(Tree::decode(vec![0], &[2]).is_err());
+    }
 }
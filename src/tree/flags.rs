@@ -0,0 +1,11 @@
+//! Flags stored with each entry, set with `Op::SetFlags`.
+//!
+//! An entry's flags are a byte which is committed into the hash of its
+//! key/value pair (see `kv_hash_with_flags`), so proofs of the entry prove
+//! its flags too. Entries have no flags (0) until they are set, and setting
+//! a value keeps the entry's flags. The bits not defined here are free for
+//! applications to use, e.g. to mark keys owned by a system module.
+
+/// Write-protects the entry: batches which put, delete or move it fail with
+/// `Error::Frozen`, until its flags are set again without this bit.
+pub const FROZEN: u8 = 1;
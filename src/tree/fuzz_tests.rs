@@ -1,10 +1,11 @@
-#![cfg(tests)]
+#![cfg(all(test, feature = "full"))]
 
 use crate::test_utils::*;
 use crate::tree::*;
 use rand::prelude::*;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::iter::FromIterator;
 
 const ITERATIONS: usize = 2_000;
 type Map = BTreeMap<Vec<u8>, Vec<u8>>;
@@ -124,6 +125,7 @@ fn apply_to_map(map: &mut Map, batch: &Batch) {
             (key, Op::Delete) => {
                 map.remove(key);
             }
+            _ => unreachable!("only puts and deletes are generated"),
         }
     }
 }
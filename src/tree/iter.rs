@@ -47,7 +47,8 @@ impl<'a> Iter<'a> {
 
 impl<'a> Tree {
     /// Creates an iterator which yields `(key, value)` tuples for all of the
-    /// tree's nodes which are retained in memory (skipping pruned subtrees).
+    /// tree's nodes which are retained in memory (skipping pruned subtrees,
+    /// and nodes whose values are not loaded).
     pub fn iter(&'a self) -> Iter<'a> {
         Iter::new(self)
     }
@@ -70,6 +71,9 @@ impl<'a> Iterator for Iter<'a> {
             self.next()
         } else if !last.traversed.1 {
             last.traversed.1 = true;
+            if !last.tree.value_loaded() {
+                return self.next();
+            }
             Some(last.to_entry())
         } else if !last.traversed.2 {
             last.traversed.2 = true;
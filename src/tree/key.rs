@@ -0,0 +1,334 @@
+//! Keys of in-memory nodes, which can share the allocation of their prefix.
+//!
+//! Applications often give their keys long common prefixes (e.g. a table or
+//! account name), which every node of the in-memory tree and every reference
+//! to a pruned child otherwise stores a copy of. While a `KeyInterner` is in
+//! scope (see `KeyInterner::scope`), the keys of nodes which are fetched or
+//! created, and of references to their children, are split at a fixed prefix
+//! length, and keys with the same prefix share one allocation of it. Reading
+//! an interned key as a contiguous slice copies it, so interning trades some
+//! CPU time for the memory of the in-memory tree.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+thread_local! {
+    static INTERNER: RefCell<Option<KeyInterner>> = const { RefCell::new(None) };
+}
+
+/// The key of an in-memory node or of a reference to one, whose prefix may be
+/// shared with other keys.
+#[derive(Clone, Default)]
+pub struct Key {
+    prefix: Option<Arc<[u8]>>,
+    suffix: Vec<u8>,
+}
+
+impl Key {
+    /// Returns the key as a contiguous slice, which is only copied if the key
+    /// is interned.
+    #[inline]
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match &self.prefix {
+            None => Cow::Borrowed(self.suffix.as_slice()),
+            Some(prefix) => {
+                let mut key = Vec::with_capacity(prefix.len() + self.suffix.len());
+                key.extend_from_slice(prefix);
+                key.extend_from_slice(&self.suffix);
+                Cow::Owned(key)
+            }
+        }
+    }
+
+    /// Returns the key's prefix and suffix, which together are the key.
+    #[inline]
+    pub fn parts(&self) -> (&[u8], &[u8]) {
+        (self.prefix.as_deref().unwrap_or_default(), &self.suffix)
+    }
+
+    /// Returns an iterator over the bytes of the key.
+    #[inline]
+    pub fn bytes(&self) -> impl Iterator<Item = &u8> {
+        let (prefix, suffix) = self.parts();
+        prefix.iter().chain(suffix)
+    }
+
+    /// Returns the length of the key.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.prefix.as_ref().map_or(0, |prefix| prefix.len()) + self.suffix.len()
+    }
+
+    /// Returns `true` if the key is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the key shares its prefix with other keys.
+    #[inline]
+    pub fn is_interned(&self) -> bool {
+        self.prefix.is_some()
+    }
+
+    /// Compares the key to `other` without copying it.
+    #[inline]
+    pub fn cmp_bytes(&self, other: &[u8]) -> Ordering {
+        let (prefix, suffix) = self.parts();
+        let split = prefix.len().min(other.len());
+        prefix
+            .cmp(&other[..split])
+            .then_with(|| suffix.cmp(&other[split..]))
+    }
+
+    /// Returns the number of bytes the key owns on the heap, not counting a
+    /// shared prefix.
+    #[inline]
+    pub fn heap_size(&self) -> usize {
+        self.suffix.capacity()
+    }
+
+    /// Consumes the key and returns it as a `Vec`, which is only copied if the
+    /// key is interned.
+    #[inline]
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.prefix {
+            None => self.suffix,
+            Some(prefix) => {
+                let mut key = Vec::with_capacity(prefix.len() + self.suffix.len());
+                key.extend_from_slice(&prefix);
+                key.extend_from_slice(&self.suffix);
+                key
+            }
+        }
+    }
+
+    /// Takes the key's suffix buffer if the key is not interned, so decoding
+    /// can reuse it.
+    #[inline]
+    pub(crate) fn take_buffer(&mut self) -> Vec<u8> {
+        self.prefix = None;
+        mem::take(&mut self.suffix)
+    }
+}
+
+impl From<Vec<u8>> for Key {
+    #[inline]
+    fn from(key: Vec<u8>) -> Key {
+        Key {
+            prefix: None,
+            suffix: key,
+        }
+    }
+}
+
+impl PartialEq for Key {
+    #[inline]
+    fn eq(&self, other: &Key) -> bool {
+        let (prefix, suffix) = other.parts();
+        self.len() == other.len() && prefix.iter().chain(suffix).eq(self.bytes())
+    }
+}
+
+impl Eq for Key {}
+
+impl PartialEq<[u8]> for Key {
+    #[inline]
+    fn eq(&self, other: &[u8]) -> bool {
+        self.len() == other.len() && self.cmp_bytes(other) == Ordering::Equal
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_bytes().fmt(f)
+    }
+}
+
+/// Interns the given key with the current thread's interner, if one is in
+/// scope.
+#[inline]
+pub(crate) fn intern(key: Vec<u8>) -> Key {
+    INTERNER.with(|cell| match cell.borrow_mut().as_mut() {
+        Some(interner) => interner.intern(key),
+        None => Key::from(key),
+    })
+}
+
+/// Re-interns a key which may have been created while no interner was in
+/// scope, e.g. one of a node loaded before interning was enabled.
+#[cfg(feature = "full")]
+pub(crate) fn reintern(key: &mut Key) {
+    if key.is_interned() {
+        return;
+    }
+    INTERNER.with(|cell| {
+        if let Some(interner) = cell.borrow_mut().as_mut() {
+            let taken = mem::take(key);
+            *key = interner.intern(taken.into_vec());
+        }
+    });
+}
+
+/// Shares the prefixes of keys which are longer than `prefix_length`.
+pub struct KeyInterner {
+    prefix_length: usize,
+    prefixes: HashSet<Arc<[u8]>>,
+}
+
+impl KeyInterner {
+    /// Creates an interner which shares the first `prefix_length` bytes of
+    /// longer keys. An interner with a prefix length of 0 does not intern any
+    /// keys.
+    pub fn new(prefix_length: usize) -> KeyInterner {
+        KeyInterner {
+            prefix_length,
+            prefixes: HashSet::new(),
+        }
+    }
+
+    /// Returns the length of the prefixes which are shared.
+    pub fn prefix_length(&self) -> usize {
+        self.prefix_length
+    }
+
+    /// Returns the number of distinct prefixes currently shared.
+    pub fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    /// Returns `true` if no prefixes are shared.
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    /// Splits `key` into a shared prefix and a suffix, if it is longer than
+    /// the prefix length.
+    pub fn intern(&mut self, key: Vec<u8>) -> Key {
+        if self.prefix_length == 0 || key.len() <= self.prefix_length {
+            return Key::from(key);
+        }
+
+        let (prefix, suffix) = key.split_at(self.prefix_length);
+        let prefix = match self.prefixes.get(prefix) {
+            Some(shared) => shared.clone(),
+            None => {
+                let shared: Arc<[u8]> = Arc::from(prefix);
+                self.prefixes.insert(shared.clone());
+                shared
+            }
+        };
+        Key {
+            prefix: Some(prefix),
+            suffix: suffix.to_vec(),
+        }
+    }
+
+    /// Forgets the prefixes which are no longer used by any key.
+    pub fn shrink(&mut self) {
+        self.prefixes.retain(|prefix| Arc::strong_count(prefix) > 1);
+    }
+
+    /// Runs `f` with the interner installed for the current thread, so keys of
+    /// nodes created while it runs are interned. Interners do not nest: while
+    /// `f` runs, an outer interner of the same thread is not used. Prefixes
+    /// no longer used by any key are forgotten when `f` returns.
+    pub fn scope<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        struct Restore<'a> {
+            interner: &'a mut KeyInterner,
+            outer: Option<KeyInterner>,
+        }
+
+        impl<'a> Drop for Restore<'a> {
+            fn drop(&mut self) {
+                let outer = self.outer.take();
+                if let Some(mut interner) = INTERNER.with(|cell| cell.replace(outer)) {
+                    interner.shrink();
+                    *self.interner = interner;
+                }
+            }
+        }
+
+        let interner = mem::replace(self, KeyInterner::new(self.prefix_length));
+        let outer = INTERNER.with(|cell| cell.replace(Some(interner)));
+        let _restore = Restore {
+            interner: self,
+            outer,
+        };
+        f()
+    }
+}
+
+impl Default for KeyInterner {
+    fn default() -> KeyInterner {
+        KeyInterner::new(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shares_prefixes() {
+        let mut interner = KeyInterner::new(4);
+        let a = interner.intern(b"prefix-a".to_vec());
+        let b = interner.intern(b"prefix-b".to_vec());
+        let short = interner.intern(b"pref".to_vec());
+
+        assert!(a.is_interned() && b.is_interned());
+        assert!(!short.is_interned());
+        assert_eq!(interner.len(), 1);
+        assert_eq!(a.as_bytes().as_ref(), b"prefix-a");
+        assert_eq!(a.heap_size(), 4);
+        assert_eq!(a.clone().into_vec(), b"prefix-a".to_vec());
+        assert!(a == b"prefix-a"[..]);
+        assert_ne!(a, b);
+        assert_eq!(a, Key::from(b"prefix-a".to_vec()));
+
+        drop((a, b));
+        interner.shrink();
+        assert!(interner.is_empty());
+    }
+
+    #[test]
+    fn compares_without_copying() {
+        let mut interner = KeyInterner::new(2);
+        let key = interner.intern(vec![1, 2, 3]);
+        for other in [
+            &[][..],
+            &[1],
+            &[1, 2],
+            &[1, 2, 3],
+            &[1, 2, 4],
+            &[1, 3],
+            &[0, 9, 9],
+        ] {
+            assert_eq!(
+                key.cmp_bytes(other),
+                [1, 2, 3][..].cmp(other),
+                "{:?}",
+                other
+            );
+        }
+    }
+
+    #[test]
+    fn interns_in_scope() {
+        let mut interner = KeyInterner::new(2);
+        assert!(!intern(vec![1, 2, 3]).is_interned());
+        let kept = interner.scope(|| {
+            let kept = intern(vec![1, 2, 3]);
+            intern(vec![4, 5, 6]);
+            kept
+        });
+        assert!(kept.is_interned());
+        // the prefix of the dropped key is forgotten
+        assert_eq!(interner.len(), 1);
+    }
+}
@@ -1,6 +1,8 @@
-use super::hash::{kv_hash, Hash, Hasher, HASH_LENGTH, NULL_HASH};
+use super::hash::{kv_hash, kv_hash_with_flags, Hash, Hasher, HASH_LENGTH, NULL_HASH};
+use super::key::{self, Key};
 use ed::{Decode, Encode, Result};
 use std::{
+    borrow::Cow,
     io::{Read, Write},
     num::TryFromIntError,
 };
@@ -12,23 +14,57 @@ use std::{
 
 /// Contains a key/value pair, and the hash of the key/value pair.
 pub struct KV {
-    pub(super) key: Vec<u8>,
+    pub(super) key: Key,
     pub(super) value: Vec<u8>,
     pub(super) hash: Hash,
+    /// Whether the value is unchanged since it was decoded from or written to
+    /// a store, so stores which keep large values apart from their nodes do not
+    /// need to write it again.
+    pub(super) stored: bool,
+    /// Whether the value is stored apart from the node, under the node's key,
+    /// as of when it was decoded or committed, so it is written there when a
+    /// node whose value was stored in its record starts being stored without
+    /// it.
+    pub(super) separated: bool,
+    /// Whether the value has not been loaded yet, see `Tree::decode_lazy`.
+    /// The value is stored apart from the node, under the node's key, and
+    /// `value` is empty until it is loaded.
+    pub(super) lazy: bool,
+    /// The entry's flags, see `tree::flags`, which are hashed along with the
+    /// key and value.
+    pub(super) flags: u8,
 }
 
 impl KV {
     /// Creates a new `KV` with the given key and value and computes its hash.
+    /// The key is interned if a `KeyInterner` is in scope.
     #[inline]
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> std::result::Result<Self, TryFromIntError> {
-        kv_hash::<Hasher>(key.as_slice(), value.as_slice()).map(|hash| KV { key, value, hash })
+        kv_hash::<Hasher>(key.as_slice(), value.as_slice()).map(|hash| KV {
+            key: key::intern(key),
+            value,
+            hash,
+            stored: false,
+            separated: false,
+            lazy: false,
+            flags: 0,
+        })
     }
 
     /// Creates a new `KV` with the given key, value, and hash. The hash is not
-    /// checked to be correct for the given key/value.
+    /// checked to be correct for the given key/value. The key is interned if a
+    /// `KeyInterner` is in scope.
     #[inline]
     pub fn from_fields(key: Vec<u8>, value: Vec<u8>, hash: Hash) -> Self {
-        KV { key, value, hash }
+        KV {
+            key: key::intern(key),
+            value,
+            hash,
+            stored: false,
+            separated: false,
+            lazy: false,
+            flags: 0,
+        }
     }
 
     /// Replaces the `KV`'s value with the given value, updates the hash, and
@@ -36,19 +72,44 @@ impl KV {
     #[inline]
     pub fn with_value(mut self, value: Vec<u8>) -> std::result::Result<Self, TryFromIntError> {
         self.value = value;
-        self.hash = kv_hash::<Hasher>(self.key(), self.value())?;
+        self.lazy = false;
+        self.hash = kv_hash_with_flags::<Hasher>(&self.key(), self.value(), self.flags)?;
+        self.stored = false;
         Ok(self)
     }
 
-    /// Returns the key as a slice.
+    /// Replaces the `KV`'s flags with the given flags, updates the hash, and
+    /// returns the modified `KV`. The value must have been loaded.
     #[inline]
-    pub fn key(&self) -> &[u8] {
-        self.key.as_slice()
+    pub fn with_flags(mut self, flags: u8) -> std::result::Result<Self, TryFromIntError> {
+        self.flags = flags;
+        self.hash = kv_hash_with_flags::<Hasher>(&self.key(), self.value(), flags)?;
+        Ok(self)
+    }
+
+    /// Returns the flags.
+    #[inline]
+    pub fn flags(&self) -> u8 {
+        self.flags
     }
 
-    /// Returns the value as a slice.
+    /// Returns the key as a slice, which is a copy if the key is interned.
+    #[inline]
+    pub fn key(&self) -> Cow<'_, [u8]> {
+        self.key.as_bytes()
+    }
+
+    /// Returns the key, whose prefix may be shared with other keys.
+    #[inline]
+    pub fn interned_key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Returns the value as a slice. The value must have been loaded, see
+    /// `Tree::value_loaded`.
     #[inline]
     pub fn value(&self) -> &[u8] {
+        debug_assert!(!self.lazy, "Value of {:?} is not loaded", self.key);
         self.value.as_slice()
     }
 
@@ -58,10 +119,11 @@ impl KV {
         &self.hash
     }
 
-    /// Consumes the `KV` and returns its key without allocating or cloning.
+    /// Consumes the `KV` and returns its key without allocating or cloning,
+    /// unless the key is interned.
     #[inline]
     pub fn take_key(self) -> Vec<u8> {
-        self.key
+        self.key.into_vec()
     }
 }
 
@@ -84,9 +146,13 @@ impl Decode for KV {
     #[inline]
     fn decode<R: Read>(input: R) -> Result<Self> {
         let mut kv = KV {
-            key: Vec::with_capacity(0),
+            key: Key::default(),
             value: Vec::with_capacity(128),
             hash: NULL_HASH,
+            stored: true,
+            separated: false,
+            lazy: false,
+            flags: 0,
         };
         KV::decode_into(&mut kv, input)?;
         Ok(kv)
@@ -94,12 +160,16 @@ impl Decode for KV {
 
     #[inline]
     fn decode_into<R: Read>(&mut self, mut input: R) -> Result<()> {
-        self.key.clear();
+        self.key = Key::default();
 
         input.read_exact(&mut self.hash[..])?;
 
         self.value.clear();
         input.read_to_end(self.value.as_mut())?;
+        self.stored = true;
+        self.separated = false;
+        self.lazy = false;
+        self.flags = 0;
 
         Ok(())
     }
@@ -113,17 +183,32 @@ mod test {
     fn new_kv() -> std::result::Result<(), TryFromIntError> {
         let kv = KV::new(vec![1, 2, 3], vec![4, 5, 6])?;
 
-        assert_eq!(kv.key(), &[1, 2, 3]);
+        assert_eq!(kv.key().as_ref(), &[1, 2, 3]);
         assert_eq!(kv.value(), &[4, 5, 6]);
         assert_ne!(kv.hash(), &super::super::hash::NULL_HASH);
         Ok(())
     }
 
+    #[test]
+    fn with_flags() -> std::result::Result<(), TryFromIntError> {
+        let kv = KV::new(vec![1, 2, 3], vec![4, 5, 6])?;
+        let hash = *kv.hash();
+        let kv = kv.with_flags(1)?;
+        assert_eq!(kv.flags(), 1);
+        assert_ne!(kv.hash(), &hash);
+
+        // the flags are kept when the value changes
+        let kv = kv.with_value(vec![4, 5, 6])?;
+        assert_eq!(kv.flags(), 1);
+        assert_eq!(kv.with_flags(0)?.hash(), &hash);
+        Ok(())
+    }
+
     #[test]
     fn with_value() -> std::result::Result<(), TryFromIntError> {
         let kv = KV::new(vec![1, 2, 3], vec![4, 5, 6])?.with_value(vec![7, 8, 9])?;
 
-        assert_eq!(kv.key(), &[1, 2, 3]);
+        assert_eq!(kv.key().as_ref(), &[1, 2, 3]);
         assert_eq!(kv.value(), &[7, 8, 9]);
         assert_ne!(kv.hash(), &super::super::hash::NULL_HASH);
         Ok(())
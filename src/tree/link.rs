@@ -1,10 +1,11 @@
+use std::borrow::Cow;
 use std::cmp::max;
 use std::io::{Read, Write};
 
 use ed::{Decode, Encode, Result, Terminated};
 
 use super::hash::Hash;
-use super::Tree;
+use super::{Key, Tree};
 
 // TODO: optimize memory footprint
 
@@ -17,7 +18,7 @@ pub enum Link {
     Reference {
         hash: Hash,
         child_heights: (u8, u8),
-        key: Vec<u8>,
+        key: Key,
     },
 
     /// Represents a tree node which has been modified since the `Tree`'s last
@@ -91,14 +92,21 @@ impl Link {
         matches!(self, Link::Loaded { .. })
     }
 
-    /// Returns the key of the tree referenced by this link, as a slice.
+    /// Returns the key of the tree referenced by this link, as a slice, which
+    /// is a copy if the key is interned (see `KeyInterner`).
     #[inline]
-    pub fn key(&self) -> &[u8] {
+    pub fn key(&self) -> Cow<'_, [u8]> {
+        self.interned_key().as_bytes()
+    }
+
+    /// Returns the key of the tree referenced by this link without copying it.
+    #[inline]
+    pub fn interned_key(&self) -> &Key {
         match self {
-            Link::Reference { key, .. } => key.as_slice(),
-            Link::Modified { tree, .. } => tree.key(),
-            Link::Uncommitted { tree, .. } => tree.key(),
-            Link::Loaded { tree, .. } => tree.key(),
+            Link::Reference { key, .. } => key,
+            Link::Modified { tree, .. } => tree.interned_key(),
+            Link::Uncommitted { tree, .. } => tree.interned_key(),
+            Link::Loaded { tree, .. } => tree.interned_key(),
         }
     }
 
@@ -132,13 +140,19 @@ impl Link {
     /// if any (note: not the height of the referenced tree itself). Return
     /// value is `(left_child_height, right_child_height)`.
     #[inline]
-    pub fn height(&self) -> u8 {
-        let (left_height, right_height) = match self {
+    pub fn child_heights(&self) -> (u8, u8) {
+        match self {
             Link::Reference { child_heights, .. } => *child_heights,
             Link::Modified { child_heights, .. } => *child_heights,
             Link::Uncommitted { child_heights, .. } => *child_heights,
             Link::Loaded { child_heights, .. } => *child_heights,
-        };
+        }
+    }
+
+    /// Returns the height of the tree referenced by the link.
+    #[inline]
+    pub fn height(&self) -> u8 {
+        let (left_height, right_height) = self.child_heights();
         1 + max(left_height, right_height)
     }
 
@@ -169,7 +183,7 @@ impl Link {
             } => Link::Reference {
                 hash,
                 child_heights,
-                key: tree.take_key(),
+                key: super::pool::recycle(tree),
             },
         }
     }
@@ -206,25 +220,27 @@ impl Encode for Link {
                 hash,
                 key,
                 child_heights,
-            } => (hash, key.as_slice(), child_heights),
+            } => (hash, key, child_heights),
             Link::Loaded {
                 hash,
                 tree,
                 child_heights,
-            } => (hash, tree.key(), child_heights),
+            } => (hash, tree.interned_key(), child_heights),
             Link::Uncommitted {
                 hash,
                 tree,
                 child_heights,
-            } => (hash, tree.key(), child_heights),
+            } => (hash, tree.interned_key(), child_heights),
 
             Link::Modified { .. } => panic!("No encoding for Link::Modified"),
         };
 
         debug_assert!(key.len() < 256, "Key length must be less than 256");
 
+        let (prefix, suffix) = key.parts();
         out.write_all(&[key.len() as u8])?;
-        out.write_all(key)?;
+        out.write_all(prefix)?;
+        out.write_all(suffix)?;
 
         out.write_all(hash)?;
 
@@ -235,13 +251,16 @@ impl Encode for Link {
 
     #[inline]
     fn encoding_length(&self) -> Result<usize> {
-        debug_assert!(self.key().len() < 256, "Key length must be less than 256");
+        debug_assert!(
+            self.interned_key().len() < 256,
+            "Key length must be less than 256"
+        );
 
         Ok(match self {
             Link::Reference { key, .. } => 1 + key.len() + 32 + 2,
             Link::Modified { .. } => panic!("No encoding for Link::Modified"),
-            Link::Uncommitted { tree, .. } => 1 + tree.key().len() + 32 + 2,
-            Link::Loaded { tree, .. } => 1 + tree.key().len() + 32 + 2,
+            Link::Uncommitted { tree, .. } => 1 + tree.interned_key().len() + 32 + 2,
+            Link::Loaded { tree, .. } => 1 + tree.interned_key().len() + 32 + 2,
         })
     }
 }
@@ -250,7 +269,7 @@ impl Link {
     #[inline]
     fn default_reference() -> Self {
         Link::Reference {
-            key: Vec::with_capacity(64),
+            key: Vec::with_capacity(64).into(),
             hash: Default::default(),
             child_heights: (0, 0),
         }
@@ -281,8 +300,10 @@ impl Decode for Link {
         {
             let length = read_u8(&mut input)? as usize;
 
-            key.resize(length, 0);
-            input.read_exact(key.as_mut())?;
+            let mut bytes = key.take_buffer();
+            bytes.resize(length, 0);
+            input.read_exact(bytes.as_mut())?;
+            *key = super::key::intern(bytes);
 
             input.read_exact(&mut hash[..])?;
 
@@ -317,7 +338,7 @@ mod test {
         let link = Link::from_modified_tree(tree);
         assert!(link.is_modified());
         assert_eq!(link.height(), 1);
-        assert_eq!(link.tree().expect("expected tree").key(), &[0]);
+        assert_eq!(link.tree().expect("expected tree").key().as_ref(), &[0]);
         if let Link::Modified { pending_writes, .. } = link {
             assert_eq!(pending_writes, 1);
             Ok(())
@@ -342,7 +363,7 @@ mod test {
         let hash = NULL_HASH;
         let child_heights = (0, 0);
         let pending_writes = 1;
-        let key = vec![0];
+        let key = Key::from(vec![0]);
         let tree = || Tree::new(vec![0], vec![1]);
 
         let reference = Link::Reference {
@@ -440,7 +461,7 @@ mod test {
     #[test]
     fn encode_link() {
         let link = Link::Reference {
-            key: vec![1, 2, 3],
+            key: vec![1, 2, 3].into(),
             child_heights: (123, 124),
             hash: [55; 32],
         };
@@ -461,7 +482,7 @@ mod test {
     #[should_panic]
     fn encode_link_long_key() {
         let link = Link::Reference {
-            key: vec![123; 300],
+            key: vec![123; 300].into(),
             child_heights: (123, 124),
             hash: [55; 32],
         };
@@ -2,25 +2,45 @@ mod commit;
 #[cfg(feature = "full")]
 mod debug;
 mod encoding;
+pub mod flags;
 mod fuzz_tests;
 mod hash;
 mod iter;
+mod key;
 mod kv;
 mod link;
+#[cfg(all(target_arch = "x86_64", not(feature = "poseidon")))]
+mod multibuffer;
 mod ops;
+mod pool;
+#[cfg(feature = "poseidon")]
+pub mod poseidon;
+pub mod stats;
+mod tree_ref;
 mod walk;
 
-use std::cmp::max;
+use std::borrow::Cow;
+use std::cmp::{max, Ordering};
 
 use ed::{Decode, Encode};
 
-use super::error::Result;
+use super::error::{Error, Result};
 pub use commit::{Commit, NoopCommit};
-pub use hash::{kv_hash, node_hash, Hash, Hasher, HASH_LENGTH, NULL_HASH};
+pub use encoding::RecordFormat;
+pub use hash::{
+    kv_hash, kv_hash_with_flags, node_hash, node_hashes, Hash, HashPool, Hasher, NodeHashInput,
+    HASH_LENGTH, NULL_HASH,
+};
+pub use key::{Key, KeyInterner};
 use kv::KV;
 pub use link::Link;
-pub use ops::{Batch, BatchEntry, Op, PanicSource};
-pub use walk::{Fetch, RefWalker, Walker};
+pub use ops::{
+    check_sorted, merge_batches, resolve_moves, Batch, BatchEntry, BatchEntryRef, Conflict, Entry,
+    Op, OpRef, PanicSource,
+};
+pub use pool::NodePool;
+pub use tree_ref::{LinkRef, TreeRef};
+pub use walk::{DynSource, Fetch, FetchBytes, RefWalker, Walker};
 
 // TODO: remove need for `TreeInner`, and just use `Box<Self>` receiver for
 // relevant methods
@@ -33,6 +53,21 @@ pub struct TreeInner {
     kv: KV,
 }
 
+/// A modified node whose hash is computed by `Tree::compute_hashes`.
+struct PendingHash {
+    level: usize,
+    kv_hash: Hash,
+    left: PendingChild,
+    right: PendingChild,
+}
+
+/// The hash of a child of a `PendingHash` node, which is either known or
+/// pending at the given index.
+enum PendingChild {
+    Hashed(Hash),
+    Pending(usize),
+}
+
 /// A binary AVL tree data structure, with Merkle hashes.
 ///
 /// Trees' inner fields are stored on the heap so that nodes can recursively
@@ -48,12 +83,20 @@ impl Tree {
     ///
     /// Hashes the key/value pair and initializes the `kv_hash` field.
     pub fn new(key: Vec<u8>, value: Vec<u8>) -> Result<Self> {
-        KV::new(key, value).map_err(Into::into).map(|kv| Tree {
-            inner: Box::new(TreeInner {
-                kv,
-                left: None,
-                right: None,
-            }),
+        let kv = KV::new(key, value)?;
+        Ok(match pool::take() {
+            // the value is already allocated, so only the node is reused
+            Some(mut tree) => {
+                tree.inner.kv = kv;
+                tree
+            }
+            None => Tree {
+                inner: Box::new(TreeInner {
+                    kv,
+                    left: None,
+                    right: None,
+                }),
+            },
         })
     }
 
@@ -75,31 +118,82 @@ impl Tree {
         }
     }
 
-    /// Returns the root node's key as a slice.
+    /// Returns the root node's key as a slice, which is a copy if the key is
+    /// interned (see `KeyInterner`).
     #[inline]
-    pub fn key(&self) -> &[u8] {
+    pub fn key(&self) -> Cow<'_, [u8]> {
         self.inner.kv.key()
     }
 
+    /// Returns the root node's key without copying it.
+    #[inline]
+    pub fn interned_key(&self) -> &Key {
+        self.inner.kv.interned_key()
+    }
+
     /// Consumes the tree and returns its root node's key, without having to
-    /// clone or allocate.
+    /// clone or allocate unless the key is interned.
     #[inline]
     pub fn take_key(self) -> Vec<u8> {
         self.inner.kv.take_key()
     }
 
-    /// Returns the root node's value as a slice.
+    /// Returns the root node's value as a slice. The value must have been
+    /// loaded, see `value_loaded`.
     #[inline]
     pub fn value(&self) -> &[u8] {
         self.inner.kv.value()
     }
 
+    /// Returns `false` if the root node was decoded with `decode_lazy` and its
+    /// value has not been loaded since.
+    #[inline]
+    pub fn value_loaded(&self) -> bool {
+        !self.inner.kv.lazy
+    }
+
+    /// Loads the root node's value with `load_value`, called with the node's
+    /// key, if it has not been loaded yet.
+    pub fn load_value_with<F>(&mut self, load_value: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        if self.inner.kv.lazy {
+            self.inner.kv.value = load_value(&self.key())?;
+            self.inner.kv.lazy = false;
+        }
+        Ok(())
+    }
+
+    /// Returns the root node's flags, see `tree::flags`.
+    #[inline]
+    pub fn flags(&self) -> u8 {
+        self.inner.kv.flags()
+    }
+
     /// Returns the hash of the root node's key/value pair.
     #[inline]
     pub fn kv_hash(&self) -> &Hash {
         self.inner.kv.hash()
     }
 
+    /// Returns `true` if the root node's value has not changed since the node
+    /// was decoded or committed.
+    #[inline]
+    #[cfg(feature = "full")]
+    pub(crate) fn value_stored(&self) -> bool {
+        self.inner.kv.stored
+    }
+
+    /// Returns `true` if the root node's value is stored apart from the node,
+    /// as of when the node was decoded or committed, see
+    /// `Commit::separate_value`.
+    #[inline]
+    #[cfg(feature = "full")]
+    pub(crate) fn value_separated(&self) -> bool {
+        self.inner.kv.separated
+    }
+
     /// Returns a reference to the root node's `Link` on the given side, if any.
     /// If there is no child, returns `None`.
     #[inline]
@@ -304,6 +398,23 @@ impl Tree {
         }
     }
 
+    /// Interns the keys of the in-memory nodes of the tree, and of the
+    /// references to their pruned children, with the current thread's
+    /// `KeyInterner`. Keys which are already interned are kept as they are.
+    #[cfg(feature = "full")]
+    pub(crate) fn intern_keys(&mut self) {
+        key::reintern(&mut self.inner.kv.key);
+        for left in [true, false] {
+            match self.slot_mut(left) {
+                Some(Link::Reference { key, .. }) => key::reintern(key),
+                Some(Link::Modified { tree, .. })
+                | Some(Link::Uncommitted { tree, .. })
+                | Some(Link::Loaded { tree, .. }) => tree.intern_keys(),
+                None => {}
+            }
+        }
+    }
+
     /// Replaces the root node's value with the given value and returns the
     /// modified `Tree`.
     #[inline]
@@ -312,57 +423,153 @@ impl Tree {
         Ok(self)
     }
 
-    // TODO: add compute_hashes method
+    /// Replaces the root node's flags with the given flags and returns the
+    /// modified `Tree`. The value must have been loaded, since it is hashed
+    /// along with the flags.
+    #[inline]
+    pub fn with_flags(mut self, flags: u8) -> Result<Self> {
+        self.inner.kv = self.inner.kv.with_flags(flags)?;
+        Ok(self)
+    }
 
-    /// Called to finalize modifications to a tree, recompute its hashes, and
-    /// write the updated nodes to a backing store.
+    /// Computes the hashes of all modified nodes, replacing their
+    /// `Link::Modified` links with `Link::Uncommitted` variants. The root
+    /// node's own hash is still computed on demand by `hash`.
     ///
-    /// Traverses through the tree, computing hashes for all modified links and
-    /// replacing them with `Link::Loaded` variants, writes out all changes to
-    /// the given `Commit` object's `write` method, and calls the its `prune`
-    /// method to test whether or not to keep or prune nodes from memory.
-    #[inline]
-    pub fn commit<C: Commit>(&mut self, c: &mut C) -> Result<()> {
-        // TODO: make this method less ugly
-        // TODO: call write in-order for better performance in writing batch to db?
+    /// A node's hash depends on the hashes of its children, so the modified
+    /// nodes are first gathered into levels, where each level only depends on
+    /// the levels before it: the modified nodes without modified children,
+    /// then their parents, and so on. Each level is then hashed in one call to
+    /// `node_hashes`, on the calling thread.
+    pub fn compute_hashes(&mut self) {
+        self.compute_hashes_with(None)
+    }
+
+    /// Like `compute_hashes`, but hashes large levels on the threads of
+    /// `pool`, if it is given, see `node_hashes`.
+    pub fn compute_hashes_with(&mut self, pool: Option<&HashPool>) {
+        let mut nodes = vec![];
+        self.plan_hashes(&mut nodes);
+        if nodes.is_empty() {
+            return;
+        }
+
+        let levels = 1 + nodes.iter().map(|node| node.level).max().unwrap_or(0);
+        let mut by_level = vec![vec![]; levels];
+        for (index, node) in nodes.iter().enumerate() {
+            by_level[node.level].push(index);
+        }
 
-        if let Some(Link::Modified { .. }) = self.inner.left {
+        let mut hashes = vec![NULL_HASH; nodes.len()];
+        for indices in by_level {
+            let inputs: Vec<NodeHashInput> = indices
+                .iter()
+                .map(|index| {
+                    let node = &nodes[*index];
+                    let child = |hash: &PendingChild| match hash {
+                        PendingChild::Hashed(hash) => *hash,
+                        PendingChild::Pending(index) => hashes[*index],
+                    };
+                    (node.kv_hash, child(&node.left), child(&node.right))
+                })
+                .collect();
+            for (index, hash) in indices.iter().zip(node_hashes(&inputs, pool)) {
+                hashes[*index] = hash;
+            }
+        }
+
+        self.apply_hashes(&hashes, &mut 0);
+    }
+
+    /// Pushes the modified descendants of the node onto `nodes` in post-order,
+    /// and returns the node's children and its level (see `compute_hashes`).
+    fn plan_hashes(&self, nodes: &mut Vec<PendingHash>) -> (PendingChild, PendingChild, usize) {
+        let mut level = 0;
+        let mut plan_child = |left| match self.link(left) {
+            Some(Link::Modified { tree, .. }) => {
+                let (left, right, child_level) = tree.plan_hashes(nodes);
+                level = max(level, child_level + 1);
+                nodes.push(PendingHash {
+                    level: child_level,
+                    kv_hash: *tree.kv_hash(),
+                    left,
+                    right,
+                });
+                PendingChild::Pending(nodes.len() - 1)
+            }
+            Some(link) => PendingChild::Hashed(*link.hash()),
+            None => PendingChild::Hashed(NULL_HASH),
+        };
+        let left = plan_child(true);
+        let right = plan_child(false);
+        (left, right, level)
+    }
+
+    /// Replaces the modified links of the node's descendants with
+    /// `Link::Uncommitted` links holding their computed `hashes`, visiting
+    /// them in the same order as `plan_hashes`.
+    fn apply_hashes(&mut self, hashes: &[Hash], next: &mut usize) {
+        for left in [true, false].iter() {
+            if !matches!(self.link(*left), Some(Link::Modified { .. })) {
+                continue;
+            }
             if let Some(Link::Modified {
                 mut tree,
                 child_heights,
                 ..
-            }) = self.inner.left.take()
+            }) = self.slot_mut(*left).take()
             {
-                tree.commit(c)?;
-                self.inner.left = Some(Link::Loaded {
-                    hash: tree.hash(),
-                    tree,
+                tree.apply_hashes(hashes, next);
+                *self.slot_mut(*left) = Some(Link::Uncommitted {
+                    hash: hashes[*next],
                     child_heights,
+                    tree,
                 });
-            } else {
-                unreachable!()
+                *next += 1;
             }
         }
+    }
 
-        if let Some(Link::Modified { .. }) = self.inner.right {
-            if let Some(Link::Modified {
+    /// Called to finalize modifications to a tree, recompute its hashes, and
+    /// write the updated nodes to a backing store.
+    ///
+    /// Computes hashes for all modified links (see `compute_hashes`), then
+    /// traverses through the tree replacing them with `Link::Loaded` variants,
+    /// writes out all changes to the given `Commit` object's `write` method,
+    /// and calls the its `prune` method to test whether or not to keep or prune
+    /// nodes from memory.
+    #[inline]
+    pub fn commit<C: Commit>(&mut self, c: &mut C) -> Result<()> {
+        self.compute_hashes();
+        self.commit_hashed(c)
+    }
+
+    fn commit_hashed<C: Commit>(&mut self, c: &mut C) -> Result<()> {
+        // TODO: call write in-order for better performance in writing batch to db?
+
+        for left in [true, false].iter() {
+            if !matches!(self.link(*left), Some(Link::Uncommitted { .. })) {
+                continue;
+            }
+            if let Some(Link::Uncommitted {
                 mut tree,
+                hash,
                 child_heights,
-                ..
-            }) = self.inner.right.take()
+            }) = self.slot_mut(*left).take()
             {
-                tree.commit(c)?;
-                self.inner.right = Some(Link::Loaded {
-                    hash: tree.hash(),
+                tree.commit_hashed(c)?;
+                *self.slot_mut(*left) = Some(Link::Loaded {
+                    hash,
                     tree,
                     child_heights,
                 });
-            } else {
-                unreachable!()
             }
         }
 
+        let separated = c.separate_value(self);
         c.write(self)?;
+        self.inner.kv.stored = true;
+        self.inner.kv.separated = separated;
 
         let (prune_left, prune_right) = c.prune(self);
         if prune_left {
@@ -380,15 +587,20 @@ impl Tree {
     /// `Link::Loaded`).
     #[inline]
     pub fn load<S: Fetch>(&mut self, left: bool, source: &S) -> Result<()> {
-        // TODO: return Err instead of panic?
-        let link = self.link(left).expect("Expected link");
-        let (child_heights, hash) = match link {
-            Link::Reference {
-                child_heights,
-                hash,
-                ..
-            } => (child_heights, hash),
-            _ => panic!("Expected Some(Link::Reference)"),
+        let (link, child_heights, hash) = match self.link(left) {
+            Some(
+                link @ Link::Reference {
+                    child_heights,
+                    hash,
+                    ..
+                },
+            ) => (link, child_heights, hash),
+            _ => {
+                return Err(Error::InvariantViolation {
+                    key: self.key().to_vec(),
+                    detail: format!("Expected {} link to be Link::Reference", side_to_str(left)),
+                })
+            }
         };
 
         let tree = source.fetch(link)?;
@@ -406,12 +618,15 @@ impl Tree {
         let mut cursor = self;
 
         loop {
-            if key == cursor.key() {
+            let order = cursor.interned_key().cmp_bytes(key);
+            if order == Ordering::Equal {
+                if !cursor.value_loaded() {
+                    return Ok(GetResult::Pruned);
+                }
                 return Ok(GetResult::Found(cursor.value().to_vec()));
             }
 
-            let left = key < cursor.key();
-            let link = match cursor.link(left) {
+            let link = match cursor.link(order == Ordering::Greater) {
                 None => return Ok(GetResult::NotFound), // not found
                 Some(link) => link,
             };
@@ -442,14 +657,14 @@ pub fn side_to_str(left: bool) -> &'static str {
 #[cfg(test)]
 mod test {
     use super::commit::NoopCommit;
-    use super::hash::NULL_HASH;
+    use super::hash::{node_hash, node_hashes, Hash, HashPool, Hasher, NodeHashInput, NULL_HASH};
     use super::Tree;
     use crate::error::Result;
 
     #[test]
     fn build_tree() -> Result<()> {
         let tree = Tree::new(vec![1], vec![101])?;
-        assert_eq!(tree.key(), &[1]);
+        assert_eq!(tree.key().as_ref(), &[1]);
         assert_eq!(tree.value(), &[101]);
         assert!(tree.child(true).is_none());
         assert!(tree.child(false).is_none());
@@ -459,13 +674,13 @@ mod test {
         assert!(tree.child(false).is_none());
 
         let tree = tree.attach(true, Some(Tree::new(vec![2], vec![102])?));
-        assert_eq!(tree.key(), &[1]);
-        assert_eq!(tree.child(true).unwrap().key(), &[2]);
+        assert_eq!(tree.key().as_ref(), &[1]);
+        assert_eq!(tree.child(true).unwrap().key().as_ref(), &[2]);
         assert!(tree.child(false).is_none());
 
         let tree = Tree::new(vec![3], vec![103])?.attach(false, Some(tree));
-        assert_eq!(tree.key(), &[3]);
-        assert_eq!(tree.child(false).unwrap().key(), &[1]);
+        assert_eq!(tree.key().as_ref(), &[3]);
+        assert_eq!(tree.child(false).unwrap().key().as_ref(), &[1]);
         assert!(tree.child(true).is_none());
         Ok(())
     }
@@ -492,7 +707,7 @@ mod test {
             .attach(false, Some(Tree::new(vec![4], vec![5])?));
 
         let tree = tree.walk(true, |left_opt| {
-            assert_eq!(left_opt.as_ref().unwrap().key(), &[2]);
+            assert_eq!(left_opt.as_ref().unwrap().key().as_ref(), &[2]);
             None
         });
         assert!(tree.child(true).is_none());
@@ -502,10 +717,10 @@ mod test {
             assert!(left_opt.is_none());
             fixed_tree
         });
-        assert_eq!(tree.link(true).unwrap().key(), &[2]);
+        assert_eq!(tree.link(true).unwrap().key().as_ref(), &[2]);
 
         let tree = tree.walk_expect(false, |right| {
-            assert_eq!(right.key(), &[4]);
+            assert_eq!(right.key().as_ref(), &[4]);
             None
         });
         assert!(tree.child(true).is_some());
@@ -605,4 +820,58 @@ mod test {
         assert!(tree.link(false).expect("expected link").is_stored());
         Ok(())
     }
+
+    #[test]
+    fn compute_hashes() -> Result<()> {
+        fn expected_hash(tree: &Tree) -> Hash {
+            let child = |left| tree.child(left).map_or(NULL_HASH, expected_hash);
+            node_hash::<Hasher>(tree.kv_hash(), &child(true), &child(false))
+        }
+
+        let leaf = |key| Tree::new(vec![key], vec![key]);
+        let mut tree = Tree::new(vec![4], vec![4])?
+            .attach(
+                true,
+                Some(Tree::new(vec![2], vec![2])?.attach(true, Some(leaf(1)?))),
+            )
+            .attach(false, Some(leaf(5)?));
+        let expected = expected_hash(&tree);
+
+        tree.compute_hashes();
+        for left in [true, false].iter() {
+            assert!(tree.link(*left).unwrap().is_uncommitted());
+        }
+        assert!(tree
+            .child(true)
+            .unwrap()
+            .link(true)
+            .unwrap()
+            .is_uncommitted());
+        assert_eq!(tree.hash(), expected);
+
+        tree.commit(&mut NoopCommit {})?;
+        assert!(tree.child(true).unwrap().link(true).unwrap().is_stored());
+        assert_eq!(tree.hash(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_node_hashes() {
+        let inputs: Vec<NodeHashInput> = (0..10_000u32)
+            .map(|i| {
+                let mut kv = NULL_HASH;
+                kv[..4].copy_from_slice(&i.to_be_bytes());
+                (kv, [1; 32], [2; 32])
+            })
+            .collect();
+        let hashes = node_hashes(&inputs, None);
+        assert_eq!(hashes.len(), inputs.len());
+        for (hash, (kv, left, right)) in hashes.iter().zip(inputs.iter()) {
+            assert_eq!(*hash, node_hash::<Hasher>(kv, left, right));
+        }
+        // the pool is reused across batches
+        let pool = HashPool::new(3);
+        assert_eq!(node_hashes(&inputs, Some(&pool)), hashes);
+        assert_eq!(node_hashes(&inputs[1..], Some(&pool)), &hashes[1..]);
+    }
 }
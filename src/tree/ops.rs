@@ -1,13 +1,32 @@
-use super::{Fetch, Tree, Walker};
-use crate::error::Result;
-use std::collections::LinkedList;
+use super::{flags::FROZEN, stats, Fetch, Tree, Walker};
+use crate::error::{Error, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, LinkedList};
 use std::fmt;
 use Op::*;
 
 /// An operation to be applied to a key in the store.
+///
+/// An empty value is a value like any other: `Put(vec![])` stores the key,
+/// which is then returned by gets as `Some(vec![])` and proven to be present,
+/// while `Delete` removes the key so it is proven to be absent.
+#[derive(Clone, PartialEq, Eq)]
 pub enum Op {
+    /// Sets the value of the key, inserting it if it does not exist.
     Put(Vec<u8>),
+    /// Removes the key. Deleting a key which does not exist has no effect.
     Delete,
+    /// Removes the key and puts its value to the key `to`, without the value
+    /// passing through the caller. The key must exist, and `to` must not be
+    /// given another operation in the same batch unless it is moved itself,
+    /// so keys can be swapped. Moves are resolved by `Merk::apply` against
+    /// the values before the batch, and cannot be applied to a tree directly.
+    Move { to: Vec<u8> },
+    /// Sets the flags of the key (see `tree::flags`), keeping its value. The
+    /// key must exist. Flags can be set on frozen keys, e.g. to unfreeze
+    /// them.
+    SetFlags(u8),
 }
 
 impl fmt::Debug for Op {
@@ -18,6 +37,8 @@ impl fmt::Debug for Op {
             match self {
                 Put(value) => format!("Put({value:?})"),
                 Delete => "Delete".to_string(),
+                Move { to } => format!("Move {{ to: {to:?} }}"),
+                SetFlags(flags) => format!("SetFlags({flags:#04x})"),
             }
         )
     }
@@ -29,6 +50,226 @@ pub type BatchEntry = (Vec<u8>, Op);
 /// A mapping of keys and operations. Keys should be sorted and unique.
 pub type Batch = [BatchEntry];
 
+/// An `Op` which borrows its value, see `BatchEntryRef`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpRef<'a> {
+    Put(&'a [u8]),
+    Delete,
+    Move { to: &'a [u8] },
+    SetFlags(u8),
+}
+
+impl OpRef<'_> {
+    /// Copies the operation into an owned `Op`.
+    pub fn to_op(self) -> Op {
+        match self {
+            OpRef::Put(value) => Put(value.to_vec()),
+            OpRef::Delete => Delete,
+            OpRef::Move { to } => Move { to: to.to_vec() },
+            OpRef::SetFlags(flags) => SetFlags(flags),
+        }
+    }
+}
+
+impl<'a> From<&'a Op> for OpRef<'a> {
+    fn from(op: &'a Op) -> OpRef<'a> {
+        match op {
+            Put(value) => OpRef::Put(value),
+            Delete => OpRef::Delete,
+            Move { to } => OpRef::Move { to },
+            SetFlags(flags) => OpRef::SetFlags(*flags),
+        }
+    }
+}
+
+/// A `(key, operation)` pair which borrows its key and value, so a batch can
+/// be built from data the caller already holds (e.g. the transactions of a
+/// block) without copying every key and value into a `Vec` first. The tree
+/// copies them once, into the nodes it creates.
+pub type BatchEntryRef<'a> = (&'a [u8], OpRef<'a>);
+
+/// An entry of a batch, either owned (`BatchEntry`) or borrowed
+/// (`BatchEntryRef`). Batches of either can be applied.
+pub trait Entry {
+    fn key(&self) -> &[u8];
+
+    fn op(&self) -> OpRef<'_>;
+
+    /// Returns `batch` as owned entries, for the parts of the store which
+    /// take a `Batch` (such as commit hooks), borrowing it if it is already
+    /// owned.
+    fn to_batch(batch: &[Self]) -> Cow<'_, Batch>
+    where
+        Self: Sized;
+}
+
+impl Entry for BatchEntry {
+    #[inline]
+    fn key(&self) -> &[u8] {
+        &self.0
+    }
+
+    #[inline]
+    fn op(&self) -> OpRef<'_> {
+        (&self.1).into()
+    }
+
+    fn to_batch(batch: &[Self]) -> Cow<'_, Batch> {
+        Cow::Borrowed(batch)
+    }
+}
+
+impl Entry for BatchEntryRef<'_> {
+    #[inline]
+    fn key(&self) -> &[u8] {
+        self.0
+    }
+
+    #[inline]
+    fn op(&self) -> OpRef<'_> {
+        self.1
+    }
+
+    fn to_batch(batch: &[Self]) -> Cow<'_, Batch> {
+        Cow::Owned(
+            batch
+                .iter()
+                .map(|(key, op)| (key.to_vec(), op.to_op()))
+                .collect(),
+        )
+    }
+}
+
+/// Two batches gave different operations for the same key.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Conflicting operations for key {key:?}: {ours:?} and {theirs:?}")]
+pub struct Conflict {
+    pub key: Vec<u8>,
+    pub ours: Op,
+    pub theirs: Op,
+}
+
+/// Combines two batches into a single batch, so independently built sets of
+/// changes can be applied at once. Keys in both batches must be sorted and
+/// unique, and the merged batch will be too.
+///
+/// A key which appears in both batches is only allowed if both batches give
+/// it the same operation, otherwise the first such key is returned as a
+/// `Conflict`.
+pub fn merge_batches(
+    ours: &Batch,
+    theirs: &Batch,
+) -> std::result::Result<Vec<BatchEntry>, Conflict> {
+    let mut merged = Vec::with_capacity(ours.len() + theirs.len());
+    let mut ours = ours.iter().peekable();
+    let mut theirs = theirs.iter().peekable();
+
+    loop {
+        let entry = match (ours.peek(), theirs.peek()) {
+            (Some((our_key, _)), Some((their_key, _))) if our_key < their_key => {
+                ours.next().unwrap()
+            }
+            (Some((our_key, _)), Some((their_key, _))) if our_key > their_key => {
+                theirs.next().unwrap()
+            }
+            (Some(_), Some(_)) => {
+                let (key, our_op) = ours.next().unwrap();
+                let (_, their_op) = theirs.next().unwrap();
+                if our_op != their_op {
+                    return Err(Conflict {
+                        key: key.clone(),
+                        ours: our_op.clone(),
+                        theirs: their_op.clone(),
+                    });
+                }
+                merged.push((key.clone(), our_op.clone()));
+                continue;
+            }
+            (Some(_), None) => ours.next().unwrap(),
+            (None, Some(_)) => theirs.next().unwrap(),
+            (None, None) => break,
+        };
+        merged.push(entry.clone());
+    }
+
+    Ok(merged)
+}
+
+/// Resolves the moves in a batch into a delete of each moved key and a put
+/// of its value to the key it is moved to, reading the values of the moved
+/// keys with `get`. Returns `None` if the batch has no moves, otherwise the
+/// resolved batch, which is sorted and unique if `batch` is.
+///
+/// Fails if a moved key does not exist, if two keys are moved to the same
+/// key, or if a key is moved to a key which has another operation in the
+/// batch without being moved itself.
+pub fn resolve_moves<F>(batch: &Batch, mut get: F) -> Result<Option<Vec<BatchEntry>>>
+where
+    F: FnMut(&[u8]) -> Result<Option<Vec<u8>>>,
+{
+    if !batch.iter().any(|(_, op)| matches!(op, Move { .. })) {
+        return Ok(None);
+    }
+
+    let mut puts = BTreeMap::new();
+    for (key, op) in batch {
+        if let Move { to } = op {
+            let value = get(key)?.ok_or_else(|| Error::KeyMove(key.clone()))?;
+            if puts.insert(to.clone(), value).is_some() {
+                return Err(Error::BatchKey(format!(
+                    "Multiple keys moved to key {:?}",
+                    to
+                )));
+            }
+        }
+    }
+
+    let mut resolved = Vec::with_capacity(batch.len() + puts.len());
+    for (key, op) in batch {
+        match op {
+            // a key moved away and replaced by another is put by the move
+            Move { .. } if puts.contains_key(key) => {}
+            Move { .. } => resolved.push((key.clone(), Delete)),
+            _ if puts.contains_key(key) => {
+                return Err(Error::BatchKey(format!(
+                    "Key {:?} is both moved to and given another operation",
+                    key
+                )));
+            }
+            op => resolved.push((key.clone(), op.clone())),
+        }
+    }
+    resolved.extend(puts.into_iter().map(|(key, value)| (key, Put(value))));
+    resolved.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(Some(resolved))
+}
+
+/// Returns `Error::BatchUnsorted` or `Error::DuplicateKey` for the first key
+/// in `batch` which is not greater than the key before it.
+pub fn check_sorted<E: Entry>(batch: &[E]) -> Result<()> {
+    for (index, pair) in batch.windows(2).enumerate() {
+        match pair[0].key().cmp(pair[1].key()) {
+            Ordering::Less => {}
+            Ordering::Equal => {
+                return Err(Error::DuplicateKey {
+                    key: pair[1].key().to_vec(),
+                })
+            }
+            Ordering::Greater => return Err(Error::BatchUnsorted { index: index + 1 }),
+        }
+    }
+    Ok(())
+}
+
+fn unresolved_move() -> Error {
+    Error::BatchKey("Moves must be resolved before applying a batch to a tree".into())
+}
+
+fn flags_of_missing_key(key: &[u8]) -> Error {
+    Error::KeySetFlags(key.to_vec())
+}
+
 /// A source of data which panics when called. Useful when creating a store
 /// which always keeps the state in memory.
 #[derive(Clone)]
@@ -49,9 +290,9 @@ where
     /// not require a non-empty tree.
     ///
     /// Keys in batch must be sorted and unique.
-    pub fn apply_to(
+    pub fn apply_to<E: Entry>(
         maybe_tree: Option<Self>,
-        batch: &Batch,
+        batch: &[E],
         source: S,
     ) -> Result<(Option<Tree>, LinkedList<Vec<u8>>)> {
         let (maybe_walker, deleted_keys) = if batch.is_empty() {
@@ -70,15 +311,15 @@ where
     /// Builds a `Tree` from a batch of operations.
     ///
     /// Keys in batch must be sorted and unique.
-    fn build(batch: &Batch, source: S) -> Result<Option<Tree>> {
+    fn build<E: Entry>(batch: &[E], source: S) -> Result<Option<Tree>> {
         if batch.is_empty() {
             return Ok(None);
         }
 
         let mid_index = batch.len() / 2;
-        let (mid_key, mid_op) = &batch[mid_index];
-        let mid_value = match mid_op {
-            Delete => {
+        let mid_key = batch[mid_index].key();
+        let mid_value = match batch[mid_index].op() {
+            OpRef::Delete => {
                 let left_batch = &batch[..mid_index];
                 let right_batch = &batch[mid_index + 1..];
 
@@ -91,11 +332,14 @@ where
                 };
                 return Ok(maybe_tree.map(|tree| tree.into()));
             }
-            Put(value) => value,
+            OpRef::Put(value) => value,
+            OpRef::Move { .. } => return Err(unresolved_move()),
+            OpRef::SetFlags(_) => return Err(flags_of_missing_key(mid_key)),
         };
 
         // TODO: take from batch so we don't have to clone
         let mid_tree = Tree::new(mid_key.to_vec(), mid_value.to_vec())?;
+        stats::record(|counts| counts.nodes_created += 1);
         let mid_walker = Walker::new(mid_tree, PanicSource {});
         Ok(mid_walker
             .recurse(batch, mid_index, true)?
@@ -107,16 +351,22 @@ where
     /// `Walker<S>::apply`_to, but requires a populated tree.
     ///
     /// Keys in batch must be sorted and unique.
-    fn apply(self, batch: &Batch) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
+    fn apply<E: Entry>(self, batch: &[E]) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
         // binary search to see if this node's key is in the batch, and to split
         // into left and right batches
-        let search = batch.binary_search_by(|(key, _op)| key.as_slice().cmp(self.tree().key()));
+        let key = self.tree().interned_key();
+        let search = batch.binary_search_by(|entry| key.cmp_bytes(entry.key()).reverse());
         let tree = if let Ok(index) = search {
             // a key matches this node's key, apply op to this node
-            match &batch[index].1 {
-                // TODO: take vec from batch so we don't need to clone
-                Put(value) => self.with_value(value.to_vec()),
-                Delete => {
+            let op = batch[index].op();
+            if self.tree().flags() & FROZEN != 0 && !matches!(op, OpRef::SetFlags(_)) {
+                return Err(Error::Frozen(self.tree().key().to_vec()));
+            }
+            match op {
+                OpRef::Put(value) => self.with_value(value.to_vec()),
+                OpRef::SetFlags(flags) => self.with_flags(flags),
+                OpRef::Move { .. } => return Err(unresolved_move()),
+                OpRef::Delete => {
                     let source = self.clone_source();
                     let key = self.tree().key().to_vec();
 
@@ -159,9 +409,9 @@ where
     ///
     /// This recursion executes serially in the same thread, but in the future
     /// will be dispatched to workers in other threads.
-    fn recurse(
+    fn recurse<E: Entry>(
         self,
-        batch: &Batch,
+        batch: &[E],
         mid: usize,
         exclusive: bool,
     ) -> Result<(Option<Self>, LinkedList<Vec<u8>>)> {
@@ -233,6 +483,7 @@ where
     /// Applies an AVL tree rotation, a constant-time operation which only needs
     /// to swap pointers in order to rebalance a tree.
     fn rotate(self, left: bool) -> Result<Self> {
+        stats::record(|counts| counts.rotations += 1);
         let (tree, child) = self.detach_expect(left)?;
         let (child, maybe_grandchild) = child.detach(!left)?;
 
@@ -251,18 +502,20 @@ where
         let has_right = tree.link(false).is_some();
         let left = tree.child_height(true) > tree.child_height(false);
 
-        let maybe_tree = if has_left && has_right {
+        let (removed, maybe_tree) = if has_left && has_right {
             // two children, promote edge of taller child
             let (tree, tall_child) = self.detach_expect(left)?;
-            let (_, short_child) = tree.detach_expect(!left)?;
-            Some(tall_child.promote_edge(!left, short_child)?)
+            let (tree, short_child) = tree.detach_expect(!left)?;
+            (tree, Some(tall_child.promote_edge(!left, short_child)?))
         } else if has_left || has_right {
             // single child, promote it
-            Some(self.detach_expect(left)?.1)
+            let (tree, child) = self.detach_expect(left)?;
+            (tree, Some(child))
         } else {
             // no child
-            None
+            (self, None)
         };
+        super::pool::recycle(removed.into_inner());
 
         Ok(maybe_tree)
     }
@@ -295,7 +548,7 @@ where
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "full"))]
 mod test {
     use super::*;
     use crate::test_utils::{
@@ -303,6 +556,70 @@ mod test {
     };
     use crate::tree::*;
 
+    #[test]
+    fn merge_disjoint_batches() {
+        let ours = [put_entry(1), put_entry(3), del_entry(5)];
+        let theirs = [del_entry(2), put_entry(4), put_entry(6)];
+        let merged = merge_batches(&ours, &theirs).unwrap();
+
+        let keys: Vec<_> = merged.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, (1..=6).map(seq_key).collect::<Vec<_>>());
+        assert_eq!(merged[4], del_entry(5));
+    }
+
+    #[test]
+    fn merge_conflicting_batches() {
+        let ours = [put_entry(1), put_entry(2)];
+        assert_eq!(
+            merge_batches(&ours, &[put_entry(2), put_entry(3)])
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let conflict = merge_batches(&ours, &[del_entry(2)]).unwrap_err();
+        assert_eq!(conflict.key, seq_key(2));
+        assert_eq!(conflict.theirs, Op::Delete);
+    }
+
+    #[test]
+    fn resolve_batch_moves() {
+        let get = |key: &[u8]| Ok(Some(key.to_vec()).filter(|key| key[0] < 5));
+        let move_to = |to: u8| Op::Move { to: vec![to] };
+
+        assert!(resolve_moves(&[(vec![1], Op::Delete)], get)
+            .unwrap()
+            .is_none());
+
+        // moves 1 to 7, and swaps 3 and 4
+        let batch = [
+            (vec![1], move_to(7)),
+            (vec![2], Op::Delete),
+            (vec![3], move_to(4)),
+            (vec![4], move_to(3)),
+        ];
+        let resolved = resolve_moves(&batch, get).unwrap().unwrap();
+        assert_eq!(
+            resolved,
+            vec![
+                (vec![1], Op::Delete),
+                (vec![2], Op::Delete),
+                (vec![3], Op::Put(vec![4])),
+                (vec![4], Op::Put(vec![3])),
+                (vec![7], Op::Put(vec![1])),
+            ]
+        );
+
+        assert!(resolve_moves(&[(vec![6], move_to(7))], get).is_err());
+        assert!(resolve_moves(&[(vec![1], move_to(7)), (vec![2], move_to(7))], get).is_err());
+        assert!(resolve_moves(&[(vec![1], move_to(2)), (vec![2], Op::Delete)], get).is_err());
+
+        // the tree itself does not resolve moves
+        let walker = Walker::new(make_tree_seq(1), PanicSource {});
+        let batch = [(seq_key(0), move_to(1))];
+        assert!(Walker::apply_to(Some(walker), &batch, PanicSource {}).is_err());
+    }
+
     #[test]
     fn simple_insert() -> Result<()> {
         let batch = [(b"foo2".to_vec(), Op::Put(b"bar2".to_vec()))];
@@ -311,8 +628,11 @@ mod test {
             .apply(&batch)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
-        assert_eq!(walker.tree().key(), b"foo");
-        assert_eq!(walker.into_inner().child(false).unwrap().key(), b"foo2");
+        assert_eq!(walker.tree().key().as_ref(), b"foo");
+        assert_eq!(
+            walker.into_inner().child(false).unwrap().key().as_ref(),
+            b"foo2"
+        );
         assert!(deleted_keys.is_empty());
         Ok(())
     }
@@ -325,7 +645,7 @@ mod test {
             .apply(&batch)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
-        assert_eq!(walker.tree().key(), b"foo");
+        assert_eq!(walker.tree().key().as_ref(), b"foo");
         assert_eq!(walker.tree().value(), b"bar2");
         assert!(walker.tree().link(true).is_none());
         assert!(walker.tree().link(false).is_none());
@@ -351,7 +671,7 @@ mod test {
             .apply(&batch)
             .expect("apply errored");
         let walker = maybe_walker.expect("should be Some");
-        assert_eq!(walker.tree().key(), b"foo");
+        assert_eq!(walker.tree().key().as_ref(), b"foo");
         assert_eq!(walker.tree().value(), b"bar");
         assert!(walker.tree().link(true).is_none());
         assert!(walker.tree().link(false).is_none());
@@ -456,7 +776,8 @@ mod test {
     #[test]
     fn apply_empty_none() {
         let (maybe_tree, deleted_keys) =
-            Walker::<PanicSource>::apply_to(None, &[], PanicSource {}).expect("apply_to failed");
+            Walker::<PanicSource>::apply_to::<BatchEntry>(None, &[], PanicSource {})
+                .expect("apply_to failed");
         assert!(maybe_tree.is_none());
         assert!(deleted_keys.is_empty());
     }
@@ -467,7 +788,7 @@ mod test {
         let (maybe_tree, deleted_keys) =
             Walker::<PanicSource>::apply_to(None, &batch, PanicSource {}).expect("apply_to failed");
         let tree = maybe_tree.expect("expected tree");
-        assert_eq!(tree.key(), &[0]);
+        assert_eq!(tree.key().as_ref(), &[0]);
         assert_eq!(tree.value(), &[1]);
         assert_tree_invariants(&tree);
         assert!(deleted_keys.is_empty());
@@ -478,9 +799,12 @@ mod test {
         let tree = Tree::new(vec![5], vec![123])?;
         let batch = vec![(vec![6], Op::Put(vec![123]))];
         let tree = apply_memonly(tree, &batch);
-        assert_eq!(tree.key(), &[5]);
+        assert_eq!(tree.key().as_ref(), &[5]);
         assert!(tree.child(true).is_none());
-        assert_eq!(tree.child(false).expect("expected child").key(), &[6]);
+        assert_eq!(
+            tree.child(false).expect("expected child").key().as_ref(),
+            &[6]
+        );
         Ok(())
     }
 
@@ -489,9 +813,15 @@ mod test {
         let tree = Tree::new(vec![5], vec![123])?;
         let batch = vec![(vec![4], Op::Put(vec![123])), (vec![6], Op::Put(vec![123]))];
         let tree = apply_memonly(tree, &batch);
-        assert_eq!(tree.key(), &[5]);
-        assert_eq!(tree.child(true).expect("expected child").key(), &[4]);
-        assert_eq!(tree.child(false).expect("expected child").key(), &[6]);
+        assert_eq!(tree.key().as_ref(), &[5]);
+        assert_eq!(
+            tree.child(true).expect("expected child").key().as_ref(),
+            &[4]
+        );
+        assert_eq!(
+            tree.child(false).expect("expected child").key().as_ref(),
+            &[6]
+        );
         Ok(())
     }
 
@@ -505,9 +835,15 @@ mod test {
         let batch = vec![(vec![7], Op::Put(vec![123]))];
         let tree = apply_memonly(tree, &batch);
 
-        assert_eq!(tree.key(), &[6]);
-        assert_eq!(tree.child(true).expect("expected child").key(), &[5]);
-        assert_eq!(tree.child(false).expect("expected child").key(), &[7]);
+        assert_eq!(tree.key().as_ref(), &[6]);
+        assert_eq!(
+            tree.child(true).expect("expected child").key().as_ref(),
+            &[5]
+        );
+        assert_eq!(
+            tree.child(false).expect("expected child").key().as_ref(),
+            &[7]
+        );
         Ok(())
     }
 
@@ -520,9 +856,15 @@ mod test {
             tree = apply_memonly(tree, &batch);
         }
 
-        assert_eq!(tree.key(), &[63]);
-        assert_eq!(tree.child(true).expect("expected child").key(), &[31]);
-        assert_eq!(tree.child(false).expect("expected child").key(), &[79]);
+        assert_eq!(tree.key().as_ref(), &[63]);
+        assert_eq!(
+            tree.child(true).expect("expected child").key().as_ref(),
+            &[31]
+        );
+        assert_eq!(
+            tree.child(false).expect("expected child").key().as_ref(),
+            &[79]
+        );
         Ok(())
     }
 
@@ -0,0 +1,170 @@
+//! Reuses the allocations of nodes dropped from the in-memory tree.
+//!
+//! Every node of the in-memory tree is a separate heap allocation holding the
+//! node's fields, whose value buffer is a second one. Applying a large batch
+//! fetches many nodes, then commits and prunes them again, so without reuse
+//! each apply/commit cycle allocates and frees every node it touches. While a
+//! `NodePool` is in scope (see `NodePool::scope`), nodes which are pruned or
+//! deleted are kept in the pool instead of being freed, and fetched or newly
+//! created nodes are built in them. Fetched nodes reuse both allocations,
+//! since their values are decoded into the pooled buffer. Newly created nodes
+//! only reuse the node allocation, since they take ownership of the value
+//! they are given, and the pooled buffer is freed.
+
+use std::cell::RefCell;
+use std::mem;
+
+use super::{Key, Tree, TreeInner, KV};
+
+/// Value buffers larger than this are freed rather than kept in the pool, so
+/// a few large values do not stay allocated.
+const MAX_POOLED_VALUE_CAPACITY: usize = 1024;
+
+thread_local! {
+    static POOL: RefCell<Option<NodePool>> = const { RefCell::new(None) };
+}
+
+/// A pool of node allocations, which keeps at most `capacity` nodes.
+pub struct NodePool {
+    nodes: Vec<Tree>,
+    capacity: usize,
+}
+
+impl NodePool {
+    /// Creates an empty pool which keeps at most `capacity` nodes.
+    pub fn new(capacity: usize) -> NodePool {
+        NodePool {
+            nodes: vec![],
+            capacity,
+        }
+    }
+
+    /// Returns the number of nodes in the pool.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if the pool has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the maximum number of nodes kept in the pool.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sets the maximum number of nodes kept in the pool, freeing any nodes
+    /// above it.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.nodes.truncate(capacity);
+    }
+
+    /// Runs `f` with the pool installed for the current thread, so nodes
+    /// dropped from trees while it runs are returned to the pool and new nodes
+    /// are taken from it. Pools do not nest: while `f` runs, an outer pool of
+    /// the same thread is not used.
+    pub fn scope<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        struct Restore<'a> {
+            pool: &'a mut NodePool,
+            outer: Option<NodePool>,
+        }
+
+        impl<'a> Drop for Restore<'a> {
+            fn drop(&mut self) {
+                let outer = self.outer.take();
+                if let Some(pool) = POOL.with(|cell| cell.replace(outer)) {
+                    *self.pool = pool;
+                }
+            }
+        }
+
+        let pool = mem::replace(self, NodePool::new(self.capacity));
+        let outer = POOL.with(|cell| cell.replace(Some(pool)));
+        let _restore = Restore { pool: self, outer };
+        f()
+    }
+}
+
+impl Default for NodePool {
+    fn default() -> NodePool {
+        NodePool::new(0)
+    }
+}
+
+/// Takes a node from the current thread's pool, if one is in scope and not
+/// empty. The node's fields are empty, except for the capacity of its value
+/// buffer.
+pub(crate) fn take() -> Option<Tree> {
+    POOL.with(|cell| cell.borrow_mut().as_mut().and_then(|pool| pool.nodes.pop()))
+}
+
+/// Returns a node to the current thread's pool, or drops it if no pool is in
+/// scope or the pool is full. The node's key is returned, since its
+/// allocation cannot be reused and callers often still need it.
+pub(crate) fn recycle(mut tree: Tree) -> Key {
+    let key = mem::take(&mut tree.inner.kv.key);
+    POOL.with(|cell| {
+        if let Some(pool) = cell.borrow_mut().as_mut() {
+            if pool.nodes.len() < pool.capacity {
+                clear(&mut tree.inner);
+                pool.nodes.push(tree);
+            }
+        }
+    });
+    key
+}
+
+fn clear(inner: &mut TreeInner) {
+    inner.left = None;
+    inner.right = None;
+    let mut value = mem::take(&mut inner.kv.value);
+    if value.capacity() > MAX_POOLED_VALUE_CAPACITY {
+        value = vec![];
+    }
+    value.clear();
+    inner.kv = KV::from_fields(vec![], value, Default::default());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reuses_nodes() {
+        let mut pool = NodePool::new(2);
+        pool.scope(|| {
+            let trees: Vec<_> = (0..3)
+                .map(|i| Tree::new(vec![i], vec![i; 10]).unwrap())
+                .collect();
+            for (i, tree) in trees.into_iter().enumerate() {
+                assert_eq!(recycle(tree).into_vec(), vec![i as u8]);
+            }
+            assert_eq!(take().unwrap().key(), &[] as &[u8]);
+            let tree = take().unwrap();
+            assert!(tree.key().is_empty());
+            assert!(tree.value().is_empty());
+            assert!(tree.link(true).is_none());
+            assert!(tree.inner.kv.value.capacity() >= 10);
+        });
+        assert_eq!(pool.len(), 0);
+
+        // nodes are freed outside of a scope
+        recycle(Tree::new(vec![0], vec![0]).unwrap());
+        assert!(take().is_none());
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn nested_scopes() {
+        let mut outer = NodePool::new(10);
+        let mut inner = NodePool::new(10);
+        outer.scope(|| {
+            inner.scope(|| recycle(Tree::new(vec![1], vec![]).unwrap()));
+            recycle(Tree::new(vec![2], vec![]).unwrap());
+        });
+        assert_eq!(inner.len(), 1);
+        assert_eq!(outer.len(), 1);
+    }
+}
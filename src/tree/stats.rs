@@ -0,0 +1,63 @@
+//! Counts the work done by tree operations on the current thread, for reports
+//! such as `Merk::apply_explain`.
+
+use std::cell::Cell;
+
+thread_local! {
+    static COUNTS: Cell<Option<OpCounts>> = const { Cell::new(None) };
+}
+
+/// The number of times each kind of work was done while `count` ran.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpCounts {
+    /// Pruned nodes fetched from the backing store.
+    pub nodes_loaded: usize,
+    /// Nodes created for keys which were not in the tree.
+    pub nodes_created: usize,
+    /// AVL rotations done to rebalance the tree (a double rotation counts as
+    /// two).
+    pub rotations: usize,
+}
+
+/// Runs `f` and returns the work done by tree operations on the current thread
+/// while it ran. Work counted by a nested call is also counted by the outer
+/// one.
+pub fn count<T>(f: impl FnOnce() -> T) -> (T, OpCounts) {
+    struct Restore(Option<OpCounts>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let inner = COUNTS.with(|cell| cell.replace(self.0));
+            if let (Some(outer), Some(inner)) = (self.0, inner) {
+                COUNTS.with(|cell| cell.set(Some(outer.add(inner))));
+            }
+        }
+    }
+
+    let restore = Restore(COUNTS.with(|cell| cell.replace(Some(OpCounts::default()))));
+    let value = f();
+    let counts = COUNTS.with(|cell| cell.get()).unwrap_or_default();
+    drop(restore);
+    (value, counts)
+}
+
+/// Records work done by a tree operation, if it is being counted.
+#[inline]
+pub(crate) fn record(f: impl FnOnce(&mut OpCounts)) {
+    COUNTS.with(|cell| {
+        if let Some(mut counts) = cell.get() {
+            f(&mut counts);
+            cell.set(Some(counts));
+        }
+    })
+}
+
+impl OpCounts {
+    fn add(self, other: OpCounts) -> OpCounts {
+        OpCounts {
+            nodes_loaded: self.nodes_loaded + other.nodes_loaded,
+            nodes_created: self.nodes_created + other.nodes_created,
+            rotations: self.rotations + other.rotations,
+        }
+    }
+}
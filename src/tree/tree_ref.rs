@@ -0,0 +1,298 @@
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::panic,
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::indexing_slicing
+    )
+)]
+
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+use super::encoding::{
+    read_slice, read_varint, strip_checksum, strip_flags, take, take_array, COMPACT_TAG,
+    INLINED_TAG, SEPARATED_TAG,
+};
+use super::hash::{node_hash, Hash, Hasher, NULL_HASH};
+use crate::error::{Error, Result};
+
+/// A borrowed view of an encoded node, which reads the node's fields in place
+/// rather than decoding them into a `Tree`.
+///
+/// Decoding a `Tree` allocates the node, its key, value and child keys, which
+/// is wasted work on read paths which only look at a few fields of each node
+/// before moving on. A `TreeRef` can be decoded from any record written by
+/// `Tree::encode_record_into`, including a pinned slice straight from the
+/// database, and only allocates for child keys stored in the compact encoding.
+pub struct TreeRef<'a> {
+    key: &'a [u8],
+    left: Option<LinkRef<'a>>,
+    right: Option<LinkRef<'a>>,
+    kv_hash: &'a Hash,
+    value: Option<&'a [u8]>,
+    flags: u8,
+    inlined: [Option<&'a [u8]>; 2],
+}
+
+/// A borrowed view of a node's link to one of its children.
+pub struct LinkRef<'a> {
+    key: Cow<'a, [u8]>,
+    hash: &'a Hash,
+    child_heights: (u8, u8),
+}
+
+impl<'a> TreeRef<'a> {
+    /// Decodes a view of the node with the given key from its encoding.
+    pub fn decode(key: &'a [u8], bytes: &'a [u8]) -> Result<TreeRef<'a>> {
+        let (flags, bytes) = strip_flags(strip_checksum(key, bytes)?)?;
+        let (separated, bytes) = match bytes.split_first() {
+            Some((&SEPARATED_TAG, rest)) => (true, rest),
+            _ => (false, bytes),
+        };
+
+        let mut node = match bytes.split_first() {
+            Some((&INLINED_TAG, mut rest)) => {
+                let mut node = TreeRef::decode_node(key, read_slice(&mut rest)?)?;
+                while let Some((&side, tail)) = rest.split_first() {
+                    rest = tail;
+                    let [right, left] = &mut node.inlined;
+                    *(if side != 0 { left } else { right }) = Some(read_slice(&mut rest)?);
+                }
+                node
+            }
+            _ => TreeRef::decode_node(key, bytes)?,
+        };
+
+        if separated {
+            node.value = None;
+        }
+        node.flags = flags;
+        Ok(node)
+    }
+
+    fn decode_node(key: &'a [u8], mut input: &'a [u8]) -> Result<TreeRef<'a>> {
+        let (left, right) = match input.split_first() {
+            Some((&tag, rest)) if tag & !3 == COMPACT_TAG => {
+                input = rest;
+                let left = (tag & 1 != 0)
+                    .then(|| LinkRef::decode_compact(key, &mut input))
+                    .transpose()?;
+                let right = (tag & 2 != 0)
+                    .then(|| LinkRef::decode_compact(key, &mut input))
+                    .transpose()?;
+                (left, right)
+            }
+            _ => {
+                let left = LinkRef::decode_option(&mut input)?;
+                let right = LinkRef::decode_option(&mut input)?;
+                (left, right)
+            }
+        };
+
+        Ok(TreeRef {
+            key,
+            left,
+            right,
+            kv_hash: take_hash(&mut input)?,
+            value: Some(input),
+            flags: 0,
+            inlined: [None, None],
+        })
+    }
+
+    /// Returns the node's key.
+    #[inline]
+    pub fn key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    /// Returns the node's value, or `None` if it is stored apart from the
+    /// node's record (see `Tree::encode_separated_into`).
+    #[inline]
+    pub fn value(&self) -> Option<&'a [u8]> {
+        self.value
+    }
+
+    /// Returns the node's flags, see `tree::flags`.
+    #[inline]
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Returns the hash of the node's key/value pair.
+    #[inline]
+    pub fn kv_hash(&self) -> &'a Hash {
+        self.kv_hash
+    }
+
+    /// Returns the link to the child on the given side, if any.
+    #[inline]
+    pub fn link(&self, left: bool) -> Option<&LinkRef<'a>> {
+        if left {
+            self.left.as_ref()
+        } else {
+            self.right.as_ref()
+        }
+    }
+
+    /// Returns the encoding of the child on the given side if it was inlined
+    /// into the node's record, which can be decoded with the key of the link.
+    #[inline]
+    pub fn inlined(&self, left: bool) -> Option<&'a [u8]> {
+        let [right_inlined, left_inlined] = self.inlined;
+        if left {
+            left_inlined
+        } else {
+            right_inlined
+        }
+    }
+
+    /// Computes the hash of the node.
+    pub fn hash(&self) -> Hash {
+        let child_hash = |link: Option<&LinkRef>| link.map_or(NULL_HASH, |link| *link.hash);
+        node_hash::<Hasher>(
+            self.kv_hash,
+            &child_hash(self.link(true)),
+            &child_hash(self.link(false)),
+        )
+    }
+}
+
+impl<'a> LinkRef<'a> {
+    /// Decodes a link as encoded by `Option<Link>`.
+    fn decode_option(input: &mut &'a [u8]) -> Result<Option<LinkRef<'a>>> {
+        let [tag] = take_array(input)?;
+        match tag {
+            0 => Ok(None),
+            1 => {
+                let [length] = take_array(input)?;
+                let key = Cow::Borrowed(take(input, length as usize)?);
+                LinkRef::decode_rest(key, input).map(Some)
+            }
+            byte => Err(ed::Error::UnexpectedByte(byte).into()),
+        }
+    }
+
+    /// Decodes a link from the compact encoding, whose key is stored as a
+    /// suffix of the prefix it shares with `parent_key`.
+    fn decode_compact(parent_key: &[u8], input: &mut &'a [u8]) -> Result<LinkRef<'a>> {
+        let shared = read_varint(input)?;
+        let suffix_length = read_varint(input)?;
+        let prefix = parent_key
+            .get(..shared)
+            .ok_or_else(|| Error::Decode("Invalid shared key prefix length".into()))?;
+        let suffix = take(input, suffix_length)?;
+        let key = if prefix.is_empty() {
+            Cow::Borrowed(suffix)
+        } else {
+            Cow::Owned([prefix, suffix].concat())
+        };
+        LinkRef::decode_rest(key, input)
+    }
+
+    fn decode_rest(key: Cow<'a, [u8]>, input: &mut &'a [u8]) -> Result<LinkRef<'a>> {
+        let hash = take_hash(input)?;
+        let [left_height, right_height] = take_array(input)?;
+        Ok(LinkRef {
+            key,
+            hash,
+            child_heights: (left_height, right_height),
+        })
+    }
+
+    /// Returns the key of the child.
+    #[inline]
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the hash of the child.
+    #[inline]
+    pub fn hash(&self) -> &'a Hash {
+        self.hash
+    }
+
+    /// Returns the heights of the child's children, like
+    /// `Link::child_heights`.
+    #[inline]
+    pub fn child_heights(&self) -> (u8, u8) {
+        self.child_heights
+    }
+}
+
+fn take_hash<'a>(input: &mut &'a [u8]) -> Result<&'a Hash> {
+    take(input, 32)?
+        .try_into()
+        .map_err(|_| Error::Decode("Invalid hash length".into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{NoopCommit, RecordFormat, Tree};
+    use super::*;
+
+    fn make_tree() -> Tree {
+        let mut tree = Tree::new(b"key/5".to_vec(), vec![5; 20])
+            .unwrap()
+            .attach(true, Some(Tree::new(b"key/3".to_vec(), vec![3]).unwrap()))
+            .attach(false, Some(Tree::new(b"other".to_vec(), vec![7]).unwrap()));
+        tree.commit(&mut NoopCommit {}).unwrap();
+        tree
+    }
+
+    #[test]
+    fn matches_decoded_tree() {
+        let tree = make_tree();
+        for inline_child_length in [0, 100] {
+            for separate_value in [false, true] {
+                for compact in [false, true] {
+                    let format = RecordFormat {
+                        inline_child_length,
+                        separate_value,
+                        compact,
+                        ..Default::default()
+                    };
+                    let mut bytes = vec![];
+                    tree.encode_record_into(format, &mut bytes);
+
+                    let key = tree.key();
+                    let node = TreeRef::decode(&key, &bytes).unwrap();
+                    assert_eq!(node.key(), key.as_ref());
+                    assert_eq!(node.kv_hash(), tree.kv_hash());
+                    assert_eq!(node.hash(), tree.hash());
+                    assert_eq!(node.value().is_none(), separate_value);
+                    if !separate_value {
+                        assert_eq!(node.value().unwrap(), tree.value());
+                    }
+
+                    for left in [true, false] {
+                        let link = node.link(left).unwrap();
+                        let expected = tree.link(left).unwrap();
+                        assert_eq!(link.key(), expected.key().as_ref());
+                        assert_eq!(link.hash(), expected.hash());
+                        assert_eq!(link.child_heights(), expected.child_heights());
+
+                        let inlined = node.inlined(left).unwrap_or_default();
+                        assert_eq!(inlined.is_empty(), inline_child_length == 0);
+                        if !inlined.is_empty() {
+                            let child = TreeRef::decode(link.key(), inlined).unwrap();
+                            assert_eq!(child.hash(), *expected.hash());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let tree = make_tree();
+        let bytes = tree.encode();
+        for len in 0..34 {
+            assert!(TreeRef::decode(&tree.key(), &bytes[..len]).is_err());
+        }
+        assert!(TreeRef::decode(&tree.key(), &[9]).is_err());
+    }
+}
@@ -1,20 +1,88 @@
+use std::sync::Arc;
+
 use super::super::{Link, Tree};
 use crate::error::{Error, Result};
 
 /// A source of data to be used by the tree when encountering a pruned node.
 /// This typcially means fetching the tree node from a backing store by its key,
 /// but could also implement an in-memory cache for example.
+///
+/// `Walker` and `RefWalker` drive traversals and batch applications over any
+/// source, so trees can be kept in storage other than a `Merk` store, e.g. a
+/// light client's partial tree whose nodes are requested from a full node.
+/// Only `fetch_by_key` must be implemented. Nodes are usually decoded with
+/// `Tree::decode`, which takes the node's key since encodings do not include
+/// it.
+///
+/// The trait is object safe, and is implemented for references, `Box`es and
+/// `Arc`s of sources, so a walker can use a `DynSource` instead of a concrete
+/// type.
 pub trait Fetch {
+    /// Returns the node with the given key, or `None` if the source does not
+    /// have it.
     fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>>;
 
     /// Called when the tree needs to fetch a node with the given `Link`. The
     /// `link` value will always be a `Link::Reference` variant.
     fn fetch(&self, link: &Link) -> Result<Tree> {
-        self.fetch_by_key_expect(link.key())
+        self.fetch_by_key_expect(&link.key())
+    }
+
+    /// Returns the value of a node fetched without it, see `Tree::decode_lazy`.
+    /// Sources which always fetch nodes with their values do not need to
+    /// implement it.
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::Fetch(format!(
+            "Value of node {:?} is not loaded",
+            key
+        )))
     }
 
+    /// Returns the node with the given key, failing with
+    /// `Error::InvariantViolation` if the source does not have it.
     fn fetch_by_key_expect(&self, key: &[u8]) -> Result<Tree> {
         self.fetch_by_key(key)?
-            .ok_or_else(|| Error::Key(format!("Key does not exist: {key:?}")))
+            .ok_or_else(|| Error::InvariantViolation {
+                key: key.to_vec(),
+                detail: "Referenced node does not exist".into(),
+            })
     }
 }
+
+/// A source which can be shared between threads without naming its type, for
+/// `Walker<DynSource>`.
+pub type DynSource = Arc<dyn Fetch + Send + Sync>;
+
+macro_rules! forward_fetch {
+    ($($ty:ty),*) => {
+        $(impl<F: Fetch + ?Sized> Fetch for $ty {
+            fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
+                (**self).fetch_by_key(key)
+            }
+
+            fn fetch(&self, link: &Link) -> Result<Tree> {
+                (**self).fetch(link)
+            }
+
+            fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>> {
+                (**self).fetch_value(key)
+            }
+        })*
+    };
+}
+
+forward_fetch!(&F, Box<F>, Arc<F>);
+
+/// A source of encoded nodes which can be read in place as `TreeRef`s, for
+/// read paths which do not need to load nodes into a `Tree`.
+pub trait FetchBytes {
+    /// The encoded node, e.g. a slice pinned in the database's cache.
+    type Bytes: AsRef<[u8]>;
+
+    /// Returns the encoding of the node with the given key, if it exists.
+    fn fetch_bytes(&self, key: &[u8]) -> Result<Option<Self::Bytes>>;
+
+    /// Returns the value of a node which is stored apart from the node's
+    /// encoding.
+    fn fetch_value(&self, key: &[u8]) -> Result<Vec<u8>>;
+}
@@ -1,10 +1,18 @@
+//! Traversal of trees whose nodes may be pruned from memory, fetching them
+//! from a `Fetch` source as they are reached.
+//!
+//! `Walker` takes ownership of the nodes it walks, so it is what batches are
+//! applied with (see `Walker::apply_to`), while `RefWalker` walks a borrowed
+//! tree, e.g. to build proofs. Both work with any source, so trees can be
+//! kept in storage of the caller's own.
+
 mod fetch;
 mod ref_walker;
 
-use super::{Link, Tree};
-use crate::error::Result;
+use super::{side_to_str, stats, Link, Tree};
+use crate::error::{Error, Result};
 use crate::owner::Owner;
-pub use fetch::Fetch;
+pub use fetch::{DynSource, Fetch, FetchBytes};
 pub use ref_walker::RefWalker;
 
 /// Allows traversal of a `Tree`, fetching from the given source when traversing
@@ -48,6 +56,7 @@ where
                 Some(Link::Reference { .. }) => (),
                 _ => unreachable!("Expected Some(Link::Reference)"),
             }
+            stats::record(|counts| counts.nodes_loaded += 1);
             self.source.fetch(&link.unwrap())?
         };
 
@@ -62,10 +71,10 @@ where
         if let Some(child) = maybe_child {
             Ok((walker, child))
         } else {
-            panic!(
-                "Expected {} child, got None",
-                if left { "left" } else { "right" }
-            );
+            Err(Error::InvariantViolation {
+                key: walker.tree().key().to_vec(),
+                detail: format!("Expected {} child, got None", side_to_str(left)),
+            })
         }
     }
 
@@ -132,6 +141,17 @@ where
         self.tree.own_fallible(|t| t.with_value(value))?;
         Ok(self)
     }
+
+    /// Similar to `Tree#with_flags`, loading the node's value from the source
+    /// first if it is not loaded.
+    pub fn with_flags(mut self, flags: u8) -> Result<Self> {
+        let source = &self.source;
+        self.tree.own_fallible(|mut t| -> Result<Tree> {
+            t.load_value_with(|key| source.fetch_value(key))?;
+            t.with_flags(flags)
+        })?;
+        Ok(self)
+    }
 }
 
 impl<S> From<Walker<S>> for Tree
@@ -168,7 +188,10 @@ mod test {
 
         let walker = walker
             .walk(true, |child| -> Result<Option<Tree>> {
-                assert_eq!(child.expect("should have child").tree().key(), b"foo");
+                assert_eq!(
+                    child.expect("should have child").tree().key().as_ref(),
+                    b"foo"
+                );
                 Ok(None)
             })
             .expect("walk failed");
@@ -187,7 +210,10 @@ mod test {
 
         let walker = walker
             .walk(true, |child| -> Result<Option<Tree>> {
-                assert_eq!(child.expect("should have child").tree().key(), b"foo");
+                assert_eq!(
+                    child.expect("should have child").tree().key().as_ref(),
+                    b"foo"
+                );
                 Ok(None)
             })
             .expect("walk failed");
@@ -203,7 +229,7 @@ mod test {
             Default::default(),
             Some(Link::Reference {
                 hash: Default::default(),
-                key: b"foo".to_vec(),
+                key: b"foo".to_vec().into(),
                 child_heights: (0, 0),
             }),
             None,
@@ -214,7 +240,7 @@ mod test {
 
         let walker = walker
             .walk_expect(true, |child| -> Result<Option<Tree>> {
-                assert_eq!(child.tree().key(), b"foo");
+                assert_eq!(child.tree().key().as_ref(), b"foo");
                 Ok(None)
             })
             .expect("walk failed");
@@ -236,4 +262,95 @@ mod test {
             .expect("walk failed");
         Ok(())
     }
+
+    #[test]
+    fn detach_expect_none() -> Result<()> {
+        let tree = Tree::new(b"test".to_vec(), b"abc".to_vec())?;
+        let walker = Walker::new(tree, MockSource {});
+
+        match walker.detach_expect(false) {
+            Err(Error::InvariantViolation { key, .. }) => assert_eq!(key, b"test"),
+            _ => panic!("expected invariant violation"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn walk_dyn_source() -> Result<()> {
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        use crate::tree::{Commit, Op, PanicSource};
+
+        struct MapCommit(HashMap<Vec<u8>, Vec<u8>>);
+
+        impl Commit for MapCommit {
+            fn write(&mut self, tree: &Tree) -> Result<()> {
+                self.0.insert(tree.key().to_vec(), tree.encode());
+                Ok(())
+            }
+        }
+
+        // e.g. nodes requested from a full node
+        struct MapSource(HashMap<Vec<u8>, Vec<u8>>);
+
+        impl Fetch for MapSource {
+            fn fetch_by_key(&self, key: &[u8]) -> Result<Option<Tree>> {
+                self.0
+                    .get(key)
+                    .map(|bytes| Tree::decode(key.to_vec(), bytes))
+                    .transpose()
+            }
+        }
+
+        let apply = |maybe_tree: Option<Tree>, batch: &[(Vec<u8>, Op)], source: DynSource| {
+            let maybe_walker = maybe_tree.map(|tree| Walker::new(tree, source.clone()));
+            Ok::<_, Error>(Walker::apply_to(maybe_walker, batch, source)?.0.unwrap())
+        };
+        let batch: Vec<_> = (0..20).map(|i| (vec![i], Op::Put(vec![i]))).collect();
+        let mut expected = apply(None, &batch, Arc::new(PanicSource {}))?;
+        expected.commit(&mut NoopCommit {})?;
+        let mut tree = apply(None, &batch, Arc::new(PanicSource {}))?;
+        let mut commit = MapCommit(HashMap::new());
+        tree.commit(&mut commit)?;
+        assert!(tree.link(true).unwrap().is_reference());
+
+        let batch = [(vec![3], Op::Delete), (vec![17], Op::Put(vec![0]))];
+        let mut expected = apply(Some(expected), &batch, Arc::new(PanicSource {}))?;
+        expected.commit(&mut NoopCommit {})?;
+        let mut tree = apply(Some(tree), &batch, Arc::new(MapSource(commit.0)))?;
+        tree.commit(&mut NoopCommit {})?;
+        assert_eq!(tree.hash(), expected.hash());
+        Ok(())
+    }
+
+    #[test]
+    fn walk_missing_node() {
+        #[derive(Clone)]
+        struct EmptySource {}
+
+        impl Fetch for EmptySource {
+            fn fetch_by_key(&self, _key: &[u8]) -> Result<Option<Tree>> {
+                Ok(None)
+            }
+        }
+
+        let tree = Tree::from_fields(
+            b"test".to_vec(),
+            b"abc".to_vec(),
+            Default::default(),
+            Some(Link::Reference {
+                hash: Default::default(),
+                key: b"foo".to_vec().into(),
+                child_heights: (0, 0),
+            }),
+            None,
+        );
+        let walker = Walker::new(tree, EmptySource {});
+
+        match walker.detach(true) {
+            Err(Error::InvariantViolation { key, .. }) => assert_eq!(key, b"foo"),
+            _ => panic!("expected invariant violation"),
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use super::super::{Link, Tree};
 use super::Fetch;
-use crate::error::Result;
+use crate::error::{Error, Result};
 
 /// Allows read-only traversal of a `Tree`, fetching from the given source when
 /// traversing to a pruned node. The fetched nodes are then retained in memory
@@ -31,10 +31,15 @@ where
         self.tree
     }
 
+    /// Returns the source pruned nodes are fetched from.
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
     /// Traverses to the child on the given side (if any), fetching from the
     /// source if pruned. When fetching, the link is upgraded from
     /// `Link::Reference` to `Link::Loaded`.
-    pub fn walk(&mut self, left: bool) -> Result<Option<RefWalker<S>>> {
+    pub fn walk(&mut self, left: bool) -> Result<Option<RefWalker<'_, S>>> {
         let link = match self.tree.link(left) {
             None => return Ok(None),
             Some(link) => link,
@@ -44,7 +49,12 @@ where
             Link::Reference { .. } => {
                 self.tree.load(left, &self.source)?;
             }
-            Link::Modified { .. } => panic!("Cannot traverse Link::Modified"),
+            Link::Modified { .. } => {
+                return Err(Error::InvariantViolation {
+                    key: link.key().to_vec(),
+                    detail: "Cannot traverse Link::Modified".into(),
+                })
+            }
             Link::Uncommitted { .. } | Link::Loaded { .. } => {}
         }
 